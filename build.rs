@@ -5,7 +5,7 @@ fn main() {
     let out_dir = var("OUT_DIR").unwrap();
 
     let cmd = Command::new("gcc")
-        .args(&[
+        .args([
             "-fPIC",
             "-shared",
             "-o",
@@ -0,0 +1,17 @@
+#!/bin/sh
+# Compare cold evaluation time with buffered and unbuffered trace output.
+
+expr=${1:-'with import <nixpkgs> {}; mkShell { buildInputs = [ hello ]; }'}
+
+bench() {
+	printf '%s: ' "$1"
+	env LD_PRELOAD=$PWD/build/trace-nix.so \
+		TRACE_NIX=/dev/null TRACE_NIX_BUFFER="$2" \
+		time -f "%e s" -- nix-shell --pure --run : -E "$expr" 2>&1 >/dev/null |
+		tail -n1
+}
+
+for _ in 1 2 3; do
+	bench unbuffered 0
+	bench buffered 65536
+done
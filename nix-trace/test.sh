@@ -100,6 +100,64 @@ run 'builtins.readDir ./test-tmp/many-dirs'
 check builtins.readDir-many-dirs \
 	"d$PWD/test-tmp/many-dirs" "$(dir_md5sum ./test-tmp/many-dirs)"
 
+# Records buffered before fork() and ones written by a child that calls
+# _exit() are not lost.
+cat > test-tmp/fork.c <<'EOF'
+#include <fcntl.h>
+#include <sys/wait.h>
+#include <unistd.h>
+int main(int argc, char **argv) {
+	open(argv[1], O_RDONLY|O_CLOEXEC);
+	if (fork() == 0) {
+		open(argv[2], O_RDONLY|O_CLOEXEC);
+		_exit(0);
+	}
+	wait(NULL);
+	return 0;
+}
+EOF
+gcc -o test-tmp/fork test-tmp/fork.c
+rm -f test-tmp/log
+LD_PRELOAD=$PWD/build/trace-nix.so TRACE_NIX=test-tmp/log \
+	test-tmp/fork "$PWD/test-tmp/test.nix" "$PWD/test-tmp/empty"
+check fork-parent \
+	"f$PWD/test-tmp/test.nix" \
+	"$(md5sum ./test-tmp/test.nix | head -c 32)"
+check fork-child \
+	"f$PWD/test-tmp/empty" \
+	"$(md5sum ./test-tmp/empty | head -c 32)"
+
+# Records buffered before exec() are not lost, nor ones of a thread that
+# exited.
+cat > test-tmp/exec.c <<'EOF'
+#include <fcntl.h>
+#include <pthread.h>
+#include <unistd.h>
+static void *read_file(void *path) {
+	open(path, O_RDONLY|O_CLOEXEC);
+	return NULL;
+}
+int main(int argc, char **argv) {
+	pthread_t thread;
+	pthread_create(&thread, NULL, read_file, argv[2]);
+	pthread_join(thread, NULL);
+	open(argv[1], O_RDONLY|O_CLOEXEC);
+	char *args[] = { "true", NULL };
+	execvp(args[0], args);
+	return 1;
+}
+EOF
+gcc -o test-tmp/exec test-tmp/exec.c -lpthread
+rm -f test-tmp/log
+LD_PRELOAD=$PWD/build/trace-nix.so TRACE_NIX=test-tmp/log \
+	test-tmp/exec "$PWD/test-tmp/test.nix" "$PWD/test-tmp/empty"
+check exec \
+	"f$PWD/test-tmp/test.nix" \
+	"$(md5sum ./test-tmp/test.nix | head -c 32)"
+check exec-thread \
+	"f$PWD/test-tmp/empty" \
+	"$(md5sum ./test-tmp/empty | head -c 32)"
+
 run_without_p
 check implicit:shell.nix \
 	"s$PWD/shell.nix" "-"
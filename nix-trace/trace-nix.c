@@ -7,6 +7,7 @@
 #include <limits.h>
 #include <openssl/md5.h>
 #include <pthread.h>
+#include <spawn.h>
 #include <stdarg.h>
 #include <stdio.h>
 #include <stdlib.h>
@@ -14,15 +15,39 @@
 #include <sys/mman.h>
 #include <sys/stat.h>
 #include <sys/types.h>
+#include <sys/uio.h>
 #include <unistd.h>
 
-static pthread_mutex_t mutex = PTHREAD_RECURSIVE_MUTEX_INITIALIZER_NP;
-static FILE *log_f = NULL;
+// Records of a thread, written to the log in whole records.  The lock is
+// only contended when another thread flushes every buffer.
+struct buffer {
+	pthread_mutex_t lock;
+	struct buffer *next;
+	size_t len;
+	char data[];
+};
+
+static int log_fd = -1;
 static const char *pwd = NULL;
+static size_t buffer_size = 0; // 0: each record is written immediately
+static pthread_key_t buffer_key;
+static __thread struct buffer *buffer = NULL;
+static pthread_mutex_t buffers_lock = PTHREAD_MUTEX_INITIALIZER;
+static struct buffer *buffers = NULL;
 
 static int (*real___lxstat)(int ver, const char *path, struct stat *buf) = NULL;
 static int (*real_open)(const char *path, int flags, ...) = NULL;
 static DIR *(*real_opendir)(const char *name) = NULL;
+static int (*real_execve)(const char *, char *const [], char *const []) = NULL;
+static int (*real_execv)(const char *, char *const []) = NULL;
+static int (*real_execvp)(const char *, char *const []) = NULL;
+static int (*real_execvpe)(const char *, char *const [], char *const []) = NULL;
+static int (*real_posix_spawn)(pid_t *, const char *,
+	const posix_spawn_file_actions_t *, const posix_spawnattr_t *,
+	char *const [], char *const []) = NULL;
+static int (*real_posix_spawnp)(pid_t *, const char *,
+	const posix_spawn_file_actions_t *, const posix_spawnattr_t *,
+	char *const [], char *const []) = NULL;
 
 #define REAL(FUN) \
 	(real_##FUN == NULL ? (real_##FUN = dlsym(RTLD_NEXT, #FUN)) : real_##FUN)
@@ -36,9 +61,15 @@ static DIR *(*real_opendir)(const char *name) = NULL;
 
 // Predeclarations
 
+static void atfork_child();
+static void atfork_parent();
+static void atfork_prepare();
+static void buffer_exit(void *);
 static void dir_md5sum(char [static 33], DIR *);
 static int enable(const char *);
 static void file_md5sum(char [static 33], int);
+static void flush(struct buffer *);
+static void flush_all();
 static void md5_convert_digest(char [static 33], const unsigned char [static 16]);
 static void print_log(char, const char *, const char *);
 static int strcmp_qsort(const void *, const void *);
@@ -52,8 +83,11 @@ static void __attribute__((constructor)) init() {
 
 	const char *fname = getenv("TRACE_NIX");
 	if (fname != NULL) {
-		log_f = fopen(fname, "w");
-		if (log_f == NULL) {
+		// O_APPEND: writes of whole records from several threads, or from
+		// forked children, don't overwrite or split each other.
+		log_fd = REAL(open)(fname, O_WRONLY|O_CREAT|O_TRUNC|O_APPEND|O_CLOEXEC,
+			0666);
+		if (log_fd == -1) {
 			fprintf(stderr, "trace-nix: can't open file %s: %s\n", fname,
 				strerror(errno));
 			errno = 0;
@@ -63,11 +97,92 @@ static void __attribute__((constructor)) init() {
 			FATAL();
 	}
 	unsetenv("TRACE_NIX");
+
+	// Records are buffered per thread to save a write() per traced call;
+	// TRACE_NIX_BUFFER=0 writes each record immediately.
+	const char *size = getenv("TRACE_NIX_BUFFER");
+	buffer_size = size == NULL ? 65536 : strtoul(size, NULL, 10);
+	if (log_fd != -1) {
+		if (pthread_key_create(&buffer_key, buffer_exit) != 0)
+			FATAL();
+		if (pthread_atfork(atfork_prepare, atfork_parent, atfork_child) != 0)
+			FATAL();
+	}
+	unsetenv("TRACE_NIX_BUFFER");
+}
+
+static void __attribute__((destructor)) fini() {
+	if (log_fd != -1)
+		flush_all();
+}
+
+// Flush before fork(), so the child doesn't inherit (and write again) records
+// of the parent.  The child may _exit() without flushing, so it writes each
+// record immediately.
+static void atfork_prepare() {
+	pthread_mutex_lock(&buffers_lock);
+	for (struct buffer *b = buffers; b; b = b->next) {
+		pthread_mutex_lock(&b->lock);
+		flush(b);
+	}
+}
+
+static void atfork_parent() {
+	for (struct buffer *b = buffers; b; b = b->next)
+		pthread_mutex_unlock(&b->lock);
+	pthread_mutex_unlock(&buffers_lock);
+}
+
+static void atfork_child() {
+	// The locks are owned by the parent's thread, so they can't be unlocked.
+	static const pthread_mutex_t unlocked = PTHREAD_MUTEX_INITIALIZER;
+	for (struct buffer *b = buffers; b; b = b->next)
+		b->lock = unlocked;
+	buffers_lock = unlocked;
+	buffer_size = 0;
+}
+
+// exec() discards the buffers, so they are written first.  posix_spawn()
+// doesn't, but the child may inherit the log file too.
+int execve(const char *path, char *const argv[], char *const envp[]) {
+	flush_all();
+	return REAL(execve)(path, argv, envp);
+}
+
+int execv(const char *path, char *const argv[]) {
+	flush_all();
+	return REAL(execv)(path, argv);
+}
+
+int execvp(const char *file, char *const argv[]) {
+	flush_all();
+	return REAL(execvp)(file, argv);
+}
+
+int execvpe(const char *file, char *const argv[], char *const envp[]) {
+	flush_all();
+	return REAL(execvpe)(file, argv, envp);
+}
+
+int posix_spawn(pid_t *pid, const char *path,
+		const posix_spawn_file_actions_t *file_actions,
+		const posix_spawnattr_t *attrp,
+		char *const argv[], char *const envp[]) {
+	flush_all();
+	return REAL(posix_spawn)(pid, path, file_actions, attrp, argv, envp);
+}
+
+int posix_spawnp(pid_t *pid, const char *file,
+		const posix_spawn_file_actions_t *file_actions,
+		const posix_spawnattr_t *attrp,
+		char *const argv[], char *const envp[]) {
+	flush_all();
+	return REAL(posix_spawnp)(pid, file, file_actions, attrp, argv, envp);
 }
 
 int __lxstat(int ver, const char *path, struct stat *sb) {
-	static char *buf = NULL;
-	static size_t buf_len = 0;
+	static __thread char *buf = NULL;
+	static __thread size_t buf_len = 0;
 
 	int result = REAL(__lxstat)(ver, path, sb);
 
@@ -75,7 +190,6 @@ int __lxstat(int ver, const char *path, struct stat *sb) {
 		if (result != 0) {
 			print_log('s', path, "-");
 		} else if (S_ISLNK(sb->st_mode)) {
-			pthread_mutex_lock(&mutex);
 			if (buf_len < sb->st_size + 1) {
 				buf_len = sb->st_size + 1;
 				buf = realloc(buf, buf_len);
@@ -87,7 +201,6 @@ int __lxstat(int ver, const char *path, struct stat *sb) {
 				FATAL();
 			buf[sb->st_size] = 0;
 			print_log('s', path, buf);
-			pthread_mutex_unlock(&mutex);
 		} else {
 			print_log('s', path, "+");
 		}
@@ -133,7 +246,7 @@ DIR *opendir(const char *path) {
 ////////////////////////////////////////////////////////////////////////////////
 
 static int enable(const char *path) {
-	if (log_f == NULL || (*path != '/' && strcmp(path, "shell.nix")))
+	if (log_fd == -1 || (*path != '/' && strcmp(path, "shell.nix")))
 		return 0;
 
 	static const char *ignored_paths[] = {
@@ -160,17 +273,91 @@ static int enable(const char *path) {
 }
 
 static void print_log(char op, const char *path, const char *result) {
-	pthread_mutex_lock(&mutex);
-	fprintf(
-		log_f,
-		"%c" "%s%s" "%s%c" "%s%c",
-		op,
-		path[0] == '/' ? "" : pwd, path[0] == '/' ? "" : "/",
-		path, (char)0,
-		result, (char)0
-	);
-	fflush(log_f);
-	pthread_mutex_unlock(&mutex);
+	int relative = path[0] != '/';
+	struct iovec record[] = {
+		{ &op, 1 },
+		{ (char *)pwd, relative ? strlen(pwd) : 0 },
+		{ "/", relative ? 1 : 0 },
+		{ (char *)path, strlen(path) + 1 },
+		{ (char *)result, strlen(result) + 1 },
+	};
+	size_t len = 0;
+	for (int i = 0; i < 5; i++)
+		len += record[i].iov_len;
+
+	if (buffer == NULL && buffer_size != 0) {
+		buffer = malloc(sizeof(struct buffer) + buffer_size);
+		if (buffer == NULL)
+			FATAL();
+		pthread_mutex_init(&buffer->lock, NULL);
+		buffer->len = 0;
+		pthread_mutex_lock(&buffers_lock);
+		buffer->next = buffers;
+		buffers = buffer;
+		pthread_mutex_unlock(&buffers_lock);
+		if (pthread_setspecific(buffer_key, buffer) != 0)
+			FATAL();
+	}
+
+	if (buffer != NULL)
+		pthread_mutex_lock(&buffer->lock);
+	if (buffer != NULL && buffer->len + len > buffer_size)
+		flush(buffer);
+	if (buffer != NULL && len <= buffer_size) {
+		for (int i = 0; i < 5; i++) {
+			memcpy(buffer->data + buffer->len, record[i].iov_base,
+				record[i].iov_len);
+			buffer->len += record[i].iov_len;
+		}
+	} else {
+		// One writev() per record, so that it isn't split.
+		if (writev(log_fd, record, 5) != (ssize_t)len)
+			FATAL();
+	}
+	if (buffer != NULL)
+		pthread_mutex_unlock(&buffer->lock);
+}
+
+// Write the records of `b`, which is locked.
+static void flush(struct buffer *b) {
+	for (size_t done = 0; done < b->len; ) {
+		ssize_t n = write(log_fd, b->data + done, b->len - done);
+		if (n < 0)
+			FATAL();
+		done += n;
+	}
+	b->len = 0;
+}
+
+static void flush_all() {
+	if (log_fd == -1)
+		return;
+	pthread_mutex_lock(&buffers_lock);
+	for (struct buffer *b = buffers; b; b = b->next) {
+		pthread_mutex_lock(&b->lock);
+		flush(b);
+		pthread_mutex_unlock(&b->lock);
+	}
+	pthread_mutex_unlock(&buffers_lock);
+}
+
+// Write and free the buffer of an exiting thread.
+static void buffer_exit(void *arg) {
+	struct buffer *b = arg;
+	pthread_mutex_lock(&buffers_lock);
+	for (struct buffer **p = &buffers; *p; p = &(*p)->next) {
+		if (*p == b) {
+			*p = b->next;
+			break;
+		}
+	}
+	pthread_mutex_unlock(&buffers_lock);
+	pthread_mutex_lock(&b->lock);
+	flush(b);
+	pthread_mutex_unlock(&b->lock);
+	pthread_mutex_destroy(&b->lock);
+	free(b);
+	buffer = NULL;
 }
 
 static void file_md5sum(char digest_s[static 33], int fd) {
@@ -1,2 +1,22 @@
-[ "$IN_NIX_SHELL" = impure ] && [ -n "$PS1" ] && [ -e ~/.bashrc ] && source ~/.bashrc
+# Shell options and aliases set by the shell, which bash doesn't inherit.
+if [ -n "${CACHED_NIX_SHELL_SETUP+x}" ]; then
+	eval "$CACHED_NIX_SHELL_SETUP"
+	unset CACHED_NIX_SHELL_SETUP
+fi
+# --rcfile FILE replaces ~/.bashrc, which is only sourced in impure shells.
+if [ -n "${CACHED_NIX_SHELL_RCFILE+x}" ]; then
+	__cached_nix_shell_rcfile=$CACHED_NIX_SHELL_RCFILE
+	unset CACHED_NIX_SHELL_RCFILE
+	source "$__cached_nix_shell_rcfile"
+	unset __cached_nix_shell_rcfile
+elif [ "$IN_NIX_SHELL" = impure ] && [ -n "$PS1" ] && [ -e ~/.bashrc ]; then
+	source ~/.bashrc
+fi
 [ -n "$PS1" ] && PS1='\n\[\033[1;32m\][cached-nix-shell:\w]\$\[\033[0m\] '
+# --command: run it here, then exit unless it ends with `return`, like nix-shell.
+if [ -n "${CACHED_NIX_SHELL_COMMAND+x}" ]; then
+	__cached_nix_shell_command=$CACHED_NIX_SHELL_COMMAND
+	unset CACHED_NIX_SHELL_COMMAND
+	eval "$__cached_nix_shell_command"
+	exit
+fi
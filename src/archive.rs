@@ -0,0 +1,156 @@
+//! `--export-cache FILE` and `--import-cache FILE`: move cache entries between
+//! machines or CI runs as a tarball, using the `tar` binary.
+//!
+//! Only entry files are archived.  gc roots live outside of the cache
+//! directory and point to a particular user's cache, so they are recreated
+//! after import instead.
+
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Names of files belonging to cache entries in `dir`, sorted.
+fn entry_files(dir: &Path) -> Vec<OsString> {
+    let mut files = std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let ext = path.extension()?.to_str()?;
+            if !crate::entry::ALL.contains(&ext) {
+                return None;
+            }
+            Some(path.file_name()?.to_owned())
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
+
+fn tar(args: &[&OsStr]) -> Result<(), String> {
+    let status = Command::new("tar")
+        .args(args)
+        .status()
+        .map_err(|e| format!("can't run tar: {}", e))?;
+    if !status.success() {
+        return Err(format!("tar failed: {}", status));
+    }
+    Ok(())
+}
+
+fn absolute(file: &Path) -> Result<PathBuf, String> {
+    std::env::current_dir()
+        .map(|cwd| cwd.join(file))
+        .map_err(|e| format!("can't get cwd: {}", e))
+}
+
+pub fn export(dir: &Path, file: &Path) -> Result<(), String> {
+    let file = absolute(file)?;
+    let files = entry_files(dir);
+    let mut args = vec!["-cf".as_ref(), file.as_os_str()];
+    if files.is_empty() {
+        // tar refuses to create an archive from an empty list of names, and
+        // the cache directory may not even exist yet.
+        args.extend([OsStr::new("-T"), OsStr::new("/dev/null")]);
+    } else {
+        args.extend([OsStr::new("-C"), dir.as_os_str(), OsStr::new("--")]);
+        args.extend(files.iter().map(|x| x.as_os_str()));
+    }
+    tar(&args)
+}
+
+/// Extract entries into `dir`.  Returns hashes of the imported entries whose
+/// derivations are still present in the store, so gc roots can be recreated.
+pub fn import(dir: &Path, file: &Path) -> Result<Vec<String>, String> {
+    let file = absolute(file)?;
+    std::fs::create_dir_all(dir)
+        .map_err(|e| format!("can't create {:?}: {}", dir, e))?;
+    // Extract into a temporary directory first, so that unrelated files in
+    // the tarball never end up in the cache.
+    let tmp = tempfile::tempdir_in(dir)
+        .map_err(|e| format!("can't create temporary directory: {}", e))?;
+    tar(&[
+        "-xf".as_ref(),
+        file.as_os_str(),
+        "-C".as_ref(),
+        tmp.path().as_os_str(),
+    ])?;
+
+    let mut hashes = Vec::new();
+    for fname in entry_files(tmp.path()) {
+        std::fs::rename(tmp.path().join(&fname), dir.join(&fname))
+            .map_err(|e| format!("can't import {:?}: {}", fname, e))?;
+        let path = PathBuf::from(&fname);
+        if path.extension().is_some_and(|x| x == "drv")
+            && dir.join(&fname).exists()
+        {
+            if let Some(hash) = path.file_stem().and_then(|x| x.to_str()) {
+                hashes.push(hash.to_string());
+            }
+        }
+    }
+    Ok(hashes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_export_import() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("cache");
+        let store = dir.path().join("store");
+        std::fs::create_dir_all(&cache).unwrap();
+        std::fs::create_dir_all(&store).unwrap();
+        std::fs::write(store.join("a.drv"), "").unwrap();
+        for hash in &["aaa", "bbb"] {
+            std::fs::write(
+                crate::entry::path(&cache, hash, crate::entry::ENV),
+                hash,
+            )
+            .unwrap();
+            std::fs::write(
+                crate::entry::path(&cache, hash, crate::entry::TRACE),
+                "",
+            )
+            .unwrap();
+        }
+        // The derivation of "aaa" is present, the one of "bbb" is collected.
+        std::os::unix::fs::symlink(store.join("a.drv"), cache.join("aaa.drv"))
+            .unwrap();
+        std::os::unix::fs::symlink(store.join("b.drv"), cache.join("bbb.drv"))
+            .unwrap();
+        std::fs::write(cache.join("stats.log"), "").unwrap();
+
+        let tarball = dir.path().join("cache.tar");
+        export(&cache, &tarball).unwrap();
+        let exported = entry_files(&cache);
+        std::fs::remove_dir_all(&cache).unwrap();
+
+        assert_eq!(import(&cache, &tarball).unwrap(), vec!["aaa"]);
+        assert_eq!(entry_files(&cache), exported);
+        assert_eq!(std::fs::read(cache.join("bbb.env")).unwrap(), b"bbb");
+        assert_eq!(
+            std::fs::read_link(cache.join("aaa.drv")).unwrap(),
+            store.join("a.drv")
+        );
+        assert!(!cache.join("stats.log").exists());
+    }
+
+    #[test]
+    fn test_export_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("cache");
+        let tarball = dir.path().join("cache.tar");
+        // Not created yet.
+        export(&cache, &tarball).unwrap();
+        std::fs::create_dir(&cache).unwrap();
+        std::fs::write(cache.join("stats.log"), "").unwrap();
+        export(&cache, &tarball).unwrap();
+
+        let imported = dir.path().join("imported");
+        assert_eq!(import(&imported, &tarball).unwrap(), Vec::<String>::new());
+        assert!(entry_files(&imported).is_empty());
+    }
+}
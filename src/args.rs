@@ -10,95 +10,747 @@
 //! compatible way, so it is appropriate to code this explicitly rather than use
 //! such libraries.
 
+use crate::color::Choice;
+use crate::path_clean::PathClean;
 use std::collections::VecDeque;
 use std::ffi::{OsStr, OsString};
-use std::os::unix::ffi::OsStrExt;
-use ufcs::Pipe;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::PathBuf;
+use std::time::Duration;
 
+#[derive(Debug)]
 pub enum RunMode {
     /// no arg
     InteractiveShell,
-    /// --run CMD | --command CMD
+    /// --run CMD
     Shell(OsString),
+    /// --command CMD (like --run, but in an interactive shell if stdin is a
+    /// terminal, which stays open if CMD ends with `return`)
+    CommandThenInteractive(OsString),
     /// --exec CMD ARGS...
     Exec(OsString, Vec<OsString>),
 }
 
+#[derive(Debug)]
 pub struct Args {
-    /// true: -p | --packages
+    /// true: -p | --packages | --packages-file
     pub packages: bool,
+    /// --packages-file FILE (canonicalized; its lines are added to `rest`)
+    pub packages_files: Vec<PathBuf>,
+    /// --merge-file FILE (canonicalized; the shells of these files are
+    /// merged into one, instead of using a shell file)
+    pub merge_files: Vec<PathBuf>,
     /// true: --pure; false: --impure
     pub pure: bool,
+    /// true: --clean-env (keep only `HOME`, `TERM` and `--keep` variables of
+    /// the caller)
+    pub clean_env: bool,
+    /// true: --keep-path (keep the caller's PATH in pure shells)
+    pub keep_path: bool,
+    /// --keep NAME (variables of the caller kept in pure shells)
+    pub keep: Vec<OsString>,
+    /// --env-file FILE (variables applied on top of the shell environment)
+    pub env_files: Vec<OsString>,
+    /// --keep-env-file FILE (save the environment of the command there; not
+    /// in shebang)
+    pub keep_env_file: Option<OsString>,
+    /// --trace-scope DIR (absolute; only files under these directories and
+    /// in the nix store are dependencies)
+    pub trace_scope: Vec<PathBuf>,
+    /// true: --trace-filter-store-only (only the store paths of the
+    /// environment are dependencies)
+    pub trace_filter_store_only: bool,
+    /// --trace-dir-depth N (only record directory listings at most N levels
+    /// below the project root)
+    pub trace_dir_depth: Option<usize>,
+    /// --trace-to FILE (save the raw trace of nix-shell, for debugging)
+    pub trace_to: Option<OsString>,
+    /// true: --key-env-file (make contents of env files a part of cache key)
+    pub key_env_files: bool,
+    /// true: --git-aware (make the git HEAD of the project a part of cache
+    /// key)
+    pub git_aware: bool,
     /// -i (in shebang)
     pub interpreter: OsString,
     /// --run | --command | --exec (not in shebang)
     pub run: RunMode,
+    /// --timeout DURATION (kill the command after that long)
+    pub timeout: Option<Duration>,
+    /// arguments after `--` once --run or --command is given (positional
+    /// parameters of the command)
+    pub run_args: Vec<OsString>,
+    /// true: --watch (re-run the command when dependencies change)
+    pub watch: bool,
+    /// --cache-key-from STRING (key the entry by STRING alone)
+    pub cache_key_from: Option<OsString>,
+    /// true: --restore-limits (set the limit of open files of the shell)
+    pub restore_limits: bool,
+    /// true: --keep-build-dir (leave the scratch files of a build behind)
+    pub keep_build_dir: bool,
+    /// true: --cache-failed-hook (cache shells whose shellHook failed)
+    pub cache_failed_hook: bool,
+    /// true: --timings (print how long looking up and building took)
+    pub timings: bool,
+    /// true: --no-gc-root (don't register the cached shell as a gc root)
+    pub no_gc_root: bool,
+    /// true: --fast-validate (trust store paths, check the trace in parallel)
+    pub fast_validate: bool,
+    /// true: --reuse-env (run simple --run commands without bash)
+    pub reuse_env: bool,
+    /// --rcfile FILE (source it instead of `~/.bashrc` in interactive shells;
+    /// not in shebang)
+    pub rcfile: Option<PathBuf>,
+    /// true: --audit (compare cache hits with a fresh build; not listed in
+    /// `FLAGS`, since it's only useful to debug cached-nix-shell itself)
+    pub audit: bool,
+    /// true: --quiet (suppress informational messages and warnings)
+    pub quiet: bool,
+    /// true: -v | --verbose (show the build output even with --quiet)
+    pub verbose: bool,
+    /// --color WHEN | --color=WHEN | --no-color (ANSI colors in our own
+    /// messages)
+    pub color: Choice,
     /// other positional arguments (after --)
     pub rest: Vec<OsString>,
     /// other keyword arguments
     pub other_kw: Vec<OsString>,
+    /// warnings about the arguments, printed once `--quiet` is known
+    pub warnings: Vec<String>,
+    /// suggestions to replace deprecated spellings that still work, printed
+    /// with `--verbose` and by `--lint`
+    pub advisories: Vec<String>,
+    /// other keyword arguments that affect only diagnostics or failed builds,
+    /// or `--arg`s named by --key-ignore; they are passed to nix-shell, but
+    /// aren't a part of the cache key
+    pub other_kw_unkeyed: Vec<OsString>,
+}
+
+/// Flags accepted by `Args::parse` outside of shebang, with descriptions.
+/// Used to generate shell completions.
+pub const FLAGS: &[(&str, &str)] = &[
+    // Passed to nix.
+    ("--attr", "attribute to build"),
+    ("-A", "attribute to build"),
+    ("-I", "add a path to the nix search path"),
+    ("--nixpkgs-rev", "use nixpkgs at a revision from GitHub"),
+    ("--arg", "pass a nix expression as an argument"),
+    ("--argstr", "pass a string as an argument"),
+    ("--argjson", "pass a JSON value as an argument"),
+    ("--option", "set a nix configuration option"),
+    ("--max-jobs", "maximum number of parallel builds"),
+    ("-j", "maximum number of parallel builds"),
+    ("--show-trace", "show the trace on evaluation errors"),
+    ("--keep-failed", "keep the build directory of failed builds"),
+    ("-K", "keep the build directory of failed builds"),
+    // Messages.
+    ("--quiet", "suppress informational messages"),
+    ("-q", "lower the log level of nix (repeatable)"),
+    ("--verbose", "show more of the build output"),
+    ("-v", "show more of the build output"),
+    ("--color", "color messages: auto, always or never"),
+    ("--no-color", "do not color messages"),
+    ("--timings", "print how long the lookup and the build took"),
+    // The cache entry.
+    ("--no-gc-root", "do not protect the cached shell from gc"),
+    ("--keep-build-dir", "keep the scratch files of the build"),
+    (
+        "--cache-failed-hook",
+        "cache the shell even if its shellHook failed",
+    ),
+    ("--cache-key-from", "key the cache entry by a string alone"),
+    (
+        "--fast-validate",
+        "check the cache faster, trusting the store",
+    ),
+    // What the trace and the key cover.
+    ("--trace-to", "save the raw trace of nix to a file"),
+    ("--trace-scope", "only track files under a directory"),
+    (
+        "--trace-filter-store-only",
+        "only track the store paths of the environment",
+    ),
+    (
+        "--trace-dir-depth",
+        "only track directory listings up to a depth",
+    ),
+    (
+        "--key-env-file",
+        "invalidate the cache when env files change",
+    ),
+    ("--key-ignore", "leave a nix argument out of the cache key"),
+    ("--git-aware", "invalidate the cache when git HEAD changes"),
+    // The environment.
+    ("--pure", "clear the environment"),
+    ("--impure", "keep the environment"),
+    ("--clean-env", "keep only HOME and TERM of the environment"),
+    ("--keep-path", "keep the PATH of the caller in pure shells"),
+    ("--keep", "keep a variable of the caller in pure shells"),
+    ("--env-file", "apply variables from a dotenv file"),
+    ("--keep-env-file", "save the environment to a dotenv file"),
+    (
+        "--restore-limits",
+        "set the limit of open files that the shell sets",
+    ),
+    // The shell.
+    ("--packages", "set up a shell with the given packages"),
+    ("-p", "set up a shell with the given packages"),
+    (
+        "--packages-file",
+        "set up a shell with packages listed in a file",
+    ),
+    ("--merge-file", "merge the shells of several files into one"),
+    // The command.
+    ("--run", "run a command in a non-interactive shell"),
+    (
+        "--command",
+        "run a command, then stay in the shell if it returns",
+    ),
+    ("--exec", "execute a program in the shell environment"),
+    ("--watch", "re-run the command when dependencies change"),
+    ("--timeout", "kill the command after a duration"),
+    ("--reuse-env", "run simple --run commands without bash"),
+    (
+        "--rcfile",
+        "source a file instead of ~/.bashrc in interactive shells",
+    ),
+];
+
+/// Flags that `Args::parse` takes besides `FLAGS`, and which aren't
+/// completed: `--audit` only debugs cached-nix-shell itself, `-i` is only
+/// for shebangs, and `--` ends the flags.
+const OTHER_FLAGS: &[&str] = &["--audit", "-i", "--"];
+
+/// Long flags that other programs commonly take too, so they aren't
+/// reported after `--exec`.
+const GENERIC_FLAGS: &[&str] = &[
+    "--color",
+    "--command",
+    "--keep",
+    "--no-color",
+    "--quiet",
+    "--timeout",
+    "--verbose",
+    "--watch",
+];
+
+/// Arguments of `--exec` that look like options of cached-nix-shell, e.g.
+/// in `--exec make --pure`, which are not.  Words after `--` are left
+/// alone.
+fn flags_after_exec(cmd_args: &[OsString]) -> Vec<&OsString> {
+    cmd_args
+        .iter()
+        .take_while(|x| *x != "--")
+        .filter(|x| {
+            x.as_bytes().starts_with(b"--")
+                && !GENERIC_FLAGS.iter().any(|flag| x == flag)
+                && FLAGS.iter().any(|(flag, _)| x == flag)
+        })
+        .collect()
 }
 
 impl Args {
+    /// Parse nix-shell arguments.  An empty list yields the defaults: an
+    /// interactive bash shell for whatever file nix-shell would pick, i.e.
+    /// `shell.nix` or `default.nix` in the current directory.
     pub fn parse(
         args: Vec<OsString>,
         in_shebang: bool,
     ) -> Result<Args, String> {
         let mut res = Args {
             packages: false,
+            packages_files: Vec::new(),
+            merge_files: Vec::new(),
             pure: false,
+            clean_env: false,
+            keep_path: false,
+            keep: Vec::new(),
+            env_files: Vec::new(),
+            keep_env_file: None,
+            key_env_files: false,
+            git_aware: false,
+            trace_to: None,
+            trace_scope: Vec::new(),
+            trace_filter_store_only: false,
+            trace_dir_depth: None,
             interpreter: OsString::from("bash"),
             run: RunMode::InteractiveShell,
+            timeout: None,
+            run_args: Vec::new(),
+            watch: false,
+            timings: false,
+            keep_build_dir: false,
+            cache_failed_hook: false,
+            cache_key_from: None,
+            restore_limits: false,
+            no_gc_root: false,
+            fast_validate: false,
+            reuse_env: false,
+            rcfile: None,
+            audit: false,
+            quiet: false,
+            verbose: false,
+            color: Choice::Auto,
             rest: Vec::new(),
             other_kw: Vec::new(),
+            other_kw_unkeyed: Vec::new(),
+            warnings: Vec::new(),
+            advisories: Vec::new(),
         };
+        let mut key_ignore = Vec::new();
         let mut it = VecDeque::<OsString>::from(args);
         while let Some(arg) = get_next_arg(&mut it) {
             let mut next = || -> Result<OsString, String> {
-                it.pop_front()
-                    .ok_or_else(|| {
-                        format!("flag {:?} requires more arguments", arg)
-                    })?
-                    .clone()
-                    .pipe(Ok)
+                it.pop_front().ok_or_else(|| {
+                    format!("flag {:?} requires more arguments", arg)
+                })
             };
-            if arg == "--attr" || arg == "-A" {
-                res.other_kw.extend(vec!["-A".into(), next()?]);
-            } else if arg == "-I" {
-                res.other_kw.extend(vec!["-I".into(), next()?]);
-            } else if arg == "--arg" {
-                res.other_kw.extend(vec!["--arg".into(), next()?, next()?]);
-            } else if arg == "--argstr" {
-                res.other_kw
-                    .extend(vec!["--argstr".into(), next()?, next()?]);
-            } else if arg == "--option" {
-                res.other_kw
-                    .extend(vec!["--option".into(), next()?, next()?]);
-            } else if arg == "-j" || arg == "--max-jobs" {
-                res.other_kw.extend(vec!["--max-jobs".into(), next()?]);
-            } else if arg == "--pure" {
-                res.pure = true;
-            } else if arg == "--impure" {
-                res.pure = false;
-            } else if arg == "--packages" || arg == "-p" {
-                res.packages = true;
-            } else if arg == "-i" && in_shebang {
-                res.interpreter = next()?;
-            } else if (arg == "--run" || arg == "--command") && !in_shebang {
-                res.run = RunMode::Shell(next()?);
-            } else if arg == "--exec" && !in_shebang {
-                res.run = RunMode::Exec(next()?, it.into());
-                break;
-            } else if arg.as_bytes().first() == Some(&b'-') {
-                return Err(format!("unexpected arg {:?}", arg));
-            } else {
-                res.rest.push(arg.clone());
+            // Only the flags of the table are taken.
+            let flag = FLAGS
+                .iter()
+                .map(|(flag, _)| *flag)
+                .chain(OTHER_FLAGS.iter().copied())
+                .find(|flag| arg == *flag);
+            match flag {
+                Some("--attr" | "-A") => {
+                    res.other_kw.extend(vec!["-A".into(), next()?]);
+                }
+                Some("-I") => {
+                    let path = expand_home(next()?);
+                    let path = if in_shebang {
+                        path
+                    } else {
+                        absolute_include(path)
+                    };
+                    res.other_kw.extend(vec!["-I".into(), path]);
+                }
+                Some("--nixpkgs-rev") => {
+                    let rev = next()?;
+                    if rev.is_empty() {
+                        return Err("--nixpkgs-rev requires a revision".into());
+                    }
+                    res.other_kw.extend(vec!["-I".into(), nixpkgs_at(&rev)]);
+                }
+                Some("--arg") => {
+                    res.other_kw.extend(vec!["--arg".into(), next()?, next()?]);
+                }
+                Some("--argstr") => {
+                    res.other_kw.extend(vec![
+                        "--argstr".into(),
+                        next()?,
+                        next()?,
+                    ]);
+                }
+                Some("--argjson") => {
+                    res.other_kw.extend(vec![
+                        "--argjson".into(),
+                        next()?,
+                        next()?,
+                    ]);
+                }
+                Some("--option") => {
+                    let (name, value) = (next()?, next()?);
+                    if let Some(new) = renamed_setting(&name) {
+                        res.advisories.push(format!(
+                        "--option {} is a deprecated alias, use --option {}",
+                        name.to_string_lossy(),
+                        new
+                    ));
+                    }
+                    res.other_kw.extend(vec!["--option".into(), name, value]);
+                }
+                Some("-j" | "--max-jobs") => {
+                    let jobs = next()?;
+                    if !is_max_jobs(&jobs) {
+                        return Err(format!(
+                        "flag {:?} requires a non-negative integer or \"auto\", got {:?}",
+                        arg, jobs
+                    ));
+                    }
+                    let jobs = match clamp_max_jobs(&jobs) {
+                        Some(clamped) => {
+                            res.warnings.push(format!(
+                                "{} {:?} is too large, using {}",
+                                arg.to_string_lossy(),
+                                jobs,
+                                clamped.to_string_lossy()
+                            ));
+                            clamped
+                        }
+                        None => jobs,
+                    };
+                    res.other_kw.extend(vec!["--max-jobs".into(), jobs]);
+                }
+                Some("--show-trace" | "--keep-failed" | "-K") => {
+                    res.other_kw_unkeyed.push(arg);
+                }
+                Some("--quiet") => {
+                    res.quiet = true;
+                    res.other_kw_unkeyed.push(arg);
+                }
+                Some("-q") => {
+                    // Only for nix: `-qq` lowers its log level twice, like
+                    // `--quiet --quiet`, while messages of cached-nix-shell
+                    // itself are left alone.
+                    res.other_kw_unkeyed.push("--quiet".into());
+                }
+                Some("--verbose" | "-v") => {
+                    res.verbose = true;
+                    res.other_kw_unkeyed.push(arg);
+                }
+                Some("--color") => {
+                    res.color = crate::color::parse(&next()?)?;
+                }
+                _ if arg.as_bytes().starts_with(b"--color=") => {
+                    let when = &arg.as_bytes()[b"--color=".len()..];
+                    res.color = crate::color::parse(OsStr::from_bytes(when))?;
+                }
+                Some("--no-color") => {
+                    res.color = Choice::Never;
+                }
+                Some("--restore-limits") => {
+                    res.restore_limits = true;
+                }
+                Some("--cache-key-from") => {
+                    res.cache_key_from = Some(next()?);
+                }
+                Some("--keep-build-dir") => {
+                    res.keep_build_dir = true;
+                }
+                Some("--cache-failed-hook") => {
+                    res.cache_failed_hook = true;
+                }
+                Some("--timings") => {
+                    res.timings = true;
+                }
+                Some("--no-gc-root") => {
+                    res.no_gc_root = true;
+                }
+                Some("--fast-validate") => {
+                    res.fast_validate = true;
+                }
+                Some("--reuse-env") if !in_shebang => {
+                    res.reuse_env = true;
+                }
+                Some("--rcfile") if !in_shebang => {
+                    let file = PathBuf::from(next()?);
+                    let cwd = std::env::current_dir()
+                        .map_err(|e| format!("can't get cwd: {}", e))?;
+                    res.rcfile = Some(cwd.join(file).clean());
+                }
+                Some("--audit") => {
+                    res.audit = true;
+                }
+                Some("--pure") => {
+                    res.pure = true;
+                }
+                Some("--impure") => {
+                    res.pure = false;
+                }
+                Some("--clean-env") => {
+                    res.clean_env = true;
+                }
+                Some("--keep-path") => {
+                    res.keep_path = true;
+                }
+                Some("--keep") => {
+                    res.keep.push(next()?);
+                }
+                Some("--env-file") => {
+                    res.env_files.push(next()?);
+                }
+                Some("--keep-env-file") if !in_shebang => {
+                    res.keep_env_file = Some(next()?);
+                }
+                Some("--trace-scope") => {
+                    let dir = PathBuf::from(next()?);
+                    let cwd = std::env::current_dir()
+                        .map_err(|e| format!("can't get cwd: {}", e))?;
+                    res.trace_scope.push(cwd.join(dir).clean());
+                }
+                Some("--trace-filter-store-only") => {
+                    res.trace_filter_store_only = true;
+                }
+                Some("--trace-dir-depth") => {
+                    let depth = next()?;
+                    match depth.to_str().and_then(|x| x.parse().ok()) {
+                    Some(depth) => res.trace_dir_depth = Some(depth),
+                    None => {
+                        return Err(format!(
+                            "--trace-dir-depth requires a non-negative integer, got {:?}",
+                            depth
+                        ))
+                    }
+                }
+                }
+                Some("--trace-to") => {
+                    res.trace_to = Some(next()?);
+                }
+                Some("--key-ignore") => {
+                    key_ignore.push(next()?);
+                }
+                Some("--key-env-file") => {
+                    res.key_env_files = true;
+                }
+                Some("--git-aware") => {
+                    res.git_aware = true;
+                }
+                Some("--packages" | "-p") => {
+                    res.packages = true;
+                }
+                Some("--packages-file") => {
+                    let (fname, packages) = read_packages_file(next()?)?;
+                    res.packages = true;
+                    res.packages_files.push(fname);
+                    res.rest.extend(packages);
+                }
+                Some("--merge-file") => {
+                    let fname = next()?;
+                    let fname =
+                        std::fs::canonicalize(expand_home(fname.clone()))
+                            .map_err(|e| {
+                                format!(
+                                    "can't read merge file {:?}: {}",
+                                    fname, e
+                                )
+                            })?;
+                    res.merge_files.push(fname);
+                }
+                Some("-i") if in_shebang => {
+                    res.interpreter = next()?;
+                }
+                Some("--run") if !in_shebang => {
+                    res.run = RunMode::Shell(read_at_file(next()?)?);
+                }
+                Some("--command") if !in_shebang => {
+                    res.run =
+                        RunMode::CommandThenInteractive(read_at_file(next()?)?);
+                }
+                Some("--exec") if !in_shebang => {
+                    // The rest is the command, whatever it looks like.
+                    let cmd = next()?;
+                    let cmd_args = Vec::from(it);
+                    let flags = flags_after_exec(&cmd_args);
+                    if !flags.is_empty() {
+                        res.warnings.push(format!(
+                            "{} after --exec {} passed to {:?}, put options \
+                         before --exec",
+                            flags
+                                .iter()
+                                .map(|x| x.to_string_lossy())
+                                .collect::<Vec<_>>()
+                                .join(" "),
+                            if flags.len() == 1 { "is" } else { "are" },
+                            cmd
+                        ));
+                    }
+                    res.run = RunMode::Exec(cmd, cmd_args);
+                    break;
+                }
+                Some("--timeout") if !in_shebang => {
+                    let timeout = next()?;
+                    let timeout = timeout
+                        .to_str()
+                        .ok_or_else(|| {
+                            format!("invalid duration {:?}", timeout)
+                        })
+                        .and_then(crate::gc::parse_duration)?;
+                    res.timeout = Some(timeout);
+                }
+                Some("--watch") if !in_shebang => {
+                    res.watch = true;
+                }
+                Some("--") => {
+                    // The rest are positional arguments, even if they look like
+                    // flags: of the command if there is one, like `bash -c`.
+                    match res.run {
+                        RunMode::Shell(_)
+                        | RunMode::CommandThenInteractive(_) => {
+                            res.run_args.extend(it)
+                        }
+                        _ => res.rest.extend(it),
+                    }
+                    break;
+                }
+                _ if arg.as_bytes().first() == Some(&b'-') => {
+                    return Err(format!("unexpected arg {:?}", arg));
+                }
+                _ => {
+                    res.rest.push(arg.clone());
+                }
+            }
+        }
+        if !res.packages {
+            res.rest = res.rest.into_iter().map(expand_home).collect();
+        }
+        if !res.merge_files.is_empty() && (res.packages || !res.rest.is_empty())
+        {
+            return Err(
+                "--merge-file can't be combined with -p or a shell file"
+                    .to_string(),
+            );
+        }
+        if let RunMode::InteractiveShell = res.run {
+            if res.watch {
+                return Err(
+                    "--watch requires --run, --command or --exec".to_string()
+                );
+            }
+            if res.timeout.is_some() {
+                return Err(
+                    "--timeout requires --run, --command or --exec".to_string()
+                );
             }
         }
+        if !key_ignore.is_empty() {
+            let (keyed, ignored) =
+                split_ignored_args(std::mem::take(&mut res.other_kw), |name| {
+                    key_ignore.iter().any(|x| x == name)
+                });
+            for name in &key_ignore {
+                if !ignored.chunks(3).any(|arg| &arg[1] == name) {
+                    res.warnings.push(format!(
+                        "--key-ignore {:?}: no --arg, --argstr or --argjson has this name",
+                        name
+                    ));
+                }
+            }
+            res.other_kw = keyed;
+            res.other_kw_unkeyed.extend(ignored);
+        }
         Ok(res)
     }
 }
 
+/// Split `--arg NAME VALUE` (or `--argstr`, `--argjson`) triples with an
+/// `ignored` name out of keyword arguments collected by `Args::parse`.
+fn split_ignored_args(
+    kw: Vec<OsString>,
+    ignored: impl Fn(&OsStr) -> bool,
+) -> (Vec<OsString>, Vec<OsString>) {
+    let mut keyed = Vec::new();
+    let mut res = Vec::new();
+    let mut it = kw.into_iter();
+    while let Some(flag) = it.next() {
+        let values = match flag.to_str() {
+            Some("--arg" | "--argstr" | "--argjson" | "--option") => 2,
+            _ => 1,
+        };
+        let arg = std::iter::once(flag)
+            .chain(it.by_ref().take(values))
+            .collect::<Vec<_>>();
+        let is_named_arg = values == 2 && arg[0] != "--option";
+        if is_named_arg && arg.len() == 3 && ignored(&arg[1]) {
+            res.extend(arg);
+        } else {
+            keyed.extend(arg);
+        }
+    }
+    (keyed, res)
+}
+
+/// Load `--run @FILE` contents verbatim; other values are returned as-is.
+/// The command is passed to `bash -c`, and arguments can't contain NUL, so
+/// a file with one is rejected.
+fn read_at_file(arg: OsString) -> Result<OsString, String> {
+    let fname = match arg.as_bytes().split_first() {
+        Some((b'@', fname)) => OsStr::from_bytes(fname),
+        _ => return Ok(arg),
+    };
+    let cmd = std::fs::read(fname)
+        .map_err(|e| format!("can't read {:?}: {}", fname, e))?;
+    if let Some(pos) = cmd.iter().position(|&b| b == 0) {
+        let line = cmd[..pos].iter().filter(|&&b| b == b'\n').count() + 1;
+        return Err(format!(
+            "{:?}: the command contains a NUL byte (line {}), which can't be passed to bash",
+            fname, line
+        ));
+    }
+    Ok(OsString::from_vec(cmd))
+}
+
+/// Read package names from a file, one per line.  Blank lines and lines
+/// starting with `#` are ignored.
+fn read_packages_file(
+    fname: OsString,
+) -> Result<(PathBuf, Vec<OsString>), String> {
+    let err = |e: std::io::Error| {
+        format!("can't read packages file {:?}: {}", fname, e)
+    };
+    let fname = std::fs::canonicalize(&fname).map_err(err)?;
+    let packages = std::fs::read(&fname)
+        .map_err(err)?
+        .split(|&b| b == b'\n')
+        .map(|line| line.trim_ascii())
+        .filter(|line| !line.is_empty() && line[0] != b'#')
+        .map(|line| OsString::from_vec(line.to_vec()))
+        .collect();
+    Ok((fname, packages))
+}
+
+/// Old names of nix settings, which nix still accepts, with their current
+/// names.  From the aliases in nix's `globals.hh`.
+const RENAMED_SETTINGS: &[(&str, &str)] = &[
+    ("binary-cache-public-keys", "trusted-public-keys"),
+    ("binary-caches", "substituters"),
+    ("build-compress-log", "compress-build-log"),
+    ("build-cores", "cores"),
+    ("build-extra-sandbox-paths", "extra-sandbox-paths"),
+    ("build-fallback", "fallback"),
+    ("build-keep-log", "keep-build-log"),
+    ("build-max-jobs", "max-jobs"),
+    ("build-max-log-size", "max-build-log-size"),
+    ("build-max-silent-time", "max-silent-time"),
+    ("build-sandbox-paths", "sandbox-paths"),
+    ("build-timeout", "timeout"),
+    ("build-use-chroot", "sandbox"),
+    ("build-use-sandbox", "sandbox"),
+    ("build-use-substitutes", "substitute"),
+    ("env-keep-derivations", "keep-env-derivations"),
+    ("gc-keep-derivations", "keep-derivations"),
+    ("gc-keep-outputs", "keep-outputs"),
+    ("trusted-binary-caches", "trusted-substituters"),
+];
+
+/// The current name of a renamed setting, also with the `extra-` prefix of
+/// list settings, e.g. `extra-binary-caches`.
+fn renamed_setting(name: &OsStr) -> Option<String> {
+    let name = name.to_str()?;
+    let (prefix, name) = match name.strip_prefix("extra-") {
+        Some(name) => ("extra-", name),
+        None => ("", name),
+    };
+    RENAMED_SETTINGS
+        .iter()
+        .find(|(old, _)| *old == name)
+        .map(|(_, new)| format!("{}{}", prefix, new))
+}
+
+fn is_max_jobs(arg: &OsStr) -> bool {
+    let argb = arg.as_bytes();
+    argb == b"auto" || !argb.is_empty() && argb.iter().all(u8::is_ascii_digit)
+}
+
+/// Far more jobs than any machine or pool of builders can run.
+const MAX_JOBS: u64 = 4096;
+
+/// `MAX_JOBS` if a `--max-jobs` value accepted by `is_max_jobs` is larger,
+/// including values that don't fit into an integer.
+fn clamp_max_jobs(jobs: &OsStr) -> Option<OsString> {
+    if jobs == "auto" {
+        return None;
+    }
+    match jobs.to_str().and_then(|x| x.parse::<u64>().ok()) {
+        Some(jobs) if jobs <= MAX_JOBS => None,
+        _ => Some(MAX_JOBS.to_string().into()),
+    }
+}
+
+/// Short flags that take a value: `-A`, `-I`, `-j`, and `-i` in shebangs.
+const SHORT_FLAGS_WITH_VALUE: &[u8] = b"AIji";
+
+/// Take the next argument, expanding bundled short options such as `-pK`.
+/// `--` itself is never expanded, and `Args::parse` takes the words after it
+/// from the deque directly, so they are never expanded either.
 fn get_next_arg(it: &mut VecDeque<OsString>) -> Option<OsString> {
     let arg = it.pop_front()?;
     let argb = arg.as_bytes();
@@ -106,14 +758,20 @@ fn get_next_arg(it: &mut VecDeque<OsString>) -> Option<OsString> {
         // Expand short options and put them back to the deque.
         // Reference: https://github.com/NixOS/nix/blob/2.3.1/src/libutil/args.cc#L29-L42
 
+        // Unlike nix, we stop after a letter that takes a value, so "-pAfoo"
+        // is "-p -A foo" rather than "-p -A -f -o -o".
         let split_idx = argb[1..]
             .iter()
             .position(|&b| !is_alpha(b))
             .unwrap_or(argb.len() - 1);
+        let split_idx = argb[1..split_idx + 1]
+            .iter()
+            .position(|b| SHORT_FLAGS_WITH_VALUE.contains(b))
+            .map_or(split_idx, |pos| pos + 1);
         // E.g. "-pj16" -> ("pj", "16")
         let (letters, rest) = argb[1..].split_at(split_idx);
 
-        if rest.len() != 0 {
+        if !rest.is_empty() {
             it.push_front(OsStr::from_bytes(rest).into());
         }
         for &c in letters.iter().rev() {
@@ -126,8 +784,72 @@ fn get_next_arg(it: &mut VecDeque<OsString>) -> Option<OsString> {
     }
 }
 
+/// Expand a leading `~/` of a path, or of the path part of a `-I` argument,
+/// to `$HOME/`.  Shells don't do it in quoted words, `name=~/x` words (in
+/// some shells) or `CACHED_NIX_SHELL_OPTS`, and nix doesn't do it at all.
+/// Other tildes (e.g. `~user/` or `a~/b`) are left alone.
+fn expand_home(arg: OsString) -> OsString {
+    match std::env::var_os("HOME") {
+        Some(home) if !home.is_empty() => expand_home_to(arg, &home),
+        _ => arg,
+    }
+}
+
+fn expand_home_to(arg: OsString, home: &OsStr) -> OsString {
+    let argb = arg.as_bytes();
+    let start = match argb.iter().position(|&b| b == b'=') {
+        Some(pos) if argb[pos + 1..].starts_with(b"~/") => pos + 1,
+        _ if argb.starts_with(b"~/") => 0,
+        _ => return arg,
+    };
+    let mut res = argb[..start].to_vec();
+    res.extend(
+        home.as_bytes()
+            .strip_suffix(b"/")
+            .unwrap_or(home.as_bytes()),
+    );
+    res.extend(&argb[start + 1..]);
+    OsString::from_vec(res)
+}
+
+/// The `-I` entry for the nixpkgs of GitHub at `rev`, a commit or a branch.
+/// Invalid revisions are reported by nix, when it fails to fetch them.
+fn nixpkgs_at(rev: &OsStr) -> OsString {
+    let mut res =
+        OsString::from("nixpkgs=https://github.com/NixOS/nixpkgs/archive/");
+    res.push(rev);
+    res.push(".tar.gz");
+    res
+}
+
+/// Make the path part of a `-I` argument (`path` or `name=path`) absolute, so
+/// it doesn't depend on the directory nix-shell is run from.  URLs and
+/// `channel:` entries are left as-is.
+fn absolute_include(arg: OsString) -> OsString {
+    let argb = arg.as_bytes();
+    let (prefix, path) = match argb.iter().position(|&b| b == b'=') {
+        Some(pos) => argb.split_at(pos + 1),
+        None => (&argb[..0], argb),
+    };
+    if path.is_empty() || path[0] == b'/' || path.contains(&b':') {
+        return arg;
+    }
+    let cwd = match std::env::current_dir() {
+        Ok(cwd) => cwd,
+        Err(_) => return arg,
+    };
+    let mut res = prefix.to_vec();
+    res.extend(
+        cwd.join(OsStr::from_bytes(path))
+            .clean()
+            .as_os_str()
+            .as_bytes(),
+    );
+    OsString::from_vec(res)
+}
+
 fn is_alpha(b: u8) -> bool {
-    b'a' <= b && b <= b'z' || b'A' <= b && b <= b'Z'
+    b.is_ascii_alphabetic()
 }
 
 #[cfg(test)]
@@ -149,5 +871,709 @@ mod test {
         assert_eq!(expand("-j4"), vec!["-j", "4"]);
         assert_eq!(expand("-j16"), vec!["-j", "16"]);
         assert_eq!(expand("-pj16"), vec!["-p", "-j", "16"]);
+        assert_eq!(expand("-pAfoo"), vec!["-p", "-A", "foo"]);
+        assert_eq!(expand("-Afoo.bar"), vec!["-A", "foo.bar"]);
+        assert_eq!(expand("-pjauto"), vec!["-p", "-j", "auto"]);
+        assert_eq!(expand("-pK"), vec!["-p", "-K"]);
+    }
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        Args::parse(args.iter().map(OsString::from).collect(), false)
+    }
+
+    fn run_cmd(args: &Args) -> Option<&OsStr> {
+        match &args.run {
+            RunMode::Shell(cmd) => Some(cmd),
+            RunMode::CommandThenInteractive(cmd) => Some(cmd),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_flags() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        for (flag, _) in FLAGS {
+            // --packages-file and --merge-file need an existing file.
+            let value = match *flag {
+                "--packages-file" | "--merge-file" => {
+                    file.path().to_str().unwrap()
+                }
+                "--timeout" => "1s",
+                "--color" => "never",
+                _ => "1",
+            };
+            // --run is for --watch and --timeout, which don't work in
+            // interactive mode.
+            // --merge-file takes no shell file.
+            let rest = if *flag == "--merge-file" { "-v" } else { "2" };
+            let args = [flag, value, rest, "--run", "true"];
+            assert!(parse(&args).is_ok(), "{} is rejected", flag);
+        }
+    }
+
+    #[test]
+    fn test_double_dash() {
+        let args = parse(&["-p", "--", "hello", "world"]).unwrap();
+        assert!(args.packages);
+        assert_eq!(args.rest, vec!["hello", "world"]);
+
+        let args = parse(&["--", "shell.nix"]).unwrap();
+        assert!(!args.packages);
+        assert_eq!(args.rest, vec!["shell.nix"]);
+    }
+
+    #[test]
+    fn test_double_dash_literal() {
+        // Words after `--` skip short option expansion, even if they look
+        // like bundled flags.
+        let args = parse(&["-p", "--", "-weird-pkg", "-pAfoo", "-j4"]).unwrap();
+        assert!(args.packages);
+        assert_eq!(args.rest, vec!["-weird-pkg", "-pAfoo", "-j4"]);
+        assert!(args.other_kw.is_empty());
+
+        let args =
+            parse(&["-p", "hello", "--run", "cmd", "--", "-pK"]).unwrap();
+        assert_eq!(args.rest, vec!["hello"]);
+        assert_eq!(args.run_args, vec!["-pK"]);
+        assert!(args.other_kw_unkeyed.is_empty());
+    }
+
+    #[test]
+    fn test_raw_values() {
+        // Values are taken by `next()` as-is, without short option expansion.
+        let args = parse(&["--argstr", "flag", "-p", "-A", "-pj16"]).unwrap();
+        assert!(!args.packages);
+        assert_eq!(
+            args.other_kw,
+            vec!["--argstr", "flag", "-p", "-A", "-pj16"]
+        );
+
+        let args = parse(&["--arg", "x", "-xyz", "-p"]).unwrap();
+        assert!(args.packages);
+        assert_eq!(args.other_kw, vec!["--arg", "x", "-xyz"]);
+    }
+
+    #[test]
+    fn test_option() {
+        let args = parse(&["--option", "foo", "-bar", "-p", "hello"]).unwrap();
+        assert_eq!(args.other_kw, vec!["--option", "foo", "-bar"]);
+        assert_eq!(args.rest, vec!["hello"]);
+
+        let args = parse(&["--option", "foo", "", "-p", "hello"]).unwrap();
+        assert_eq!(args.other_kw, vec!["--option", "foo", ""]);
+        assert_eq!(args.rest, vec!["hello"]);
+
+        let args = parse(&[
+            "--option",
+            "trusted-public-keys",
+            "cache.nixos.org-1:abc= --pure",
+            "--option",
+            "substituters",
+            "--keep-path",
+            "-p",
+        ])
+        .unwrap();
+        assert!(!args.keep_path);
+        assert_eq!(
+            args.other_kw,
+            vec![
+                "--option",
+                "trusted-public-keys",
+                "cache.nixos.org-1:abc= --pure",
+                "--option",
+                "substituters",
+                "--keep-path",
+            ]
+        );
+        assert!(parse(&["--option", "foo"]).is_err());
+    }
+
+    #[test]
+    fn test_renamed_setting() {
+        // Forwarded as given, with a suggestion.
+        let args = parse(&["--option", "build-max-jobs", "4", "-p", "hello"]);
+        let args = args.unwrap();
+        assert_eq!(args.other_kw, vec!["--option", "build-max-jobs", "4"]);
+        assert_eq!(
+            args.advisories,
+            vec!["--option build-max-jobs is a deprecated alias, use --option max-jobs"]
+        );
+        assert!(args.warnings.is_empty());
+
+        let args = parse(&["--option", "extra-binary-caches", "x", "-p", "a"]);
+        assert_eq!(
+            args.unwrap().advisories,
+            vec!["--option extra-binary-caches is a deprecated alias, use --option extra-substituters"]
+        );
+        let args = parse(&["--option", "max-jobs", "4", "-p", "hello"]);
+        assert!(args.unwrap().advisories.is_empty());
+    }
+
+    #[test]
+    fn test_argjson() {
+        let args =
+            parse(&["--argjson", "foo", r#"{"a":1}"#, "-p", "hello"]).unwrap();
+        assert!(args.packages);
+        assert_eq!(args.other_kw, vec!["--argjson", "foo", r#"{"a":1}"#]);
+        assert_eq!(args.rest, vec!["hello"]);
+        // The value isn't validated.
+        let args = parse(&["--argjson", "foo", "{not json"]).unwrap();
+        assert_eq!(args.other_kw[2], "{not json");
+        assert!(parse(&["--argjson", "foo"]).is_err());
+    }
+
+    #[test]
+    fn test_max_jobs() {
+        let args = parse(&["--max-jobs", "0"]).unwrap();
+        assert_eq!(args.other_kw, vec!["--max-jobs", "0"]);
+        let args = parse(&["-j", "4"]).unwrap();
+        assert_eq!(args.other_kw, vec!["--max-jobs", "4"]);
+        let args = parse(&["-j1024"]).unwrap();
+        assert_eq!(args.other_kw, vec!["--max-jobs", "1024"]);
+        let args = parse(&["--max-jobs", "auto"]).unwrap();
+        assert_eq!(args.other_kw, vec!["--max-jobs", "auto"]);
+
+        assert!(parse(&["--max-jobs", "abc"]).is_err());
+        assert!(parse(&["--max-jobs", ""]).is_err());
+        assert!(parse(&["-j", "4x"]).is_err());
+    }
+
+    #[test]
+    fn test_max_jobs_overflow() {
+        let args = parse(&["-j", "4096"]).unwrap();
+        assert_eq!(args.other_kw, vec!["--max-jobs", "4096"]);
+        assert!(args.warnings.is_empty());
+
+        for jobs in &["4097", "99999999999999999999", "0099999"] {
+            let args = parse(&["--max-jobs", jobs]).unwrap();
+            assert_eq!(args.other_kw, vec!["--max-jobs", "4096"]);
+            assert_eq!(
+                args.warnings,
+                vec![format!("--max-jobs {:?} is too large, using 4096", jobs)]
+            );
+        }
+
+        // A value that isn't a non-negative integer is an error instead.
+        let err = parse(&["-j", "-1"]).unwrap_err();
+        assert!(err.contains("non-negative integer"), "{}", err);
+        assert!(parse(&["--max-jobs", "-1"]).is_err());
+    }
+
+    #[test]
+    fn test_show_trace() {
+        let args = parse(&["--show-trace", "-p", "hello"]).unwrap();
+        assert_eq!(args.other_kw_unkeyed, vec!["--show-trace"]);
+        assert!(args.other_kw.is_empty());
+        assert_eq!(args.rest, vec!["hello"]);
+    }
+
+    #[test]
+    fn test_verbose() {
+        let args = parse(&["-vv", "--quiet", "-p", "hello"]).unwrap();
+        assert!(args.verbose);
+        assert!(args.quiet);
+        assert_eq!(args.other_kw_unkeyed, vec!["-v", "-v", "--quiet"]);
+        assert!(args.other_kw.is_empty());
+        assert_eq!(args.rest, vec!["hello"]);
+        assert!(!parse(&["-p", "hello"]).unwrap().verbose);
+    }
+
+    #[test]
+    fn test_color() {
+        assert_eq!(parse(&["-p", "hello"]).unwrap().color, Choice::Auto);
+        let args = parse(&["--color", "always", "-p", "hello"]).unwrap();
+        assert_eq!(args.color, Choice::Always);
+        assert!(args.other_kw.is_empty() && args.other_kw_unkeyed.is_empty());
+        let args = parse(&["--color=always", "--no-color", "-p"]).unwrap();
+        assert_eq!(args.color, Choice::Never);
+        assert!(parse(&["--color=sometimes", "-p", "hello"]).is_err());
+    }
+
+    #[test]
+    fn test_key_ignore() {
+        let args = parse(&[
+            "--argstr",
+            "name",
+            "dev-shell",
+            "-A",
+            "shell",
+            "--arg",
+            "x",
+            "1",
+            "--key-ignore",
+            "name",
+            "-p",
+            "hello",
+        ])
+        .unwrap();
+        assert_eq!(args.other_kw, vec!["-A", "shell", "--arg", "x", "1"]);
+        assert_eq!(
+            args.other_kw_unkeyed,
+            vec!["--argstr", "name", "dev-shell"]
+        );
+        assert!(args.warnings.is_empty());
+
+        // Only names of arguments are matched.
+        let args = parse(&["--option", "name", "v", "--key-ignore", "name"]);
+        let args = args.unwrap();
+        assert_eq!(args.other_kw, vec!["--option", "name", "v"]);
+        assert_eq!(args.warnings.len(), 1);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let args = parse(&["--timeout", "2m", "--run", "make test"]).unwrap();
+        assert_eq!(args.timeout, Some(Duration::from_secs(120)));
+        let args = parse(&["--timeout", "5s", "--exec", "make"]).unwrap();
+        assert_eq!(args.timeout, Some(Duration::from_secs(5)));
+        assert!(parse(&["--timeout", "5", "--run", "make"]).is_err());
+        assert!(parse(&["--timeout", "5s", "-p", "hello"]).is_err());
+        assert_eq!(parse(&["--run", "make"]).unwrap().timeout, None);
+    }
+
+    #[test]
+    fn test_packages_with_run() {
+        let args = parse(&["-p", "a", "b", "--run", "cmd"]).unwrap();
+        assert!(args.packages);
+        assert_eq!(args.rest, vec!["a", "b"]);
+        assert_eq!(run_cmd(&args), Some(OsStr::new("cmd")));
+
+        let args = parse(&["-p", "a", "--run", "cmd", "b"]).unwrap();
+        assert_eq!(args.rest, vec!["a", "b"]);
+        assert_eq!(run_cmd(&args), Some(OsStr::new("cmd")));
+    }
+
+    /// Function applications, lists and lambdas given to `-p` are single
+    /// packages, as long as they are quoted.
+    const PACKAGE_EXPRS: &[&str] = &[
+        "haskellPackages.ghcWithPackages (p: [p.lens])",
+        "python3.withPackages (ps: with ps; [ numpy requests ])",
+        "(callPackage ./pkg.nix { }).overrideAttrs (_: { doCheck = false; })",
+        "[ hello ]",
+    ];
+
+    #[test]
+    fn test_package_expressions() {
+        for expr in PACKAGE_EXPRS {
+            let args = parse(&["-p", expr, "hello", "--run", "cmd"]).unwrap();
+            assert!(args.packages);
+            assert_eq!(args.rest, vec![*expr, "hello"]);
+            assert!(args.other_kw.is_empty(), "{:?}", args.other_kw);
+            assert_eq!(run_cmd(&args), Some(OsStr::new("cmd")));
+        }
+
+        // The same in a `#! nix-shell` line.
+        let line = format!("-p {:?} hello", PACKAGE_EXPRS[1]);
+        let words = crate::shebang::shellwords(line.as_bytes())
+            .into_iter()
+            .map(OsString::from_vec)
+            .collect();
+        let args = Args::parse(words, true).unwrap();
+        assert_eq!(args.rest, vec![PACKAGE_EXPRS[1], "hello"]);
+    }
+
+    #[test]
+    fn test_many_flags() {
+        let mut args = Vec::new();
+        for i in 0..1000 {
+            args.extend(vec![
+                "--arg".to_string(),
+                format!("a{}", i),
+                "1".into(),
+            ]);
+            args.extend(vec!["-I".to_string(), format!("p{}=/p", i)]);
+        }
+        args.extend(vec!["-p".to_string(), "hello".into()]);
+        let args =
+            Args::parse(args.into_iter().map(OsString::from).collect(), false)
+                .unwrap();
+        assert_eq!(args.other_kw.len(), 5000);
+        assert_eq!(args.other_kw[..5], ["--arg", "a0", "1", "-I", "p0=/p"]);
+        assert_eq!(args.rest, vec!["hello"]);
+    }
+
+    #[test]
+    fn test_keep_failed() {
+        let args = parse(&["-K", "-p", "hello"]).unwrap();
+        assert_eq!(args.other_kw_unkeyed, vec!["-K"]);
+        let args = parse(&["--keep-failed", "-pK", "hello"]).unwrap();
+        assert_eq!(args.other_kw_unkeyed, vec!["--keep-failed", "-K"]);
+        assert!(args.other_kw.is_empty());
+        assert_eq!(args.rest, vec!["hello"]);
+    }
+
+    #[test]
+    fn test_no_gc_root() {
+        let args = parse(&["--no-gc-root", "-p", "hello"]).unwrap();
+        assert!(args.no_gc_root);
+        assert!(args.other_kw.is_empty());
+        assert!(args.other_kw_unkeyed.is_empty());
+        assert!(!parse(&["-p", "hello"]).unwrap().no_gc_root);
+    }
+
+    #[test]
+    fn test_audit() {
+        let args = parse(&["--audit", "-p", "hello"]).unwrap();
+        assert!(args.audit);
+        assert!(args.other_kw.is_empty());
+        assert!(args.other_kw_unkeyed.is_empty());
+        assert!(!FLAGS.iter().any(|(flag, _)| *flag == "--audit"));
+    }
+
+    #[test]
+    fn test_rcfile() {
+        let cwd = std::env::current_dir().unwrap();
+        let args = parse(&["--rcfile", "nix/rc.sh", "-p", "hello"]).unwrap();
+        assert_eq!(args.rcfile, Some(cwd.join("nix/rc.sh")));
+        assert!(args.other_kw.is_empty());
+        assert_eq!(args.rest, vec!["hello"]);
+        assert_eq!(parse(&["-p", "hello"]).unwrap().rcfile, None);
+    }
+
+    #[test]
+    fn test_trace_scope() {
+        let cwd = std::env::current_dir().unwrap();
+        let args = parse(&[
+            "--trace-scope",
+            "/home/user/project",
+            "--trace-scope",
+            "./nix/../lib",
+            "-p",
+            "hello",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.trace_scope,
+            vec![PathBuf::from("/home/user/project"), cwd.join("lib")]
+        );
+        assert!(args.other_kw.is_empty());
+        assert_eq!(args.rest, vec!["hello"]);
+    }
+
+    #[test]
+    fn test_trace_dir_depth() {
+        let args = parse(&["--trace-dir-depth", "2", "-p", "hello"]).unwrap();
+        assert_eq!(args.trace_dir_depth, Some(2));
+        assert!(args.other_kw.is_empty());
+        assert_eq!(args.rest, vec!["hello"]);
+        assert!(parse(&["--trace-dir-depth", "-1", "-p", "hello"]).is_err());
+        assert_eq!(parse(&["-p", "hello"]).unwrap().trace_dir_depth, None);
+    }
+
+    #[test]
+    fn test_cache_key_from() {
+        let args =
+            parse(&["--cache-key-from", "gen-42", "-p", "hello"]).unwrap();
+        assert_eq!(args.cache_key_from, Some("gen-42".into()));
+        assert!(args.other_kw.is_empty());
+        assert_eq!(args.rest, vec!["hello"]);
+    }
+
+    #[test]
+    fn test_trace_to() {
+        let args = parse(&["--trace-to", "trace.log", "-p", "hello"]).unwrap();
+        assert_eq!(args.trace_to, Some("trace.log".into()));
+        assert!(args.other_kw.is_empty());
+        assert!(args.other_kw_unkeyed.is_empty());
+        assert_eq!(args.rest, vec!["hello"]);
+    }
+
+    #[test]
+    fn test_quiet() {
+        let args = parse(&["--quiet", "-p", "hello"]).unwrap();
+        assert!(args.quiet);
+        assert_eq!(args.other_kw_unkeyed, vec!["--quiet"]);
+        assert!(args.other_kw.is_empty());
+        assert!(!parse(&["-p", "hello"]).unwrap().quiet);
+    }
+
+    #[test]
+    fn test_nix_quiet() {
+        assert_eq!(expand("-qq"), vec!["-q", "-q"]);
+        assert_eq!(expand("-pqq"), vec!["-p", "-q", "-q"]);
+        let args = parse(&["-qq", "-p", "hello"]).unwrap();
+        assert!(!args.quiet);
+        assert_eq!(args.other_kw_unkeyed, vec!["--quiet", "--quiet"]);
+        assert!(args.other_kw.is_empty());
+        assert_eq!(args.rest, vec!["hello"]);
+
+        // Each `--quiet` lowers it too.
+        let args = parse(&["--quiet", "-q", "--quiet", "-p"]).unwrap();
+        assert!(args.quiet);
+        assert_eq!(args.other_kw_unkeyed, vec!["--quiet"; 3]);
+    }
+
+    #[test]
+    fn test_watch() {
+        assert!(parse(&["--watch", "-p", "hello"]).is_err());
+        let args =
+            parse(&["--watch", "-p", "hello", "--run", "hello"]).unwrap();
+        assert!(args.watch);
+        let args =
+            parse(&["-p", "hello", "--watch", "--exec", "hello"]).unwrap();
+        assert!(args.watch);
+    }
+
+    #[test]
+    fn test_relative_include() {
+        let cwd = std::env::current_dir().unwrap();
+        let include = |arg: &str| {
+            let args = parse(&["-I", arg, "-p", "hello"]).unwrap();
+            assert_eq!(args.other_kw[0], "-I");
+            args.other_kw[1].clone()
+        };
+        let foo = cwd.join("foo").into_os_string();
+        assert_eq!(include("./foo"), foo);
+        let mut named = OsString::from("nixpkgs=");
+        named.push(&foo);
+        assert_eq!(include("nixpkgs=./foo"), named);
+        assert_eq!(include("nixpkgs=/abs/path"), "nixpkgs=/abs/path");
+        assert_eq!(
+            include("nixpkgs=https://example.com/x.tar.gz"),
+            "nixpkgs=https://example.com/x.tar.gz"
+        );
+        assert_eq!(
+            include("nixpkgs=channel:nixos-unstable"),
+            "nixpkgs=channel:nixos-unstable"
+        );
+
+        let args = Args::parse(vec!["-I".into(), "nixpkgs=./foo".into()], true)
+            .unwrap();
+        assert_eq!(args.other_kw[1], "nixpkgs=./foo");
+    }
+
+    #[test]
+    fn test_include_order() {
+        // nix looks entries up in order, so earlier ones win; other flags in
+        // between don't move them.
+        let args = parse(&[
+            "-I",
+            "nixpkgs=/a",
+            "--argstr",
+            "x",
+            "y",
+            "-I",
+            "nixpkgs=/b",
+            "--nixpkgs-rev",
+            "abc",
+            "-I/c",
+            "-p",
+            "hello",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.other_kw,
+            vec![
+                "-I",
+                "nixpkgs=/a",
+                "--argstr",
+                "x",
+                "y",
+                "-I",
+                "nixpkgs=/b",
+                "-I",
+                "nixpkgs=https://github.com/NixOS/nixpkgs/archive/abc.tar.gz",
+                "-I",
+                "/c",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expand_home() {
+        let expand =
+            |arg: &str| expand_home_to(arg.into(), OsStr::new("/home/user/"));
+        assert_eq!(expand("~/x.nix"), "/home/user/x.nix");
+        assert_eq!(expand("nixpkgs=~/np"), "nixpkgs=/home/user/np");
+        assert_eq!(expand("~"), "~");
+        assert_eq!(expand("~user/x.nix"), "~user/x.nix");
+        assert_eq!(expand("./a~/b"), "./a~/b");
+        assert_eq!(expand("nixpkgs=/a/~/b"), "nixpkgs=/a/~/b");
+
+        let home = match std::env::var("HOME") {
+            Ok(home) if home.starts_with('/') => home,
+            _ => return,
+        };
+        let home = home.trim_end_matches('/');
+        let args = parse(&["-I", "nixpkgs=~/np", "~/x.nix"]).unwrap();
+        assert_eq!(args.other_kw[1], format!("nixpkgs={}/np", home).as_str());
+        assert_eq!(args.rest, vec![format!("{}/x.nix", home).as_str()]);
+        // Package names are expressions, not paths.
+        let args = parse(&["-p", "~/x"]).unwrap();
+        assert_eq!(args.rest, vec!["~/x"]);
+    }
+
+    #[test]
+    fn test_nixpkgs_rev() {
+        let args = parse(&["--nixpkgs-rev", "abc123", "-p", "hello"]).unwrap();
+        assert_eq!(
+            args.other_kw,
+            vec![
+                "-I",
+                "nixpkgs=https://github.com/NixOS/nixpkgs/archive/abc123.tar.gz"
+            ]
+        );
+        assert!(parse(&["--nixpkgs-rev", "", "-p", "hello"]).is_err());
+        assert!(parse(&["-p", "hello", "--nixpkgs-rev"]).is_err());
+    }
+
+    #[test]
+    fn test_empty() {
+        for &in_shebang in &[false, true] {
+            let args = Args::parse(Vec::new(), in_shebang).unwrap();
+            assert!(!args.packages);
+            assert!(!args.pure);
+            assert_eq!(args.interpreter, "bash");
+            assert!(matches!(args.run, RunMode::InteractiveShell));
+            assert!(args.rest.is_empty());
+            assert!(args.other_kw.is_empty());
+            assert!(args.other_kw_unkeyed.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_packages_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(
+            &mut file,
+            b"# shared tools\ngit\n\n  python3Packages.black  \n#jq\nhello",
+        )
+        .unwrap();
+        let fname = file.path().to_str().unwrap();
+
+        let args = parse(&["--packages-file", fname, "--run", "git"]).unwrap();
+        assert!(args.packages);
+        assert_eq!(args.rest, vec!["git", "python3Packages.black", "hello"]);
+        assert_eq!(
+            args.packages_files,
+            vec![std::fs::canonicalize(file.path()).unwrap()]
+        );
+
+        let args = parse(&["--packages-file", fname, "-p", "lua"]).unwrap();
+        assert_eq!(args.rest.len(), 4);
+
+        assert!(parse(&["--packages-file", "/nonexistent/tools"]).is_err());
+    }
+
+    #[test]
+    fn test_merge_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a.nix"), dir.path().join("b.nix"));
+        std::fs::write(&a, "{}").unwrap();
+        std::fs::write(&b, "{}").unwrap();
+        let a_arg = a.to_str().unwrap();
+        let b_arg = format!("{}/./b.nix", dir.path().display());
+        let args = parse(&["--merge-file", a_arg, "--merge-file", &b_arg]);
+        let args = args.unwrap();
+        assert_eq!(
+            args.merge_files,
+            vec![a.canonicalize().unwrap(), b.canonicalize().unwrap()]
+        );
+        assert!(args.rest.is_empty());
+
+        assert!(parse(&["--merge-file", a_arg, "-p", "git"]).is_err());
+        assert!(parse(&["--merge-file", a_arg, a_arg]).is_err());
+        let missing = dir.path().join("missing.nix");
+        let err = parse(&["--merge-file", missing.to_str().unwrap()]);
+        assert!(err.unwrap_err().contains("can't read merge file"));
+    }
+
+    #[test]
+    fn test_exec_literal_args() {
+        let literal =
+            ["a b", r#""double" 'single'"#, "$HOME `id`", "--pure", ""];
+        let mut argv = vec!["-p", "hello", "--exec", "printf"];
+        argv.extend(&literal);
+        let args = parse(&argv).unwrap();
+        assert!(!args.pure);
+        match args.run {
+            RunMode::Exec(cmd, cmd_args) => {
+                assert_eq!(cmd, "printf");
+                assert_eq!(cmd_args, literal);
+            }
+            _ => panic!("expected --exec"),
+        }
+        assert_eq!(
+            args.warnings,
+            vec![
+                "--pure after --exec is passed to \"printf\", put options \
+                 before --exec"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_flags_after_exec() {
+        let args = parse(&["-p", "--exec", "make", "--pure", "--keep-path"]);
+        let args = args.unwrap();
+        assert!(!args.pure && !args.keep_path);
+        assert!(args.warnings[0].starts_with("--pure --keep-path after"));
+
+        // Flags other programs take too, short ones, and words after `--`.
+        for argv in [
+            &["-p", "--exec", "grep", "--color", "-p", "x"][..],
+            &["-p", "--exec", "env", "--", "--pure"],
+            &["-p", "--pure", "--exec", "env"],
+        ] {
+            assert!(parse(argv).unwrap().warnings.is_empty(), "{:?}", argv);
+        }
+    }
+
+    #[test]
+    fn test_run_args() {
+        let args =
+            parse(&["-p", "hello", "--run", "echo $1", "--", "hi", "-p"])
+                .unwrap();
+        assert_eq!(args.rest, vec!["hello"]);
+        assert_eq!(args.run_args, vec!["hi", "-p"]);
+        let args = parse(&["--command", "echo $1", "--", "hi"]).unwrap();
+        assert_eq!(args.run_args, vec!["hi"]);
+
+        // Without a command, they are still files or packages.
+        let args = parse(&["-p", "--", "hello", "--run"]).unwrap();
+        assert_eq!(args.rest, vec!["hello", "--run"]);
+        assert!(args.run_args.is_empty());
+    }
+
+    #[test]
+    fn test_command() {
+        let args =
+            parse(&["-p", "hello", "--command", "hello; return"]).unwrap();
+        assert!(matches!(
+            args.run,
+            RunMode::CommandThenInteractive(ref cmd) if cmd == "hello; return"
+        ));
+        let args = parse(&["-p", "hello", "--run", "hello"]).unwrap();
+        assert!(matches!(args.run, RunMode::Shell(_)));
+    }
+
+    #[test]
+    fn test_run_from_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, b"echo foo\necho bar\n").unwrap();
+        let at_fname = format!("@{}", file.path().to_str().unwrap());
+
+        let args = parse(&["--run", &at_fname]).unwrap();
+        assert_eq!(run_cmd(&args), Some(OsStr::new("echo foo\necho bar\n")));
+
+        let args = parse(&["--command", &at_fname]).unwrap();
+        assert_eq!(run_cmd(&args), Some(OsStr::new("echo foo\necho bar\n")));
+
+        let args = parse(&["--run", "echo @foo"]).unwrap();
+        assert_eq!(run_cmd(&args), Some(OsStr::new("echo @foo")));
+
+        assert!(parse(&["--run", "@/nonexistent/cmd.txt"]).is_err());
+    }
+
+    #[test]
+    fn test_run_from_file_nul() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, b"echo foo\necho \0bar\n")
+            .unwrap();
+        let at_fname = format!("@{}", file.path().to_str().unwrap());
+        for flag in ["--run", "--command"] {
+            let err = parse(&[flag, &at_fname]).unwrap_err();
+            assert!(err.contains("contains a NUL byte (line 2)"), "{}", err);
+        }
     }
 }
@@ -0,0 +1,134 @@
+//! Hidden `--audit` flag: on a cache hit, build the shell again and compare
+//! it with the cached one.  A difference means the cache was reused although
+//! it shouldn't have been, i.e. the trace misses some dependency.
+//!
+//! It is meant for hunting such bugs, so the extra build is done before
+//! running the command, however slow it is.
+
+use crate::environment::Environment;
+use crate::trace::Trace;
+use std::collections::BTreeSet;
+use std::ffi::{OsStr, OsString};
+
+#[derive(Debug, PartialEq)]
+pub struct Report {
+    /// Variables and functions that differ between the cached and the fresh
+    /// environment.
+    pub changed: Vec<OsString>,
+    /// Files used by the fresh build that the cached trace doesn't record.
+    pub untraced: Vec<OsString>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.changed.is_empty()
+    }
+}
+
+pub fn compare(
+    cached: &Environment,
+    cached_trace: &Trace,
+    fresh: &Environment,
+    fresh_trace: &Trace,
+) -> Report {
+    let cached_vars = cached.to_vars();
+    let changed = crate::dump::diff(fresh.to_vars(), &cached_vars)
+        .into_keys()
+        .collect();
+    let known = cached_trace.paths().collect::<BTreeSet<&OsStr>>();
+    let untraced = fresh_trace
+        .paths()
+        .filter(|path| !known.contains(path))
+        .map(|path| path.to_owned())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    Report { changed, untraced }
+}
+
+fn join(names: &[OsString]) -> String {
+    names
+        .iter()
+        .map(|x| x.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+pub fn print(report: &Report) {
+    if report.is_ok() {
+        note!(
+            "cached-nix-shell: audit: cached environment matches a fresh build"
+        );
+        return;
+    }
+    note!(
+        "cached-nix-shell: audit: cached environment differs from a fresh build: {}",
+        join(&report.changed)
+    );
+    if !report.untraced.is_empty() {
+        note!(
+            "cached-nix-shell: audit: files missing from the trace: {}",
+            join(&report.untraced)
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::EnvMap;
+
+    fn env(vars: &[(&str, &str)]) -> Environment {
+        Environment::from_vars(
+            vars.iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect::<EnvMap>(),
+        )
+    }
+
+    fn trace(dir: &std::path::Path, files: &[&str]) -> Trace {
+        let mut trace = Trace::read(&b""[..]).unwrap();
+        for file in files {
+            trace.add_path(&dir.join(file));
+        }
+        trace
+    }
+
+    #[test]
+    fn test_missing_dependency() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        std::fs::write(dir.join("shell.nix"), "").unwrap();
+        std::fs::write(dir.join("dep.nix"), "\"val1\"").unwrap();
+        let vars = [("out", "/nix/store/aaa"), ("GREETING", "val1")];
+
+        // The cached trace doesn't record dep.nix.
+        let cached_trace = trace(dir, &["shell.nix"]);
+        let fresh_trace = trace(dir, &["shell.nix", "dep.nix"]);
+        let report =
+            compare(&env(&vars), &cached_trace, &env(&vars), &fresh_trace);
+        assert!(report.is_ok());
+
+        std::fs::write(dir.join("dep.nix"), "\"val2\"").unwrap();
+        assert!(!cached_trace.check_for_changes());
+        let fresh = env(&[("out", "/nix/store/aaa"), ("GREETING", "val2")]);
+        let fresh_trace = trace(dir, &["shell.nix", "dep.nix"]);
+        let report = compare(&env(&vars), &cached_trace, &fresh, &fresh_trace);
+        assert_eq!(
+            report,
+            Report {
+                changed: vec!["GREETING".into()],
+                untraced: vec![dir.join("dep.nix").into_os_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_removed_function() {
+        let cached = env(&[("BASH_FUNC_greet%%", "() { :\n}")]);
+        let empty = Trace::read(&b""[..]).unwrap();
+        let report = compare(&cached, &empty, &env(&[]), &empty);
+        assert_eq!(report.changed, vec![OsString::from("BASH_FUNC_greet%%")]);
+        assert!(report.untraced.is_empty());
+    }
+}
@@ -0,0 +1,124 @@
+//! `cached-nix-shell --bench N`: time cache hits of a trivial shell, to
+//! notice performance regressions.
+//!
+//! The shell is built once into a temporary cache directory, which is
+//! removed afterwards, and then restored from the cache `N` times.  The
+//! runs make no GC root and ignore `$CACHED_NIX_SHELL_SYSTEM_CACHE`, so
+//! nothing outside of that directory is touched or used.
+
+use std::path::Path;
+use std::process::Command;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+pub fn parse_count(arg: &str) -> Result<usize, String> {
+    match arg.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("--bench requires a positive number, got {:?}", arg)),
+    }
+}
+
+/// Run the benchmark and print the time of each run and a summary.
+pub fn run(count: usize) -> Result<(), String> {
+    let tmp = TempDir::new().map_err(|e| e.to_string())?;
+    let shell_nix = tmp.path().join("shell.nix");
+    std::fs::write(&shell_nix, "with import <nixpkgs> { }; mkShell { }\n")
+        .map_err(|e| format!("{:?}: {}", shell_nix, e))?;
+    let cache_dir = tmp.path().join("cache");
+
+    let (cold, hit) = time_run(&shell_nix, &cache_dir)?;
+    if hit {
+        return Err("the first run was unexpectedly a cache hit".into());
+    }
+    println!("cold: {}", format_duration(cold));
+
+    let mut samples = Vec::with_capacity(count);
+    for i in 1..=count {
+        let (time, hit) = time_run(&shell_nix, &cache_dir)?;
+        if !hit {
+            return Err(format!("run {} was not a cache hit", i));
+        }
+        println!("hit {}: {}", i, format_duration(time));
+        samples.push(time);
+    }
+
+    let (min, median, max) = summary(&mut samples);
+    println!(
+        "hits: min {}, median {}, max {}",
+        format_duration(min),
+        format_duration(median),
+        format_duration(max)
+    );
+    Ok(())
+}
+
+/// Run `--run :` in the shell; return how long it took and whether it was a
+/// cache hit.
+fn time_run(
+    shell_nix: &Path,
+    cache_dir: &Path,
+) -> Result<(Duration, bool), String> {
+    let start = Instant::now();
+    let exec = std::env::current_exe()
+        .and_then(|exe| {
+            Command::new(exe)
+                .arg("--no-gc-root")
+                .arg(shell_nix)
+                .args(["--run", ":"])
+                .env("XDG_CACHE_HOME", cache_dir)
+                .env_remove("CACHED_NIX_SHELL_SYSTEM_CACHE")
+                .output()
+        })
+        .map_err(|e| format!("can't run: {}", e))?;
+    let time = start.elapsed();
+    let stderr = String::from_utf8_lossy(&exec.stderr);
+    if !exec.status.success() {
+        return Err(format!("{}: {}", exec.status, stderr.trim_end()));
+    }
+    Ok((time, !stderr.contains("cached-nix-shell: updating cache")))
+}
+
+/// Minimum, median and maximum of non-empty `samples`.
+fn summary(samples: &mut [Duration]) -> (Duration, Duration, Duration) {
+    samples.sort();
+    let mid = samples.len() / 2;
+    let median = if samples.len() % 2 == 1 {
+        samples[mid]
+    } else {
+        (samples[mid - 1] + samples[mid]) / 2
+    };
+    (samples[0], median, samples[samples.len() - 1])
+}
+
+fn format_duration(time: Duration) -> String {
+    format!("{:.1}ms", time.as_secs_f64() * 1000.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_count() {
+        assert_eq!(parse_count("3"), Ok(3));
+        assert!(parse_count("0").is_err());
+        assert!(parse_count("-1").is_err());
+        assert!(parse_count("x").is_err());
+    }
+
+    #[test]
+    fn test_summary() {
+        let ms = Duration::from_millis;
+        assert_eq!(summary(&mut [ms(5), ms(1), ms(3)]), (ms(1), ms(3), ms(5)));
+        assert_eq!(
+            summary(&mut [ms(4), ms(1), ms(2), ms(8)]),
+            (ms(1), ms(3), ms(8))
+        );
+        assert_eq!(summary(&mut [ms(7)]), (ms(7), ms(7), ms(7)));
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_micros(12345)), "12.3ms");
+    }
+}
@@ -0,0 +1,97 @@
+//! `--color=WHEN`: ANSI colors in the messages of cached-nix-shell itself.
+//! The output of nix and of the shell is left alone.
+//!
+//! By default (`auto`), messages are colored when stderr is a terminal and
+//! `NO_COLOR` isn't set, see <https://no-color.org>.
+
+use std::ffi::OsStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Choice {
+    Auto,
+    Always,
+    Never,
+}
+
+static CHOICE: AtomicU8 = AtomicU8::new(Choice::Auto as u8);
+
+pub fn parse(when: &OsStr) -> Result<Choice, String> {
+    match when.to_str() {
+        Some("auto") => Ok(Choice::Auto),
+        Some("always") => Ok(Choice::Always),
+        Some("never") => Ok(Choice::Never),
+        _ => Err(format!(
+            "--color requires \"auto\", \"always\" or \"never\", got {:?}",
+            when
+        )),
+    }
+}
+
+pub fn set(choice: Choice) {
+    CHOICE.store(choice as u8, Ordering::Relaxed);
+}
+
+fn choice() -> Choice {
+    match CHOICE.load(Ordering::Relaxed) {
+        x if x == Choice::Always as u8 => Choice::Always,
+        x if x == Choice::Never as u8 => Choice::Never,
+        _ => Choice::Auto,
+    }
+}
+
+fn use_color(choice: Choice, no_color: Option<&OsStr>, tty: bool) -> bool {
+    match choice {
+        Choice::Always => true,
+        Choice::Never => false,
+        Choice::Auto => tty && no_color.is_none_or(|x| x.is_empty()),
+    }
+}
+
+fn paint(code: &str, text: &str) -> String {
+    let tty = nix::unistd::isatty(2).unwrap_or(false);
+    let no_color = std::env::var_os("NO_COLOR");
+    if use_color(choice(), no_color.as_deref(), tty) {
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Bold red, for errors.
+pub fn error(text: &str) -> String {
+    paint("1;31", text)
+}
+
+/// Yellow, for warnings.
+pub fn warning(text: &str) -> String {
+    paint("33", text)
+}
+
+/// Dim, for debug messages.
+pub fn debug(text: &str) -> String {
+    paint("2", text)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("always".as_ref()), Ok(Choice::Always));
+        assert_eq!(parse("never".as_ref()), Ok(Choice::Never));
+        assert!(parse("yes".as_ref()).is_err());
+    }
+
+    #[test]
+    fn test_use_color() {
+        assert!(use_color(Choice::Auto, None, true));
+        assert!(!use_color(Choice::Auto, None, false));
+        assert!(!use_color(Choice::Auto, Some("1".as_ref()), true));
+        assert!(use_color(Choice::Auto, Some("".as_ref()), true));
+        assert!(!use_color(Choice::Never, None, true));
+        // An explicit --color=always wins over NO_COLOR and a non-terminal.
+        assert!(use_color(Choice::Always, Some("1".as_ref()), false));
+    }
+}
@@ -0,0 +1,122 @@
+//! Shell completion scripts for `cached-nix-shell --generate-completions`
+//!
+//! The flag list comes from `args::FLAGS`, so the completions follow the
+//! argument parser.
+
+use crate::args::FLAGS;
+
+/// Flags handled in `main` before argument parsing.
+const EXTRA_FLAGS: &[(&str, &str)] = &[
+    ("--version", "print version"),
+    ("--generate-completions", "print a completion script"),
+    ("--stats", "print cache statistics"),
+    ("--cache-info", "list cache entries"),
+    ("--verify", "check that caching works"),
+    ("--lint", "check the nix-shell lines of scripts"),
+    (
+        "--install-hook",
+        "build the shell after git checkouts and merges",
+    ),
+    ("--print-args", "show how arguments are interpreted"),
+    (
+        "--print-build-command",
+        "show the nix-shell command run on a cache miss",
+    ),
+    ("--dump-env", "print the shell environment"),
+    ("--explain-key", "show what the cache key is made of"),
+    ("--list-deps", "list files the cached shell depends on"),
+    (
+        "--validate-only",
+        "check the dependencies of the cached shell",
+    ),
+    ("--export-cache", "save cache entries to a tarball"),
+    ("--import-cache", "load cache entries from a tarball"),
+    ("--gc-cache", "remove stale cache entries"),
+    (
+        "--invalidate-path",
+        "remove cache entries using a store path",
+    ),
+    ("--refresh-roots", "make gc roots match cache entries"),
+];
+
+pub const SHELLS: &[&str] = &["bash", "zsh", "fish"];
+
+pub fn generate(shell: &str) -> Result<String, String> {
+    let flags = FLAGS.iter().chain(EXTRA_FLAGS.iter());
+    match shell {
+        "bash" => Ok(bash(flags)),
+        "zsh" => Ok(zsh(flags)),
+        "fish" => Ok(fish(flags)),
+        _ => Err(format!(
+            "unknown shell {:?}, expected one of: {}",
+            shell,
+            SHELLS.join(", ")
+        )),
+    }
+}
+
+fn bash<'a>(flags: impl Iterator<Item = &'a (&'a str, &'a str)>) -> String {
+    let words = flags.map(|(flag, _)| *flag).collect::<Vec<_>>().join(" ");
+    format!(
+        r#"_cached_nix_shell() {{
+	local cur="${{COMP_WORDS[COMP_CWORD]}}"
+	if [[ "$cur" == -* ]]; then
+		COMPREPLY=($(compgen -W "{}" -- "$cur"))
+	else
+		COMPREPLY=($(compgen -f -- "$cur"))
+	fi
+}}
+complete -F _cached_nix_shell cached-nix-shell
+"#,
+        words
+    )
+}
+
+fn zsh<'a>(flags: impl Iterator<Item = &'a (&'a str, &'a str)>) -> String {
+    let mut result = String::from("#compdef cached-nix-shell\n_arguments \\\n");
+    for (flag, descr) in flags {
+        result.push_str(&format!("\t'{}[{}]' \\\n", flag, descr));
+    }
+    result.push_str("\t'*:file:_files'\n");
+    result
+}
+
+fn fish<'a>(flags: impl Iterator<Item = &'a (&'a str, &'a str)>) -> String {
+    let mut result = String::new();
+    for (flag, descr) in flags {
+        let opt = match flag.strip_prefix("--") {
+            Some(long) => format!("-l {}", long),
+            None => format!("-s {}", &flag[1..]),
+        };
+        result.push_str(&format!(
+            "complete -c cached-nix-shell {} -d '{}'\n",
+            opt, descr
+        ));
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_generate() {
+        for shell in SHELLS {
+            let script = generate(shell).unwrap();
+            assert!(script.contains("cached-nix-shell"));
+            assert!(script.contains("run"));
+            assert!(script.ends_with('\n'));
+        }
+        assert!(generate("bash").unwrap().contains(" --keep-path "));
+        assert!(generate("zsh").unwrap().starts_with("#compdef "));
+        assert!(generate("zsh")
+            .unwrap()
+            .contains("\t'-A[attribute to build]'"));
+        assert!(generate("fish").unwrap().contains(" -l pure "));
+        assert!(generate("fish").unwrap().contains(" -s p "));
+        assert!(generate("tcsh").is_err());
+        // Descriptions are put into single quotes as-is
+        assert!(FLAGS.iter().all(|(_, descr)| !descr.contains('\'')));
+    }
+}
@@ -0,0 +1,170 @@
+//! Content-addressed storage of environment dumps, enabled with
+//! `CACHED_NIX_SHELL_DEDUP=1`.
+//!
+//! Dumps are stored once in `blobs/SHA1.env`, and `HASH.env` of each entry is
+//! a hard link to the blob, so readers don't need to know about it.  The link
+//! count serves as a reference count: a blob linked only from `blobs/` is
+//! unused, and gets removed by `--gc-cache`.
+
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use tempfile::NamedTempFile;
+
+pub fn enabled() -> bool {
+    crate::env_flag("CACHED_NIX_SHELL_DEDUP")
+}
+
+/// Passes writes through, hashing them on the way.
+struct Hashing<'a> {
+    inner: &'a mut dyn Write,
+    hasher: Sha1,
+}
+
+impl Write for Hashing<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.hasher.input(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Store the contents written by `write` as `dir/NAME`, sharing the blob
+/// with entries that have the same contents.
+pub fn store(
+    dir: &Path,
+    name: &str,
+    write: impl FnOnce(&mut dyn Write) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let blobs = dir.join("blobs");
+    std::fs::create_dir_all(&blobs)?;
+
+    // The name of the blob is only known once it's written, so it's
+    // written next to the entries first.  It stays linked there until the
+    // entry is, so that `remove_unused` never takes the blob for an unused
+    // one meanwhile.
+    let written = NamedTempFile::new_in(dir)?.into_temp_path();
+    let mut hasher = Sha1::new();
+    crate::write_atomic_with(&written, |w| {
+        let mut w = Hashing { inner: w, hasher };
+        write(&mut w)?;
+        hasher = w.hasher;
+        Ok(())
+    })?;
+    let ext = Path::new(name).extension().unwrap_or_default();
+    let blob = blobs.join(hasher.result_str()).with_extension(ext);
+
+    // Link under a temporary name first, since hard_link doesn't overwrite.
+    let tmp = NamedTempFile::new_in(dir)?.into_temp_path();
+    std::fs::remove_file(&tmp)?;
+    loop {
+        match std::fs::hard_link(&blob, &tmp) {
+            Ok(()) => break,
+            // New contents, or an unused blob that a concurrent
+            // `remove_unused` just removed.
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                match std::fs::hard_link(&written, &blob) {
+                    Err(e) if e.kind() != ErrorKind::AlreadyExists => {
+                        return Err(e)
+                    }
+                    _ => (),
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    std::fs::rename(&tmp, dir.join(name))
+}
+
+/// Remove blobs that no entry links to anymore.
+pub fn remove_unused(dir: &Path) -> std::io::Result<()> {
+    let entries = match std::fs::read_dir(dir.join("blobs")) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        // Another run may remove it at the same time.
+        let res = entry.metadata().and_then(|x| match x.nlink() {
+            1 => std::fs::remove_file(entry.path()),
+            _ => Ok(()),
+        });
+        match res {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn blobs(dir: &Path) -> usize {
+        std::fs::read_dir(dir.join("blobs")).unwrap().count()
+    }
+
+    #[test]
+    fn test_shared_blob() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        store(dir, "aaa.env", |w| w.write_all(b"PATH=/bin\0")).unwrap();
+        store(dir, "bbb.env", |w| w.write_all(b"PATH=/bin\0")).unwrap();
+        assert_eq!(blobs(dir), 1);
+        store(dir, "ccc.env", |w| w.write_all(b"PATH=/usr/bin\0")).unwrap();
+        assert_eq!(blobs(dir), 2);
+
+        std::fs::remove_file(dir.join("aaa.env")).unwrap();
+        remove_unused(dir).unwrap();
+        assert_eq!(blobs(dir), 2);
+        assert_eq!(std::fs::read(dir.join("bbb.env")).unwrap(), b"PATH=/bin\0");
+
+        // Overwriting the last reference leaves the blob unused.
+        store(dir, "bbb.env", |w| w.write_all(b"PATH=/usr/bin\0")).unwrap();
+        assert_eq!(blobs(dir), 2);
+        remove_unused(dir).unwrap();
+        assert_eq!(blobs(dir), 1);
+        assert_eq!(
+            std::fs::read(dir.join("bbb.env")).unwrap(),
+            b"PATH=/usr/bin\0"
+        );
+        // Only the entries are left next to them.
+        let mut names = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|x| x.unwrap().file_name())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["bbb.env", "blobs", "ccc.env"]);
+    }
+
+    #[test]
+    fn test_concurrent_remove_unused() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        std::thread::scope(|scope| {
+            let gc = scope.spawn(|| {
+                for _ in 0..200 {
+                    remove_unused(dir).unwrap();
+                }
+            });
+            for i in 0..200 {
+                // The blob of the previous one is unused once overwritten.
+                let env = format!("N={}\0", i % 2);
+                store(dir, "aaa.env", |w| w.write_all(env.as_bytes())).unwrap();
+                assert_eq!(
+                    std::fs::read(dir.join("aaa.env")).unwrap(),
+                    env.as_bytes()
+                );
+            }
+            gc.join().unwrap();
+        });
+    }
+}
@@ -0,0 +1,148 @@
+//! Parsing of dotenv-style files for `--env-file`, and writing them for
+//! `--keep-env-file`.
+//!
+//! Supported syntax: `KEY=VALUE` lines, optionally prefixed with `export `.
+//! Blank lines and lines starting with `#` are ignored.  Values can be wrapped
+//! in single or double quotes; escape sequences are not interpreted.
+
+use crate::EnvMap;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+
+pub fn load(fname: &OsStr) -> Result<EnvMap, String> {
+    std::fs::read(fname)
+        .map_err(|e| format!("can't read {:?}: {}", fname, e))
+        .and_then(|data| parse(&data))
+        .map_err(|e| format!("{:?}: {}", fname, e))
+}
+
+pub fn parse(data: &[u8]) -> Result<EnvMap, String> {
+    let mut env = EnvMap::new();
+    for (lineno, line) in data.split(|&b| b == b'\n').enumerate() {
+        let line = trim(line);
+        if line.is_empty() || line[0] == b'#' {
+            continue;
+        }
+        let line = line.strip_prefix(b"export ").map_or(line, trim);
+        let pos = line.iter().position(|&b| b == b'=');
+        let (key, value) = match pos {
+            Some(pos) if is_valid_name(&line[..pos]) => {
+                (&line[..pos], unquote(&line[pos + 1..]))
+            }
+            _ => {
+                return Err(format!(
+                    "line {}: expected KEY=VALUE, got {:?}",
+                    lineno + 1,
+                    OsStr::from_bytes(line)
+                ))
+            }
+        };
+        env.insert(
+            OsStr::from_bytes(key).into(),
+            OsStr::from_bytes(value).into(),
+        );
+    }
+    Ok(env)
+}
+
+/// `KEY="VALUE"` lines that `parse` reads back.  Only the outer quotes are
+/// removed by `parse`, so any value without a newline can be written as is.
+/// Other variables (e.g. exported bash functions) are skipped; their names
+/// are returned along with the contents.
+pub fn serialize(env: &EnvMap) -> (Vec<u8>, Vec<&OsStr>) {
+    let mut res = Vec::new();
+    let mut skipped = Vec::new();
+    for (k, v) in env {
+        if !is_valid_name(k.as_bytes()) || v.as_bytes().contains(&b'\n') {
+            skipped.push(k.as_os_str());
+            continue;
+        }
+        res.extend(k.as_bytes());
+        res.extend(b"=\"");
+        res.extend(v.as_bytes());
+        res.extend(b"\"\n");
+    }
+    (res, skipped)
+}
+
+fn trim(s: &[u8]) -> &[u8] {
+    let start = s.iter().position(|b| !b.is_ascii_whitespace());
+    let end = s.iter().rposition(|b| !b.is_ascii_whitespace());
+    match (start, end) {
+        (Some(start), Some(end)) => &s[start..=end],
+        _ => &[],
+    }
+}
+
+fn unquote(s: &[u8]) -> &[u8] {
+    match (s.first(), s.last()) {
+        (Some(b'"'), Some(b'"')) | (Some(b'\''), Some(b'\''))
+            if s.len() >= 2 =>
+        {
+            &s[1..s.len() - 1]
+        }
+        _ => s,
+    }
+}
+
+fn is_valid_name(s: &[u8]) -> bool {
+    match s.split_first() {
+        Some((first, rest)) => {
+            (first.is_ascii_alphabetic() || *first == b'_')
+                && rest.iter().all(|b| b.is_ascii_alphanumeric() || *b == b'_')
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let env = parse(
+            b"# comment\n\
+              \n\
+              FOO=bar\n\
+              export API_URL=https://example.com/?a=b\n\
+              QUOTED=\"with spaces\"\n\
+              SINGLE='it\"s'\n\
+              EMPTY=\n",
+        )
+        .unwrap();
+        let get = |k: &str| env.get(OsStr::new(k)).map(|v| v.to_str().unwrap());
+        assert_eq!(env.len(), 5);
+        assert_eq!(get("FOO"), Some("bar"));
+        assert_eq!(get("API_URL"), Some("https://example.com/?a=b"));
+        assert_eq!(get("QUOTED"), Some("with spaces"));
+        assert_eq!(get("SINGLE"), Some("it\"s"));
+        assert_eq!(get("EMPTY"), Some(""));
+
+        assert!(parse(b"FOO=bar\nnot a variable\n").is_err());
+        assert!(parse(b"1FOO=bar\n").is_err());
+        assert!(parse(b"=bar\n").is_err());
+    }
+
+    #[test]
+    fn test_serialize() {
+        let mut env = EnvMap::new();
+        for (k, v) in [
+            ("PATH", "/nix/store/a/bin:/bin"),
+            ("QUOTES", r#""it's" 'quoted'"#),
+            ("SPACES", "  padded  "),
+            ("EMPTY", ""),
+            ("MULTILINE", "a\nb"),
+            ("BASH_FUNC_f%%", "() { :\n}"),
+        ] {
+            env.insert(k.into(), v.into());
+        }
+        let (data, skipped) = serialize(&env);
+        assert_eq!(skipped, vec!["BASH_FUNC_f%%", "MULTILINE"]);
+
+        let mut expected = env.clone();
+        expected.remove(OsStr::new("MULTILINE"));
+        expected.remove(OsStr::new("BASH_FUNC_f%%"));
+        assert_eq!(parse(&data).unwrap(), expected);
+    }
+}
@@ -0,0 +1,291 @@
+//! `cached-nix-shell --dump-env=FORMAT`: print the shell environment instead
+//! of running a command in it.
+
+use crate::environment::function_name;
+use crate::EnvMap;
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+
+pub const FORMATS: &[&str] = &["json", "env", "fish"];
+
+/// Variables to set, or to unset (`None`).
+pub type Changes = BTreeMap<OsString, Option<OsString>>;
+
+/// Set every variable of the shell.
+pub fn full(env: EnvMap) -> Changes {
+    env.into_iter().map(|(k, v)| (k, Some(v))).collect()
+}
+
+/// Only the variables that the shell adds, changes, or unsets compared to
+/// the caller's environment (`--dump-env --diff`).
+pub fn diff(env: EnvMap, caller_env: &EnvMap) -> Changes {
+    let mut changes = caller_env
+        .keys()
+        .filter(|k| !env.contains_key(*k))
+        .map(|k| (k.clone(), None))
+        .collect::<Changes>();
+    for (k, v) in env {
+        if caller_env.get(&k) != Some(&v) {
+            changes.insert(k, Some(v));
+        }
+    }
+    changes
+}
+
+pub fn format(format: &str, changes: &Changes) -> Result<String, String> {
+    match format {
+        "json" => Ok(json(changes)),
+        "env" => Ok(shell_lines(changes, sh_line)),
+        "fish" => Ok(shell_lines(changes, fish_line)),
+        _ => Err(format!(
+            "unknown format {:?}, expected one of: {}",
+            format,
+            FORMATS.join(", ")
+        )),
+    }
+}
+
+/// Unset variables are `null`.
+fn json(changes: &Changes) -> String {
+    let map = changes
+        .iter()
+        .map(|(k, v)| {
+            let v = match v {
+                Some(v) => v.to_string_lossy().into_owned().into(),
+                None => serde_json::Value::Null,
+            };
+            (k.to_string_lossy().into_owned(), v)
+        })
+        .collect::<serde_json::Map<_, _>>();
+    let mut res = serde_json::to_string_pretty(&map).unwrap();
+    res.push('\n');
+    res
+}
+
+/// Format variables that can be set from a shell, skipping the rest (e.g.
+/// names that aren't valid identifiers).
+fn shell_lines(
+    changes: &Changes,
+    line: impl Fn(&OsStr, Option<&str>) -> Option<String>,
+) -> String {
+    changes
+        .iter()
+        .filter_map(|(k, v)| {
+            let v = match v {
+                Some(v) => Some(v.to_str()?),
+                None => None,
+            };
+            line(k, v)
+        })
+        .collect()
+}
+
+/// Lines to `eval` in bash or zsh.  Exported bash functions are defined
+/// again, and exported in bash only, since zsh can't export functions.
+fn sh_line(name: &OsStr, val: Option<&str>) -> Option<String> {
+    if let Some(func) = function_name(name) {
+        let func = func.to_str().filter(|x| is_identifier(x))?;
+        return Some(match val {
+            Some(def) if def.starts_with("() ") => format!(
+                "{} {}\nif [ -n \"${{BASH_VERSION-}}\" ]; then export -f {}; fi\n",
+                func, def, func
+            ),
+            Some(_) => return None,
+            None => format!("unset -f {}\n", func),
+        });
+    }
+    let name = name.to_str().filter(|x| is_identifier(x))?;
+    Some(match val {
+        Some(val) => format!("export {}={}\n", name, sh_quote(val)),
+        None => format!("unset {}\n", name),
+    })
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Quote for POSIX shells: nothing is special inside single quotes, except
+/// the single quote itself.
+pub fn sh_quote(val: &str) -> String {
+    format!("'{}'", val.replace('\'', r"'\''"))
+}
+
+/// Like `sh_quote`, but words that need no quoting are left as they are.
+pub fn sh_word(val: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "@%+=:,./_-".contains(c);
+    if !val.is_empty() && val.chars().all(plain) {
+        val.to_string()
+    } else {
+        sh_quote(val)
+    }
+}
+
+/// Quote for fish: backslashes and single quotes are escaped inside single
+/// quotes.
+fn fish_quote(val: &str) -> String {
+    format!("'{}'", val.replace('\\', r"\\").replace('\'', r"\'"))
+}
+
+/// fish keeps `PATH`-like variables as lists, so set them element-wise.
+/// Exported bash functions are skipped.
+fn fish_line(name: &OsStr, val: Option<&str>) -> Option<String> {
+    let name = name.to_str().filter(|x| is_identifier(x))?;
+    let val = match val {
+        Some(val) => val,
+        None => return Some(format!("set -e {}\n", name)),
+    };
+    let vals = if name.ends_with("PATH") {
+        val.split(':').map(fish_quote).collect::<Vec<_>>()
+    } else {
+        vec![fish_quote(val)]
+    };
+    Some(format!("set -gx {} {}\n", name, vals.join(" ")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn env() -> EnvMap {
+        let mut env = EnvMap::new();
+        env.insert("GREETING".into(), r#"it's "quoted" \ text"#.into());
+        env.insert("PATH".into(), "/a b/bin:/c/bin".into());
+        env.insert("BASH_FUNC_f%%".into(), "() { :\n}".into());
+        env
+    }
+
+    #[test]
+    fn test_json() {
+        let out = format("json", &full(env())).unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(parsed["GREETING"], r#"it's "quoted" \ text"#);
+        assert_eq!(parsed["BASH_FUNC_f%%"], "() { :\n}");
+    }
+
+    #[test]
+    fn test_env() {
+        assert_eq!(
+            format("env", &full(env())).unwrap(),
+            concat!(
+                "f () { :\n}\n",
+                "if [ -n \"${BASH_VERSION-}\" ]; then export -f f; fi\n",
+                r#"export GREETING='it'\''s "quoted" \ text'"#,
+                "\n",
+                "export PATH='/a b/bin:/c/bin'\n",
+            )
+        );
+    }
+
+    /// Evaluate `script` in bash, then run `check` in the resulting shell.
+    fn bash_eval(script: &str, check: &str) -> String {
+        let output = std::process::Command::new("bash")
+            .args(["-c", "eval \"$1\" && eval \"$2\"", "bash", script, check])
+            .env_clear()
+            .env("HOME", "/home/user")
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn test_env_eval() {
+        let mut env = EnvMap::new();
+        env.insert("GREETING".into(), "it's \"quoted\"\n$HOME `x` \\".into());
+        env.insert(
+            "BASH_FUNC_greet%%".into(),
+            "() {  printf '%s!' \"$1\"\n}".into(),
+        );
+        let mut caller = EnvMap::new();
+        caller.insert("HOME".into(), "/home/user".into());
+        let script = format("env", &diff(env, &caller)).unwrap();
+
+        assert_eq!(
+            bash_eval(&script, "printf '%s|' \"$GREETING\" \"${HOME-unset}\""),
+            "it's \"quoted\"\n$HOME `x` \\|unset|"
+        );
+        assert_eq!(bash_eval(&script, "greet hi"), "hi!");
+        // The function and the variable are exported to child processes.
+        assert_eq!(
+            bash_eval(&script, "exec /bin/sh -c 'printf %s \"$GREETING\"'"),
+            "it's \"quoted\"\n$HOME `x` \\"
+        );
+        assert_eq!(bash_eval(&script, "exec bash -c 'greet child'"), "child!");
+
+        let mut caller = EnvMap::new();
+        caller.insert("BASH_FUNC_greet%%".into(), "() { :\n}".into());
+        let script = format("env", &diff(EnvMap::new(), &caller)).unwrap();
+        assert_eq!(script, "unset -f greet\n");
+        assert_eq!(
+            bash_eval(
+                &format!("greet() {{ :; }}\n{}", script),
+                "type greet || printf gone"
+            ),
+            "gone"
+        );
+    }
+
+    #[test]
+    fn test_fish() {
+        assert_eq!(
+            format("fish", &full(env())).unwrap(),
+            concat!(
+                r#"set -gx GREETING 'it\'s "quoted" \\ text'"#,
+                "\n",
+                "set -gx PATH '/a b/bin' '/c/bin'\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut caller = env();
+        caller.insert("HOME".into(), "/home/user".into());
+        caller.insert("PATH".into(), "/usr/bin".into());
+        caller.remove(std::ffi::OsStr::new("BASH_FUNC_f%%"));
+        let changes = diff(env(), &caller);
+        assert_eq!(
+            changes.keys().collect::<Vec<_>>(),
+            vec!["BASH_FUNC_f%%", "HOME", "PATH"]
+        );
+
+        let out = format("json", &changes).unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(parsed["HOME"], serde_json::Value::Null);
+        assert_eq!(parsed["PATH"], "/a b/bin:/c/bin");
+        assert!(parsed.get("GREETING").is_none());
+
+        assert_eq!(
+            format("env", &changes).unwrap(),
+            concat!(
+                "f () { :\n}\n",
+                "if [ -n \"${BASH_VERSION-}\" ]; then export -f f; fi\n",
+                "unset HOME\n",
+                "export PATH='/a b/bin:/c/bin'\n",
+            )
+        );
+        assert_eq!(
+            format("fish", &changes).unwrap(),
+            "set -e HOME\nset -gx PATH '/a b/bin' '/c/bin'\n"
+        );
+    }
+
+    #[test]
+    fn test_sh_word() {
+        assert_eq!(sh_word("--max-jobs"), "--max-jobs");
+        assert_eq!(sh_word("PATH=/a/bin:/b/bin"), "PATH=/a/bin:/b/bin");
+        assert_eq!(sh_word("env -0"), "'env -0'");
+        assert_eq!(sh_word("$HOME"), "'$HOME'");
+        assert_eq!(sh_word(""), "''");
+    }
+
+    #[test]
+    fn test_unknown() {
+        assert!(format("xml", &full(env())).is_err());
+    }
+}
@@ -0,0 +1,29 @@
+//! Extensions of the files of a cache entry, `HASH.EXT`, as written by
+//! `store_shell`.
+
+use std::path::{Path, PathBuf};
+
+pub const INPUTS: &str = "inputs";
+pub const TRACE: &str = "trace";
+pub const DRV: &str = "drv";
+pub const META: &str = "meta";
+pub const ENV: &str = "env";
+/// The trace and the derivation that were last found valid in the store, so
+/// that hits don't ask nix again.
+pub const VALID: &str = "valid";
+
+/// All of them: `--gc-cache` removes them, and `--export-cache` saves them.
+pub const ALL: &[&str] = &[INPUTS, TRACE, DRV, META, VALID, ENV];
+/// Taken while the entry is read or written (see `lock`); not a part of the
+/// entry itself.
+pub const LOCK: &str = "lock";
+
+/// The name of the file `ext` of the entry `hash`.
+pub fn name(hash: &str, ext: &str) -> String {
+    format!("{}.{}", hash, ext)
+}
+
+/// The file `ext` of the entry `hash` in `dir`.
+pub fn path(dir: &Path, hash: &str, ext: &str) -> PathBuf {
+    dir.join(name(hash, ext))
+}
@@ -0,0 +1,344 @@
+//! Environment set up by nix-shell, as stored in `HASH.env` cache files.
+//!
+//! Bookkeeping about the entry (the derivation and the build time) is kept
+//! separately, in the `HASH.drv` symlink and in `HASH.meta` (see `meta`).
+
+use crate::{read_env, EnvMap};
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::io::{BufRead, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+/// Bash exports functions as variables named `BASH_FUNC_name%%`.
+const FUNC_PREFIX: &[u8] = b"BASH_FUNC_";
+const FUNC_SUFFIX: &[u8] = b"%%";
+
+/// The umask at the end of the shell setup, in octal, as captured along the
+/// variables (see `PRINT_ENV` in `main.rs`).
+pub const UMASK_VAR: &str = "CACHED_NIX_SHELL_UMASK";
+
+/// The soft limit of open files (`ulimit -Sn`) at the end of the shell
+/// setup: a number or `unlimited`.
+pub const NOFILE_VAR: &str = "CACHED_NIX_SHELL_NOFILE";
+
+/// The shell options (`shopt -p`) and the aliases (`alias -p`) at the end of
+/// the shell setup.
+pub const SHOPTS_VAR: &str = "CACHED_NIX_SHELL_SHOPTS";
+pub const ALIASES_VAR: &str = "CACHED_NIX_SHELL_ALIASES";
+
+/// Shell options that `bash -c` sets by default, so a shell that doesn't
+/// change them needs no `shopt`.
+const DEFAULT_SHOPTS: &[&str] = &[
+    "checkwinsize",
+    "cmdhist",
+    "complete_fullquote",
+    "extquote",
+    "force_fignore",
+    "globasciiranges",
+    "globskipdots",
+    "hostcomplete",
+    "interactive_comments",
+    "patsub_replacement",
+    "progcomp",
+    "promptvars",
+    "sourcepath",
+];
+
+/// Options that describe how bash was started, and can't be set.
+const READONLY_SHOPTS: &[&str] = &["login_shell", "restricted_shell"];
+
+/// Options that every nix-shell sets, not the shell: its rcfile sets
+/// `execfail`, stdenv's `setup` sets `inherit_errexit`, and bash is
+/// interactive, which sets `expand_aliases` (set again if there are aliases).
+const NIX_SHELL_SHOPTS: &[&str] =
+    &["execfail", "expand_aliases", "inherit_errexit"];
+
+/// `RLIM_INFINITY` on Linux, printed as `unlimited` by `ulimit`.
+pub const UNLIMITED: u64 = u64::MAX;
+
+#[derive(Debug, PartialEq)]
+pub struct Environment {
+    /// Exported variables, including `PATH`.
+    pub vars: EnvMap,
+    /// Exported bash functions: name to definition, e.g. `() { echo hi\n}`.
+    pub functions: EnvMap,
+    /// The umask set by the shell, e.g. by a `shellHook`; `None` if the
+    /// shell leaves it alone.
+    pub umask: Option<u32>,
+    /// The soft limit of open files set by the shell, like `umask`; only
+    /// applied with `--restore-limits`.
+    pub nofile: Option<u64>,
+    /// `shopt -p` and `alias -p` as printed by the shell, re-established
+    /// before the command with `shell_setup`.
+    pub shopts: Option<OsString>,
+    pub aliases: Option<OsString>,
+}
+
+impl Environment {
+    /// Split bash functions out of variables as printed by `env -0`, and
+    /// the umask, the limit of open files, the shell options and the
+    /// aliases, if they were captured.
+    pub fn from_vars(mut env: EnvMap) -> Environment {
+        let umask = env
+            .remove(OsStr::new(UMASK_VAR))
+            .and_then(|x| u32::from_str_radix(x.to_str()?, 8).ok());
+        let nofile = env
+            .remove(OsStr::new(NOFILE_VAR))
+            .and_then(|x| parse_limit(x.to_str()?));
+        let shopts = env.remove(OsStr::new(SHOPTS_VAR));
+        let aliases = env.remove(OsStr::new(ALIASES_VAR));
+        let mut vars = EnvMap::new();
+        let mut functions = EnvMap::new();
+        for (k, v) in env {
+            match function_name(&k) {
+                Some(name) => functions.insert(name.to_owned(), v),
+                None => vars.insert(k, v),
+            };
+        }
+        Environment {
+            vars,
+            functions,
+            umask,
+            nofile,
+            shopts,
+            aliases,
+        }
+    }
+
+    /// Bash commands that set the shell options the shell changed and
+    /// define its aliases, which `bash -c` wouldn't have otherwise; `None`
+    /// if there are none.  Aliases are expanded, as in an interactive shell.
+    pub fn shell_setup(&self) -> Option<OsString> {
+        let mut set = Vec::new();
+        let mut unset = Vec::new();
+        let shopts = self.shopts.as_deref().unwrap_or_default();
+        for line in shopts.as_bytes().split(|&b| b == b'\n') {
+            let (on, name) = match line.strip_prefix(b"shopt -s ") {
+                Some(name) => (true, name),
+                None => match line.strip_prefix(b"shopt -u ") {
+                    Some(name) => (false, name),
+                    None => continue,
+                },
+            };
+            let name = String::from_utf8_lossy(name);
+            if READONLY_SHOPTS.contains(&&*name)
+                || NIX_SHELL_SHOPTS.contains(&&*name)
+            {
+                continue;
+            }
+            match (on, DEFAULT_SHOPTS.contains(&&*name)) {
+                (true, false) => set.push(name),
+                (false, true) => unset.push(name),
+                _ => (),
+            }
+        }
+        let aliases = self
+            .aliases
+            .as_ref()
+            .filter(|x| !x.is_empty())
+            .map(|x| x.as_bytes());
+        if aliases.is_some() && !set.iter().any(|x| x == "expand_aliases") {
+            set.push("expand_aliases".into());
+        }
+
+        // Options unknown to an older bash are skipped.
+        let mut res = Vec::new();
+        for (flag, names) in [("-s", set), ("-u", unset)] {
+            if !names.is_empty() {
+                let line =
+                    format!("shopt {} {} 2>/dev/null\n", flag, names.join(" "));
+                res.extend(line.as_bytes());
+            }
+        }
+        if let Some(aliases) = aliases {
+            res.extend(aliases);
+            res.push(b'\n');
+        }
+        (!res.is_empty()).then(|| OsString::from_vec(res))
+    }
+
+    /// Variables to run a program with; functions are exported back as
+    /// `BASH_FUNC_name%%`.
+    pub fn to_vars(&self) -> EnvMap {
+        let mut env = self.vars.clone();
+        for (name, def) in &self.functions {
+            let mut k = FUNC_PREFIX.to_vec();
+            k.extend(name.as_bytes());
+            k.extend(FUNC_SUFFIX);
+            env.insert(OsString::from_vec(k), def.clone());
+        }
+        env
+    }
+
+    /// Write in the `env -0` format, so entries written by older versions
+    /// are still read.  Variables are written one at a time, without
+    /// copying their values.
+    pub fn write(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        let umask = self.umask.map(|x| OsString::from(format!("{:04o}", x)));
+        let nofile = self.nofile.map(|x| OsString::from(format_limit(x)));
+        let mut env = self
+            .vars
+            .iter()
+            .map(|(k, v)| (k.clone(), v.as_os_str()))
+            .collect::<BTreeMap<_, _>>();
+        for (name, def) in &self.functions {
+            let k = [FUNC_PREFIX, name.as_bytes(), FUNC_SUFFIX].concat();
+            env.insert(OsString::from_vec(k), def);
+        }
+        for (k, v) in [
+            (UMASK_VAR, &umask),
+            (NOFILE_VAR, &nofile),
+            (SHOPTS_VAR, &self.shopts),
+            (ALIASES_VAR, &self.aliases),
+        ] {
+            if let Some(v) = v {
+                env.insert(k.into(), v);
+            }
+        }
+        for (k, v) in env {
+            w.write_all(k.as_bytes())?;
+            w.write_all(b"=")?;
+            w.write_all(v.as_bytes())?;
+            w.write_all(b"\0")?;
+        }
+        Ok(())
+    }
+
+    /// The dump `write` writes, in memory.
+    #[cfg(test)]
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut res = Vec::new();
+        self.write(&mut res).unwrap();
+        res
+    }
+
+    /// The inverse of `write`; incomplete dumps are an error.
+    pub fn read(reader: impl BufRead) -> std::io::Result<Environment> {
+        Ok(Environment::from_vars(read_env(reader)?))
+    }
+}
+
+pub fn parse_limit(limit: &str) -> Option<u64> {
+    match limit {
+        "unlimited" => Some(UNLIMITED),
+        _ => limit.parse().ok(),
+    }
+}
+
+pub fn format_limit(limit: u64) -> String {
+    match limit {
+        UNLIMITED => "unlimited".to_string(),
+        _ => limit.to_string(),
+    }
+}
+
+pub fn function_name(var: &OsStr) -> Option<&OsStr> {
+    let var = var.as_bytes();
+    if var.len() > FUNC_PREFIX.len() + FUNC_SUFFIX.len()
+        && var.starts_with(FUNC_PREFIX)
+        && var.ends_with(FUNC_SUFFIX)
+    {
+        let name = &var[FUNC_PREFIX.len()..var.len() - FUNC_SUFFIX.len()];
+        Some(OsStr::from_bytes(name))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut vars = EnvMap::new();
+        vars.insert("PATH".into(), "/nix/store/a/bin:/nix/store/b/bin".into());
+        vars.insert("MULTILINE".into(), "line 1\nline 2\n".into());
+        vars.insert("BASH_FUNC_%%".into(), "not a function".into());
+        let mut functions = EnvMap::new();
+        functions.insert(
+            "greet".into(),
+            "() {  echo hello;\n echo \"$1\"\n}".into(),
+        );
+        let env = Environment {
+            vars,
+            functions,
+            umask: None,
+            nofile: None,
+            shopts: Some("shopt -s extglob\nshopt -u failglob".into()),
+            aliases: Some("alias ll='ls -l'".into()),
+        };
+
+        let serialized = env.serialize();
+        assert!(serialized
+            .split(|&b| b == 0)
+            .any(|var| var.starts_with(b"BASH_FUNC_greet%%=() {")));
+        assert_eq!(Environment::read(&serialized[..]).unwrap(), env);
+    }
+
+    #[test]
+    fn test_umask() {
+        let mut vars = EnvMap::new();
+        vars.insert("PATH".into(), "/nix/store/a/bin".into());
+        vars.insert(UMASK_VAR.into(), "0077".into());
+        let env = Environment::from_vars(vars);
+        assert_eq!(env.umask, Some(0o077));
+        assert!(!env.to_vars().contains_key(OsStr::new(UMASK_VAR)));
+        assert_eq!(Environment::read(&env.serialize()[..]).unwrap(), env);
+
+        // Entries written before the umask was captured have none.
+        let env = Environment::read(&b"PATH=/bin\0"[..]).unwrap();
+        assert_eq!(env.umask, None);
+        assert_eq!(env.nofile, None);
+    }
+
+    #[test]
+    fn test_nofile() {
+        for (limit, value) in [("512", 512), ("unlimited", UNLIMITED)] {
+            let mut vars = EnvMap::new();
+            vars.insert(NOFILE_VAR.into(), limit.into());
+            let env = Environment::from_vars(vars);
+            assert_eq!(env.nofile, Some(value));
+            assert!(env.to_vars().is_empty());
+            assert_eq!(Environment::read(&env.serialize()[..]).unwrap(), env);
+        }
+        assert_eq!(parse_limit("lots"), None);
+    }
+
+    fn setup(shopts: &str, aliases: &str) -> Option<OsString> {
+        let mut vars = EnvMap::new();
+        vars.insert(SHOPTS_VAR.into(), shopts.into());
+        vars.insert(ALIASES_VAR.into(), aliases.into());
+        Environment::from_vars(vars).shell_setup()
+    }
+
+    #[test]
+    fn test_shell_setup() {
+        let defaults =
+            "shopt -s cmdhist\nshopt -u extglob\nshopt -u login_shell";
+        assert_eq!(setup(defaults, ""), None);
+        assert_eq!(
+            setup("shopt -s execfail\nshopt -s expand_aliases", ""),
+            None
+        );
+        assert_eq!(
+            setup("shopt -s extglob\nshopt -u sourcepath\nshopt -s login_shell", ""),
+            Some(
+                "shopt -s extglob 2>/dev/null\nshopt -u sourcepath 2>/dev/null\n"
+                    .into()
+            )
+        );
+        assert_eq!(
+            setup(defaults, "alias ll='ls -l'\nalias la='ls -a'"),
+            Some(
+                concat!(
+                    "shopt -s expand_aliases 2>/dev/null\n",
+                    "alias ll='ls -l'\nalias la='ls -a'\n"
+                )
+                .into()
+            )
+        );
+        // Entries written before these were captured.
+        assert_eq!(Environment::from_vars(EnvMap::new()).shell_setup(), None);
+    }
+}
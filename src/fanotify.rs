@@ -0,0 +1,360 @@
+//! Experimental tracing with fanotify (`CACHED_NIX_SHELL_TRACE=fanotify`),
+//! instead of preloading trace-nix.so into nix-shell.
+//!
+//! The kernel reports every file and directory that is opened on the marked
+//! mounts, so accesses that the preloaded library can't see (e.g. through
+//! `*at` calls it doesn't wrap) are recorded too.  fanotify needs
+//! `CAP_SYS_ADMIN`, and it only reports successful opens: checks for files
+//! that don't exist and symlinks nix resolved aren't recorded, and opened
+//! directories are only recorded to exist, since an open doesn't tell
+//! whether they were listed.  Like with trace-nix.so, only the nix-shell
+//! process itself is traced, not the programs it runs: events only tell the
+//! pid, and the processes are usually gone when they are read, so files read
+//! by e.g. the `git` of `builtins.fetchGit` aren't recorded.
+
+use nix::libc::{c_char, c_int, c_uint};
+use nix::poll::{poll, PollFd, PollFlags};
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+use std::ffi::{CString, OsStr};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+extern "C" {
+    fn fanotify_init(flags: c_uint, event_f_flags: c_uint) -> c_int;
+    fn fanotify_mark(
+        fd: c_int,
+        flags: c_uint,
+        mask: u64,
+        dirfd: c_int,
+        pathname: *const c_char,
+    ) -> c_int;
+}
+
+// From <linux/fanotify.h>, which the libc crate doesn't have yet.
+const FAN_CLOEXEC: c_uint = 0x1;
+const FAN_NONBLOCK: c_uint = 0x2;
+const FAN_UNLIMITED_QUEUE: c_uint = 0x10;
+const FAN_MARK_ADD: c_uint = 0x1;
+const FAN_MARK_MOUNT: c_uint = 0x10;
+const FAN_OPEN: u64 = 0x20;
+const FAN_Q_OVERFLOW: u64 = 0x4000;
+const FAN_ONDIR: u64 = 0x4000_0000;
+const FANOTIFY_METADATA_VERSION: u8 = 3;
+/// Size of `struct fanotify_event_metadata`.
+const METADATA_LEN: usize = 24;
+
+/// Pseudo filesystems that nix doesn't read expressions from.
+const SKIPPED_FS: &[&str] = &[
+    "autofs",
+    "bpf",
+    "cgroup",
+    "cgroup2",
+    "configfs",
+    "debugfs",
+    "devpts",
+    "devtmpfs",
+    "mqueue",
+    "proc",
+    "pstore",
+    "securityfs",
+    "sysfs",
+    "tracefs",
+];
+
+/// The same paths as the ones trace-nix.so ignores, and devices.
+const IGNORED_PATHS: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/nix/var/nix/daemon-socket/socket",
+    "/nix",
+    "/nix/store",
+];
+const IGNORED_PREFIXES: &[&str] =
+    &["/nix/store/", "/nix/var/nix/temproots/", "/proc/", "/dev/"];
+
+/// Whether `CACHED_NIX_SHELL_TRACE` selects fanotify.
+pub fn requested() -> bool {
+    match std::env::var_os("CACHED_NIX_SHELL_TRACE") {
+        None => false,
+        Some(x) if x == "fanotify" => true,
+        Some(x) if x.is_empty() || x == "preload" => false,
+        Some(x) => {
+            warning!(
+                "unknown CACHED_NIX_SHELL_TRACE {:?}, expected \"preload\" \
+                 or \"fanotify\"",
+                x
+            );
+            false
+        }
+    }
+}
+
+/// Files opened since `start`, collected by a thread until `finish`.
+pub struct Watch {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<Result<Vec<Event>, String>>,
+}
+
+struct Event {
+    pid: u32,
+    kind: u8,
+    path: Vec<u8>,
+}
+
+impl Watch {
+    /// Watch opens on all mounts.  Fails without the privileges fanotify
+    /// needs.
+    pub fn start() -> Result<Watch, String> {
+        let flags = FAN_CLOEXEC | FAN_NONBLOCK | FAN_UNLIMITED_QUEUE;
+        let event_flags = (nix::libc::O_RDONLY
+            | nix::libc::O_LARGEFILE
+            | nix::libc::O_CLOEXEC) as c_uint;
+        // SAFETY: takes no pointers; the result is checked.
+        let fd = unsafe { fanotify_init(flags, event_flags) };
+        if fd < 0 {
+            return Err(format!("fanotify_init: {}", errno()));
+        }
+
+        let mounts = std::fs::read("/proc/self/mounts")
+            .map_err(|e| format!("can't read /proc/self/mounts: {}", e))?;
+        let mut marked = 0;
+        for dir in mount_points(&mounts) {
+            let dir = match CString::new(dir) {
+                Ok(dir) => dir,
+                Err(_) => continue,
+            };
+            // SAFETY: `dir` is a NUL-terminated string that outlives the
+            // call, and `fd` is the fanotify descriptor from above.
+            let res = unsafe {
+                fanotify_mark(
+                    fd,
+                    FAN_MARK_ADD | FAN_MARK_MOUNT,
+                    FAN_OPEN | FAN_ONDIR,
+                    nix::libc::AT_FDCWD,
+                    dir.as_ptr(),
+                )
+            };
+            if res == 0 {
+                marked += 1;
+            }
+        }
+        if marked == 0 {
+            let _ = nix::unistd::close(fd);
+            return Err(format!("fanotify_mark: {}", errno()));
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                let res = read_events(fd, &stop);
+                let _ = nix::unistd::close(fd);
+                res
+            })
+        };
+        Ok(Watch { stop, thread })
+    }
+
+    /// Stop watching, and return the records of the files that process `pid`
+    /// opened, in the format of trace-nix.so.  Their state is the one at the
+    /// time of the call.
+    pub fn finish(self, pid: u32) -> Result<Vec<u8>, String> {
+        self.stop.store(true, Ordering::Relaxed);
+        let events = self
+            .thread
+            .join()
+            .map_err(|_| "the fanotify thread panicked".to_string())??;
+        let keys = events
+            .into_iter()
+            .filter(|event| event.pid == pid)
+            .map(|event| [&[event.kind], &event.path[..]].concat())
+            .collect::<BTreeSet<_>>();
+        let mut res = Vec::new();
+        for key in keys {
+            let value = crate::trace::item_value(&key);
+            res.extend(&key);
+            res.push(0);
+            res.extend(value.as_bytes());
+            res.push(0);
+        }
+        Ok(res)
+    }
+}
+
+fn errno() -> nix::errno::Errno {
+    nix::errno::Errno::last()
+}
+
+/// Mount points listed in `/proc/self/mounts`, except pseudo filesystems.
+fn mount_points(mounts: &[u8]) -> Vec<Vec<u8>> {
+    mounts
+        .split(|&b| b == b'\n')
+        .filter_map(|line| {
+            let mut fields = line.split(|&b| b == b' ');
+            let dir = fields.nth(1)?;
+            let fs = fields.next()?;
+            if SKIPPED_FS.iter().any(|x| x.as_bytes() == fs) {
+                return None;
+            }
+            Some(unescape(dir))
+        })
+        .collect()
+}
+
+/// Spaces and the like are escaped as `\040` in `/proc/self/mounts`.
+fn unescape(field: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(field.len());
+    let mut i = 0;
+    while i < field.len() {
+        let octal = field.get(i + 1..i + 4).and_then(|x| {
+            let x = std::str::from_utf8(x).ok()?;
+            u8::from_str_radix(x, 8).ok()
+        });
+        match octal {
+            Some(b) if field[i] == b'\\' => {
+                res.push(b);
+                i += 4;
+            }
+            _ => {
+                res.push(field[i]);
+                i += 1;
+            }
+        }
+    }
+    res
+}
+
+fn ignored(path: &[u8]) -> bool {
+    IGNORED_PATHS.iter().any(|x| x.as_bytes() == path)
+        || IGNORED_PREFIXES
+            .iter()
+            .any(|x| path.starts_with(x.as_bytes()))
+        || path.ends_with(b" (deleted)")
+}
+
+/// Read events until the queue is empty after `stop` is set.  Events are
+/// generated when a file is opened, so the ones of a process that has
+/// exited are all queued by then.
+fn read_events(fd: RawFd, stop: &AtomicBool) -> Result<Vec<Event>, String> {
+    let mut events = Vec::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let stopping = stop.load(Ordering::Relaxed);
+        let len = match nix::unistd::read(fd, &mut buf) {
+            Ok(len) => len,
+            Err(nix::Error::Sys(nix::errno::Errno::EAGAIN)) => {
+                if stopping {
+                    return Ok(events);
+                }
+                let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+                let _ = poll(&mut fds, 50);
+                continue;
+            }
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+            Err(e) => return Err(format!("can't read fanotify events: {}", e)),
+        };
+        let mut data = &buf[..len];
+        while data.len() >= METADATA_LEN {
+            let event_len = u32::from_ne_bytes(data[0..4].try_into().unwrap());
+            let event_len =
+                (event_len as usize).clamp(METADATA_LEN, data.len());
+            let vers = data[4];
+            let mask = u64::from_ne_bytes(data[8..16].try_into().unwrap());
+            let event_fd = i32::from_ne_bytes(data[16..20].try_into().unwrap());
+            let pid = i32::from_ne_bytes(data[20..24].try_into().unwrap());
+            data = &data[event_len..];
+
+            if vers != FANOTIFY_METADATA_VERSION {
+                return Err(format!(
+                    "unsupported fanotify metadata version {}",
+                    vers
+                ));
+            }
+            if mask & FAN_Q_OVERFLOW != 0 {
+                return Err("fanotify events were lost".to_string());
+            }
+            if event_fd < 0 {
+                continue;
+            }
+            let link = format!("/proc/self/fd/{}", event_fd);
+            let path = nix::fcntl::readlink(OsStr::new(&link));
+            let _ = nix::unistd::close(event_fd);
+            let path = match path {
+                Ok(path) => path.into_vec(),
+                Err(_) => continue,
+            };
+            if path.starts_with(b"/") && !ignored(&path) {
+                // Opening a directory isn't listing it.
+                let kind = if mask & FAN_ONDIR != 0 { b's' } else { b'f' };
+                events.push(Event {
+                    pid: pid as u32,
+                    kind,
+                    path,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mount_points() {
+        let mounts = concat!(
+            "/dev/sda1 / ext4 rw,relatime 0 0\n",
+            "proc /proc proc rw 0 0\n",
+            "/dev/sdb1 /mnt/my\\040disk ext4 rw 0 0\n",
+        );
+        assert_eq!(
+            mount_points(mounts.as_bytes()),
+            vec![b"/".to_vec(), b"/mnt/my disk".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_ignored() {
+        assert!(ignored(b"/nix/store/abc-hello/bin/hello"));
+        assert!(ignored(b"/dev/null"));
+        assert!(ignored(b"/tmp/nix-shell-1/rc (deleted)"));
+        assert!(!ignored(b"/src/project/shell.nix"));
+    }
+
+    /// Needs `CAP_SYS_ADMIN`, so it does nothing without it.
+    #[test]
+    fn test_watch() {
+        let watch = match Watch::start() {
+            Ok(watch) => watch,
+            Err(e) => {
+                eprintln!("skipping the fanotify test: {}", e);
+                return;
+            }
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("shell.nix");
+        std::fs::write(&file, "{}").unwrap();
+        let child = std::process::Command::new("cat")
+            .arg(&file)
+            .arg(dir.path().join("missing.nix"))
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+        let pid = child.id();
+        child.wait_with_output().unwrap();
+
+        let records = watch.finish(pid).unwrap();
+        let trace = crate::trace::Trace::read(&records[..]).unwrap();
+        // The others are the program and the libraries it loads.
+        let paths = trace.paths().collect::<Vec<_>>();
+        assert!(paths.contains(&file.as_os_str()), "{:?}", paths);
+        assert!(!paths.contains(&dir.path().join("missing.nix").as_os_str()));
+        assert!(!trace.check_for_changes());
+        std::fs::write(&file, "{ x = 1; }").unwrap();
+        assert!(trace.check_for_changes());
+    }
+}
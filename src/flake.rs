@@ -0,0 +1,110 @@
+//! Shell files that call into a flake with `builtins.getFlake`.
+//!
+//! nix may read the flake from a copy in the store, so the trace doesn't
+//! necessarily show that the shell depends on `flake.lock`; it's added to the
+//! trace explicitly for the flakes the shell file refers to.
+
+use crate::path_clean::PathClean;
+use regex::bytes::Regex;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// The `flake.lock` files that `contents` of `shell_file` may depend on: the
+/// one next to it, and the ones of local flakes given as literal references,
+/// e.g. `getFlake "path:/src/app"`, `getFlake "git+file:///src/app?ref=x"`,
+/// or `getFlake (toString ./app)`.  References built from expressions can't
+/// be resolved without evaluating them.
+pub fn lock_files(shell_file: &Path, contents: &[u8]) -> Vec<PathBuf> {
+    if !contents.windows(8).any(|w| w == b"getFlake") {
+        return Vec::new();
+    }
+    let dir = shell_file.parent().unwrap_or(Path::new("/"));
+    static RE: OnceLock<Regex> = OnceLock::new();
+    let re = RE.get_or_init(|| {
+        Regex::new(concat!(
+            r#"getFlake\s*(?:"([^"$]*)"|\(\s*toString\s+"#,
+            r#"(\.{0,2}/[^\s()]*|\.{1,2})\s*\))"#
+        ))
+        .unwrap()
+    });
+
+    let mut dirs = vec![dir.to_path_buf()];
+    for caps in re.captures_iter(contents) {
+        let path = match (caps.get(1), caps.get(2)) {
+            (Some(url), _) => match local_path(url.as_bytes()) {
+                Some(path) => path,
+                None => continue,
+            },
+            (_, Some(path)) => path.as_bytes(),
+            _ => continue,
+        };
+        let path = Path::new(OsStr::from_bytes(path));
+        dirs.push(dir.join(path).clean());
+    }
+    dirs.sort();
+    dirs.dedup();
+    dirs.into_iter().map(|dir| dir.join("flake.lock")).collect()
+}
+
+/// The directory of a `path:` or `git+file:` flake reference, or of a plain
+/// path.
+fn local_path(url: &[u8]) -> Option<&[u8]> {
+    let path = if let Some(path) = url.strip_prefix(b"path:") {
+        path
+    } else if let Some(path) = url.strip_prefix(b"git+file://") {
+        path
+    } else if url.starts_with(b"/") || url.starts_with(b".") {
+        url
+    } else {
+        return None;
+    };
+    let end = path
+        .iter()
+        .position(|&b| b == b'?' || b == b'#')
+        .unwrap_or(path.len());
+    Some(&path[..end])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn locks(contents: &str) -> Vec<PathBuf> {
+        lock_files(Path::new("/src/project/shell.nix"), contents.as_bytes())
+    }
+
+    #[test]
+    fn test_no_flake() {
+        assert!(locks("with import <nixpkgs> {}; mkShell {}").is_empty());
+    }
+
+    #[test]
+    fn test_lock_files() {
+        assert_eq!(
+            locks("(builtins.getFlake (toString ./.)).devShells.x86_64-linux.default"),
+            vec![PathBuf::from("/src/project/flake.lock")]
+        );
+        assert_eq!(
+            locks(concat!(
+                r#"let app = builtins.getFlake "git+file:///src/app?ref=main";"#,
+                r#"lib = builtins.getFlake (toString ../lib); in"#,
+                r#"builtins.getFlake "path:/src/tools#x""#,
+            )),
+            vec![
+                PathBuf::from("/src/app/flake.lock"),
+                PathBuf::from("/src/lib/flake.lock"),
+                PathBuf::from("/src/project/flake.lock"),
+                PathBuf::from("/src/tools/flake.lock"),
+            ]
+        );
+        // Remote and computed references only add the lock next to the file.
+        assert_eq!(
+            locks(
+                r#"builtins.getFlake "github:owner/repo" // builtins.getFlake "path:${dir}""#
+            ),
+            vec![PathBuf::from("/src/project/flake.lock")]
+        );
+    }
+}
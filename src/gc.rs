@@ -0,0 +1,444 @@
+//! `--gc-cache [--since DURATION] [--dry-run]`: remove cache entries that
+//! can't be used anymore, optionally also the ones that haven't been used for
+//! a while.
+//!
+//! The last use of an entry is the modification time of `HASH.inputs`: it is
+//! written along with the entry and touched on every cache hit.
+//!
+//! `--refresh-roots` is here too, since it uses the same notion of entries
+//! that are still valid.
+
+use crate::entry;
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Parse durations like `30d`, `12h`, `45m` or `10s`.
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let err = || {
+        format!(
+            "invalid duration {:?}, expected a number followed by d, h, m or s",
+            text
+        )
+    };
+    let unit = match text.chars().last().ok_or_else(err)? {
+        'd' => 24 * 60 * 60,
+        'h' => 60 * 60,
+        'm' => 60,
+        's' => 1,
+        _ => return Err(err()),
+    };
+    let count = text[..text.len() - 1].parse::<u64>().map_err(|_| err())?;
+    let secs = count
+        .checked_mul(unit)
+        .ok_or_else(|| format!("duration {:?} is too long", text))?;
+    Ok(Duration::from_secs(secs))
+}
+
+/// Record that the entry has been used.
+pub fn touch(dir: &Path, hash: &str) -> std::io::Result<()> {
+    File::options()
+        .write(true)
+        .open(entry::path(dir, hash, entry::INPUTS))?
+        .set_modified(SystemTime::now())
+}
+
+fn hashes(dir: &Path) -> BTreeSet<String> {
+    std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            if !entry::ALL.contains(&path.extension()?.to_str()?) {
+                return None;
+            }
+            Some(path.file_stem()?.to_str()?.to_string())
+        })
+        .collect()
+}
+
+/// Whether the derivation of the entry has been garbage collected.
+fn is_dead(dir: &Path, hash: &str) -> bool {
+    std::fs::metadata(entry::path(dir, hash, entry::DRV)).is_err()
+}
+
+fn last_use(dir: &Path, hash: &str) -> Option<SystemTime> {
+    std::fs::metadata(entry::path(dir, hash, entry::INPUTS))
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+/// Hashes of dead entries, and of entries unused since `now - since`: the
+/// ones `collect` removes (`--dry-run`).
+pub fn select(
+    dir: &Path,
+    since: Option<Duration>,
+    now: SystemTime,
+) -> Vec<String> {
+    let cutoff = since.and_then(|since| now.checked_sub(since));
+    hashes(dir)
+        .into_iter()
+        .filter(|hash| {
+            let old = cutoff.is_some_and(|cutoff| {
+                last_use(dir, hash).is_none_or(|time| time < cutoff)
+            });
+            old || is_dead(dir, hash)
+        })
+        .collect()
+}
+
+/// Remove the entries `select` returns.  Returns hashes of the removed
+/// entries.
+pub fn collect(
+    dir: &Path,
+    since: Option<Duration>,
+    now: SystemTime,
+) -> Result<Vec<String>, String> {
+    let removed = select(dir, since, now);
+    for hash in &removed {
+        remove(dir, hash)?;
+    }
+    Ok(removed)
+}
+
+fn remove(dir: &Path, hash: &str) -> Result<(), String> {
+    for ext in entry::ALL {
+        let fname = entry::path(dir, hash, ext);
+        match std::fs::remove_file(&fname) {
+            Ok(()) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(format!("can't remove {:?}: {}", fname, e)),
+        }
+    }
+    // Not part of the entry (nor of exports); a run holding it only loses
+    // the guarantee that the shell isn't built twice at a time.
+    let _ = std::fs::remove_file(entry::path(dir, hash, entry::LOCK));
+    Ok(())
+}
+
+/// The top-level store path containing `path`, e.g. `/nix/store/HASH-name`
+/// for `/nix/store/HASH-name/bin/foo`.  Other paths are kept as they are.
+fn store_path(path: &[u8]) -> &[u8] {
+    match path.strip_prefix(b"/nix/store/") {
+        Some(rest) => {
+            let end =
+                rest.iter().position(|&c| c == b'/').unwrap_or(rest.len());
+            &path[..b"/nix/store/".len() + end]
+        }
+        None => path,
+    }
+}
+
+/// `--invalidate-path STOREPATH`: remove entries whose environment or
+/// derivation references the store path.  Returns hashes of the removed
+/// entries.
+pub fn invalidate_path(dir: &Path, path: &[u8]) -> Result<Vec<String>, String> {
+    let path = store_path(path);
+    if path.is_empty() {
+        return Err("empty store path".to_string());
+    }
+    let mut removed = Vec::new();
+    for hash in hashes(dir) {
+        let env = std::fs::read(entry::path(dir, &hash, entry::ENV))
+            .unwrap_or_default();
+        let drv = std::fs::read_link(entry::path(dir, &hash, entry::DRV))
+            .map(|x| x.into_os_string().into_vec())
+            .unwrap_or_default();
+        let references = env.windows(path.len()).any(|x| x == path)
+            || store_path(&drv) == path;
+        if references {
+            remove(dir, &hash)?;
+            removed.push(hash);
+        }
+    }
+    Ok(removed)
+}
+
+/// gc roots are named `cached-nix-shell-HASH.drv`, and point to `HASH.drv`
+/// in the cache, so the root of an entry and the entry of a root can be
+/// found from the name alone.
+const ROOT_PREFIX: &str = "cached-nix-shell-";
+
+/// The gc root of the entry `hash` in `roots`.
+pub fn root_path(roots: &Path, hash: &str) -> PathBuf {
+    roots.join(format!("{}{}.drv", ROOT_PREFIX, hash))
+}
+
+/// The hash of the entry a gc root named `name` belongs to.
+pub fn root_hash(name: &OsStr) -> Option<&str> {
+    name.to_str()?
+        .strip_prefix(ROOT_PREFIX)?
+        .strip_suffix(".drv")
+}
+
+/// Remove gc roots of removed entries.  Missing roots are fine: they may
+/// have never been created, e.g. with `--no-gc-root`.
+pub fn remove_roots(roots: &Path, hashes: &[String]) -> Result<(), String> {
+    for hash in hashes {
+        let root = root_path(roots, hash);
+        match std::fs::remove_file(&root) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(format!("can't remove {:?}: {}", root, e))
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Make `roots` match the cache: create missing gc roots of entries whose
+/// derivation is present, and remove roots pointing into the cache that don't
+/// belong to such an entry.  Roots of other cache directories are kept.
+/// Returns the numbers of created and removed roots.
+pub fn refresh_roots(
+    dir: &Path,
+    roots: &Path,
+) -> Result<(usize, usize), String> {
+    let valid = hashes(dir)
+        .into_iter()
+        .filter(|hash| !is_dead(dir, hash))
+        .collect::<BTreeSet<_>>();
+
+    let mut removed = 0;
+    let entries = match std::fs::read_dir(roots) {
+        Ok(entries) => entries.collect::<Result<Vec<_>, _>>(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+    .map_err(|e| format!("can't list {:?}: {}", roots, e))?;
+    for entry in entries {
+        let name = entry.file_name();
+        let hash = match root_hash(&name) {
+            Some(hash) => hash,
+            None => continue,
+        };
+        let target = match std::fs::read_link(entry.path()) {
+            Ok(target) => target,
+            Err(_) => continue,
+        };
+        if target.parent() == Some(dir) && !valid.contains(hash) {
+            std::fs::remove_file(entry.path()).map_err(|e| {
+                format!("can't remove {:?}: {}", entry.path(), e)
+            })?;
+            removed += 1;
+        }
+    }
+
+    let mut created = 0;
+    for hash in valid {
+        let root = root_path(roots, &hash);
+        let target = entry::path(dir, &hash, entry::DRV);
+        if std::fs::read_link(&root).is_ok_and(|x| x == target) {
+            continue;
+        }
+        std::fs::create_dir_all(roots)
+            .and_then(|()| match std::fs::remove_file(&root) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            })
+            .and_then(|()| std::os::unix::fs::symlink(&target, &root))
+            .map_err(|e| format!("can't create {:?}: {}", root, e))?;
+        created += 1;
+    }
+    Ok((created, removed))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30d"), Ok(Duration::from_secs(2592000)));
+        assert_eq!(parse_duration("12h"), Ok(Duration::from_secs(43200)));
+        assert_eq!(parse_duration("45m"), Ok(Duration::from_secs(2700)));
+        assert_eq!(parse_duration("10s"), Ok(Duration::from_secs(10)));
+        assert_eq!(parse_duration("0d"), Ok(Duration::from_secs(0)));
+        let max = format!("{}s", u64::MAX);
+        assert_eq!(parse_duration(&max), Ok(Duration::from_secs(u64::MAX)));
+        for text in &["", "d", "30", "30x", "-1d", "1.5h", "ten m"] {
+            assert!(parse_duration(text).is_err(), "{:?}", text);
+        }
+        for text in &[format!("{}d", u64::MAX / 60), format!("{}m", u64::MAX)] {
+            assert!(parse_duration(text).is_err(), "{:?}", text);
+        }
+    }
+
+    fn entry(dir: &Path, hash: &str, drv: &Path, age: Duration) {
+        std::fs::write(entry::path(dir, hash, entry::ENV), "").unwrap();
+        std::fs::write(entry::path(dir, hash, entry::INPUTS), "").unwrap();
+        std::os::unix::fs::symlink(drv, entry::path(dir, hash, entry::DRV))
+            .unwrap();
+        File::options()
+            .write(true)
+            .open(entry::path(dir, hash, entry::INPUTS))
+            .unwrap()
+            .set_modified(SystemTime::now() - age)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_collect() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("cache");
+        std::fs::create_dir(&cache).unwrap();
+        let drv = dir.path().join("a.drv");
+        std::fs::write(&drv, "").unwrap();
+        let day = Duration::from_secs(24 * 60 * 60);
+
+        entry(&cache, "recent", &drv, Duration::from_secs(60));
+        entry(&cache, "old", &drv, 10 * day);
+        entry(
+            &cache,
+            "dead",
+            &dir.path().join("b.drv"),
+            Duration::from_secs(0),
+        );
+
+        let now = SystemTime::now();
+        // Nothing is removed until `collect`.
+        assert_eq!(select(&cache, None, now), vec!["dead"]);
+        assert_eq!(select(&cache, Some(7 * day), now), vec!["dead", "old"]);
+        assert_eq!(hashes(&cache).len(), 3);
+        assert_eq!(collect(&cache, None, now), Ok(vec!["dead".to_string()]));
+        assert_eq!(hashes(&cache).len(), 2);
+        assert!(!cache.join("dead.env").exists());
+
+        // Neither entry is old enough.
+        assert_eq!(collect(&cache, Some(30 * day), now), Ok(vec![]));
+
+        assert_eq!(
+            collect(&cache, Some(7 * day), now),
+            Ok(vec!["old".to_string()])
+        );
+        assert_eq!(
+            hashes(&cache).into_iter().collect::<Vec<_>>(),
+            vec!["recent"]
+        );
+
+        touch(&cache, "recent").unwrap();
+        assert_eq!(collect(&cache, Some(day), SystemTime::now()), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_invalidate_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("cache");
+        std::fs::create_dir(&cache).unwrap();
+        let drv = dir.path().join("a.drv");
+        std::fs::write(&drv, "").unwrap();
+        let bad = "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-broken-1.0";
+        let good = "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-fine-1.0";
+
+        entry(&cache, "bad", &drv, Duration::from_secs(0));
+        std::fs::write(
+            cache.join("bad.env"),
+            format!("PATH={}/bin:{}/bin\0HOME=/home\0", good, bad),
+        )
+        .unwrap();
+        entry(&cache, "good", &drv, Duration::from_secs(0));
+        std::fs::write(cache.join("good.env"), format!("PATH={}/bin\0", good))
+            .unwrap();
+
+        // Paths inside the store path refer to all of it.
+        assert_eq!(
+            invalidate_path(&cache, format!("{}/bin/foo", bad).as_bytes()),
+            Ok(vec!["bad".to_string()])
+        );
+        assert_eq!(
+            hashes(&cache).into_iter().collect::<Vec<_>>(),
+            vec!["good"]
+        );
+        assert_eq!(invalidate_path(&cache, bad.as_bytes()), Ok(vec![]));
+        assert!(invalidate_path(&cache, b"").is_err());
+    }
+
+    #[test]
+    fn test_roots_of_evicted_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("cache");
+        let roots = dir.path().join("roots");
+        std::fs::create_dir(&cache).unwrap();
+        std::fs::create_dir(&roots).unwrap();
+        let drv = dir.path().join("a.drv");
+        std::fs::write(&drv, "").unwrap();
+        let day = Duration::from_secs(24 * 60 * 60);
+
+        entry(&cache, "old", &drv, 10 * day);
+        entry(&cache, "recent", &drv, Duration::from_secs(0));
+        assert_eq!(refresh_roots(&cache, &roots), Ok((2, 0)));
+
+        // The key gives the root, and the root gives the key.
+        let root = root_path(&roots, "old");
+        assert_eq!(std::fs::read_link(&root).unwrap(), cache.join("old.drv"));
+        assert_eq!(root_hash(root.file_name().unwrap()), Some("old"));
+        assert_eq!(root_hash(OsStr::new("result")), None);
+        assert_eq!(root_hash(OsStr::new("cached-nix-shell-old.env")), None);
+
+        let removed = collect(&cache, Some(day), SystemTime::now()).unwrap();
+        assert_eq!(removed, vec!["old"]);
+        assert_eq!(remove_roots(&roots, &removed), Ok(()));
+        assert!(std::fs::symlink_metadata(&root).is_err());
+        assert!(std::fs::symlink_metadata(root_path(&roots, "recent")).is_ok());
+        // Already gone.
+        assert_eq!(remove_roots(&roots, &removed), Ok(()));
+    }
+
+    #[test]
+    fn test_refresh_roots() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("cache");
+        let other_cache = dir.path().join("other");
+        let roots = dir.path().join("roots");
+        std::fs::create_dir(&cache).unwrap();
+        std::fs::create_dir(&roots).unwrap();
+        let drv = dir.path().join("a.drv");
+        std::fs::write(&drv, "").unwrap();
+        let root = |hash: &str| root_path(&roots, hash);
+        let symlink = |target: &Path, link: &Path| {
+            std::os::unix::fs::symlink(target, link).unwrap()
+        };
+
+        entry(&cache, "rooted", &drv, Duration::from_secs(0));
+        symlink(&cache.join("rooted.drv"), &root("rooted"));
+        // The root has been lost.
+        entry(&cache, "missing", &drv, Duration::from_secs(0));
+        // The entry has been removed, or its derivation collected.
+        symlink(&cache.join("removed.drv"), &root("removed"));
+        entry(
+            &cache,
+            "dead",
+            &dir.path().join("b.drv"),
+            Duration::from_secs(0),
+        );
+        symlink(&cache.join("dead.drv"), &root("dead"));
+        // Roots of another cache directory and unrelated roots are kept.
+        symlink(&other_cache.join("other.drv"), &root("other"));
+        symlink(&drv, &roots.join("result"));
+
+        assert_eq!(refresh_roots(&cache, &roots), Ok((1, 2)));
+        let mut names = std::fs::read_dir(&roots)
+            .unwrap()
+            .map(|x| x.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                "cached-nix-shell-missing.drv",
+                "cached-nix-shell-other.drv",
+                "cached-nix-shell-rooted.drv",
+                "result",
+            ]
+        );
+        assert_eq!(
+            std::fs::read_link(root("missing")).unwrap(),
+            cache.join("missing.drv")
+        );
+
+        assert_eq!(refresh_roots(&cache, &roots), Ok((0, 0)));
+    }
+}
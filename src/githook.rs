@@ -0,0 +1,125 @@
+//! `--install-hook [FILE]`: add git hooks that build the shell after a
+//! checkout or a merge, so that it is usually cached by the time it's used.
+
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+pub const HOOKS: &[&str] = &["post-checkout", "post-merge"];
+
+/// Marks the lines added to hooks, so they are added only once.
+const MARKER: &str = "# Added by cached-nix-shell --install-hook";
+
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Created,
+    Appended,
+    AlreadyInstalled,
+}
+
+/// Lines building the shell from `shell_file`.  Hooks run from the top of
+/// the work tree, and a failed build shouldn't look like a failed checkout.
+fn snippet(shell_file: &str) -> String {
+    format!(
+        "{}\ncached-nix-shell {} --run : || true\n",
+        MARKER,
+        crate::dump::sh_quote(shell_file)
+    )
+}
+
+/// Add the snippet to `hooks_dir/NAME`, keeping what is already there.
+pub fn install(
+    hooks_dir: &Path,
+    name: &str,
+    shell_file: &str,
+) -> Result<Action, String> {
+    let path = hooks_dir.join(name);
+    let err = |e: std::io::Error| format!("can't write {:?}: {}", path, e);
+    let action = match std::fs::read_to_string(&path) {
+        Ok(old) if old.contains(MARKER) => return Ok(Action::AlreadyInstalled),
+        Ok(old) => {
+            let separator = if old.ends_with('\n') { "\n" } else { "\n\n" };
+            std::fs::OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .and_then(|mut f| {
+                    write!(f, "{}{}", separator, snippet(shell_file))
+                })
+                .map_err(err)?;
+            Action::Appended
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            std::fs::create_dir_all(hooks_dir)
+                .and_then(|()| {
+                    std::fs::write(
+                        &path,
+                        format!("#!/bin/sh\n{}", snippet(shell_file)),
+                    )
+                })
+                .map_err(err)?;
+            Action::Created
+        }
+        Err(e) => return Err(format!("can't read {:?}: {}", path, e)),
+    };
+    // Hooks that aren't executable are silently ignored by git.
+    let mut perms = std::fs::metadata(&path).map_err(err)?.permissions();
+    perms.set_mode(perms.mode() | 0o111);
+    std::fs::set_permissions(&path, perms).map_err(err)?;
+    Ok(action)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_created() {
+        let dir = tempfile::tempdir().unwrap();
+        let hooks = dir.path().join("hooks");
+        assert_eq!(
+            install(&hooks, "post-merge", "./shell.nix"),
+            Ok(Action::Created)
+        );
+        let path = hooks.join("post-merge");
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            concat!(
+                "#!/bin/sh\n",
+                "# Added by cached-nix-shell --install-hook\n",
+                "cached-nix-shell './shell.nix' --run : || true\n",
+            )
+        );
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o111, 0o111);
+
+        assert_eq!(
+            install(&hooks, "post-merge", "./shell.nix"),
+            Ok(Action::AlreadyInstalled)
+        );
+    }
+
+    #[test]
+    fn test_existing_hook() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("post-checkout");
+        std::fs::write(&path, "#!/bin/sh\necho existing").unwrap();
+        assert_eq!(
+            install(dir.path(), "post-checkout", "./nix/dev shell.nix"),
+            Ok(Action::Appended)
+        );
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            concat!(
+                "#!/bin/sh\n",
+                "echo existing\n",
+                "\n",
+                "# Added by cached-nix-shell --install-hook\n",
+                "cached-nix-shell './nix/dev shell.nix' --run : || true\n",
+            )
+        );
+        assert_eq!(
+            install(dir.path(), "post-checkout", "./shell.nix"),
+            Ok(Action::AlreadyInstalled)
+        );
+    }
+}
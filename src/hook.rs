@@ -0,0 +1,186 @@
+//! `CACHED_NIX_SHELL_POST_HOOK=PROGRAM`: let an external program change the
+//! environment before the command starts, e.g. to add credentials from a
+//! secret store.
+//!
+//! The program gets the environment as a JSON object on stdin, and prints
+//! the new environment in the same format.  `null` values unset variables.
+//! Variables whose name or value isn't UTF-8 can't be in JSON: they aren't
+//! given to the hook, and are kept as they are unless it sets or unsets them.
+//! The hook runs on every invocation, after the cache, so its output is
+//! never cached.
+//!
+//! `CACHED_NIX_SHELL_VERIFY_HOOK=COMMAND` is the opposite: it runs once, in
+//! a shell that has just been built, and if it fails, the shell isn't
+//! cached, so that a build that succeeded but is broken (e.g. with the wrong
+//! version of a tool) isn't reused.
+
+use crate::EnvMap;
+use std::ffi::{OsStr, OsString};
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+pub fn from_env() -> Option<OsString> {
+    std::env::var_os("CACHED_NIX_SHELL_POST_HOOK").filter(|x| !x.is_empty())
+}
+
+pub fn verify_from_env() -> Option<OsString> {
+    std::env::var_os("CACHED_NIX_SHELL_VERIFY_HOOK").filter(|x| !x.is_empty())
+}
+
+/// Run `command` with bash in `env`, like a `--run` command.  Its output is
+/// only shown if it fails.
+pub fn verify(command: &OsStr, env: &EnvMap, pwd: &Path) -> Result<(), String> {
+    let output = Command::new("bash")
+        .args([OsStr::new("-c"), command])
+        .current_dir(pwd)
+        .env_clear()
+        .envs(env)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| format!("can't run verify hook {:?}: {}", command, e))?;
+    if !output.status.success() {
+        let _ = std::io::stderr().write_all(&output.stdout);
+        let _ = std::io::stderr().write_all(&output.stderr);
+        return Err(format!(
+            "verify hook {:?} failed: {}",
+            command, output.status
+        ));
+    }
+    Ok(())
+}
+
+pub fn run(hook: &OsStr, env: EnvMap) -> Result<EnvMap, String> {
+    let (input, mut kept): (EnvMap, EnvMap) = env
+        .into_iter()
+        .partition(|(k, v)| k.to_str().is_some() && v.to_str().is_some());
+    let input = input
+        .into_iter()
+        .map(|(k, v)| {
+            let v = v.into_string().unwrap().into();
+            (k.into_string().unwrap(), v)
+        })
+        .collect::<serde_json::Map<_, _>>();
+    let input = serde_json::to_vec(&input).unwrap();
+
+    let mut child = Command::new(hook)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("can't run post hook {:?}: {}", hook, e))?;
+    // The hook may exit without reading all of its input.
+    let _ = child.stdin.take().unwrap().write_all(&input);
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("can't run post hook {:?}: {}", hook, e))?;
+    if !output.status.success() {
+        return Err(format!("post hook {:?} failed: {}", hook, output.status));
+    }
+
+    let map: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_slice(&output.stdout).map_err(|e| {
+            format!("post hook {:?} printed invalid JSON: {}", hook, e)
+        })?;
+    let mut env = EnvMap::new();
+    for (k, v) in map {
+        kept.remove(OsStr::new(&k));
+        match v {
+            serde_json::Value::String(v) => {
+                env.insert(k.into(), v.into());
+            }
+            serde_json::Value::Null => (),
+            _ => {
+                return Err(format!(
+                    "post hook {:?} printed a non-string value for {:?}",
+                    hook, k
+                ))
+            }
+        }
+    }
+    env.extend(kept);
+    Ok(env)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn hook(dir: &tempfile::TempDir, script: &str) -> OsString {
+        let path = dir.path().join("hook");
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))
+            .unwrap();
+        path.into_os_string()
+    }
+
+    fn env() -> EnvMap {
+        let mut env = EnvMap::new();
+        env.insert("PATH".into(), "/nix/store/aaa-hello/bin".into());
+        env.insert("GREETING".into(), "it's \"quoted\"\n".into());
+        env
+    }
+
+    #[test]
+    fn test_add_variable() {
+        let dir = tempfile::tempdir().unwrap();
+        let hook = hook(&dir, r#"sed 's/^{/{"TOKEN":"secret",/'"#);
+        let mut expected = env();
+        expected.insert("TOKEN".into(), "secret".into());
+        assert_eq!(run(&hook, env()), Ok(expected));
+    }
+
+    #[test]
+    fn test_unset() {
+        let dir = tempfile::tempdir().unwrap();
+        let hook = hook(&dir, r#"echo '{"PATH": "/bin", "GREETING": null}'"#);
+        let mut expected = EnvMap::new();
+        expected.insert("PATH".into(), "/bin".into());
+        assert_eq!(run(&hook, env()), Ok(expected));
+    }
+
+    #[test]
+    fn test_not_utf8() {
+        use std::os::unix::ffi::OsStringExt;
+        let dir = tempfile::tempdir().unwrap();
+        let mut env = env();
+        env.insert("LATIN1".into(), OsString::from_vec(b"caf\xe9".to_vec()));
+        env.insert(OsString::from_vec(b"\xff".to_vec()), "x".into());
+        env.insert("UNSET".into(), OsString::from_vec(b"\xff".to_vec()));
+        // They aren't in the input, so this hook keeps only PATH, GREETING
+        // and what it adds.
+        let hook = hook(
+            &dir,
+            r#"in=$(cat); case $in in *LATIN1*) exit 1;; esac
+            printf '%s' "$in" | sed 's/^{/{"A":"1","UNSET":null,/'"#,
+        );
+        let mut expected = env.clone();
+        expected.remove(OsStr::new("UNSET"));
+        expected.insert("A".into(), "1".into());
+        assert_eq!(run(&hook, env), Ok(expected));
+    }
+
+    #[test]
+    fn test_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        for script in &["exit 1", "echo not json", r#"echo '{"A": 1}'"#] {
+            assert!(run(&hook(&dir, script), env()).is_err(), "{}", script);
+        }
+        let missing = dir.path().join("missing").into_os_string();
+        assert!(run(&missing, env()).is_err());
+    }
+
+    #[test]
+    fn test_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut env = env();
+        env.insert("PATH".into(), std::env::var_os("PATH").unwrap());
+        let verify = |command: &str| verify(command.as_ref(), &env, dir.path());
+        assert_eq!(
+            verify(r#"[ "$GREETING" = "it's \"quoted\""$'\n' ]"#),
+            Ok(())
+        );
+        assert_eq!(verify("[ -z \"$(ls)\" ]"), Ok(()));
+        assert!(verify("exit 3").unwrap_err().contains("exit status: 3"));
+    }
+}
@@ -0,0 +1,151 @@
+//! `--lint FILE...`: check the `#! nix-shell` lines of scripts without
+//! building anything, e.g. in CI.
+
+use crate::args::Args;
+use crate::shebang;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+
+/// Problems with the `#! nix-shell` lines of `script`, as `LINE: MESSAGE`
+/// (or just `MESSAGE` for the whole script).
+pub fn check(script: &[u8]) -> Vec<String> {
+    let mut lines = script.split(|&c| c == b'\n');
+    if !lines.next().is_some_and(|x| x.starts_with(b"#!")) {
+        return vec!["the first line isn't a shebang".to_string()];
+    }
+
+    let mut problems = Vec::new();
+    let mut args = Vec::new();
+    let mut found = false;
+    for (n, line) in lines.enumerate().map(|(n, x)| (n + 2, x)) {
+        let directive = match shebang::directive(line) {
+            Some(directive) => directive,
+            None => {
+                // nix-shell ignores these, which is unlikely to be intended.
+                if line.starts_with(b"#!") && contains(line, b"nix-shell") {
+                    problems.push(format!(
+                        "{}: not a `#! nix-shell ARGS` line, ignored",
+                        n
+                    ));
+                }
+                continue;
+            }
+        };
+        found = true;
+        if shebang::unterminated_quote(directive) {
+            problems.push(format!("{}: unterminated quote", n));
+            continue;
+        }
+        args.extend(
+            shebang::shellwords(directive)
+                .into_iter()
+                .map(|x| OsStr::from_bytes(&x).to_os_string()),
+        );
+    }
+    if !found {
+        problems.push("no `#! nix-shell` lines".to_string());
+        return problems;
+    }
+
+    match Args::parse(args, true) {
+        Ok(args) => {
+            problems.extend(check_args(&args));
+            problems.extend(args.advisories);
+        }
+        Err(e) => problems.push(e),
+    }
+    problems
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|x| x == needle)
+}
+
+fn check_args(args: &Args) -> Option<String> {
+    let interpreter = crate::interpreter_command(&args.interpreter);
+    if interpreter[0]
+        .as_bytes()
+        .iter()
+        .all(u8::is_ascii_whitespace)
+    {
+        return Some("empty interpreter given with -i".to_string());
+    }
+    None
+}
+
+/// Lint each file, printing the problems.  Returns whether there were none.
+pub fn run(fnames: &[OsString]) -> bool {
+    let mut ok = true;
+    for fname in fnames {
+        let problems = match std::fs::read(fname) {
+            Ok(script) => check(&script),
+            Err(e) => vec![format!("can't read: {}", e)],
+        };
+        for problem in &problems {
+            eprintln!("{}: {}", fname.to_string_lossy(), problem);
+        }
+        ok &= problems.is_empty();
+    }
+    ok
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_well_formed() {
+        let script = concat!(
+            "#!/usr/bin/env nix-shell\n",
+            "#! nix-shell -i python3 -p \"python3.withPackages (p: [ p.requests ])\"\n",
+            "#! nix-shell -I nixpkgs=channel:nixos-unstable\n",
+            "print('hi')\n",
+        );
+        assert_eq!(check(script.as_bytes()), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_unknown_flag() {
+        let script =
+            "#!/usr/bin/env nix-shell\n#! nix-shell -p hello --frobnicate\n";
+        assert_eq!(
+            check(script.as_bytes()),
+            vec!["unexpected arg \"--frobnicate\""]
+        );
+        // Only valid outside of shebangs.
+        let script =
+            "#!/usr/bin/env nix-shell\n#! nix-shell -p hello --run make\n";
+        assert_eq!(check(script.as_bytes()), vec!["unexpected arg \"--run\""]);
+    }
+
+    #[test]
+    fn test_unterminated_quote() {
+        let script = concat!(
+            "#!/usr/bin/env nix-shell\n",
+            "#! nix-shell -i bash\n",
+            "#! nix-shell -p \"python3.withPackages (p: [])\n",
+        );
+        assert_eq!(check(script.as_bytes()), vec!["3: unterminated quote"]);
+    }
+
+    #[test]
+    fn test_other_problems() {
+        assert_eq!(check(b"echo hi\n"), vec!["the first line isn't a shebang"]);
+        assert_eq!(
+            check(b"#!/bin/sh\necho hi\n"),
+            vec!["no `#! nix-shell` lines"]
+        );
+        assert_eq!(
+            check(b"#!/usr/bin/env nix-shell\n#!nix-shell\n#! nix-shell -p hello\n"),
+            vec!["2: not a `#! nix-shell ARGS` line, ignored"]
+        );
+        assert_eq!(
+            check(b"#!/usr/bin/env nix-shell\n#! nix-shell -i \"\" -p hello\n"),
+            vec!["empty interpreter given with -i"]
+        );
+        assert_eq!(
+            check(b"#!/usr/bin/env nix-shell\n#! nix-shell -p hello --option build-cores 2\n"),
+            vec!["--option build-cores is a deprecated alias, use --option cores"]
+        );
+    }
+}
@@ -0,0 +1,140 @@
+//! Locks of cache entries, kept in `HASH.lock` next to them.
+//!
+//! An entry is validated and read under a shared lock, so that many runs
+//! can use it at the same time, and built and written under an exclusive
+//! one, so that runs that miss at the same time build the shell only once.
+//! Locking is best effort: a cache directory that can't be written to (e.g.
+//! the system cache) is used without locks.
+
+use nix::fcntl::{flock, FlockArg};
+use std::fs::File;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// Released when dropped.
+pub struct Lock {
+    _file: File,
+    /// Whether another run held the lock.
+    pub waited: bool,
+}
+
+pub fn shared(dir: &Path, hash: &str) -> Option<Lock> {
+    lock(
+        dir,
+        hash,
+        FlockArg::LockSharedNonblock,
+        FlockArg::LockShared,
+    )
+}
+
+pub fn exclusive(dir: &Path, hash: &str) -> Option<Lock> {
+    lock(
+        dir,
+        hash,
+        FlockArg::LockExclusiveNonblock,
+        FlockArg::LockExclusive,
+    )
+}
+
+fn lock(
+    dir: &Path,
+    hash: &str,
+    try_arg: FlockArg,
+    arg: FlockArg,
+) -> Option<Lock> {
+    std::fs::create_dir_all(dir).ok()?;
+    let fname = crate::entry::path(dir, hash, crate::entry::LOCK);
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(&fname)
+        .ok()?;
+    if let Some(umask) = crate::cache_umask() {
+        let mode = std::fs::Permissions::from_mode(0o666 & !umask);
+        let _ = file.set_permissions(mode);
+    }
+    let fd = file.as_raw_fd();
+    let waited = match flock(fd, try_arg) {
+        Ok(()) => false,
+        Err(nix::Error::Sys(nix::errno::Errno::EAGAIN)) => {
+            flock(fd, arg).ok()?;
+            true
+        }
+        Err(_) => return None,
+    };
+    Some(Lock {
+        _file: file,
+        waited,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    /// Take the lock in `threads` threads, each holding it for `hold`, and
+    /// return how long they took altogether and how many of them waited.
+    fn contend(
+        dir: &Path,
+        take: fn(&Path, &str) -> Option<Lock>,
+        threads: u32,
+        hold: Duration,
+    ) -> (Duration, usize) {
+        let start = Instant::now();
+        let waited = std::thread::scope(|scope| {
+            let handles = (0..threads)
+                .map(|_| {
+                    scope.spawn(move || {
+                        let lock = take(dir, "abc").unwrap();
+                        std::thread::sleep(hold);
+                        lock.waited
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|x| x.join().unwrap())
+                .filter(|&waited| waited)
+                .count()
+        });
+        (start.elapsed(), waited)
+    }
+
+    #[test]
+    fn test_shared_overlap() {
+        let dir = tempfile::tempdir().unwrap();
+        let hold = Duration::from_millis(200);
+        let (elapsed, waited) = contend(dir.path(), shared, 8, hold);
+        assert_eq!(waited, 0);
+        assert!(elapsed < hold * 4, "{:?}", elapsed);
+    }
+
+    #[test]
+    fn test_exclusive_serialized() {
+        let dir = tempfile::tempdir().unwrap();
+        let hold = Duration::from_millis(100);
+        let (elapsed, waited) = contend(dir.path(), exclusive, 2, hold);
+        assert_eq!(waited, 1);
+        assert!(elapsed >= hold * 2, "{:?}", elapsed);
+    }
+
+    #[test]
+    fn test_exclusive_waits_for_shared() {
+        let dir = tempfile::tempdir().unwrap();
+        let reader = shared(dir.path(), "abc").unwrap();
+        std::thread::scope(|scope| {
+            let writer = scope.spawn(|| exclusive(dir.path(), "abc").unwrap());
+            std::thread::sleep(Duration::from_millis(50));
+            drop(reader);
+            assert!(writer.join().unwrap().waited);
+        });
+        // Other entries have locks of their own.
+        let _a = exclusive(dir.path(), "abc").unwrap();
+        assert!(!exclusive(dir.path(), "def").unwrap().waited);
+    }
+}
@@ -1,28 +1,89 @@
 use crate::args::Args;
+use crate::environment::Environment;
 use crate::path_clean::PathClean;
 use crate::trace::Trace;
+use crypto::blake2b::Blake2b;
 use crypto::digest::Digest;
 use crypto::sha1::Sha1;
-use std::collections::{BTreeMap, HashSet};
+use crypto::sha2::Sha256;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::env::current_dir;
 use std::ffi::{OsStr, OsString};
 use std::fs::{read_link, File};
-use std::io::{Read, Write};
-use std::os::unix::ffi::OsStrExt;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 use std::os::unix::process::CommandExt;
 use std::os::unix::process::ExitStatusExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::{exit, Command};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
 use tempfile::NamedTempFile;
 use ufcs::Pipe;
 
+/// `eprintln!` for informational messages and warnings, which are suppressed
+/// by `--quiet`.
+macro_rules! note {
+    ($($arg:tt)*) => {
+        if !crate::quiet() {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// `eprintln!` for errors, in red if colors are enabled (see `color`).
+macro_rules! error {
+    ($($arg:tt)*) => {
+        eprintln!("{}", crate::color::error(&format!($($arg)*)))
+    };
+}
+
+/// Print `Warning: ...` once, see `warnings`.
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        crate::warnings::warn(format!($($arg)*))
+    };
+}
+
+mod archive;
 mod args;
+mod audit;
+mod bench;
+mod color;
+mod completions;
+mod dedup;
+mod dotenv;
+mod dump;
+mod entry;
+mod environment;
+mod fanotify;
+mod flake;
+mod gc;
+mod githook;
+mod hook;
+mod lint;
+mod lock;
+mod merge;
+mod meta;
 mod path_clean;
 mod shebang;
+mod stats;
+mod timeout;
+mod timings;
 mod trace;
+mod verify;
+mod warnings;
+mod watch;
 
 type EnvMap = BTreeMap<OsString, OsString>;
 
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
 /// Serialize environment variables in the same way as `env -0` does.
 fn serialize_env(env: &EnvMap) -> Vec<u8> {
     let mut vec = Vec::new();
@@ -35,18 +96,34 @@ fn serialize_env(env: &EnvMap) -> Vec<u8> {
     vec
 }
 
-/// Deserealize environment variables from `env -0` format.
-fn deserealize_env(vec: Vec<u8>) -> EnvMap {
-    vec.split(|&b| b == 0)
-        .filter(|&var| !var.is_empty()) // last entry has trailing NUL
-        .map(|var| {
-            let pos = var.iter().position(|&x| x == b'=').unwrap();
-            (
-                OsStr::from_bytes(&var[0..pos]).to_owned(),
-                OsStr::from_bytes(&var[pos + 1..]).to_owned(),
-            )
-        })
-        .collect::<BTreeMap<_, _>>()
+/// Read environment variables in the `env -0` format, one at a time, so
+/// that only the variables themselves are kept in memory, not the whole
+/// dump too.  Output that is cut short or mixed with other output is an
+/// error.
+fn read_env(mut reader: impl BufRead) -> std::io::Result<EnvMap> {
+    let invalid =
+        |msg| std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+    let mut env = EnvMap::new();
+    loop {
+        let mut var = Vec::new();
+        if reader.read_until(0, &mut var)? == 0 {
+            break;
+        }
+        if var.pop() != Some(0) {
+            return Err(invalid("incomplete environment"));
+        }
+        let pos = var
+            .iter()
+            .position(|&b| b == b'=')
+            .ok_or_else(|| invalid("not an environment"))?;
+        let value = var.split_off(pos + 1);
+        var.pop();
+        env.insert(OsString::from_vec(var), OsString::from_vec(value));
+    }
+    if env.is_empty() {
+        return Err(invalid("empty environment"));
+    }
+    Ok(env)
 }
 
 fn serialize_args(args: &[OsString]) -> Vec<u8> {
@@ -67,12 +144,24 @@ fn serialize_vecs(vecs: &[&[u8]]) -> Vec<u8> {
     vec
 }
 
+// Exit codes of cached-nix-shell's own failures.  Once the command is
+// started, cached-nix-shell is replaced by it, so the command's exit code is
+// passed through as-is.
+/// Invalid arguments and other errors of cached-nix-shell itself
+const EXIT_ERROR: i32 = 2;
+/// nix-shell couldn't build the shell
+const EXIT_BUILD: i32 = 3;
+/// The command couldn't be started, like in shells
+const EXIT_NOT_RUN: i32 = 127;
+/// The command was killed by `--timeout`, like with `timeout(1)`
+const EXIT_TIMEOUT: i32 = 124;
+
 fn unwrap_or_errx<T>(x: Result<T, String>) -> T {
     match x {
         Ok(x) => x,
         Err(x) => {
-            eprintln!("cached-nix-shell: {}", x);
-            exit(1)
+            error!("cached-nix-shell: {}", x);
+            exit(EXIT_ERROR)
         }
     }
 }
@@ -81,30 +170,54 @@ struct NixShellInput {
     pwd: OsString,
     env: EnvMap,
     args: Vec<OsString>,
+    /// Arguments passed to nix-shell that are not a part of the cache key.
+    unkeyed_args: Vec<OsString>,
+    /// Cache key components that are not passed to nix-shell, e.g.
+    /// cached-nix-shell's own options that affect the resulting environment.
+    key_extra: EnvMap,
+    /// Files to add to the trace, besides the ones nix reads.
+    traced_files: Vec<PathBuf>,
+    /// `--cache-key-from`: the key instead of the other inputs.
+    key_from: Option<OsString>,
+    /// `--trace-scope` directories; empty to keep all dependencies.
+    trace_scope: Vec<PathBuf>,
+    /// `--trace-filter-store-only`: only the store paths the environment
+    /// refers to are dependencies.
+    store_only: bool,
+    /// `--trace-dir-depth`: the project root (the directory of the shell
+    /// file, or the working directory with `-p`) and the depth.
+    trace_dir_depth: Option<(PathBuf, usize)>,
+    /// The shell file, if given or found; a symlink is tracked along with
+    /// its destination, even out of `--trace-scope`.
+    shell_file: Option<PathBuf>,
+    /// Whether to stream the output of nix-shell while building; otherwise
+    /// it is only shown if the build fails.
+    show_build_output: bool,
+    /// `--keep-build-dir`: leave the scratch files of a build behind.
+    keep_build_dir: bool,
 }
 
 struct NixShellOutput {
-    env: EnvMap,
+    env: Environment,
     trace: trace::Trace,
     drv: String,
+    /// The exit status of the `shellHook`, if it could be seen.
+    hook_status: Option<i32>,
 }
 
-fn minimal_essential_path() -> OsString {
+/// The directories of `path` (the `PATH` of the caller) that nix-shell
+/// needs.
+fn minimal_essential_path(path: &OsStr) -> OsString {
     let required_binaries = ["nix-shell", "tar", "gzip", "git"];
 
     let which_dir = |binary: &&str| -> Option<PathBuf> {
-        std::env::var_os("PATH")
-            .as_ref()
-            .unwrap()
-            .pipe(std::env::split_paths)
-            .filter(|dir| {
-                nix::unistd::access(
-                    &dir.join(binary),
-                    nix::unistd::AccessFlags::X_OK,
-                )
-                .is_ok()
-            })
-            .next()
+        std::env::split_paths(path).find(|dir| {
+            nix::unistd::access(
+                &dir.join(binary),
+                nix::unistd::AccessFlags::X_OK,
+            )
+            .is_ok()
+        })
     };
 
     let required_paths = required_binaries
@@ -113,10 +226,7 @@ fn minimal_essential_path() -> OsString {
         .collect::<HashSet<PathBuf>>();
 
     // We can't just join_paths(required_paths) -- we need to preserve order
-    std::env::var_os("PATH")
-        .as_ref()
-        .unwrap()
-        .pipe(std::env::split_paths)
+    std::env::split_paths(path)
         .filter(|path_item| required_paths.contains(path_item))
         .pipe(std::env::join_paths)
         .unwrap()
@@ -141,80 +251,582 @@ fn absolute_dirname(script_fname: &OsStr) -> OsString {
         })
 }
 
-fn args_to_inp(pwd: OsString, x: &Args) -> NixShellInput {
+/// Find the store path of the nix-shell binary, e.g.
+/// `/nix/store/...-nix-2.3.1/bin/nix-shell`.  It's a part of the cache key, so
+/// upgrading nix (which may change what nix-shell sets up) rebuilds shells.
+/// This is cheaper than running `nix-shell --version` on every run.
+fn nix_shell_binary(path: &OsStr) -> Option<PathBuf> {
+    std::env::split_paths(path)
+        .map(|dir| dir.join("nix-shell"))
+        .find(|bin| bin.exists())?
+        .canonicalize()
+        .ok()
+}
+
+/// The input of nix-shell for `x`, run from `pwd` by a caller with the
+/// environment `caller_env`.
+fn args_to_inp(pwd: OsString, x: &Args, caller_env: &EnvMap) -> NixShellInput {
     let mut args = Vec::new();
 
     args.push(OsString::from("--pure"));
 
+    // nix-shell only sees these variables, and they are a part of the key,
+    // so `builtins.getEnv` needs no tracing, e.g. in overlays used by `-p`.
+    // nixpkgs finds its config and overlays with HOME and NIXPKGS_CONFIG.
     let env = {
         let mut clean_env = BTreeMap::new();
-        let whitelist =
-            &["NIX_PATH", "NIX_SSL_CERT_FILE", "XDG_RUNTIME_DIR", "TMPDIR"];
+        let whitelist = &[
+            "HOME",
+            "NIXPKGS_CONFIG",
+            "NIX_PATH",
+            "NIX_SSL_CERT_FILE",
+            "XDG_RUNTIME_DIR",
+            "TMPDIR",
+            "NIX_CONF_DIR",
+            "NIX_USER_CONF_FILES",
+            "NIX_CONFIG",
+        ];
         for var in whitelist {
-            if let Some(val) = std::env::var_os(var) {
-                clean_env.insert(OsString::from(var), val);
+            if let Some(val) = caller_env.get(OsStr::new(var)) {
+                clean_env.insert(OsString::from(var), val.clone());
             }
         }
-        clean_env.insert(OsString::from("PATH"), minimal_essential_path());
+        let path = caller_env.get(OsStr::new("PATH"));
+        let path = minimal_essential_path(path.map_or(OsStr::new(""), |x| x));
+        clean_env.insert(OsString::from("PATH"), path);
+        // nix-shell runs `env -0` in NIX_BUILD_SHELL, which defaults to
+        // bashInteractive from nixpkgs rather than the bash of the host.
+        if let Some(bash) = caller_env.get(OsStr::new("CACHED_NIX_SHELL_BASH"))
+        {
+            clean_env.insert(OsString::from("NIX_BUILD_SHELL"), bash.clone());
+        }
         clean_env
     };
 
+    let cache_dirs = [cache_dir(), gc_roots_dir().unwrap_or_default()];
+    for msg in &x.warnings {
+        warning!("{}", msg);
+    }
+    if x.verbose {
+        for msg in &x.advisories {
+            note!("cached-nix-shell: {}", msg);
+        }
+    }
+    let nix_path = env.get(OsStr::new("NIX_PATH")).map(|x| x.as_os_str());
+    for entry in includes_in_cache(&x.other_kw, nix_path, &cache_dirs) {
+        warning!(
+            "{:?} points into the cache of cached-nix-shell, which is likely a mistake",
+            entry
+        );
+    }
+
     if x.packages {
         args.push(OsString::from("--packages"));
+    } else if !x.merge_files.is_empty() {
+        args.push(OsString::from("--expr"));
     }
 
     args.push(OsString::from("--run"));
-    args.push(OsString::from("env -0"));
+    args.push(OsString::from(KEYED_PRINT_ENV));
     args.extend(x.other_kw.clone());
     args.push(OsString::from("--"));
-    args.extend(x.rest.clone());
+    if x.merge_files.is_empty() {
+        args.extend(x.rest.clone());
+    } else {
+        args.push(merge::expression(&x.merge_files));
+    }
+
+    let mut key_extra = EnvMap::new();
+    if let Some(nix) = nix_shell_binary(&env[OsStr::new("PATH")]) {
+        key_extra.insert("nix".into(), nix.into_os_string());
+    }
+    // The files nix-shell reads, with the variables it is given.
+    if let Some(conf) = nix_conf_key(|var| env.get(OsStr::new(var)).cloned()) {
+        key_extra.insert("nix-conf".into(), conf);
+    }
+    if x.keep_path {
+        key_extra.insert("keep-path".into(), "1".into());
+    }
+    if x.clean_env {
+        key_extra.insert("clean-env".into(), "1".into());
+    }
+    // Patterns are resolved here, so the variables they match are keyed.
+    let keep = kept_vars(
+        x,
+        caller_env
+            .get(OsStr::new("CACHED_NIX_SHELL_ALWAYS_KEEP"))
+            .cloned(),
+        caller_env,
+    );
+    if !keep.is_empty() {
+        let keep = keep.iter().map(|x| x.as_bytes()).collect::<Vec<_>>();
+        key_extra.insert("keep".into(), OsString::from_vec(keep.join(&b':')));
+    }
+    if !x.packages_files.is_empty() {
+        let fnames = x
+            .packages_files
+            .iter()
+            .map(|x| x.as_os_str().as_bytes())
+            .collect::<Vec<_>>();
+        key_extra.insert(
+            "packages-file".into(),
+            OsStr::from_bytes(&serialize_vecs(&fnames)).into(),
+        );
+    }
+    if !x.trace_scope.is_empty() {
+        let dirs = x
+            .trace_scope
+            .iter()
+            .map(|x| x.as_os_str().as_bytes())
+            .collect::<Vec<_>>();
+        key_extra.insert(
+            "trace-scope".into(),
+            OsStr::from_bytes(&serialize_vecs(&dirs)).into(),
+        );
+    }
+    if x.trace_filter_store_only {
+        key_extra.insert("trace-filter-store-only".into(), "1".into());
+    }
+    if x.key_env_files {
+        let env_files = x
+            .env_files
+            .iter()
+            .map(|fname| dotenv::load(fname).pipe(unwrap_or_errx))
+            .map(|env| serialize_env(&env))
+            .collect::<Vec<_>>();
+        let env_files = env_files.iter().map(|x| &x[..]).collect::<Vec<_>>();
+        key_extra.insert(
+            "env-file".into(),
+            OsStr::from_bytes(&serialize_vecs(&env_files)).into(),
+        );
+    }
+
+    let shell_file = match x.rest.first() {
+        Some(file) if !x.packages && x.merge_files.is_empty() => {
+            Some(Path::new(&pwd).join(file).clean())
+        }
+        _ => None,
+    };
+    let trace_dir_depth = x.trace_dir_depth.map(|depth| {
+        let root = match &shell_file {
+            Some(file) if file.is_dir() => file.clone(),
+            Some(file) => file.parent().unwrap_or(Path::new("/")).to_owned(),
+            // With `-p`, the empty directory nix-shell runs in.
+            None => PathBuf::from(&pwd),
+        };
+        key_extra.insert("trace-dir-depth".into(), depth.to_string().into());
+        (root, depth)
+    });
+    if x.git_aware {
+        let dir = match &shell_file {
+            Some(file) if file.is_dir() => file.clone(),
+            Some(file) => file.parent().unwrap_or(Path::new("/")).to_owned(),
+            None => current_dir().expect("Can't get cwd"),
+        };
+        if let Some(head) = git_head(&dir) {
+            key_extra.insert("git-head".into(), head);
+        }
+    }
 
-    NixShellInput { pwd, env, args }
+    NixShellInput {
+        pwd,
+        env,
+        args,
+        unkeyed_args: x.other_kw_unkeyed.clone(),
+        key_extra,
+        traced_files: [&x.packages_files[..], &x.merge_files[..]].concat(),
+        key_from: x.cache_key_from.clone(),
+        trace_scope: x.trace_scope.clone(),
+        store_only: x.trace_filter_store_only,
+        trace_dir_depth,
+        shell_file,
+        show_build_output: x.verbose || !x.quiet,
+        keep_build_dir: x.keep_build_dir,
+    }
 }
 
-fn run_nix_shell(inp: &NixShellInput) -> NixShellOutput {
-    let trace_file = NamedTempFile::new().expect("can't create temporary file");
+/// The nix.conf files that nix reads, in the same order: the system one,
+/// then either `NIX_USER_CONF_FILES` or `nix/nix.conf` in the XDG config
+/// directories.
+fn nix_conf_files(var: impl Fn(&str) -> Option<OsString>) -> Vec<PathBuf> {
+    let var = |name| var(name).filter(|x| !x.is_empty());
+    let conf_dir = var("NIX_CONF_DIR").unwrap_or_else(|| "/etc/nix".into());
+    let mut files = vec![PathBuf::from(conf_dir).join("nix.conf")];
+    if let Some(user_files) = var("NIX_USER_CONF_FILES") {
+        files.extend(std::env::split_paths(&user_files));
+        return files;
+    }
+    let config_dirs =
+        var("XDG_CONFIG_DIRS").unwrap_or_else(|| "/etc/xdg".into());
+    let mut dirs = std::env::split_paths(&config_dirs).collect::<Vec<_>>();
+    dirs.reverse();
+    match var("XDG_CONFIG_HOME") {
+        Some(dir) => dirs.push(dir.into()),
+        None => dirs.extend(var("HOME").map(|x| Path::new(&x).join(".config"))),
+    }
+    files.extend(dirs.into_iter().map(|dir| dir.join("nix/nix.conf")));
+    files
+}
 
-    let env = {
-        let exec = Command::new("nix-shell")
-            .args(&inp.args)
-            .stderr(std::process::Stdio::inherit())
-            .current_dir(&inp.pwd)
-            .env_clear()
-            .envs(&inp.env)
-            .env("LD_PRELOAD", env!("CARGO_TRACE_NIX_SO"))
-            .env("TRACE_NIX", trace_file.path())
-            .output()
+/// A hash of the nix.conf files, since settings like `sandbox` or
+/// `system-features` can change the result.  Those files are traced when nix
+/// reads them, but keying on them also keeps entries made with different
+/// settings apart.  `include` directives aren't followed.  `None` if there
+/// are no such files.
+fn nix_conf_key(var: impl Fn(&str) -> Option<OsString>) -> Option<OsString> {
+    let mut hasher = Sha1::new();
+    let mut found = false;
+    for file in nix_conf_files(var) {
+        if let Ok(contents) = std::fs::read(&file) {
+            found = true;
+            hasher.input(&serialize_vecs(&[
+                file.as_os_str().as_bytes(),
+                &contents,
+            ]));
+        }
+    }
+    found.then(|| hasher.result_str().into())
+}
+
+/// `-I` and `NIX_PATH` entries (`path` or `name=path`) under one of `dirs`.
+fn includes_in_cache(
+    other_kw: &[OsString],
+    nix_path: Option<&OsStr>,
+    dirs: &[PathBuf],
+) -> Vec<OsString> {
+    let includes = other_kw
+        .windows(2)
+        .filter(|pair| pair[0] == "-I")
+        .map(|pair| pair[1].as_bytes());
+    let nix_path = nix_path
+        .map_or(&b""[..], |x| x.as_bytes())
+        .split(|&b| b == b':');
+    let dirs = dirs
+        .iter()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .map(|dir| std::fs::canonicalize(dir).unwrap_or_else(|_| dir.clean()))
+        .collect::<Vec<_>>();
+    includes
+        .chain(nix_path)
+        .filter(|entry| {
+            let path = match entry.iter().position(|&b| b == b'=') {
+                Some(pos) => &entry[pos + 1..],
+                None => entry,
+            };
+            if path.first() != Some(&b'/') {
+                // URLs, `channel:` entries, and `<nixpkgs>` lookups.
+                return false;
+            }
+            let path = PathBuf::from(OsStr::from_bytes(path));
+            let path =
+                std::fs::canonicalize(&path).unwrap_or_else(|_| path.clean());
+            dirs.iter().any(|dir| path.starts_with(dir))
+        })
+        .map(|entry| OsStr::from_bytes(entry).to_owned())
+        .collect()
+}
+
+/// Well-known locations of nixpkgs config and overlays, for the HOME and
+/// NIXPKGS_CONFIG that nix-shell is given.  They are tracked even if nix
+/// didn't read them (e.g. they don't exist yet), so that creating or editing
+/// them invalidates the cache.
+fn nixpkgs_config_paths(
+    home: Option<&OsString>,
+    nixpkgs_config: Option<&OsString>,
+) -> Vec<PathBuf> {
+    let mut paths = nixpkgs_config
+        .into_iter()
+        .map(PathBuf::from)
+        .collect::<Vec<_>>();
+    if let Some(home) = home {
+        let home = PathBuf::from(home);
+        paths.extend(vec![
+            home.join(".config/nixpkgs/config.nix"),
+            home.join(".config/nixpkgs/overlays.nix"),
+            home.join(".config/nixpkgs/overlays"),
+            home.join(".nixpkgs/config.nix"),
+        ]);
+    }
+    paths
+}
+
+/// Files are traced the same way with `-p` and with a file: the expression
+/// built from packages reads nixpkgs and overlays too.  Disabling the trace
+/// is only useful to check `--verify` itself.
+fn tracing_enabled() -> bool {
+    !env_flag("CACHED_NIX_SHELL_NO_TRACE")
+}
+
+/// Whether the variable `name`, which turns a feature on or off, is set: an
+/// empty value or `0` is the same as none.
+fn env_flag(name: &str) -> bool {
+    flag_value(std::env::var_os(name))
+}
+
+fn flag_value(value: Option<OsString>) -> bool {
+    value.is_some_and(|x| !x.is_empty() && x != "0")
+}
+
+/// Set to `capture_id` of the shell that nix-shell captures, and kept in its
+/// `--pure` shell, so that cached-nix-shell run by a `shellHook` for the same
+/// shell notices it.
+const CAPTURING_VAR: &str = "CACHED_NIX_SHELL_CAPTURING";
+
+/// Set by `HOOK_PROBE` to the exit status of the `shellHook`.
+const HOOK_STATUS_VAR: &str = "CACHED_NIX_SHELL_HOOK_STATUS";
+
+/// Loaded as `BASH_ENV` by the bash that nix-shell runs, before its rc file.
+/// nix-shell runs the `shellHook` with `runHook shellHook`, which ignores
+/// its status, so just before that, the `_callImplicitHook` function of
+/// stdenv that it calls is wrapped to export the status.  Nothing is
+/// recorded with a stdenv or a nix-shell that works differently.
+const HOOK_PROBE: &str = r#"unset BASH_ENV
+trap '
+if [ "$BASH_COMMAND" = "runHook shellHook" ] &&
+    declare -F _callImplicitHook >/dev/null; then
+  trap - DEBUG
+  eval "__cached_nix_shell$(declare -f _callImplicitHook)"
+  _callImplicitHook() {
+    __cached_nix_shell_callImplicitHook "$@"
+    local status=$?
+    if [ "$2" = shellHook ]; then
+      export CACHED_NIX_SHELL_HOOK_STATUS=$status
+    fi
+    return $status
+  }
+fi' DEBUG
+"#;
+
+/// The `--run` command of nix-shell as it appears in the cache key.
+const KEYED_PRINT_ENV: &str = "env -0";
+
+/// The `--run` command that is actually run: the umask, the limit of open
+/// files, the shell options and the aliases are captured too, without
+/// changing the keys of existing entries.
+const PRINT_ENV: &str = concat!(
+    "CACHED_NIX_SHELL_UMASK=$(umask) ",
+    "CACHED_NIX_SHELL_NOFILE=$(ulimit -Sn) ",
+    "CACHED_NIX_SHELL_SHOPTS=\"$(shopt -p)\" ",
+    "CACHED_NIX_SHELL_ALIASES=\"$(alias -p)\" env -0"
+);
+
+/// The nix-shell invocation that prints the environment of the shell.
+/// `trace` is the trace library to preload and the file it writes to, and
+/// `hook_probe` the file with `HOOK_PROBE`.
+fn nix_shell_command(
+    inp: &NixShellInput,
+    trace: Option<(&Path, &Path)>,
+    hook_probe: Option<&Path>,
+) -> Command {
+    // Our `--run` comes before the arguments of the user.
+    let mut args = inp.args.clone();
+    if let Some(i) = args.iter().position(|x| x == "--run") {
+        if args.get(i + 1).is_some_and(|x| x == KEYED_PRINT_ENV) {
+            args[i + 1] = PRINT_ENV.into();
+        }
+    }
+    let mut cmd = Command::new("nix-shell");
+    cmd.args(["--keep", CAPTURING_VAR]);
+    if hook_probe.is_some() {
+        cmd.args(["--keep", "BASH_ENV"]);
+    }
+    cmd.args(&inp.unkeyed_args)
+        .args(&args)
+        .current_dir(&inp.pwd)
+        .env_clear()
+        .envs(&inp.env)
+        .env(CAPTURING_VAR, capture_id(inp));
+    if let Some(probe) = hook_probe {
+        cmd.env("BASH_ENV", probe);
+    }
+    // Only nix-shell itself is traced: it evaluates the expression before
+    // starting the shell, and `--pure` clears these for the bash it starts,
+    // so they are deliberately not kept.
+    if let Some((lib, trace_file)) = trace {
+        cmd.env("LD_PRELOAD", lib).env("TRACE_NIX", trace_file);
+    }
+    cmd
+}
+
+/// Implementation of `--print-build-command`: the nix-shell invocation that
+/// a cache miss would run, as a shell command.  The trace goes to a fixed
+/// file in the temporary directory rather than to a fresh one.
+fn print_build_command(args: Vec<OsString>) -> String {
+    let (_, inp) = parse_args(args);
+    let lib = Path::new(env!("CARGO_TRACE_NIX_SO"));
+    let trace_file = std::env::temp_dir().join("cached-nix-shell-trace");
+    let cmd = nix_shell_command(
+        &inp,
+        Some((lib, trace_file.as_path())).filter(|_| tracing_enabled()),
+        None,
+    );
+
+    let word = |x: &OsStr| dump::sh_word(&x.to_string_lossy());
+    let mut res = String::new();
+    if let Some(dir) = cmd.get_current_dir() {
+        res.push_str(&format!("cd {} &&\n", word(dir.as_os_str())));
+    }
+    res.push_str("env -i");
+    let env = cmd
+        .get_envs()
+        .filter_map(|(k, v)| Some((k, v?)))
+        .collect::<BTreeMap<_, _>>();
+    for (k, v) in env {
+        let var = [k.as_bytes(), b"=", v.as_bytes()].concat();
+        res.push_str(&format!(" \\\n  {}", word(OsStr::from_bytes(&var))));
+    }
+    res.push_str(&format!(" \\\n  {}", word(cmd.get_program())));
+    for arg in cmd.get_args() {
+        res.push(' ');
+        res.push_str(&word(arg));
+    }
+    res.push('\n');
+    res
+}
+
+/// Build the shell and capture its environment.  The raw trace is also
+/// copied to `trace_to`, if given.
+fn run_nix_shell(
+    inp: &NixShellInput,
+    trace_to: Option<&OsStr>,
+) -> NixShellOutput {
+    let build_dir = tempfile::Builder::new()
+        .prefix("cached-nix-shell-build")
+        .tempdir()
+        .expect("can't create temporary directory");
+    let trace_file = build_dir.path().join("trace");
+    File::create(&trace_file).expect("can't create temporary file");
+    // A `BASH_ENV` of the caller is left alone.
+    let hook_probe = if inp.env.contains_key(OsStr::new("BASH_ENV")) {
+        None
+    } else {
+        let path = build_dir.path().join("bash-env");
+        std::fs::write(&path, HOOK_PROBE).expect("can't write temporary file");
+        Some(path)
+    };
+
+    let mut env = {
+        let mut fanotify = None;
+        if tracing_enabled() && fanotify::requested() {
+            match fanotify::Watch::start() {
+                Ok(watch) => fanotify = Some(watch),
+                Err(e) => {
+                    warning!(
+                        "can't trace with fanotify, using LD_PRELOAD: {}",
+                        e
+                    )
+                }
+            }
+        }
+        let preload = if tracing_enabled() && fanotify.is_none() {
+            preload_path(env!("CARGO_TRACE_NIX_SO").as_ref())
+                .expect("can't link the trace library")
+                .pipe(Some)
+        } else {
+            None
+        };
+        let trace = preload
+            .as_ref()
+            .map(|(lib, _link_dir)| (lib.as_path(), trace_file.as_path()));
+        let mut cmd = nix_shell_command(inp, trace, hook_probe.as_deref());
+        let stderr = if inp.show_build_output {
+            std::process::Stdio::inherit()
+        } else {
+            std::process::Stdio::piped()
+        };
+        // stdin is left untouched for the command, e.g. `jq` in
+        // `echo '{}' | cached-nix-shell -p jq --run 'jq .'`.
+        // Written to a file rather than read through a pipe, so that huge
+        // variables are never buffered twice.
+        let env_file = File::create(build_dir.path().join("env"))
+            .expect("can't create temporary file");
+        cmd.stdin(std::process::Stdio::null())
+            .stdout(env_file)
+            .stderr(stderr);
+        let child = cmd.spawn().expect("failed to execute nix-shell");
+        let pid = child.id();
+        let exec = child
+            .wait_with_output()
             .expect("failed to execute nix-shell");
+        if let Some(watch) = fanotify {
+            // Caught by `or_fallback` too, since the trace is incomplete.
+            let records = watch
+                .finish(pid)
+                .unwrap_or_else(|e| panic!("can't trace with fanotify: {}", e));
+            std::fs::write(&trace_file, records)
+                .expect("can't write trace file");
+        }
+        if inp.keep_build_dir {
+            keep_build_files(build_dir.path(), &exec);
+        }
         if !exec.status.success() {
-            eprintln!("cached-nix-shell: nix-shell: {}", exec.status);
-            let code = exec
-                .status
-                .code()
-                .or_else(|| exec.status.signal().map(|x| x + 127))
-                .unwrap_or(255);
+            let _ = std::io::stderr().write_all(&exec.stderr);
+            error!("cached-nix-shell: nix-shell: {}", exec.status);
+            let code = match exec.status.signal() {
+                Some(signal) => signal + 127,
+                None => EXIT_BUILD,
+            };
             exit(code);
         }
-        let mut env = deserealize_env(exec.stdout);
+        // Caught by `or_fallback`, so nothing gets cached.
+        let mut env = File::open(build_dir.path().join("env"))
+            .map(BufReader::new)
+            .and_then(read_env)
+            .unwrap_or_else(|e| panic!("bad output from nix-shell: {}", e));
         env.remove(OsStr::new("PWD"));
+        env.remove(OsStr::new(CAPTURING_VAR));
         env
     };
+    let hook_status = env
+        .remove(OsStr::new(HOOK_STATUS_VAR))
+        .and_then(|x| x.to_str()?.parse().ok());
 
     let env_out = env
         .get(OsStr::new("out"))
         .expect("expected to have `out` environment variable");
 
-    let mut trace_file =
-        trace_file.reopen().expect("can't reopen temporary file");
-    let mut trace_data = Vec::new();
-    trace_file
-        .read_to_end(&mut trace_data)
+    if let Some(trace_to) = trace_to {
+        if let Err(e) = std::fs::copy(&trace_file, trace_to) {
+            warning!("can't write trace to {:?}: {}", trace_to, e);
+        }
+    }
+
+    let mut trace = File::open(&trace_file)
+        .expect("can't reopen temporary file")
+        .pipe(BufReader::new)
+        .pipe(Trace::read)
         .expect("Can't read trace file");
-    let trace = Trace::load(trace_data);
+    let config_paths = nixpkgs_config_paths(
+        inp.env.get(OsStr::new("HOME")),
+        inp.env.get(OsStr::new("NIXPKGS_CONFIG")),
+    );
+    for path in config_paths.iter().chain(&inp.traced_files) {
+        trace.add_path(path);
+    }
+    if !inp.trace_scope.is_empty() {
+        trace.retain_scope(&inp.trace_scope);
+    }
+    if let Some((root, depth)) = &inp.trace_dir_depth {
+        trace.limit_dir_depth(root, *depth);
+    }
+    if inp.store_only {
+        trace.retain_store_closure(env.values().map(|x| x.as_os_str()));
+    } else if let Some(file) = &inp.shell_file {
+        trace.add_symlink(file);
+        if let Ok(contents) = std::fs::read(file) {
+            for lock in flake::lock_files(file, &contents) {
+                trace.add_path(&lock);
+            }
+        }
+    }
     if trace.check_for_changes() {
-        eprintln!("cached-nix-shell: some files are already updated, cache won't be reused");
+        note!("cached-nix-shell: some files are already updated, cache won't be reused");
+    }
+    if inp.keep_build_dir {
+        build_dir.into_path();
+    } else {
+        std::mem::drop(build_dir);
     }
-    std::mem::drop(trace_file);
 
     let drv: String = {
         let exec = Command::new("nix")
@@ -223,7 +835,7 @@ fn run_nix_shell(inp: &NixShellInput) -> NixShellOutput {
             .output()
             .expect("failed to execute nix show-derivation");
         if !exec.status.success() {
-            exit(1);
+            exit(EXIT_BUILD);
         }
         let output = String::from_utf8(exec.stdout).expect("failed to decode");
         let output: serde_json::Value =
@@ -234,7 +846,114 @@ fn run_nix_shell(inp: &NixShellInput) -> NixShellOutput {
         drv.clone()
     };
 
-    NixShellOutput { env, trace, drv }
+    let mut env = Environment::from_vars(env);
+    // Unchanged, the umask and limits of the caller are left alone on cache
+    // hits.  They are compared with the ones cached-nix-shell started with,
+    // since `--watch` may have applied the ones of an earlier hit.
+    let (umask, nofile) = caller_limits();
+    if env.umask == Some(umask) {
+        env.umask = None;
+    }
+    if env.nofile.is_some() && env.nofile == nofile {
+        env.nofile = None;
+    }
+
+    NixShellOutput {
+        env,
+        trace,
+        drv,
+        hook_status,
+    }
+}
+
+/// The umask and the soft limit of open files that cached-nix-shell was
+/// started with (see `main`).
+fn caller_limits() -> (u32, Option<u64>) {
+    static LIMITS: OnceLock<(u32, Option<u64>)> = OnceLock::new();
+    *LIMITS.get_or_init(|| (current_umask(), nofile_limit().map(|x| x.0)))
+}
+
+fn current_umask() -> u32 {
+    use nix::sys::stat::{umask, Mode};
+    let old = umask(Mode::empty());
+    umask(old);
+    old.bits()
+}
+
+/// The soft and hard limits of open files.
+fn nofile_limit() -> Option<(u64, u64)> {
+    let mut limit = nix::libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // The nix crate of this version has no `sys::resource` yet.
+    // SAFETY: `limit` is a valid rlimit to write to.
+    let res =
+        unsafe { nix::libc::getrlimit(nix::libc::RLIMIT_NOFILE, &mut limit) };
+    nix::errno::Errno::result(res).ok()?;
+    Some((limit.rlim_cur, limit.rlim_max))
+}
+
+/// `--restore-limits`: set the soft limit of open files, within the hard
+/// limit.
+fn set_nofile_limit(soft: u64) -> Result<(), String> {
+    let (_, hard) =
+        nofile_limit().ok_or("can't get the limit of open files")?;
+    let limit = nix::libc::rlimit {
+        rlim_cur: soft.min(hard),
+        rlim_max: hard,
+    };
+    // SAFETY: `limit` is a valid rlimit.
+    let res = unsafe { nix::libc::setrlimit(nix::libc::RLIMIT_NOFILE, &limit) };
+    nix::errno::Errno::result(res).map(drop).map_err(|e| {
+        format!(
+            "can't set the limit of open files to {}: {}",
+            environment::format_limit(soft),
+            e
+        )
+    })
+}
+
+/// `--keep-build-dir`: besides the raw trace and the environment, save what
+/// nix-shell printed to stderr, and tell where the files are.  They stay
+/// even if the build fails.
+fn keep_build_files(dir: &Path, exec: &std::process::Output) {
+    if !exec.stderr.is_empty() {
+        let fname = dir.join("stderr");
+        if let Err(e) = std::fs::write(&fname, &exec.stderr) {
+            warning!("can't write {:?}: {}", fname, e);
+        }
+    }
+    eprintln!("cached-nix-shell: build files kept in {}", dir.display());
+}
+
+/// ld.so splits `LD_PRELOAD` at spaces and colons, with no way to escape
+/// them, so a library with such a path is preloaded through a symlink in a
+/// temporary directory, which lives as long as the returned `TempDir`.
+fn preload_path(
+    lib: &Path,
+) -> std::io::Result<(PathBuf, Option<tempfile::TempDir>)> {
+    let has_separator = |path: &Path| {
+        path.as_os_str()
+            .as_bytes()
+            .iter()
+            .any(|&b| b == b' ' || b == b':')
+    };
+    if !has_separator(lib) {
+        return Ok((lib.to_owned(), None));
+    }
+    let tmp = std::env::temp_dir();
+    let tmp = if has_separator(&tmp) {
+        PathBuf::from("/tmp")
+    } else {
+        tmp
+    };
+    let dir = tempfile::Builder::new()
+        .prefix("cached-nix-shell")
+        .tempdir_in(tmp)?;
+    let link = dir.path().join("trace-nix.so");
+    std::os::unix::fs::symlink(lib, &link)?;
+    Ok((link, Some(dir)))
 }
 
 fn run_script(
@@ -243,22 +962,102 @@ fn run_script(
     script_args: Vec<OsString>,
 ) {
     let nix_shell_args = Args::parse(nix_shell_args, true).pipe(unwrap_or_errx);
-    let inp = args_to_inp(absolute_dirname(&fname), &nix_shell_args);
-    let env = cached_shell_env(nix_shell_args.pure, &inp);
+    QUIET.store(nix_shell_args.quiet, Ordering::Relaxed);
+    color::set(nix_shell_args.color);
+    let inp = args_to_inp(
+        absolute_dirname(&fname),
+        &nix_shell_args,
+        &std::env::vars_os().collect(),
+    );
+    // nix-shell reads the `#! nix-shell` lines itself.
+    let plain = [std::slice::from_ref(&fname), &script_args[1..]].concat();
+    let env = or_fallback(plain, || cached_shell_env(&nix_shell_args, &inp));
 
-    let mut interpreter_args = script_args;
-    interpreter_args.insert(0, fname);
-    let exec = Command::new(nix_shell_args.interpreter)
-        .args(interpreter_args)
-        .env_clear()
-        .envs(&env)
-        .exec();
-    eprintln!("cached-nix-shell: couldn't run: {:?}", exec);
-    exit(1);
+    let exec =
+        script_command(&nix_shell_args.interpreter, fname, script_args, &env)
+            .exec();
+    error!("cached-nix-shell: couldn't run: {:?}", exec);
+    exit(EXIT_NOT_RUN);
 }
 
-fn run_from_args(args: Vec<OsString>) {
+/// The command that runs the script in the shell environment `env`.  The
+/// interpreter is usually provided by the shell (e.g. `-i lua -p lua`), so a
+/// name is looked up in the `PATH` of `env` rather than of the caller: std
+/// does that when the command's environment sets `PATH`.
+fn script_command(
+    interpreter: &OsStr,
+    fname: OsString,
+    script_args: Vec<OsString>,
+    env: &EnvMap,
+) -> Command {
+    let mut interpreter_args = interpreter_command(interpreter);
+    let interpreter = interpreter_args.remove(0);
+    interpreter_args.push(fname);
+    interpreter_args.extend(script_args);
+    let mut cmd = Command::new(interpreter);
+    cmd.args(interpreter_args).env_clear().envs(env);
+    cmd
+}
+
+/// Split the `-i` value into a program and its arguments.  nix-shell passes
+/// it through a shell, so `-i "env python3"` works there; `env` is dropped
+/// and the program after it is run directly.  The program itself is run as
+/// given, whether it's a name to look up in `PATH` or an absolute path.
+/// Arguments are split the same way as `#! nix-shell` lines, so they may be
+/// quoted with escaped double quotes: `-i "python3 -W \"ignore x\""`.
+fn interpreter_command(interpreter: &OsStr) -> Vec<OsString> {
+    let mut words = shebang::shellwords(interpreter.as_bytes())
+        .into_iter()
+        .filter(|word| !word.is_empty())
+        .map(OsString::from_vec)
+        .collect::<Vec<_>>();
+    let is_env = words
+        .first()
+        .and_then(|w| PathBuf::from(w).file_name().map(|n| n == "env"))
+        .unwrap_or(false);
+    if is_env && words.len() > 1 {
+        words.remove(0);
+    }
+    if words.is_empty() {
+        words.push(interpreter.to_owned());
+    }
+    words
+}
+
+/// Options from `CACHED_NIX_SHELL_OPTS`, split like `#! nix-shell` lines,
+/// followed by the command line ones, which override or add to them (e.g.
+/// `--impure` after `--pure`, or more `-I` entries).  Scripts aren't
+/// affected, so they run the same everywhere.
+fn with_default_opts(
+    opts: Option<&OsStr>,
+    args: Vec<OsString>,
+) -> Vec<OsString> {
+    let mut res = opts
+        .map(|opts| shebang::shellwords(opts.as_bytes()))
+        .unwrap_or_default()
+        .into_iter()
+        .map(OsString::from_vec)
+        .collect::<Vec<_>>();
+    res.extend(args);
+    res
+}
+
+/// Parse command line arguments and work out what to pass to nix-shell.
+fn parse_args(args: Vec<OsString>) -> (Args, NixShellInput) {
+    let opts = std::env::var_os("CACHED_NIX_SHELL_OPTS");
+    let args = with_default_opts(opts.as_deref(), args);
     let mut args = Args::parse(args, false).pipe(unwrap_or_errx);
+    QUIET.store(args.quiet, Ordering::Relaxed);
+    color::set(args.color);
+
+    if !args.packages && args.merge_files.is_empty() && args.rest.is_empty() {
+        let cwd = current_dir().expect("Can't get cwd");
+        if let Some(fname) =
+            default_shell_file(&cwd, std::env::var_os("NIX_SHELL_FILE"))
+        {
+            args.rest.push(fname.into_os_string());
+        }
+    }
 
     let nix_shell_pwd = if args.packages {
         OsString::from(env!("CARGO_VAR_EMPTY"))
@@ -271,187 +1070,3401 @@ fn run_from_args(args: Vec<OsString>) {
             .into_os_string();
         pwd
     } else {
-        // nix-shell will use ./shell.nix or ./default.nix
+        // Neither file exists, let nix-shell report an error
         current_dir().expect("Can't get cwd").into_os_string()
     };
 
-    let inp = args_to_inp(nix_shell_pwd, &args);
-    let env = cached_shell_env(args.pure, &inp);
-
-    let (cmd, cmd_args) = match args.run {
-        args::RunMode::InteractiveShell => (
-            "bash".into(),
-            vec!["--rcfile".into(), env!("CARGO_RCFILE").into()],
-        ),
-        args::RunMode::Shell(cmd) => ("bash".into(), vec!["-c".into(), cmd]),
-        args::RunMode::Exec(cmd, cmd_args) => (cmd, cmd_args),
-    };
-
-    let exec = Command::new(cmd)
-        .args(cmd_args)
-        .env_clear()
-        .envs(&env)
-        .exec();
-    eprintln!("cached-nix-shell: couldn't run: {:?}", exec);
-    exit(1);
-}
-
-fn cached_shell_env(pure: bool, inp: &NixShellInput) -> EnvMap {
-    let inputs = serialize_vecs(&[
-        &serialize_env(&inp.env),
-        &serialize_args(&inp.args),
-        inp.pwd.as_bytes(),
-    ]);
-
-    let inputs_hash = {
-        let mut hasher = Sha1::new();
-        hasher.input(&inputs);
-        hasher.result_str()
-    };
+    let inp = args_to_inp(nix_shell_pwd, &args, &std::env::vars_os().collect());
+    (args, inp)
+}
 
-    let mut env = if let Some(env) = check_cache(&inputs_hash) {
-        env
-    } else {
-        eprintln!("cached-nix-shell: updating cache");
-        let outp = run_nix_shell(inp);
+/// Implementation of `--list-deps`: show the files the cached shell depends
+/// on, without building it.
+fn list_deps(args: Vec<OsString>, verbose: bool) -> Result<String, String> {
+    let (_, inp) = parse_args(args);
+    let hash = cache_hash(&cache_inputs(&inp));
+    let trace = load_trace(&hash)
+        .ok_or_else(|| "not cached yet, run the shell first".to_string())?;
+    Ok(trace.list(verbose))
+}
 
-        // TODO: use flock
-        cache_write(&inputs_hash, "inputs", &inputs);
-        cache_write(&inputs_hash, "env", &serialize_env(&outp.env));
-        cache_write(&inputs_hash, "trace", &outp.trace.serialize());
-        cache_symlink(&inputs_hash, "drv", &outp.drv);
+/// Implementation of `--validate-only ARGS...`: check every dependency of the
+/// cached shell, without building or running anything.  Returns the report
+/// and whether the entry would be reused.  Like `check_cache`, the entry of
+/// the first cache directory that has a valid one is reported, or else the
+/// first entry found.
+fn validate_only(args: Vec<OsString>) -> Result<(String, bool), String> {
+    let (_, inp) = parse_args(args);
+    let hash = cache_hash(&cache_inputs(&inp));
+    let reports = cache_dirs()
+        .into_iter()
+        .filter(|dir| entry::path(dir, &hash, entry::TRACE).exists())
+        .map(|dir| validate_in(&dir, &hash))
+        .collect::<Vec<_>>();
+    reports
+        .iter()
+        .find(|(_, valid)| *valid)
+        .or_else(|| reports.first())
+        .cloned()
+        .ok_or_else(|| "not cached yet, run the shell first".to_string())
+}
 
-        outp.env
+fn validate_in(dir: &Path, hash: &str) -> (String, bool) {
+    let mut res = String::new();
+    let mut valid = true;
+    let mut line = |ok: bool, what: &str, path: &OsStr| {
+        let status = if ok { "ok" } else { "stale" };
+        res.push_str(&format!(
+            "{:8}{:8}{}\n",
+            status,
+            what,
+            path.to_string_lossy()
+        ));
+        valid &= ok;
     };
-
-    env.insert(OsString::from("IN_CACHED_NIX_SHELL"), OsString::from("1"));
-
-    if pure {
-        env
-    } else {
-        merge_env(env)
+    let trace = match load_trace_in(dir, hash) {
+        Some(trace) => trace,
+        None => {
+            let trace = entry::path(dir, hash, entry::TRACE);
+            line(false, "trace", trace.as_os_str());
+            return (res, false);
+        }
+    };
+    let env = entry::path(dir, hash, entry::ENV);
+    line(env.exists(), "env", env.as_os_str());
+    let drv = read_link(entry::path(dir, hash, entry::DRV)).unwrap_or_default();
+    line(drv.exists(), "drv", drv.as_os_str());
+    let mut stale = 0;
+    let items = trace.validate();
+    for (access, path, ok) in &items {
+        line(*ok, access, path);
+        stale += usize::from(!ok);
     }
+    res.push_str(&format!(
+        "{} of {} dependencies changed\n",
+        stale,
+        items.len()
+    ));
+    (res, valid)
 }
 
-// Merge ambient (impure) environment into cached env.
-fn merge_env(mut env: EnvMap) -> EnvMap {
-    let mut delim = EnvMap::new();
-    delim.insert(OsString::from("PATH"), OsString::from(":"));
-    delim.insert(OsString::from("HOST_PATH"), OsString::from(":"));
+/// Implementation of `--dump-env=FORMAT [--diff]`.
+fn dump_env(format: &str, args: Vec<OsString>) {
+    let (diff, args) = match args.split_first() {
+        Some((first, rest)) if first == "--diff" => (true, rest.to_vec()),
+        _ => (false, args),
+    };
+    let (args, inp) = parse_args(args);
+    let plain = plain_nix_shell_args(&args, &inp);
+    let env = or_fallback(plain, || cached_shell_env(&args, &inp));
+    let changes = if diff {
+        dump::diff(env, &std::env::vars_os().collect())
+    } else {
+        dump::full(env)
+    };
+    print!("{}", dump::format(format, &changes).pipe(unwrap_or_errx));
+}
 
-    // Set to "/no-cert-file.crt" by setup.sh for pure envs.
-    env.remove(OsStr::new("SSL_CERT_FILE"));
-    env.remove(OsStr::new("NIX_SSL_CERT_FILE"));
+/// cached-nix-shell never changes its own working directory (nix-shell is
+/// started in `inp.pwd` with `Command::current_dir` instead), so the command
+/// runs in the directory cached-nix-shell was invoked from.
+fn run_from_args(args: Vec<OsString>) {
+    let (args, inp) = parse_args(args);
+    if args.watch {
+        watch_loop(&args, &inp);
+    }
+    let plain = plain_nix_shell_args(&args, &inp);
+    let (mut env, setup) = or_fallback(plain, || cached_shell(&args, &inp));
+    if let Some(fname) = &args.keep_env_file {
+        keep_env_file(fname, &env);
+    }
 
-    env.insert(OsString::from("IN_NIX_SHELL"), OsString::from("impure"));
+    // With --timeout, the command is killed when the time is up, so it
+    // doesn't stay in an interactive shell.
+    let interactive = foreground_terminal(0) && args.timeout.is_none();
+    // An alias could stand for the command.
+    let (cmd, cmd_args, rc_env) = (args.reuse_env && setup.is_none())
+        .then(|| direct_command(&args.run, &args.run_args, &env))
+        .flatten()
+        .map(|(cmd, cmd_args)| (cmd, cmd_args, Vec::new()))
+        .unwrap_or_else(|| {
+            shell_command(
+                &args.run,
+                &args.run_args,
+                setup.as_deref(),
+                args.rcfile.as_deref(),
+                interactive,
+            )
+        });
+    env.extend(rc_env);
 
-    for (var, val) in std::env::vars_os() {
-        env.entry(var.clone())
-            .and_modify(|old_val| {
-                if let Some(d) = delim.get(&var) {
-                    *old_val = OsString::from(OsStr::from_bytes(
-                        &[
-                            old_val.as_os_str().as_bytes(),
-                            d.as_os_str().as_bytes(),
-                            val.as_os_str().as_bytes(),
-                        ]
-                        .concat(),
-                    ));
-                }
-            })
-            .or_insert_with(|| val);
+    let mut cmd = Command::new(cmd);
+    cmd.args(cmd_args).env_clear().envs(&env);
+    if let Some(timeout) = args.timeout {
+        exit(run_with_timeout(&mut cmd, timeout));
     }
-
-    env
+    let exec = cmd.exec();
+    error!("cached-nix-shell: couldn't run: {:?}", exec);
+    exit(EXIT_NOT_RUN);
 }
 
-fn check_cache(hash: &str) -> Option<BTreeMap<OsString, OsString>> {
-    let xdg_dirs =
-        xdg::BaseDirectories::with_prefix("cached-nix-shell").unwrap();
+/// `--keep-env-file`: save the environment the command runs with.  The
+/// command runs anyway when the file can't be written.  It may hold
+/// secrets, so a new file is only readable by the user.
+fn keep_env_file(fname: &OsStr, env: &EnvMap) {
+    let (data, skipped) = dotenv::serialize(env);
+    if !skipped.is_empty() {
+        note!(
+            "cached-nix-shell: {:?}: skipped variables that dotenv files can't hold: {}",
+            fname,
+            skipped
+                .iter()
+                .map(|x| x.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+    }
+    let res = File::options()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(fname)
+        .and_then(|mut file| file.write_all(&data));
+    if let Err(e) = res {
+        warning!("can't write {:?}: {}", fname, e);
+    }
+}
 
-    let env_fname = xdg_dirs.find_cache_file(format!("{}.env", hash))?;
-    let drv_fname = xdg_dirs.find_cache_file(format!("{}.drv", hash))?;
-    let trace_fname = xdg_dirs.find_cache_file(format!("{}.trace", hash))?;
+/// Whether `fd` is a terminal that we may read from: e.g. stdin of
+/// `ssh host cached-nix-shell ...` is a pipe, and a background job (`&`)
+/// would be stopped by `SIGTTIN` as soon as an interactive shell read from
+/// it.  Then `--command` runs like `--run` instead of waiting for input.
+fn foreground_terminal(fd: std::os::unix::io::RawFd) -> bool {
+    nix::unistd::isatty(fd).unwrap_or(false)
+        && nix::unistd::tcgetpgrp(fd)
+            .is_ok_and(|pgrp| pgrp == nix::unistd::getpgrp())
+}
 
-    let mut env_file = File::open(env_fname).unwrap();
-    let mut env_buf = Vec::<u8>::new();
-    env_file.read_to_end(&mut env_buf).unwrap();
-    let env = deserealize_env(env_buf);
+/// The program and arguments to run for `run`, and the variables for
+/// `rcfile.sh` in an interactive shell: the command to run in it, and the
+/// `--rcfile` of the user to source instead of `~/.bashrc`.  `setup` (see
+/// `Environment::shell_setup`) runs before the command in bash.  `--command` is
+/// interactive only if `interactive` is set, which is when stdin is a
+/// terminal, like in nix-shell; otherwise it's the same as `--run`.
+/// `run_args` are the positional parameters of `--run` and `--command`.
+fn shell_command(
+    run: &args::RunMode,
+    run_args: &[OsString],
+    setup: Option<&OsStr>,
+    user_rcfile: Option<&Path>,
+    interactive: bool,
+) -> (OsString, Vec<OsString>, Vec<(OsString, OsString)>) {
+    let rcfile = || vec!["--rcfile".into(), env!("CARGO_RCFILE").into()];
+    let rc_env = |command: Option<&OsString>| {
+        let mut env = Vec::new();
+        if let Some(setup) = setup {
+            env.push(("CACHED_NIX_SHELL_SETUP".into(), setup.into()));
+        }
+        if let Some(command) = command {
+            env.push(("CACHED_NIX_SHELL_COMMAND".into(), command.clone()));
+        }
+        if let Some(file) = user_rcfile {
+            env.push(("CACHED_NIX_SHELL_RCFILE".into(), file.into()));
+        }
+        env
+    };
+    match run {
+        args::RunMode::InteractiveShell => {
+            ("bash".into(), rcfile(), rc_env(None))
+        }
+        args::RunMode::CommandThenInteractive(cmd) if interactive => {
+            let mut cmd_args = rcfile();
+            if !run_args.is_empty() {
+                cmd_args.push("-s".into());
+                cmd_args.extend(run_args.iter().cloned());
+            }
+            ("bash".into(), cmd_args, rc_env(Some(cmd)))
+        }
+        args::RunMode::Shell(cmd)
+        | args::RunMode::CommandThenInteractive(cmd) => {
+            // On lines of their own, so that the aliases apply to the
+            // command.
+            let cmd = match setup {
+                Some(setup) => [setup, cmd.as_os_str()].join(OsStr::new("")),
+                None => cmd.clone(),
+            };
+            // `$0` is `bash`, as in nix-shell.
+            let mut cmd_args = vec!["-c".into(), cmd, "bash".into()];
+            cmd_args.extend(run_args.iter().cloned());
+            ("bash".into(), cmd_args, Vec::new())
+        }
+        args::RunMode::Exec(cmd, cmd_args) => {
+            (cmd.clone(), cmd_args.clone(), Vec::new())
+        }
+    }
+}
 
-    let drv_store_fname = read_link(drv_fname).ok()?;
-    std::fs::metadata(drv_store_fname).ok()?;
+/// Bash builtins and keywords, which need bash even as simple commands.
+const BASH_BUILTINS: &[&str] = &[
+    "!",
+    ".",
+    ":",
+    "[",
+    "[[",
+    "alias",
+    "bg",
+    "bind",
+    "break",
+    "builtin",
+    "caller",
+    "case",
+    "cd",
+    "command",
+    "compgen",
+    "complete",
+    "compopt",
+    "continue",
+    "coproc",
+    "declare",
+    "dirs",
+    "disown",
+    "echo",
+    "enable",
+    "eval",
+    "exec",
+    "exit",
+    "export",
+    "false",
+    "fc",
+    "fg",
+    "for",
+    "function",
+    "getopts",
+    "hash",
+    "help",
+    "history",
+    "if",
+    "jobs",
+    "kill",
+    "let",
+    "local",
+    "logout",
+    "mapfile",
+    "popd",
+    "printf",
+    "pushd",
+    "pwd",
+    "read",
+    "readarray",
+    "readonly",
+    "return",
+    "select",
+    "set",
+    "shift",
+    "shopt",
+    "source",
+    "suspend",
+    "test",
+    "time",
+    "times",
+    "trap",
+    "true",
+    "type",
+    "typeset",
+    "ulimit",
+    "umask",
+    "unalias",
+    "unset",
+    "until",
+    "wait",
+    "while",
+    "{",
+];
 
-    let mut trace_file = File::open(trace_fname).unwrap();
-    let mut trace_buf = Vec::<u8>::new();
-    trace_file.read_to_end(&mut trace_buf).unwrap();
-    let trace = Trace::load(trace_buf);
-    if trace.check_for_changes() {
+/// `--reuse-env`: the program and arguments of a `--run` command that bash
+/// would just look up in `PATH` and run, so it can be run without bash.
+/// `None` if the command uses any shell syntax (quotes, variables, globs,
+/// redirections, ...), a builtin, or a function exported by the shell, or if
+/// it has positional parameters.
+fn direct_command(
+    run: &args::RunMode,
+    run_args: &[OsString],
+    env: &EnvMap,
+) -> Option<(OsString, Vec<OsString>)> {
+    let cmd = match run {
+        args::RunMode::Shell(cmd) if run_args.is_empty() => cmd.to_str()?,
+        _ => return None,
+    };
+    let plain = |c: char| c.is_ascii_alphanumeric() || "%+,-./:=@_".contains(c);
+    let words = cmd
+        .split([' ', '\t'])
+        .filter(|x| !x.is_empty())
+        .collect::<Vec<_>>();
+    let program = *words.first()?;
+    if !words.iter().all(|word| word.chars().all(plain))
+        // `VAR=value program`
+        || program.contains('=')
+        || BASH_BUILTINS.contains(&program)
+        || env.keys().any(|k| {
+            environment::function_name(k).is_some_and(|x| x == program)
+        })
+    {
         return None;
     }
-
-    Some(env)
+    let words = words.into_iter().map(OsString::from);
+    Some((program.into(), words.skip(1).collect()))
 }
 
-fn cache_write(hash: &str, ext: &str, text: &[u8]) {
-    let f = || -> Result<(), std::io::Error> {
-        let xdg_dirs =
-            xdg::BaseDirectories::with_prefix("cached-nix-shell").unwrap();
-        let fname = xdg_dirs.place_cache_file(format!("{}.{}", hash, ext))?;
-        let mut file = File::create(fname)?;
-        file.write_all(text)?;
-        Ok(())
-    };
-    match f() {
-        Ok(_) => (),
-        Err(e) => eprintln!("Warning: can't store cache: {}", e),
+/// Run the command of `--timeout`, and return the exit code to pass on:
+/// the command's own, `128 + signal` if it was killed by a signal, like in
+/// shells, or `EXIT_TIMEOUT`.  Exits if the command can't be started.
+fn run_with_timeout(cmd: &mut Command, timeout: std::time::Duration) -> i32 {
+    match timeout::run(cmd, timeout, foreground_terminal(0)) {
+        Ok(Some(status)) => status
+            .code()
+            .or_else(|| status.signal().map(|x| x + 128))
+            .unwrap_or(EXIT_ERROR),
+        Ok(None) => {
+            error!("cached-nix-shell: command timed out after {:?}", timeout);
+            EXIT_TIMEOUT
+        }
+        Err(e) => {
+            error!("cached-nix-shell: couldn't run: {:?}", e);
+            exit(EXIT_NOT_RUN);
+        }
     }
 }
 
-fn cache_symlink(hash: &str, ext: &str, target: &str) {
-    let f = || -> Result<(), std::io::Error> {
-        let xdg_dirs =
-            xdg::BaseDirectories::with_prefix("cached-nix-shell").unwrap();
-        let fname = xdg_dirs.place_cache_file(format!("{}.{}", hash, ext))?;
-        let _ = std::fs::remove_file(&fname);
-        std::os::unix::fs::symlink(target, &fname)?;
-        Ok(())
+/// Implementation of `--watch`: run the command, then wait for some dependency
+/// to change and start over.
+fn watch_loop(args: &Args, inp: &NixShellInput) -> ! {
+    if let args::RunMode::InteractiveShell = args.run {
+        unreachable!();
+    }
+    let hash = cache_hash(&cache_inputs(inp));
+    loop {
+        let plain = plain_nix_shell_args(args, inp);
+        let (env, setup) = or_fallback(plain, || cached_shell(args, inp));
+        // The command is re-run, so it never stays in an interactive shell.
+        let (cmd, cmd_args, _) = shell_command(
+            &args.run,
+            &args.run_args,
+            setup.as_deref(),
+            None,
+            false,
+        );
+        if let Some(fname) = &args.keep_env_file {
+            keep_env_file(fname, &env);
+        }
+        let mut command = Command::new(&cmd);
+        command.args(&cmd_args).env_clear().envs(&env);
+        if let Some(timeout) = args.timeout {
+            match run_with_timeout(&mut command, timeout) {
+                0 => (),
+                code => note!("cached-nix-shell: command failed: {}", code),
+            }
+        } else {
+            match command.status() {
+                Ok(status) if !status.success() => {
+                    note!("cached-nix-shell: command failed: {}", status)
+                }
+                Ok(_) => (),
+                Err(e) => {
+                    error!("cached-nix-shell: couldn't run: {:?}", e);
+                    exit(EXIT_NOT_RUN);
+                }
+            }
+        }
+
+        let trace = load_trace(&hash)
+            .ok_or_else(|| "can't load the trace".to_string())
+            .pipe(unwrap_or_errx);
+        note!("cached-nix-shell: waiting for changes");
+        watch::wait_for_changes(&trace).pipe(unwrap_or_errx);
+    }
+}
+
+/// Find a file nix-shell would use when no file is specified:
+/// `$NIX_SHELL_FILE` if set, otherwise `shell.nix` or `default.nix`.
+fn default_shell_file(
+    dir: &std::path::Path,
+    override_: Option<OsString>,
+) -> Option<PathBuf> {
+    if let Some(fname) = override_ {
+        return Some(dir.join(fname));
+    }
+    ["shell.nix", "default.nix"]
+        .iter()
+        .map(|fname| dir.join(fname))
+        .find(|path| path.exists())
+}
+
+/// Version of the cache key format, independent of the crate version, so
+/// that upgrading cached-nix-shell keeps existing entries.
+///
+/// Bump it when the same invocation must stop hitting old entries: e.g. the
+/// way `cache_inputs` serializes inputs changes, or entries written before
+/// some fix are wrong.  Adding a new `key_extra` component that is omitted
+/// by default, or changing anything outside of the key, doesn't need a bump.
+/// `test_key_golden` fails whenever keys change.
+///
+/// Version 1 is the format from before versioning, so it isn't a part of the
+/// inputs; later versions are.  Version 2 keys the nix.conf files.
+const KEY_VERSION: u32 = 2;
+
+fn cache_inputs(inp: &NixShellInput) -> Vec<u8> {
+    // Marked, so that no automatic key can be the same.
+    if let Some(key) = &inp.key_from {
+        return serialize_vecs(&[b"cache-key-from", key.as_bytes()]);
+    }
+    let env = serialize_env(&inp.env);
+    let args = serialize_args(&inp.args);
+    let mut inputs = vec![&env[..], &args[..], inp.pwd.as_bytes()];
+    // Omitted when empty, as before it existed.  It is only empty when
+    // nix-shell can't be found, though: `nix` is there otherwise.
+    let key_extra = serialize_env(&inp.key_extra);
+    if !key_extra.is_empty() {
+        inputs.push(&key_extra);
+    }
+    let version = KEY_VERSION.to_string();
+    if KEY_VERSION != 1 {
+        inputs.push(version.as_bytes());
+    }
+    serialize_vecs(&inputs)
+}
+
+/// The components of the cache key, in the order `cache_inputs` hashes
+/// them, one per line: the working directory, the arguments of nix-shell
+/// (which include the shell file or the packages), the
+/// environment variables passed to it (e.g. `NIX_PATH`), and the other
+/// inputs, e.g. the nix-shell binary and the nix configuration.  Values are
+/// quoted, so lines of two invocations can be compared with `diff`.
+fn key_components(inp: &NixShellInput) -> Vec<String> {
+    if let Some(key) = &inp.key_from {
+        return vec![format!("cache-key-from {:?}", key)];
+    }
+    let mut res = vec![format!("pwd {:?}", inp.pwd)];
+    res.extend(inp.args.iter().map(|arg| format!("arg {:?}", arg)));
+    for (prefix, vars) in [("env", &inp.env), ("extra", &inp.key_extra)] {
+        res.extend(vars.iter().map(|(k, v)| {
+            format!("{} {}={:?}", prefix, k.to_string_lossy(), v)
+        }));
+    }
+    if KEY_VERSION != 1 {
+        res.push(format!("version {}", KEY_VERSION));
+    }
+    res
+}
+
+/// Implementation of `--explain-key ARGS...`: the key of the shell, and
+/// what it is made of.
+fn explain_key(args: Vec<OsString>) -> String {
+    let (_, inp) = parse_args(args);
+    let mut res = format!("key {}\n", cache_hash(&cache_inputs(&inp)));
+    for line in key_components(&inp) {
+        res.push_str(&line);
+        res.push('\n');
+    }
+    res
+}
+
+/// Describe the shell for `--cache-info --verbose`.
+fn describe(args: &Args, pwd: &OsStr) -> String {
+    let rest = args.rest.iter().map(|x| x.to_string_lossy());
+    if args.packages {
+        format!("-p {}", rest.collect::<Vec<_>>().join(" "))
+    } else if !args.merge_files.is_empty() {
+        args.merge_files
+            .iter()
+            .map(|x| format!("--merge-file {}", x.to_string_lossy()))
+            .collect::<Vec<_>>()
+            .join(" ")
+    } else {
+        let target = args.rest.first().map_or("".as_ref(), |x| x.as_os_str());
+        PathBuf::from(pwd)
+            .join(target)
+            .clean()
+            .to_string_lossy()
+            .into()
+    }
+}
+
+/// Implementation of `--print-args`: show how a script's shebang or the
+/// command line arguments are interpreted.
+fn print_args(args: Vec<OsString>) -> Result<String, String> {
+    let args = match args.first().and_then(|x| shebang::parse_script(x)) {
+        Some(nix_shell_args) => Args::parse(nix_shell_args, true)?,
+        None => {
+            let opts = std::env::var_os("CACHED_NIX_SHELL_OPTS");
+            Args::parse(with_default_opts(opts.as_deref(), args), false)?
+        }
     };
-    match f() {
-        Ok(_) => (),
-        Err(e) => eprintln!("Warning: can't symlink to cache: {}", e),
+    Ok(format!("{:#?}\n", args))
+}
+
+/// Run `f`, falling back to a plain `nix-shell` with `nix_shell_args` (see
+/// `plain_nix_shell_args`) if it panics.  Errors reported by nix-shell
+/// itself exit before reaching here.
+fn or_fallback<T>(nix_shell_args: Vec<OsString>, f: impl FnOnce() -> T) -> T {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+        Ok(x) => x,
+        Err(_) => {
+            note!("cached-nix-shell: internal error, running nix-shell");
+            exec_nix_shell(nix_shell_args);
+        }
     }
 }
 
-fn main() {
-    let argv: Vec<OsString> = std::env::args_os().collect();
+/// Run nix-shell instead.
+fn exec_nix_shell(args: Vec<OsString>) -> ! {
+    let exec = Command::new("nix-shell").args(args).exec();
+    error!("cached-nix-shell: couldn't run nix-shell: {:?}", exec);
+    exit(EXIT_NOT_RUN);
+}
 
-    if argv.len() == 2 && argv[1] == "--version" {
-        let git_commit = match option_env!("CARGO_GIT_COMMIT") {
-            Some(x) => format!("-{}", x),
-            None => format!(""),
-        };
-        println!(
-            "cached-nix-shell: {}{}",
-            env!("CARGO_PKG_VERSION"),
-            git_commit
+/// What identifies a shell while it's captured: unlike the cache key, not the
+/// environment, since the `--pure` shell of nix-shell doesn't keep it.
+fn capture_id(inp: &NixShellInput) -> String {
+    let args = serialize_args(&inp.args);
+    cache_hash(&serialize_vecs(&[&args, inp.pwd.as_bytes()]))
+}
+
+/// The arguments of a plain nix-shell for the same shell and command, without
+/// the options of cached-nix-shell.  It runs in the current directory rather
+/// than in `inp.pwd`, so the shell file is given with its whole path.
+fn plain_nix_shell_args(args: &Args, inp: &NixShellInput) -> Vec<OsString> {
+    fn quote(word: &OsStr) -> OsString {
+        let mut res = b"'".to_vec();
+        for &b in word.as_bytes() {
+            match b {
+                b'\'' => res.extend(b"'\\''"),
+                _ => res.push(b),
+            }
+        }
+        res.push(b'\'');
+        OsString::from_vec(res)
+    }
+    let words = |prefix: &str, words: &[OsString]| {
+        let mut res = OsString::from(prefix);
+        for word in words {
+            res.push(" ");
+            res.push(quote(word));
+        }
+        res
+    };
+    // Positional parameters are set before the command, since nix-shell
+    // passes none.
+    let with_params = |cmd: &OsString| match args.run_args.is_empty() {
+        true => cmd.clone(),
+        false => [&words("set --", &args.run_args), OsStr::new("\n"), cmd]
+            .join(OsStr::new("")),
+    };
+    let run = match &args.run {
+        args::RunMode::InteractiveShell => vec![],
+        args::RunMode::Shell(cmd) => vec!["--run".into(), with_params(cmd)],
+        args::RunMode::CommandThenInteractive(cmd) => {
+            vec!["--command".into(), with_params(cmd)]
+        }
+        args::RunMode::Exec(cmd, cmd_args) => {
+            let cmd = [std::slice::from_ref(cmd), &cmd_args[..]].concat();
+            vec!["--run".into(), words("exec", &cmd)]
+        }
+    };
+    let mut res = inp.unkeyed_args.clone();
+    let mut it = inp.args.iter().peekable();
+    // `--pure` is only kept if it was asked for.
+    if !args.pure {
+        it.next_if(|x| *x == "--pure");
+    }
+    while let Some(arg) = it.next() {
+        if arg == "--run" && it.peek().is_some_and(|x| *x == KEYED_PRINT_ENV) {
+            it.next();
+            res.extend(run.iter().cloned());
+        } else if arg == "--" {
+            res.push(arg.clone());
+            if let Some(file) = &inp.shell_file {
+                it.next();
+                res.push(file.clone().into_os_string());
+            }
+        } else {
+            res.push(arg.clone());
+        }
+    }
+    res
+}
+
+/// Hash cache inputs with the algorithm from `$CACHED_NIX_SHELL_HASH_ALGO`.
+fn cache_hash(inputs: &[u8]) -> String {
+    std::env::var_os("CACHED_NIX_SHELL_HASH_ALGO")
+        .pipe(|algo| hash_inputs(algo.as_deref(), inputs))
+        .pipe(unwrap_or_errx)
+}
+
+fn cached_shell_env(args: &Args, inp: &NixShellInput) -> EnvMap {
+    cached_shell(args, inp).0
+}
+
+/// Like `cached_shell_env`, with the shell options and aliases to set up in
+/// bash (`Environment::shell_setup`).
+fn cached_shell(
+    args: &Args,
+    inp: &NixShellInput,
+) -> (EnvMap, Option<OsString>) {
+    // A `shellHook` that runs cached-nix-shell for its own shell would
+    // capture it again and again; plain nix-shell caches nothing.
+    if std::env::var_os(CAPTURING_VAR).is_some_and(|x| x == *capture_id(inp)) {
+        note!(
+            "cached-nix-shell: run while capturing this shell, running nix-shell"
         );
-        let exec = Command::new("nix-shell").arg("--version").exec();
-        eprintln!(
-            "cached-nix-shell: couldn't run nix-shell --version {:?}",
-            exec
+        exec_nix_shell(plain_nix_shell_args(args, inp));
+    }
+    let inputs = cache_inputs(inp);
+    let inputs_hash = cache_hash(&inputs);
+
+    let cache_dir = cache_dir();
+    if debug_enabled() {
+        note!(
+            "{}",
+            color::debug(&format!(
+                "cached-nix-shell: debug: cache directory {:?}",
+                cache_dir
+            ))
         );
-        exit(1);
+    }
+    // A cache shared with a group is used with many HOMEs on purpose.
+    if cache_umask().is_none() {
+        let home = std::env::var_os("HOME");
+        if let Some(msg) = check_home(&cache_dir, home.as_deref()) {
+            warning!("{}", msg);
+        }
     }
 
-    if argv.len() >= 2 {
-        let fname = &argv[1];
-        if let Some(nix_shell_args) = shebang::parse_script(&fname) {
-            run_script(
-                fname.clone(),
-                nix_shell_args,
-                std::env::args_os().skip(1).collect(),
+    let lookup_start = std::time::Instant::now();
+    let mut hit = {
+        let _lock = lock::shared(&cache_dir, &inputs_hash);
+        check_cache(&cache_dirs(), &inputs_hash, args.fast_validate)
+    };
+    if args.timings {
+        eprint!("{}", timings::lookup(lookup_start.elapsed(), hit.is_some()));
+    }
+    // Held until the entry is written.
+    let mut build_lock = None;
+    if hit.is_none() {
+        build_lock = lock::exclusive(&cache_dir, &inputs_hash);
+        if debug_enabled() && build_lock.as_ref().is_some_and(|x| x.waited) {
+            note!(
+                "{}",
+                color::debug("cached-nix-shell: debug: waited for another run")
             );
         }
+        // Another run may have built the entry since the check, whether this
+        // one waited for the lock or got it between two such runs.
+        hit = check_cache(&cache_dirs(), &inputs_hash, args.fast_validate);
+    }
+    let env = if let Some((env, dir)) = hit {
+        stats::record(stats::Record::Hit);
+        // Best effort: a read-only cache is still usable.  The system
+        // cache is never written to.
+        if dir == cache_dir {
+            let _ = gc::touch(&cache_dir, &inputs_hash);
+        }
+        if args.audit {
+            audit_cache_hit(args, inp, &inputs_hash, &env);
+        }
+        env
+    } else {
+        note!("cached-nix-shell: updating cache");
+        let start = std::time::Instant::now();
+        let outp = run_nix_shell(inp, args.trace_to.as_deref());
+        stats::record(stats::Record::Miss(start.elapsed()));
+        if args.timings {
+            let traced = tracing_enabled().then_some(&outp.trace);
+            eprint!("{}", timings::build(start.elapsed(), traced));
+        }
+        // nix-shell enters such a shell, so it is still used, just once.
+        let hook_failed = outp.hook_status.filter(|&x| x != 0);
+        if let Some(status) = hook_failed.filter(|_| !args.cache_failed_hook) {
+            warning!(
+                "the shellHook failed with status {}, not caching the shell \
+                 (use --cache-failed-hook to cache it anyway)",
+                status
+            );
+        } else {
+            if let Some(command) = hook::verify_from_env() {
+                let env = outp.env.to_vars();
+                if let Err(e) = hook::verify(&command, &env, inp.pwd.as_ref()) {
+                    error!("cached-nix-shell: {}; not caching the shell", e);
+                    exit(EXIT_BUILD);
+                }
+            }
+            store_shell(args, inp, &inputs_hash, &inputs, &outp);
+        }
+
+        outp.env
+    };
+    std::mem::drop(build_lock);
+    // Inherited by the command.
+    if let Some(mask) = env.umask {
+        nix::sys::stat::umask(nix::sys::stat::Mode::from_bits_truncate(mask));
+    }
+    if let Some(nofile) = env.nofile.filter(|_| args.restore_limits) {
+        if let Err(e) = set_nofile_limit(nofile) {
+            warning!("{}", e);
+        }
+    }
+    let setup = env.shell_setup();
+    let mut env = env.to_vars();
+
+    env.insert(OsString::from("IN_CACHED_NIX_SHELL"), OsString::from("1"));
+
+    let mut env = combine_with_caller(args, env, std::env::vars_os().collect());
+
+    // Env files override variables set by the shell.
+    for fname in &args.env_files {
+        env.extend(dotenv::load(fname).pipe(unwrap_or_errx));
+    }
+
+    remove_internal_vars(&mut env, env!("CARGO_TRACE_NIX_SO"));
+    let env = match hook::from_env() {
+        Some(hook) => hook::run(&hook, env).pipe(unwrap_or_errx),
+        None => env,
+    };
+    (env, setup)
+}
+
+/// Write the cache entry of a shell that was just built.
+fn store_shell(
+    args: &Args,
+    inp: &NixShellInput,
+    inputs_hash: &str,
+    inputs: &[u8],
+    outp: &NixShellOutput,
+) {
+    // The env file is written last: entries without it are ignored, so
+    // an interrupted write never leaves a mix of old and new files.
+    cache_remove(inputs_hash, entry::ENV);
+    cache_write(inputs_hash, entry::INPUTS, inputs);
+    // The trace is replaced rather than merged with the old one, so files
+    // that are no longer read (e.g. deleted ones) don't keep
+    // invalidating the entry.
+    cache_write(inputs_hash, entry::TRACE, &outp.trace.serialize());
+    cache_symlink(inputs_hash, entry::DRV, &outp.drv);
+    // Everything was just built or used by nix, so it's valid.
+    let dir = cache_dir();
+    if let Some(stamp) = validity_stamp(&dir, inputs_hash) {
+        cache_write(inputs_hash, entry::VALID, &stamp);
+    }
+    if !args.no_gc_root {
+        cache_gc_root(inputs_hash);
+    }
+    let meta = meta::Meta::new(describe(args, &inp.pwd));
+    cache_write(inputs_hash, entry::META, &meta.serialize());
+    cache_write_with(inputs_hash, entry::ENV, |w| outp.env.write(w));
+}
+
+/// Combine the cached environment with the caller's one according to
+/// `--pure`, `--impure` or `--clean-env`.
+fn combine_with_caller(args: &Args, env: EnvMap, caller_env: EnvMap) -> EnvMap {
+    let always_keep = caller_env
+        .get(OsStr::new("CACHED_NIX_SHELL_ALWAYS_KEEP"))
+        .cloned();
+    let mut env = if args.clean_env {
+        clean_env(env, kept_vars(args, always_keep, &caller_env), &caller_env)
+    } else if !args.pure {
+        merge_env(env, caller_env)
+    } else {
+        let env = if args.keep_path {
+            keep_caller_path(env, caller_env.get(OsStr::new("PATH")).cloned())
+        } else {
+            env
+        };
+        let keep = kept_vars(args, always_keep, &caller_env);
+        let env = keep_caller_vars(env, &keep, &caller_env);
+        if caller_env
+            .get(OsStr::new("CACHED_NIX_SHELL_WARN_PURE"))
+            .is_some_and(|x| x == "1")
+        {
+            let dropped = dropped_vars(&env, &caller_env);
+            if !dropped.is_empty() {
+                warning!(
+                    "--pure drops {} from your environment, \
+                     use --keep to keep them",
+                    dropped.join(", ")
+                );
+            }
+        }
+        env
+    };
+    // The captured value is always `pure`, since the environment is captured
+    // with `nix-shell --pure`, so set it for the current mode.
+    let in_nix_shell = if args.pure || args.clean_env {
+        "pure"
+    } else {
+        "impure"
+    };
+    env.insert(OsString::from("IN_NIX_SHELL"), OsString::from(in_nix_shell));
+    env
+}
+
+fn audit_cache_hit(
+    args: &Args,
+    inp: &NixShellInput,
+    hash: &str,
+    env: &Environment,
+) {
+    let cached_trace = match load_trace(hash) {
+        Some(trace) => trace,
+        None => return,
+    };
+    note!("cached-nix-shell: audit: building the shell again");
+    let outp = run_nix_shell(inp, args.trace_to.as_deref());
+    audit::print(&audit::compare(env, &cached_trace, &outp.env, &outp.trace));
+}
+
+/// Remove variables used to trace nix-shell, so nested cached-nix-shell
+/// invocations start clean.
+fn remove_internal_vars(env: &mut EnvMap, trace_nix_so: &str) {
+    env.remove(OsStr::new("TRACE_NIX"));
+    if let Some(preload) = env.get_mut(OsStr::new("LD_PRELOAD")) {
+        // ld.so accepts both spaces and colons as separators
+        let libs = preload
+            .as_bytes()
+            .split(|&b| b == b' ' || b == b':')
+            .filter(|lib| !lib.is_empty() && *lib != trace_nix_so.as_bytes())
+            .collect::<Vec<_>>()
+            .join(&b' ');
+        *preload = OsString::from_vec(libs);
+    }
+    if env
+        .get(OsStr::new("LD_PRELOAD"))
+        .is_some_and(|x| x.is_empty())
+    {
+        env.remove(OsStr::new("LD_PRELOAD"));
+    }
+}
+
+/// Variables to keep in pure shells: `--keep` flags and the colon-separated
+/// list from `$CACHED_NIX_SHELL_ALWAYS_KEEP`.  Names with `*` are patterns,
+/// e.g. `AWS_*`, which are replaced by the variables of `caller_env` that
+/// match them.
+fn kept_vars(
+    args: &Args,
+    always_keep: Option<OsString>,
+    caller_env: &EnvMap,
+) -> BTreeSet<OsString> {
+    let mut names = args.keep.clone();
+    if let Some(always_keep) = always_keep {
+        names.extend(
+            always_keep
+                .as_bytes()
+                .split(|&b| b == b':')
+                .filter(|name| !name.is_empty())
+                .map(|name| OsStr::from_bytes(name).to_owned()),
+        );
+    }
+    let mut keep = BTreeSet::new();
+    for name in names {
+        if name.as_bytes().contains(&b'*') {
+            keep.extend(
+                caller_env
+                    .keys()
+                    .filter(|var| glob_match(name.as_bytes(), var.as_bytes()))
+                    .cloned(),
+            );
+        } else {
+            keep.insert(name);
+        }
+    }
+    keep
+}
+
+/// Whether `name` matches `pattern`, where `*` matches any sequence of bytes.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.iter().position(|&b| b == b'*') {
+        None => pattern == name,
+        Some(star) => {
+            let (prefix, rest) = (&pattern[..star], &pattern[star + 1..]);
+            name.starts_with(prefix)
+                && (prefix.len()..=name.len())
+                    .any(|i| glob_match(rest, &name[i..]))
+        }
+    }
+}
+
+/// Copy the kept variables from the caller's environment, unless the shell
+/// sets them itself.
+fn keep_caller_vars(
+    mut env: EnvMap,
+    keep: &BTreeSet<OsString>,
+    caller_env: &EnvMap,
+) -> EnvMap {
+    for name in keep {
+        if let Some(val) = caller_env.get(name) {
+            env.entry(name.clone()).or_insert_with(|| val.clone());
+        }
+    }
+    env
+}
+
+/// Variables that tools often need, checked by `CACHED_NIX_SHELL_WARN_PURE`.
+const PURE_ADVISORY_VARS: &[&str] = &[
+    "LANG",
+    "LC_ALL",
+    "NIX_SSL_CERT_FILE",
+    "SSH_AUTH_SOCK",
+    "SSL_CERT_FILE",
+    "TERM",
+];
+
+/// The `PURE_ADVISORY_VARS` that are set by the caller, but not in `env`.
+fn dropped_vars(env: &EnvMap, caller_env: &EnvMap) -> Vec<&'static str> {
+    PURE_ADVISORY_VARS
+        .iter()
+        .copied()
+        .filter(|&name| {
+            caller_env.contains_key(OsStr::new(name))
+                && !env.contains_key(OsStr::new(name))
+        })
+        .collect()
+}
+
+/// Variables of the caller kept by `--clean-env`, besides `--keep` ones.
+const CLEAN_ENV_BASE: &[&str] = &["HOME", "TERM"];
+
+/// `--clean-env`: like `env -i`, only the shell environment and a minimal
+/// base are left.  `PATH` is the one of the shell, which has coreutils.
+fn clean_env(
+    env: EnvMap,
+    mut keep: BTreeSet<OsString>,
+    caller_env: &EnvMap,
+) -> EnvMap {
+    keep.extend(CLEAN_ENV_BASE.iter().map(OsString::from));
+    keep_caller_vars(env, &keep, caller_env)
+}
+
+/// Append the caller's PATH after the shell's one (`--pure --keep-path`).
+fn keep_caller_path(mut env: EnvMap, caller_path: Option<OsString>) -> EnvMap {
+    if let Some(caller_path) = caller_path {
+        env.entry(OsString::from("PATH"))
+            .and_modify(|path| {
+                path.push(":");
+                path.push(&caller_path);
+            })
+            .or_insert(caller_path);
+    }
+    env
+}
+
+/// Hash cache inputs using the given algorithm (sha1 by default).
+/// Different algorithms produce different keys for the same inputs.
+fn hash_inputs(algo: Option<&OsStr>, inputs: &[u8]) -> Result<String, String> {
+    fn hash<D: Digest>(mut hasher: D, inputs: &[u8]) -> String {
+        hasher.input(inputs);
+        hasher.result_str()
+    }
+    match algo.map(|x| x.to_str()) {
+        None | Some(Some("sha1")) => Ok(hash(Sha1::new(), inputs)),
+        Some(Some("sha256")) => Ok(hash(Sha256::new(), inputs)),
+        Some(Some("blake2b")) => Ok(hash(Blake2b::new(32), inputs)),
+        Some(_) => Err(format!(
+            "unsupported CACHED_NIX_SHELL_HASH_ALGO {:?}, expected sha1, sha256 or blake2b",
+            algo.unwrap()
+        )),
+    }
+}
+
+// Merge ambient (impure) environment into cached env.
+fn merge_env(mut env: EnvMap, ambient: EnvMap) -> EnvMap {
+    let mut delim = EnvMap::new();
+    delim.insert(OsString::from("PATH"), OsString::from(":"));
+    delim.insert(OsString::from("HOST_PATH"), OsString::from(":"));
+
+    // Set to "/no-cert-file.crt" by setup.sh for pure envs.
+    env.remove(OsStr::new("SSL_CERT_FILE"));
+    env.remove(OsStr::new("NIX_SSL_CERT_FILE"));
+
+    env.insert(OsString::from("IN_NIX_SHELL"), OsString::from("impure"));
+
+    if debug_enabled() {
+        if let (Some(shell_path), Some(caller_path)) =
+            (env.get(OsStr::new("PATH")), ambient.get(OsStr::new("PATH")))
+        {
+            note_shadowed_binaries(shell_path, caller_path);
+        }
+    }
+
+    // Shell-provided PATH entries go first, as in nix-shell.
+    for (var, val) in ambient {
+        env.entry(var.clone())
+            .and_modify(|old_val| {
+                if let Some(d) = delim.get(&var) {
+                    *old_val = OsString::from(OsStr::from_bytes(
+                        &[
+                            old_val.as_os_str().as_bytes(),
+                            d.as_os_str().as_bytes(),
+                            val.as_os_str().as_bytes(),
+                        ]
+                        .concat(),
+                    ));
+                }
+            })
+            .or_insert_with(|| val);
+    }
+
+    env
+}
+
+/// Report binaries that would be shadowed if the caller's PATH came first.
+fn note_shadowed_binaries(shell_path: &OsStr, caller_path: &OsStr) {
+    let shell_dirs = std::env::split_paths(shell_path).collect::<Vec<_>>();
+    let list_dir = |dir: &PathBuf| {
+        std::fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| Some(entry.ok()?.file_name()))
+            .collect::<Vec<_>>()
+    };
+    let shell_bins = shell_dirs
+        .iter()
+        .flat_map(list_dir)
+        .collect::<HashSet<OsString>>();
+    for dir in std::env::split_paths(caller_path) {
+        if shell_dirs.contains(&dir) {
+            continue;
+        }
+        for bin in list_dir(&dir) {
+            if shell_bins.contains(&bin) {
+                note!(
+                    "{}",
+                    color::debug(&format!(
+                        "cached-nix-shell: debug: {:?} from caller's PATH is shadowed by the shell",
+                        dir.join(bin),
+                    ))
+                );
+            }
+        }
+    }
+}
+
+fn debug_enabled() -> bool {
+    !quiet()
+        && std::env::var_os("CACHED_NIX_SHELL_DEBUG")
+            .is_some_and(|x| !x.is_empty())
+}
+
+/// Notice when the cache in `dir` is used with another `HOME` than the one
+/// it was made with, or by another user (e.g. `sudo` without `-H`): the
+/// other `HOME` has its own cache, so entries get rebuilt there, and entries
+/// here may contain paths of the other `HOME`.  The first `HOME` is recorded
+/// in `dir/home`; removing that file accepts the current one.
+fn check_home(dir: &Path, home: Option<&OsStr>) -> Option<String> {
+    use std::os::unix::fs::MetadataExt;
+    if let Ok(meta) = std::fs::metadata(dir) {
+        if meta.uid() != nix::unistd::getuid().as_raw() {
+            return Some(format!(
+                "the cache in {:?} belongs to another user, check HOME",
+                dir
+            ));
+        }
+    }
+    let home = home?;
+    let fname = dir.join("home");
+    match std::fs::read(&fname) {
+        Ok(recorded) if recorded == home.as_bytes() => None,
+        Ok(recorded) => Some(format!(
+            "HOME is {:?}, but the cache in {:?} was made with HOME={:?}; remove {:?} if that is intended",
+            home,
+            dir,
+            OsStr::from_bytes(&recorded),
+            fname
+        )),
+        Err(_) => {
+            // Best effort, like the rest of the cache.
+            let _ = std::fs::create_dir_all(dir)
+                .and_then(|()| write_atomic(&fname, home.as_bytes()));
+            None
+        }
+    }
+}
+
+/// The user cache, where entries are written.
+fn cache_dir() -> PathBuf {
+    xdg::BaseDirectories::with_prefix("cached-nix-shell")
+        .unwrap()
+        .get_cache_home()
+}
+
+/// Directories to look entries up in: the user cache, then the read-only
+/// system cache of `$CACHED_NIX_SHELL_SYSTEM_CACHE`, if any.  Entries are
+/// only ever written to the user cache.
+fn cache_dirs() -> Vec<PathBuf> {
+    let user = cache_dir();
+    let system = std::env::var_os("CACHED_NIX_SHELL_SYSTEM_CACHE")
+        .filter(|x| !x.is_empty())
+        .map(PathBuf::from);
+    std::iter::once(user).chain(system).collect()
+}
+
+/// The environment of the first up-to-date entry in `dirs`, and the
+/// directory it was found in.
+fn check_cache(
+    dirs: &[PathBuf],
+    hash: &str,
+    fast: bool,
+) -> Option<(Environment, PathBuf)> {
+    dirs.iter()
+        .find_map(|dir| Some((check_cache_in(dir, hash, fast)?, dir.clone())))
+}
+
+fn check_cache_in(dir: &Path, hash: &str, fast: bool) -> Option<Environment> {
+    let env = File::open(entry::path(dir, hash, entry::ENV))
+        .map(BufReader::new)
+        .and_then(Environment::read)
+        .ok()?;
+    let drv_fname = entry::path(dir, hash, entry::DRV);
+
+    let drv_store_fname = read_link(drv_fname).ok()?;
+    std::fs::metadata(&drv_store_fname).ok()?;
+
+    let trace = load_trace_in(dir, hash)?;
+    let changed = if fast {
+        trace.check_for_changes_fast()
+    } else {
+        trace.check_for_changes()
+    };
+    if changed {
+        return None;
+    }
+
+    // Unless the store is trusted, paths that exist but that nix doesn't
+    // know about (e.g. copied by hand from another machine, or left behind
+    // by an interrupted copy) rebuild the shell too.  That's checked once
+    // per trace and derivation, so usual hits don't run nix-store.
+    let stamp_fname = entry::path(dir, hash, entry::VALID);
+    let stamp = validity_stamp(dir, hash).filter(|x| {
+        !fast && std::fs::read(&stamp_fname).ok().as_ref() != Some(x)
+    });
+    if let Some(stamp) = stamp {
+        // Only paths that exist: the environment refers to the output of the
+        // shell derivation, which is never built, and the trace records
+        // files that nix found missing.
+        let mut paths = BTreeSet::new();
+        paths.insert(drv_store_fname.as_os_str().as_bytes());
+        for path in trace.paths() {
+            paths.extend(trace::store_paths(path.as_bytes()));
+        }
+        paths.retain(|x| {
+            std::fs::symlink_metadata(OsStr::from_bytes(x)).is_ok()
+        });
+        let invalid = invalid_store_paths("nix-store", &paths);
+        if let Some(path) = invalid.first() {
+            note!(
+                "cached-nix-shell: {:?} is not valid in the nix store, cache won't be reused",
+                path
+            );
+            return None;
+        }
+        // Best effort: the system cache is read-only.
+        let _ = write_atomic(&stamp_fname, &stamp);
+    }
+
+    Some(env)
+}
+
+/// What the validity of the store paths of an entry depends on: the
+/// identity of its trace file (replaced on every rebuild, or import) and
+/// its derivation.
+fn validity_stamp(dir: &Path, hash: &str) -> Option<Vec<u8>> {
+    use std::os::unix::fs::MetadataExt;
+    let trace = std::fs::metadata(entry::path(dir, hash, entry::TRACE)).ok()?;
+    let drv = read_link(entry::path(dir, hash, entry::DRV)).ok()?;
+    let mut res =
+        format!("{} {}.{} ", trace.ino(), trace.mtime(), trace.mtime_nsec())
+            .into_bytes();
+    res.extend(drv.as_os_str().as_bytes());
+    Some(res)
+}
+
+/// Store paths among `paths` that aren't valid in the local store, as told
+/// by `nix-store --check-validity`.  Other paths are skipped, and nothing
+/// is invalid if it can't be checked.
+fn invalid_store_paths(
+    nix_store: &str,
+    paths: &BTreeSet<&[u8]>,
+) -> Vec<OsString> {
+    let paths = paths
+        .iter()
+        .filter(|x| trace::store_paths(x).next() == Some(**x))
+        .map(|x| OsStr::from_bytes(x))
+        .collect::<Vec<_>>();
+    if paths.is_empty() {
+        return Vec::new();
+    }
+    let output = Command::new(nix_store)
+        .args(["--check-validity", "--print-invalid"])
+        .args(&paths)
+        .stdin(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .output();
+    match output {
+        Ok(output) if output.status.success() => output
+            .stdout
+            .split(|&b| b == b'\n')
+            .filter(|x| !x.is_empty())
+            .map(|x| OsString::from_vec(x.to_vec()))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// The trace of the entry in the first of `cache_dirs` that has it.
+fn load_trace(hash: &str) -> Option<Trace> {
+    cache_dirs()
+        .iter()
+        .filter(|dir| entry::path(dir, hash, entry::TRACE).exists())
+        .find_map(|dir| load_trace_in(dir, hash))
+}
+
+/// A corrupt trace (e.g. truncated when the disk was full) is treated as a
+/// missing one, so the entry is rebuilt.
+fn load_trace_in(dir: &Path, hash: &str) -> Option<Trace> {
+    let trace_fname = entry::path(dir, hash, entry::TRACE);
+    let res = File::open(&trace_fname)
+        .map_err(|e| e.to_string())
+        .map(BufReader::new)
+        .and_then(Trace::load);
+    match res {
+        Ok(trace) => Some(trace),
+        Err(e) => {
+            note!("cached-nix-shell: ignoring {:?}: {}", trace_fname, e);
+            None
+        }
+    }
+}
+
+/// `$CACHED_NIX_SHELL_CACHE_UMASK` (octal), to share the cache directory
+/// with a group.  Cache files are private by default.
+fn cache_umask() -> Option<u32> {
+    let umask = std::env::var("CACHED_NIX_SHELL_CACHE_UMASK").ok()?;
+    match u32::from_str_radix(&umask, 8) {
+        Ok(umask) => Some(umask & 0o777),
+        Err(_) => {
+            warning!("invalid CACHED_NIX_SHELL_CACHE_UMASK {:?}", umask);
+            None
+        }
+    }
+}
+
+/// Write into a temporary file first, so readers never see a partially
+/// written file.  Permissions are set before it's renamed into place.
+fn write_atomic(fname: &std::path::Path, text: &[u8]) -> std::io::Result<()> {
+    write_atomic_with(fname, |w| w.write_all(text))
+}
+
+/// Like `write_atomic`, with the contents written by `write`.
+fn write_atomic_with(
+    fname: &Path,
+    write: impl FnOnce(&mut dyn Write) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let mut file = NamedTempFile::new_in(fname.parent().unwrap())?;
+    {
+        let mut w = BufWriter::new(file.as_file_mut());
+        write(&mut w)?;
+        w.flush()?;
+    }
+    // Otherwise a crash soon after the rename may leave an empty file.
+    file.as_file().sync_all()?;
+    if let Some(umask) = cache_umask() {
+        file.as_file()
+            .set_permissions(std::fs::Permissions::from_mode(0o666 & !umask))?;
+    }
+    file.persist(fname)?;
+    Ok(())
+}
+
+fn cache_write(hash: &str, ext: &str, text: &[u8]) {
+    cache_write_with(hash, ext, |w| w.write_all(text))
+}
+
+/// Like `cache_write`, with the contents written by `write`.
+fn cache_write_with(
+    hash: &str,
+    ext: &str,
+    write: impl FnOnce(&mut dyn Write) -> std::io::Result<()>,
+) {
+    let f = || -> Result<(), std::io::Error> {
+        let xdg_dirs =
+            xdg::BaseDirectories::with_prefix("cached-nix-shell").unwrap();
+        let fname = xdg_dirs.place_cache_file(entry::name(hash, ext))?;
+        let dir = fname.parent().unwrap();
+        if let Some(umask) = cache_umask() {
+            // Best effort: the directory may be owned by another user.
+            let mode = std::fs::Permissions::from_mode(0o777 & !umask);
+            let _ = std::fs::set_permissions(dir, mode);
+        }
+        if ext == entry::ENV && dedup::enabled() {
+            return dedup::store(dir, &entry::name(hash, ext), write);
+        }
+        write_atomic_with(&fname, write)
+    };
+    match f() {
+        Ok(_) => (),
+        Err(e) => warning!("can't store cache: {}", e),
+    }
+}
+
+fn cache_remove(hash: &str, ext: &str) {
+    let fname = xdg::BaseDirectories::with_prefix("cached-nix-shell")
+        .unwrap()
+        .find_cache_file(entry::name(hash, ext));
+    if let Some(fname) = fname {
+        if let Err(e) = std::fs::remove_file(fname) {
+            warning!("can't remove old cache entry: {}", e);
+        }
+    }
+}
+
+fn cache_symlink(hash: &str, ext: &str, target: &str) {
+    let f = || -> Result<(), std::io::Error> {
+        let xdg_dirs =
+            xdg::BaseDirectories::with_prefix("cached-nix-shell").unwrap();
+        let fname = xdg_dirs.place_cache_file(entry::name(hash, ext))?;
+        let _ = std::fs::remove_file(&fname);
+        std::os::unix::fs::symlink(target, &fname)?;
+        Ok(())
+    };
+    match f() {
+        Ok(_) => (),
+        Err(e) => warning!("can't symlink to cache: {}", e),
+    }
+}
+
+fn gc_roots_dir() -> Result<PathBuf, std::io::Error> {
+    let user = std::env::var_os("USER").ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "$USER is unset")
+    })?;
+    Ok(PathBuf::from("/nix/var/nix/gcroots/per-user").join(user))
+}
+
+fn gc_root_path(hash: &str) -> Result<PathBuf, std::io::Error> {
+    Ok(gc::root_path(&gc_roots_dir()?, hash))
+}
+
+/// Register a cache symlink as an indirect gc root.  Failing to do so is not
+/// fatal: if the store path gets collected, `check_cache` will notice it.
+fn cache_gc_root(hash: &str) {
+    let f = || -> Result<(), std::io::Error> {
+        let xdg_dirs =
+            xdg::BaseDirectories::with_prefix("cached-nix-shell").unwrap();
+        let target =
+            xdg_dirs.place_cache_file(entry::name(hash, entry::DRV))?;
+        let fname = gc_root_path(hash)?;
+        let _ = std::fs::remove_file(&fname);
+        std::os::unix::fs::symlink(target, &fname)?;
+        Ok(())
+    };
+    match f() {
+        Ok(_) => (),
+        Err(e) => warning!("can't create gc root: {}", e),
+    }
+}
+
+/// With `dry_run`, list the entries that would be removed instead.
+fn gc_cache(since: Option<std::time::Duration>, dry_run: bool) {
+    let dir = cache_dir();
+    if dry_run {
+        let selected = gc::select(&dir, since, std::time::SystemTime::now());
+        for hash in &selected {
+            println!("{}", meta::describe(&dir, hash));
+        }
+        note!(
+            "cached-nix-shell: would remove {} cache entries",
+            selected.len()
+        );
+        return;
+    }
+    let removed = gc::collect(&dir, since, std::time::SystemTime::now())
+        .pipe(unwrap_or_errx);
+    forget_entries(&dir, &removed);
+    note!("cached-nix-shell: removed {} cache entries", removed.len());
+}
+
+fn invalidate_path(path: &OsStr) {
+    let dir = cache_dir();
+    let removed =
+        gc::invalidate_path(&dir, path.as_bytes()).pipe(unwrap_or_errx);
+    forget_entries(&dir, &removed);
+    note!(
+        "cached-nix-shell: removed {} cache entries referencing {:?}",
+        removed.len(),
+        path
+    );
+}
+
+/// The commit checked out in the git repository containing `dir`, if any.
+fn git_head(dir: &Path) -> Option<OsString> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["rev-parse", "--verify", "-q", "HEAD"])
+        .stderr(std::process::Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    let head = output.stdout.trim_ascii_end();
+    (!head.is_empty()).then(|| OsStr::from_bytes(head).into())
+}
+
+/// The path printed by `git rev-parse ARGS` in the current directory.
+fn git_rev_parse(args: &[&str]) -> Result<PathBuf, String> {
+    let output = Command::new("git")
+        .arg("rev-parse")
+        .args(args)
+        .stderr(std::process::Stdio::null())
+        .output()
+        .map_err(|e| format!("can't run git: {}", e))?;
+    if !output.status.success() {
+        return Err("not inside a git repository".to_string());
+    }
+    let mut path = output.stdout;
+    if path.last() == Some(&b'\n') {
+        path.pop();
+    }
+    let cwd = current_dir().map_err(|e| format!("can't get cwd: {}", e))?;
+    Ok(cwd.join(OsString::from_vec(path)).clean())
+}
+
+/// Implementation of `--install-hook [FILE]`.  FILE defaults to the one
+/// nix-shell would pick at the top of the work tree.
+fn install_hook(fname: Option<&OsString>) -> Result<(), String> {
+    let top = git_rev_parse(&["--show-toplevel"])?;
+    // Respects `core.hooksPath` and worktrees.
+    let hooks_dir = git_rev_parse(&["--git-path", "hooks"])?;
+    let fname = match fname {
+        Some(fname) => current_dir()
+            .map_err(|e| format!("can't get cwd: {}", e))?
+            .join(fname)
+            .clean(),
+        None => default_shell_file(&top, std::env::var_os("NIX_SHELL_FILE"))
+            .ok_or_else(|| {
+                format!("no shell.nix or default.nix in {:?}", top)
+            })?,
+    };
+    // Hooks run from the top of the work tree.
+    let fname = match fname.strip_prefix(&top) {
+        Ok(rel) => Path::new(".").join(rel),
+        Err(_) => fname,
+    };
+    let fname = fname
+        .to_str()
+        .ok_or_else(|| format!("unsupported file name {:?}", fname))?;
+    for name in githook::HOOKS {
+        let action = githook::install(&hooks_dir, name, fname)?;
+        let path = hooks_dir.join(name);
+        match action {
+            githook::Action::Created => {
+                note!("cached-nix-shell: created {:?}", path)
+            }
+            githook::Action::Appended => {
+                note!("cached-nix-shell: added to {:?}", path)
+            }
+            githook::Action::AlreadyInstalled => {
+                note!("cached-nix-shell: already in {:?}", path)
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Clean up after removing cache entries: their gc roots, and the blobs
+/// that were only used by them.
+fn forget_entries(dir: &Path, removed: &[String]) {
+    let roots = gc_roots_dir()
+        .map_err(|e| format!("can't find gc roots: {}", e))
+        .and_then(|roots| gc::remove_roots(&roots, removed));
+    if let Err(e) = roots {
+        warning!("{}", e);
+    }
+    if let Err(e) = dedup::remove_unused(dir) {
+        warning!("can't remove unused blobs: {}", e);
+    }
+}
+
+fn main() {
+    let argv: Vec<OsString> = std::env::args_os().collect();
+    // Before any shell changes them.
+    caller_limits();
+
+    if argv.len() == 2 && argv[1] == "--version" {
+        let git_commit = match option_env!("CARGO_GIT_COMMIT") {
+            Some(x) => format!("-{}", x),
+            None => String::new(),
+        };
+        println!(
+            "cached-nix-shell: {}{}",
+            env!("CARGO_PKG_VERSION"),
+            git_commit
+        );
+        let exec = Command::new("nix-shell").arg("--version").exec();
+        error!(
+            "cached-nix-shell: couldn't run nix-shell --version {:?}",
+            exec
+        );
+        exit(EXIT_NOT_RUN);
+    }
+
+    if argv.get(1).is_some_and(|x| x == "--print-args") {
+        print!("{}", print_args(argv[2..].to_vec()).pipe(unwrap_or_errx));
+        exit(0);
+    }
+
+    // Empty if not even argv[0] was given.
+    let args = argv.get(1..).unwrap_or_default();
+    if args == ["--cache-info"] || args == ["--cache-info", "--verbose"] {
+        let dir = cache_dir();
+        print!("{}", meta::list(&dir, argv.len() == 3));
+        exit(0);
+    }
+
+    if argv
+        .get(1)
+        .is_some_and(|x| x.as_bytes().starts_with(b"--dump-env"))
+    {
+        let format = match argv[1].as_bytes() {
+            b"--dump-env" => "json",
+            arg => arg
+                .strip_prefix(b"--dump-env=")
+                .and_then(|x| std::str::from_utf8(x).ok())
+                .ok_or_else(|| format!("unexpected arg {:?}", argv[1]))
+                .pipe(unwrap_or_errx),
+        };
+        dump_env(format, argv[2..].to_vec());
+        exit(0);
+    }
+
+    if argv.get(1).is_some_and(|x| x == "--print-build-command") {
+        print!("{}", print_build_command(argv[2..].to_vec()));
+        exit(0);
+    }
+
+    if argv.get(1).is_some_and(|x| x == "--explain-key") {
+        print!("{}", explain_key(argv[2..].to_vec()));
+        exit(0);
+    }
+
+    if argv.get(1).is_some_and(|x| x == "--list-deps") {
+        let verbose = argv.get(2).is_some_and(|x| x == "--verbose");
+        let args = argv[if verbose { 3 } else { 2 }..].to_vec();
+        print!("{}", list_deps(args, verbose).pipe(unwrap_or_errx));
+        exit(0);
+    }
+
+    if argv.get(1).is_some_and(|x| x == "--validate-only") {
+        // A missing entry wouldn't be reused either.
+        match validate_only(argv[2..].to_vec()) {
+            Ok((report, valid)) => {
+                print!("{}", report);
+                exit(if valid { 0 } else { 1 });
+            }
+            Err(e) => {
+                error!("cached-nix-shell: {}", e);
+                exit(1);
+            }
+        }
+    }
+
+    if argv.len() == 3 && argv[1] == "--export-cache" {
+        let dir = cache_dir();
+        archive::export(&dir, argv[2].as_ref()).pipe(unwrap_or_errx);
+        exit(0);
+    }
+
+    if argv.len() == 3 && argv[1] == "--import-cache" {
+        let dir = cache_dir();
+        for hash in archive::import(&dir, argv[2].as_ref()).pipe(unwrap_or_errx)
+        {
+            cache_gc_root(&hash);
+        }
+        exit(0);
+    }
+
+    if argv.get(1).is_some_and(|x| x == "--gc-cache") {
+        let mut gc_args = argv[2..].to_vec();
+        let dry_run = gc_args.iter().any(|x| x == "--dry-run");
+        gc_args.retain(|x| x != "--dry-run");
+        let since = match &gc_args[..] {
+            [] => None,
+            [flag, duration] if flag == "--since" => duration
+                .to_str()
+                .ok_or_else(|| format!("invalid duration {:?}", duration))
+                .and_then(gc::parse_duration)
+                .pipe(unwrap_or_errx)
+                .pipe(Some),
+            _ => {
+                error!(
+                    "cached-nix-shell: usage: --gc-cache [--since DURATION] [--dry-run]"
+                );
+                exit(EXIT_ERROR);
+            }
+        };
+        gc_cache(since, dry_run);
+        exit(0);
+    }
+
+    if argv.len() == 3 && argv[1] == "--invalidate-path" {
+        invalidate_path(&argv[2]);
+        exit(0);
+    }
+
+    if argv.len() == 2 && argv[1] == "--refresh-roots" {
+        let dir = cache_dir();
+        let (created, removed) = gc_roots_dir()
+            .map_err(|e| format!("can't find gc roots: {}", e))
+            .and_then(|roots| gc::refresh_roots(&dir, &roots))
+            .pipe(unwrap_or_errx);
+        note!(
+            "cached-nix-shell: created {} gc roots, removed {}",
+            created,
+            removed
+        );
+        exit(0);
+    }
+
+    if (argv.len() == 2 || argv.len() == 3) && argv[1] == "--install-hook" {
+        install_hook(argv.get(2)).pipe(unwrap_or_errx);
+        exit(0);
+    }
+
+    if argv.len() >= 3 && argv[1] == "--lint" {
+        exit(if lint::run(&argv[2..]) { 0 } else { 1 });
+    }
+
+    if argv.len() == 3 && argv[1] == "--bench" {
+        let count = argv[2]
+            .to_str()
+            .ok_or_else(|| format!("--bench: invalid number {:?}", argv[2]))
+            .and_then(bench::parse_count)
+            .pipe(unwrap_or_errx);
+        bench::run(count).pipe(unwrap_or_errx);
+        exit(0);
+    }
+
+    if argv.len() == 2 && argv[1] == "--verify" {
+        exit(if verify::run() { 0 } else { 1 });
+    }
+
+    if args == ["--stats"] || args == ["--stats", "--json"] {
+        let summary = stats::log_path()
+            .map_err(|e| format!("can't find stats log: {}", e))
+            .pipe(unwrap_or_errx)
+            .pipe(|path| stats::summarize(&path));
+        if argv.len() == 3 {
+            let dir = cache_dir();
+            println!("{}", summary.json(&stats::cache_size(&dir)));
+        } else {
+            summary.print();
+        }
+        exit(0);
+    }
+
+    if argv.len() == 3 && argv[1] == "--generate-completions" {
+        argv[2]
+            .to_str()
+            .ok_or_else(|| format!("unknown shell {:?}", argv[2]))
+            .and_then(completions::generate)
+            .pipe(unwrap_or_errx)
+            .pipe(|script| print!("{}", script));
+        exit(0);
+    }
+
+    if let Some(fname) = argv.get(1) {
+        if let Some(nix_shell_args) = shebang::parse_script(fname) {
+            run_script(fname.clone(), nix_shell_args, argv[1..].to_vec());
+        }
+    }
+    // `#!/path/to/cached-nix-shell -i bash -p hello` as the first line.
+    if argv.len() >= 3 {
+        if let Some(combined) = shebang::split_combined(&argv[1]) {
+            let fname = &argv[2];
+            if let Some(nix_shell_args) = shebang::parse_script(fname) {
+                run_script(
+                    fname.clone(),
+                    [combined, nix_shell_args].concat(),
+                    argv[2..].to_vec(),
+                );
+            }
+        }
+    }
+    run_from_args(std::env::args_os().skip(1).collect());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Set for a test run again by `child_test`, to a scratch directory.
+    pub(crate) const CHILD_TEST_VAR: &str = "CACHED_NIX_SHELL_CHILD_TEST";
+
+    /// Start the test `name` again in a child process, for tests that change
+    /// the state of the whole process (signal handlers, limits).
+    pub(crate) fn child_test(name: &str, dir: &Path) -> std::process::Child {
+        Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", name, "--test-threads=1", "--quiet"])
+            .env(CHILD_TEST_VAR, dir)
+            .stdout(std::process::Stdio::null())
+            .spawn()
+            .unwrap()
+    }
+
+    /// The input for `args` run from `pwd`, with an empty caller
+    /// environment.
+    fn input(pwd: impl AsRef<OsStr>, args: &[&str]) -> NixShellInput {
+        let args =
+            Args::parse(args.iter().map(OsString::from).collect(), false)
+                .unwrap();
+        args_to_inp(pwd.as_ref().into(), &args, &EnvMap::new())
+    }
+
+    fn key(pwd: impl AsRef<OsStr>, args: &[&str]) -> Vec<u8> {
+        cache_inputs(&input(pwd, args))
+    }
+
+    fn env(vars: &[(&str, &str)]) -> EnvMap {
+        vars.iter()
+            .map(|(k, v)| (OsString::from(k), OsString::from(v)))
+            .collect()
+    }
+
+    #[test]
+    fn test_flag_value() {
+        assert!(!flag_value(None));
+        assert!(!flag_value(Some("".into())));
+        assert!(!flag_value(Some("0".into())));
+        assert!(flag_value(Some("1".into())));
+        assert!(flag_value(Some("yes".into())));
+    }
+
+    #[test]
+    fn test_validate_in_corrupt_trace() {
+        let dir = tempfile::tempdir().unwrap();
+        let trace = entry::path(dir.path(), "aaa", entry::TRACE);
+        std::fs::write(&trace, "no leading NUL").unwrap();
+        assert_eq!(
+            validate_in(dir.path(), "aaa"),
+            (format!("stale   trace   {}\n", trace.display()), false)
+        );
+    }
+
+    #[test]
+    fn test_key_golden() {
+        // Nothing of the host is found: no nix-shell in the PATH, and no
+        // nix.conf.
+        let caller = env(&[
+            (
+                "NIX_PATH",
+                "nixpkgs=/nix/var/nix/profiles/per-user/root/channels/nixpkgs",
+            ),
+            ("PATH", "/nonexistent/bin"),
+            ("NIX_CONF_DIR", "/nonexistent/etc"),
+            ("NIX_USER_CONF_FILES", "/nonexistent/nix.conf"),
+        ]);
+        let inp = |extra: &[&str]| {
+            let args = [extra, &["--show-trace", "./shell.nix"]].concat();
+            let args =
+                Args::parse(args.iter().map(OsString::from).collect(), false)
+                    .unwrap();
+            args_to_inp("/home/user/project".into(), &args, &caller)
+        };
+        assert_eq!(inp(&[]).key_extra, EnvMap::new());
+        // If this fails, bump KEY_VERSION and update the values.
+        let hash = |inp: &NixShellInput| {
+            hash_inputs(None, &cache_inputs(inp)).unwrap()
+        };
+        assert_eq!(hash(&inp(&[])), "fe2caef0c0d99d64ddb3b9fb4a4ef61a3b8a27a6");
+        assert_eq!(
+            hash(&inp(&["--keep-path"])),
+            "677e252322d5fb8d42dfd2743cf4fcd283752139"
+        );
+    }
+
+    #[test]
+    fn test_key_components() {
+        let args = ["-I", "nixpkgs=/np", "-p", "hello", "--keep-path"];
+        let args =
+            Args::parse(args.iter().map(OsString::from).collect(), false)
+                .unwrap();
+        let mut inp = args_to_inp("/src".into(), &args, &EnvMap::new());
+        inp.env = env(&[("NIX_PATH", "nixpkgs=/np"), ("PATH", "/bin")]);
+        inp.key_extra
+            .insert("nix".into(), "/nix/store/x-nix/bin/nix-shell".into());
+        let lines = key_components(&inp);
+        for line in [
+            r#"pwd "/src""#,
+            r#"arg "nixpkgs=/np""#,
+            r#"arg "hello""#,
+            r#"env NIX_PATH="nixpkgs=/np""#,
+            r#"env PATH="/bin""#,
+            r#"extra keep-path="1""#,
+            r#"extra nix="/nix/store/x-nix/bin/nix-shell""#,
+        ] {
+            assert!(lines.iter().any(|x| x == line), "{} in {:?}", line, lines);
+        }
+        // Every input shows up: changing a line changes the key.
+        let key = cache_hash(&cache_inputs(&inp));
+        inp.env.insert("NIX_PATH".into(), "nixpkgs=/other".into());
+        assert_ne!(cache_hash(&cache_inputs(&inp)), key);
+        assert!(key_components(&inp)
+            .contains(&r#"env NIX_PATH="nixpkgs=/other""#.to_string()));
+
+        inp.key_from = Some("gen-1".into());
+        assert_eq!(key_components(&inp), vec![r#"cache-key-from "gen-1""#]);
+    }
+
+    #[test]
+    fn test_nix_conf_files() {
+        let vars = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(k, _)| *k == name)
+                    .map(|(_, v)| OsString::from(v))
+            }
+        };
+        assert_eq!(
+            nix_conf_files(vars(&[("HOME", "/home/u")])),
+            vec![
+                PathBuf::from("/etc/nix/nix.conf"),
+                PathBuf::from("/etc/xdg/nix/nix.conf"),
+                PathBuf::from("/home/u/.config/nix/nix.conf"),
+            ]
+        );
+        assert_eq!(
+            nix_conf_files(vars(&[
+                ("NIX_CONF_DIR", "/conf"),
+                ("XDG_CONFIG_DIRS", "/a:/b"),
+                ("XDG_CONFIG_HOME", "/c"),
+            ])),
+            vec![
+                PathBuf::from("/conf/nix.conf"),
+                PathBuf::from("/b/nix/nix.conf"),
+                PathBuf::from("/a/nix/nix.conf"),
+                PathBuf::from("/c/nix/nix.conf"),
+            ]
+        );
+        assert_eq!(
+            nix_conf_files(vars(&[
+                ("HOME", "/home/u"),
+                ("NIX_USER_CONF_FILES", "/x.conf:/y.conf"),
+            ])),
+            vec![
+                PathBuf::from("/etc/nix/nix.conf"),
+                PathBuf::from("/x.conf"),
+                PathBuf::from("/y.conf"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nix_conf_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let conf_dir = dir.path().join("etc");
+        let user_conf = dir.path().join("user.conf");
+        std::fs::create_dir(&conf_dir).unwrap();
+        let key = || {
+            nix_conf_key(|name| match name {
+                "NIX_CONF_DIR" => Some(conf_dir.clone().into_os_string()),
+                "NIX_USER_CONF_FILES" => {
+                    Some(user_conf.clone().into_os_string())
+                }
+                _ => None,
+            })
+        };
+
+        assert_eq!(key(), None);
+        std::fs::write(conf_dir.join("nix.conf"), "sandbox = true\n").unwrap();
+        let sandbox = key().unwrap();
+        std::fs::write(conf_dir.join("nix.conf"), "sandbox = false\n").unwrap();
+        let no_sandbox = key().unwrap();
+        assert_ne!(sandbox, no_sandbox);
+
+        std::fs::write(&user_conf, "system-features = kvm\n").unwrap();
+        assert_ne!(key().unwrap(), no_sandbox);
+        std::fs::remove_file(&user_conf).unwrap();
+        assert_eq!(key().unwrap(), no_sandbox);
+    }
+
+    #[test]
+    fn test_check_home() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("cache");
+        let home = |x: &str| check_home(&cache, Some(OsStr::new(x)));
+
+        assert_eq!(home("/home/user"), None);
+        assert_eq!(std::fs::read(cache.join("home")).unwrap(), b"/home/user");
+        assert_eq!(home("/home/user"), None);
+        assert_eq!(check_home(&cache, None), None);
+
+        // e.g. XDG_CACHE_HOME is set, but HOME has changed.
+        let msg = home("/root").unwrap();
+        assert!(msg.contains("\"/root\""), "{}", msg);
+        assert!(msg.contains("HOME=\"/home/user\""), "{}", msg);
+
+        std::fs::remove_file(cache.join("home")).unwrap();
+        assert_eq!(home("/root"), None);
+        assert!(home("/home/user").is_some());
+    }
+
+    #[test]
+    fn test_shell_command() {
+        let command = args::RunMode::CommandThenInteractive("setup".into());
+        let (cmd, cmd_args, rc_env) =
+            shell_command(&command, &[], None, None, true);
+        assert_eq!(cmd, "bash");
+        assert_eq!(cmd_args, vec!["--rcfile", env!("CARGO_RCFILE")]);
+        assert_eq!(
+            rc_env,
+            vec![("CACHED_NIX_SHELL_COMMAND".into(), "setup".into())]
+        );
+
+        // Without a terminal, it's the same as --run.
+        let (cmd, cmd_args, rc_env) =
+            shell_command(&command, &[], None, None, false);
+        assert_eq!(cmd, "bash");
+        assert_eq!(cmd_args, vec!["-c", "setup", "bash"]);
+        assert_eq!(rc_env, vec![]);
+
+        let run = args::RunMode::Shell("setup".into());
+        assert_eq!(
+            shell_command(&run, &[], None, None, true).1,
+            vec!["-c", "setup", "bash"]
+        );
+    }
+
+    #[test]
+    fn test_default_opts() {
+        let cli = |args: &[&str]| args.iter().map(OsString::from).collect();
+        let parse = |opts: &str, args: &[&str]| {
+            Args::parse(
+                with_default_opts(Some(opts.as_ref()), cli(args)),
+                false,
+            )
+            .unwrap()
+        };
+        let args =
+            parse(r#"--pure -I "nixpkgs=/src/my nixpkgs""#, &["-p", "git"]);
+        assert!(args.pure);
+        assert_eq!(args.other_kw, vec!["-I", "nixpkgs=/src/my nixpkgs"]);
+        assert_eq!(args.rest, vec!["git"]);
+
+        // The command line wins, or adds to the defaults.
+        let args = parse(
+            "--pure -I nixpkgs=/a --run make",
+            &["--impure", "-I", "x=/b", "--run", "test", "-p", "git"],
+        );
+        assert!(!args.pure);
+        assert_eq!(args.other_kw, vec!["-I", "nixpkgs=/a", "-I", "x=/b"]);
+        assert!(matches!(args.run, args::RunMode::Shell(ref x) if x == "test"));
+
+        assert_eq!(
+            with_default_opts(None, cli(&["-p", "git"])),
+            cli(&["-p", "git"])
+        );
+        assert_eq!(
+            with_default_opts(Some("".as_ref()), cli(&["x"])),
+            cli(&["x"])
+        );
+    }
+
+    #[test]
+    fn test_keep_env_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let fname = tmp.path().join("env");
+        let vars = env(&[("GREETING", "hello world"), ("PATH", "/bin")]);
+        keep_env_file(fname.as_os_str(), &vars);
+        assert_eq!(dotenv::load(fname.as_os_str()).unwrap(), vars);
+        let mode = std::fs::metadata(&fname).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // Only a warning.
+        keep_env_file(tmp.path().join("missing/env").as_os_str(), &vars);
+    }
+
+    #[test]
+    fn test_foreground_terminal() {
+        // Like stdin of a command run by ssh without a terminal.
+        let (read, write) = nix::unistd::pipe().unwrap();
+        assert!(!foreground_terminal(read));
+        let null = File::open("/dev/null").unwrap();
+        assert!(!foreground_terminal(std::os::unix::io::AsRawFd::as_raw_fd(
+            &null
+        )));
+        nix::unistd::close(read).unwrap();
+        nix::unistd::close(write).unwrap();
+    }
+
+    #[test]
+    fn test_shell_command_user_rcfile() {
+        let rc = Path::new("/home/user/rc.sh");
+        let shell = args::RunMode::InteractiveShell;
+        let (_, cmd_args, rc_env) =
+            shell_command(&shell, &[], None, Some(rc), true);
+        assert_eq!(cmd_args, vec!["--rcfile", env!("CARGO_RCFILE")]);
+        assert_eq!(rc_env, vec![("CACHED_NIX_SHELL_RCFILE".into(), rc.into())]);
+
+        // Non-interactive shells don't read startup files.
+        let run = args::RunMode::Shell("setup".into());
+        assert_eq!(shell_command(&run, &[], None, Some(rc), true).2, vec![]);
+    }
+
+    /// A cache entry in `dir` with an empty trace, and `env` as environment.
+    fn write_entry(dir: &Path, hash: &str, env: &str, drv: &Path) {
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(entry::path(dir, hash, entry::ENV), env).unwrap();
+        std::fs::write(entry::path(dir, hash, entry::TRACE), "").unwrap();
+        std::os::unix::fs::symlink(drv, entry::path(dir, hash, entry::DRV))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_validity_stamp() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (a, b) = (tmp.path().join("a.drv"), tmp.path().join("b.drv"));
+        std::fs::write(&a, "").unwrap();
+        std::fs::write(&b, "").unwrap();
+        let dir = tmp.path().join("cache");
+        write_entry(&dir, "aaa", "GREETING=hi\0", &a);
+        let stamp = validity_stamp(&dir, "aaa").unwrap();
+        assert_eq!(validity_stamp(&dir, "aaa"), Some(stamp.clone()));
+
+        // A hit records that it checked the paths.
+        assert!(check_cache_in(&dir, "aaa", false).is_some());
+        assert_eq!(std::fs::read(dir.join("aaa.valid")).unwrap(), stamp);
+        assert!(check_cache_in(&dir, "aaa", false).is_some());
+
+        // Rebuilt or imported entries are checked again.
+        let trace = dir.join("aaa.trace");
+        write_atomic(&trace, b"").unwrap();
+        assert_ne!(validity_stamp(&dir, "aaa"), Some(stamp.clone()));
+        std::fs::remove_file(dir.join("aaa.drv")).unwrap();
+        std::os::unix::fs::symlink(&b, dir.join("aaa.drv")).unwrap();
+        let relinked = validity_stamp(&dir, "aaa").unwrap();
+        assert!(relinked.ends_with(b.as_os_str().as_bytes()));
+        assert_eq!(validity_stamp(&dir, "bbb"), None);
+    }
+
+    #[test]
+    fn test_invalid_store_paths() {
+        // Like nix-store, for a store where only `-registered` paths are
+        // valid.
+        let dir = tempfile::tempdir().unwrap();
+        let nix_store = dir.path().join("nix-store");
+        std::fs::write(
+            &nix_store,
+            concat!(
+                "#!/bin/sh\n",
+                "shift 2\n",
+                "for p; do case $p in *-registered) ;; *) echo $p;; esac; done\n",
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(
+            &nix_store,
+            std::fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        let nix_store = nix_store.to_str().unwrap();
+        let registered =
+            b"/nix/store/00000000000000000000000000000000-registered";
+        let copied = b"/nix/store/11111111111111111111111111111111-copied";
+        let paths = |x: &[&'static [u8]]| x.iter().copied().collect();
+
+        assert_eq!(
+            invalid_store_paths(nix_store, &paths(&[registered, copied])),
+            vec![OsStr::from_bytes(copied)]
+        );
+        assert!(
+            invalid_store_paths(nix_store, &paths(&[registered])).is_empty()
+        );
+        // Paths out of the store aren't asked about.
+        assert!(invalid_store_paths(
+            nix_store,
+            &paths(&[b"/tmp/aaa.drv", b"/nix/store/short"])
+        )
+        .is_empty());
+        // Nothing can be told without nix-store.
+        assert!(invalid_store_paths(
+            "/nonexistent/nix-store",
+            &paths(&[copied])
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_system_cache() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (user, system) =
+            (tmp.path().join("user"), tmp.path().join("system"));
+        let drv = tmp.path().join("aaa.drv");
+        std::fs::write(&drv, "").unwrap();
+        std::fs::create_dir(&user).unwrap();
+        write_entry(&system, "bbb", "GREETING=system\0", &drv);
+        let dirs = [user.clone(), system.clone()];
+        let greeting = |(env, dir): (Environment, PathBuf)| {
+            (env.to_vars()[OsStr::new("GREETING")].clone(), dir)
+        };
+
+        assert_eq!(
+            check_cache(&dirs, "bbb", false).map(greeting),
+            Some(("system".into(), system.clone()))
+        );
+        assert!(check_cache(&dirs[..1], "bbb", false).is_none());
+
+        // The user cache comes first.
+        write_entry(&user, "bbb", "GREETING=user\0", &drv);
+        assert_eq!(
+            check_cache(&dirs, "bbb", false).map(greeting),
+            Some(("user".into(), user.clone()))
+        );
+        // Unless its entry is stale.
+        std::fs::remove_file(user.join("bbb.drv")).unwrap();
+        std::os::unix::fs::symlink(
+            tmp.path().join("gone"),
+            user.join("bbb.drv"),
+        )
+        .unwrap();
+        assert_eq!(
+            check_cache(&dirs, "bbb", false).map(greeting),
+            Some(("system".into(), system))
+        );
+    }
+
+    #[test]
+    fn test_direct_command() {
+        let run = |cmd: &str| args::RunMode::Shell(cmd.into());
+        let direct = |cmd: &str| direct_command(&run(cmd), &[], &EnvMap::new());
+        assert_eq!(
+            direct("  make -j4 --keep-going  all"),
+            Some((
+                "make".into(),
+                vec!["-j4".into(), "--keep-going".into(), "all".into()]
+            ))
+        );
+        assert_eq!(
+            direct("./build.sh CC=gcc"),
+            Some(("./build.sh".into(), vec!["CC=gcc".into()]))
+        );
+        for cmd in [
+            "",
+            "echo hello",
+            "cd src",
+            "CC=gcc make",
+            "make \"all\"",
+            "ls ~",
+            "ls *.nix",
+            "make > log",
+            "make && make install",
+            "make $TARGET",
+            "make; make install",
+            "make\ninstall",
+        ] {
+            assert_eq!(direct(cmd), None, "{:?}", cmd);
+        }
+
+        let mut env = EnvMap::new();
+        env.insert("BASH_FUNC_build%%".into(), "() { make\n}".into());
+        assert_eq!(direct_command(&run("build"), &[], &env), None);
+        let run_args = ["a".into()];
+        assert_eq!(direct_command(&run("make"), &run_args, &env), None);
+        let exec = args::RunMode::Exec("make".into(), vec![]);
+        assert_eq!(direct_command(&exec, &[], &env), None);
+    }
+
+    #[test]
+    fn test_run_args() {
+        let run_args = vec![OsString::from("hello"), OsString::from("a b")];
+        let run = args::RunMode::Shell("printf '%s|' \"$0\" \"$@\"".into());
+        let (cmd, cmd_args, _) =
+            shell_command(&run, &run_args, None, None, false);
+        let output = Command::new(cmd).args(cmd_args).output().unwrap();
+        assert_eq!(output.stdout, b"bash|hello|a b|");
+
+        let command = args::RunMode::CommandThenInteractive("setup".into());
+        assert_eq!(
+            shell_command(&command, &run_args, None, None, true).1,
+            vec!["--rcfile", env!("CARGO_RCFILE"), "-s", "hello", "a b"]
+        );
+    }
+
+    /// Run an interactive bash with `rcfile.sh` and the variables `env` for
+    /// it, reading `stdin`.
+    fn rcfile_bash(env: &[(&str, &str)], stdin: &[u8]) -> String {
+        let mut child = Command::new("bash")
+            .args(["--rcfile", env!("CARGO_RCFILE"), "-i"])
+            .env_clear()
+            .envs(env.iter().copied())
+            .env("HOME", "/nonexistent")
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+        // The shell may exit without reading it.
+        let _ = child.stdin.take().unwrap().write_all(stdin);
+        let output = child.wait_with_output().unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn test_rcfile_command() {
+        let script = b"echo \"after:${CACHED_NIX_SHELL_COMMAND-unset}\"\n";
+        // The shell exits after the command, unless it returns.
+        let command = |cmd| [("CACHED_NIX_SHELL_COMMAND", cmd)];
+        assert_eq!(rcfile_bash(&command("echo setup"), script), "setup\n");
+        assert_eq!(
+            rcfile_bash(&command("echo setup; return"), script),
+            "setup\nafter:unset\n"
+        );
+    }
+
+    #[test]
+    fn test_rcfile_setup() {
+        let env = [("CACHED_NIX_SHELL_SETUP", "alias greet='echo hi'\n")];
+        let script = b"greet; echo \"${CACHED_NIX_SHELL_SETUP-unset}\"\n";
+        assert_eq!(rcfile_bash(&env, script), "hi\nunset\n");
+    }
+
+    #[test]
+    fn test_rcfile_user() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rc = tmp.path().join("rc.sh");
+        std::fs::write(&rc, "greet() { echo \"hi $1\"; }\n").unwrap();
+        let rc = rc.to_str().unwrap();
+        let script =
+            b"greet there; echo \"${CACHED_NIX_SHELL_RCFILE-unset}\"\n";
+        assert_eq!(
+            rcfile_bash(&[("CACHED_NIX_SHELL_RCFILE", rc)], script),
+            "hi there\nunset\n"
+        );
+        // The user's rcfile is sourced before --command runs.
+        let env = [
+            ("CACHED_NIX_SHELL_RCFILE", rc),
+            ("CACHED_NIX_SHELL_COMMAND", "greet setup"),
+        ];
+        assert_eq!(rcfile_bash(&env, b""), "hi setup\n");
+    }
+
+    #[test]
+    fn test_read_env() {
+        let env = read_env(&b"A=1\0B=\0C=x=y\nz\0"[..]).unwrap();
+        assert_eq!(env[OsStr::new("C")], "x=y\nz");
+        assert_eq!(env[OsStr::new("B")], "");
+        assert!(read_env(&b""[..]).is_err());
+        assert!(read_env(&b"A=1\0B=2"[..]).is_err());
+        assert!(read_env(&b"A=1\0\0"[..]).is_err());
+        assert!(read_env(&b"shellHook output\nA=1\0B\0"[..]).is_err());
+    }
+
+    #[test]
+    fn test_large_env() {
+        let big = |c: char| c.to_string().repeat(8 << 20);
+        let mut vars = EnvMap::new();
+        vars.insert("A".into(), big('a').into());
+        vars.insert("B".into(), "small".into());
+        vars.insert(
+            "BASH_FUNC_f%%".into(),
+            format!("() {{ {}\n}}", big('f')).into(),
+        );
+        let env = Environment::from_vars(vars.clone());
+
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("x.env");
+        write_atomic_with(&fname, |w| env.write(w)).unwrap();
+        assert_eq!(
+            std::fs::metadata(&fname).unwrap().len() as usize,
+            env.serialize().len()
+        );
+        // Read through a small buffer, one variable at a time.
+        let reader =
+            BufReader::with_capacity(4096, File::open(&fname).unwrap());
+        let restored = Environment::read(reader).unwrap();
+        assert_eq!(restored, env);
+        assert_eq!(restored.to_vars(), vars);
+    }
+
+    #[test]
+    fn test_hash_inputs() {
+        let hash = |algo: Option<&str>| {
+            hash_inputs(algo.map(OsStr::new), b"abc").unwrap()
+        };
+        assert_eq!(hash(None), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(hash(Some("sha1")), hash(None));
+        assert_eq!(
+            hash(Some("sha256")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            hash(Some("blake2b")),
+            "bddd813c634239723171ef3fee98579b94964e3bb1cb3e427262c8c068d52319"
+        );
+        assert!(hash_inputs(Some(OsStr::new("md5")), b"abc").is_err());
+    }
+
+    #[test]
+    fn test_keep_path() {
+        let kept = keep_caller_path(
+            env(&[("PATH", "/nix/store/git/bin")]),
+            Some("/usr/bin".into()),
+        );
+        assert_eq!(kept[OsStr::new("PATH")], "/nix/store/git/bin:/usr/bin");
+
+        let parse = |args: &[&str]| key("/", args);
+        assert_ne!(
+            parse(&["--pure", "-p", "git"]),
+            parse(&["--pure", "--keep-path", "-p", "git"]),
+        );
+    }
+
+    #[test]
+    fn test_nix_shell_binary() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |version: &str| {
+            let store = dir.path().join(format!("nix-{}", version));
+            std::fs::create_dir_all(store.join("bin")).unwrap();
+            std::fs::write(store.join("bin/nix-shell"), "").unwrap();
+            let profile = dir.path().join(format!("profile-{}", version));
+            std::os::unix::fs::symlink(store.join("bin"), &profile).unwrap();
+            let path = format!("/nonexistent:{}", profile.to_str().unwrap());
+            nix_shell_binary(OsStr::new(&path)).unwrap()
+        };
+        let old = path("2.3.1");
+        assert_eq!(old, dir.path().join("nix-2.3.1/bin/nix-shell"));
+        let new = path("2.4");
+
+        let inp = |nix: PathBuf| NixShellInput {
+            pwd: "/".into(),
+            env: EnvMap::new(),
+            args: vec!["--pure".into()],
+            unkeyed_args: vec![],
+            key_extra: env(&[("nix", nix.to_str().unwrap())]),
+            traced_files: vec![],
+            key_from: None,
+            trace_scope: vec![],
+            store_only: false,
+            trace_dir_depth: None,
+            shell_file: None,
+            show_build_output: true,
+            keep_build_dir: false,
+        };
+        assert_ne!(cache_inputs(&inp(old)), cache_inputs(&inp(new)));
+        assert!(nix_shell_binary(OsStr::new("/nonexistent")).is_none());
+    }
+
+    #[test]
+    fn test_includes_in_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("cache");
+        std::fs::create_dir_all(cache.join("blobs")).unwrap();
+        let inside = format!("nixpkgs={}", cache.join("blobs").display());
+        let outside = format!("nixpkgs={}", dir.path().display());
+        let dirs = [cache.clone(), PathBuf::new()];
+        let check = |includes: &[&str], nix_path: Option<&str>| {
+            let mut kw = Vec::new();
+            for include in includes {
+                kw.extend(vec!["-I".into(), OsString::from(include)]);
+            }
+            includes_in_cache(&kw, nix_path.map(OsStr::new), &dirs)
+        };
+
+        assert_eq!(check(&[&inside], None), vec![OsString::from(&inside)]);
+        assert!(check(&[&outside, "channel:nixos-24.05"], None).is_empty());
+        let via_dots = format!("{}/../cache/x.nix", cache.display());
+        assert_eq!(check(&[&via_dots], None).len(), 1);
+
+        let nix_path = format!("https://example.com/x.tar.gz:{}", inside);
+        assert_eq!(check(&[], Some(&nix_path)), vec![OsString::from(&inside)]);
+        assert!(check(&[], Some("nixpkgs=/nix/var/nix/profiles")).is_empty());
+    }
+
+    #[test]
+    fn test_nixpkgs_config_paths() {
+        assert!(nixpkgs_config_paths(None, None).is_empty());
+        let paths = nixpkgs_config_paths(
+            Some(&"/home/u".into()),
+            Some(&"/etc/c.nix".into()),
+        );
+        assert_eq!(paths[0], PathBuf::from("/etc/c.nix"));
+        assert!(
+            paths.contains(&PathBuf::from("/home/u/.config/nixpkgs/overlays"))
+        );
+    }
+
+    #[test]
+    fn test_no_gc_root_key() {
+        let key = |args: &[&str]| key("/", args);
+        assert_eq!(key(&["-p", "git"]), key(&["--no-gc-root", "-p", "git"]));
+    }
+
+    #[test]
+    fn test_packages_file_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("tools");
+        let key = || key("/", &["--packages-file", fname.to_str().unwrap()]);
+        std::fs::write(&fname, "git\n").unwrap();
+        let git = key();
+        std::fs::write(&fname, "# comment\ngit\n\n").unwrap();
+        assert_eq!(key(), git);
+        std::fs::write(&fname, "git\nhello\n").unwrap();
+        assert_ne!(key(), git);
+
+        // The same packages given with -p are another entry, since the file
+        // is traced.
+        let p_git = input("/", &["-p", "git"]);
+        assert!(p_git.traced_files.is_empty());
+        std::fs::write(&fname, "git\n").unwrap();
+        assert_ne!(cache_inputs(&p_git), key());
+    }
+
+    #[test]
+    fn test_merge_file_inp() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a.nix"), dir.path().join("b.nix"));
+        std::fs::write(&a, "{}").unwrap();
+        std::fs::write(&b, "{}").unwrap();
+        let inp = |files: &[&Path]| {
+            let args = files
+                .iter()
+                .flat_map(|x| vec!["--merge-file".into(), x.into()])
+                .collect();
+            Args::parse(args, false)
+                .unwrap()
+                .pipe(|args| args_to_inp("/".into(), &args, &EnvMap::new()))
+        };
+        let ab = inp(&[&a, &b]);
+        assert!(ab.args.contains(&"--expr".into()));
+        let expr = ab.args.last().unwrap().to_str().unwrap();
+        assert!(expr.contains(&format!("\"{}\"", a.display())), "{}", expr);
+        assert!(expr.contains(&format!("\"{}\"", b.display())), "{}", expr);
+        assert_eq!(ab.traced_files, vec![a.clone(), b.clone()]);
+        assert_eq!(ab.shell_file, None);
+        // Later files take precedence, so the order matters.
+        assert_ne!(cache_inputs(&ab), cache_inputs(&inp(&[&b, &a])));
+    }
+
+    #[test]
+    fn test_argjson_key() {
+        let key = |json| key("/", &["--argjson", "foo", json, "-p", "git"]);
+        assert_eq!(key(r#"{"a":1}"#), key(r#"{"a":1}"#));
+        assert_ne!(key(r#"{"a":1}"#), key(r#"{"a":2}"#));
+    }
+
+    #[test]
+    fn test_key_package_expressions() {
+        let key = |packages: &[&str]| key("/", &[&["-p"], packages].concat());
+        let exprs = [
+            "haskellPackages.ghcWithPackages (p: [p.lens])",
+            "haskellPackages.ghcWithPackages (p: [p.lens p.text])",
+            "python3.withPackages (ps: [ps.numpy])",
+            "[ hello ]",
+        ];
+        for (i, a) in exprs.iter().enumerate() {
+            assert_eq!(key(&[a]), key(&[a]));
+            for b in &exprs[i + 1..] {
+                assert_ne!(key(&[a]), key(&[b]), "{} and {}", a, b);
+            }
+        }
+        // One expression isn't the same as its words as separate packages.
+        assert_ne!(
+            key(&["python3.withPackages (ps: [ps.numpy])"]),
+            key(&["python3.withPackages", "(ps:", "[ps.numpy])"])
+        );
+    }
+
+    #[test]
+    fn test_key_from() {
+        let key = |args: &[&str]| cache_hash(&key("/", args));
+        let from = |s, pkg| key(&["--cache-key-from", s, "-p", pkg]);
+        // Only the string matters.
+        assert_eq!(from("gen-1", "git"), from("gen-1", "hello"));
+        assert_ne!(from("gen-1", "git"), from("gen-2", "git"));
+        assert_ne!(from("gen-1", "git"), key(&["-p", "git"]));
+        assert_eq!(from("gen-1", "git").len(), key(&["-p", "git"]).len());
+    }
+
+    #[test]
+    fn test_git_aware_key() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("shell.nix"), "{}").unwrap();
+        let key = |extra| key(dir.path(), &[extra, "./shell.nix"]);
+        // Outside of a repository, the flag does nothing.
+        assert_eq!(key("--git-aware"), key("--impure"));
+
+        let git = |args: &[&str]| {
+            Command::new("git")
+                .arg("-C")
+                .arg(dir.path())
+                .args(["-c", "user.name=a", "-c", "user.email=a@b"])
+                .args(args)
+                .output()
+                .map(|output| output.status.success())
+                .unwrap_or(false)
+        };
+        if !git(&["init", "-q"]) {
+            eprintln!("skipping the git test: git is not available");
+            return;
+        }
+        // No commit yet.
+        assert_eq!(key("--git-aware"), key("--impure"));
+        assert!(git(&["commit", "-q", "--allow-empty", "-m", "1"]));
+        let first = key("--git-aware");
+        assert_ne!(first, key("--impure"));
+        assert_eq!(key("--git-aware"), first);
+
+        assert!(git(&["commit", "-q", "--allow-empty", "-m", "2"]));
+        let second = key("--git-aware");
+        assert_ne!(second, first);
+        assert!(git(&["checkout", "-q", "HEAD~"]));
+        assert_eq!(key("--git-aware"), first);
+    }
+
+    #[test]
+    fn test_nixpkgs_rev() {
+        let inp = |args: &[&str]| input("/", args);
+        let rev = |rev| inp(&["--nixpkgs-rev", rev, "-p", "hello"]);
+        let key = |inp: &NixShellInput| cache_hash(&cache_inputs(inp));
+        assert_ne!(key(&rev("abc")), key(&rev("def")));
+        assert_ne!(key(&rev("abc")), key(&inp(&["-p", "hello"])));
+        assert_eq!(key(&rev("abc")), key(&rev("abc")));
+
+        let cmd = nix_shell_command(&rev("abc"), None, None);
+        let args = cmd.get_args().collect::<Vec<_>>();
+        let i = args.iter().position(|&x| x == "-I").unwrap();
+        assert_eq!(
+            args[i + 1],
+            "nixpkgs=https://github.com/NixOS/nixpkgs/archive/abc.tar.gz"
+        );
+        assert!(i < args.iter().position(|&x| x == "--").unwrap());
+    }
+
+    #[test]
+    fn test_key_ignore_key() {
+        let key = |args: &[&str]| key("/", args);
+        let named = |name| {
+            key(&[
+                "--argstr",
+                "name",
+                name,
+                "--key-ignore",
+                "name",
+                "-p",
+                "git",
+            ])
+        };
+        assert_eq!(named("a"), named("b"));
+        assert_eq!(named("a"), key(&["-p", "git"]));
+        assert_ne!(
+            key(&["--argstr", "name", "a", "-p", "git"]),
+            key(&["--argstr", "name", "b", "-p", "git"])
+        );
+    }
+
+    #[test]
+    fn test_nix_shell_command_capturing() {
+        let cmd = nix_shell_command(&input("/", &["-p", "git"]), None, None);
+        let cmd_args = cmd.get_args().collect::<Vec<_>>();
+        assert_eq!(cmd_args[..3], ["--keep", CAPTURING_VAR, "--pure"]);
+        let id = capture_id(&input("/", &["-p", "git"]));
+        assert!(cmd
+            .get_envs()
+            .any(|(k, v)| k == CAPTURING_VAR && v == Some(id.as_ref())));
+        let other = capture_id(&input("/", &["-p", "hello"]));
+        assert_ne!(id, other);
+    }
+
+    #[test]
+    fn test_plain_nix_shell_args() {
+        let plain = |args: &[&str]| {
+            let args =
+                Args::parse(args.iter().map(OsString::from).collect(), false)
+                    .unwrap();
+            plain_nix_shell_args(
+                &args,
+                &args_to_inp("/".into(), &args, &EnvMap::new()),
+            )
+        };
+        assert_eq!(
+            plain(&["--timeout", "5s", "-p", "git", "--run", "git log"]),
+            ["--packages", "--run", "git log", "--", "git"]
+        );
+        assert_eq!(
+            plain(&["--pure", "-p", "git"]),
+            ["--pure", "--packages", "--", "git"]
+        );
+        assert_eq!(
+            plain(&["-p", "git", "--exec", "git", "it's"]),
+            ["--packages", "--run", "exec 'git' 'it'\\''s'", "--", "git"]
+        );
+        assert_eq!(
+            plain(&["--keep-path", "--color", "never", "-p", "git"]),
+            ["--packages", "--", "git"]
+        );
+
+        let args = Args::parse(vec!["./shell.nix".into()], false).unwrap();
+        let inp = args_to_inp("/src".into(), &args, &EnvMap::new());
+        assert_eq!(
+            plain_nix_shell_args(&args, &inp).last().unwrap(),
+            "/src/shell.nix"
+        );
+    }
+
+    #[test]
+    fn test_nix_shell_command_trace() {
+        let args = Args::parse(vec!["-p".into(), "git".into()], false).unwrap();
+        let (lib, trace_file) = (Path::new("/lib/trace.so"), Path::new("/t"));
+        let cmd = nix_shell_command(
+            &args_to_inp("/".into(), &args, &EnvMap::new()),
+            Some((lib, trace_file)),
+            None,
+        );
+        let envs = cmd.get_envs().collect::<BTreeMap<_, _>>();
+        assert_eq!(envs[OsStr::new("LD_PRELOAD")], Some(lib.as_os_str()));
+        assert_eq!(envs[OsStr::new("TRACE_NIX")], Some(trace_file.as_os_str()));
+        let cmd_args = cmd.get_args().collect::<Vec<_>>();
+        assert!(cmd_args.contains(&OsStr::new("--pure")));
+        assert!(!cmd_args.contains(&OsStr::new("LD_PRELOAD")));
+        assert!(!cmd_args.contains(&OsStr::new("TRACE_NIX")));
+    }
+
+    #[test]
+    fn test_caller_limits() {
+        // The umask is process-wide.
+        if std::env::var_os(CHILD_TEST_VAR).is_none() {
+            let tmp = tempfile::tempdir().unwrap();
+            let mut child = child_test("test::test_caller_limits", tmp.path());
+            assert!(child.wait().unwrap().success());
+            return;
+        }
+        let (umask, _) = caller_limits();
+        nix::sys::stat::umask(nix::sys::stat::Mode::from_bits_truncate(
+            !umask & 0o777,
+        ));
+        assert_ne!(current_umask(), umask);
+        assert_eq!(caller_limits().0, umask);
+    }
+
+    #[test]
+    fn test_print_env_umask() {
+        // What nix-shell runs after a shellHook that sets the umask.
+        let output = Command::new("bash")
+            .args(["-c", &format!("umask 027; ulimit -Sn 123; {}", PRINT_ENV)])
+            .env_clear()
+            .env("A", "1")
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        let env = Environment::from_vars(read_env(&output.stdout[..]).unwrap());
+        assert_eq!(env.umask, Some(0o027));
+        assert_eq!(env.nofile, Some(123));
+        assert_eq!(env.vars[OsStr::new("A")], "1");
+        assert!(!env.vars.contains_key(OsStr::new("CACHED_NIX_SHELL_UMASK")));
+    }
+
+    #[test]
+    fn test_print_env_expanded() {
+        // Variables are captured with their values, whatever they were
+        // defined in terms of, so the order they are set in doesn't matter.
+        // A `$` that is part of a value is kept as it is.
+        let output = Command::new("bash")
+            .args([
+                "-c",
+                &format!(
+                    concat!(
+                        "export out=/nix/store/aaa-shell; ",
+                        "export PKG_CONFIG_PATH=$out/lib/pkgconfig; ",
+                        "export DATA=\"$PKG_CONFIG_PATH:$out/share\"; ",
+                        "export TEMPLATE='$out/bin'; {}"
+                    ),
+                    PRINT_ENV
+                ),
+            ])
+            .env_clear()
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        let env = Environment::from_vars(read_env(&output.stdout[..]).unwrap());
+        let restored =
+            Environment::read(&env.serialize()[..]).unwrap().to_vars();
+        assert_eq!(
+            restored[OsStr::new("DATA")],
+            "/nix/store/aaa-shell/lib/pkgconfig:/nix/store/aaa-shell/share"
+        );
+        assert_eq!(restored[OsStr::new("TEMPLATE")], "$out/bin");
+    }
+
+    /// Run `shellHook` like nix-shell does, with the relevant functions of
+    /// stdenv, and return the status recorded by `HOOK_PROBE`.
+    fn probed_hook_status(shell_hook: &str) -> Option<String> {
+        let dir = tempfile::tempdir().unwrap();
+        let probe = dir.path().join("bash-env");
+        std::fs::write(&probe, HOOK_PROBE).unwrap();
+        let rc = r#"
+            _eval() {
+                if declare -F "$1" > /dev/null 2>&1; then "$@"; else eval "$1"; fi
+            }
+            _callImplicitHook() {
+                local def="$1" hookName="$2"
+                if [ -n "${!hookName:-}" ]; then eval "${!hookName}"
+                else return "$def"; fi
+            }
+            runHook() {
+                local hookName="$1"; shift
+                local hooksSlice="${hookName%Hook}Hooks[@]" hook
+                for hook in "_callImplicitHook 0 $hookName" ${!hooksSlice+"${!hooksSlice}"}; do
+                    _eval "$hook" "$@"
+                done
+                return 0
+            }
+            set +e
+            if [ "$(type -t runHook)" = function ]; then runHook shellHook; fi
+            unset NIX_ENFORCE_PURITY
+            printf '%s' "${BASH_ENV-unset}/${CACHED_NIX_SHELL_HOOK_STATUS-}"
+        "#;
+        let output = Command::new("bash")
+            .args(["-c", rc])
+            .env_clear()
+            .env("BASH_ENV", &probe)
+            .env("shellHook", shell_hook)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        let output = String::from_utf8(output.stdout).unwrap();
+        let status = output.strip_prefix("unset/").unwrap();
+        (!status.is_empty()).then(|| status.to_string())
+    }
+
+    #[test]
+    fn test_hook_probe() {
+        assert_eq!(probed_hook_status("echo hi >&2"), Some("0".into()));
+        assert_eq!(probed_hook_status("false"), Some("1".into()));
+        assert_eq!(probed_hook_status("return 3"), Some("3".into()));
+        assert_eq!(
+            probed_hook_status("[ -e /nonexistent ] && echo found"),
+            Some("1".into())
+        );
+    }
+
+    #[test]
+    fn test_print_env_nix_shell_rc() {
+        // What the rcfile of `nix-shell --run` does around the command, in
+        // the interactive bash it starts.
+        let tmp = tempfile::tempdir().unwrap();
+        let rc = tmp.path().join("rc");
+        std::fs::write(
+            &rc,
+            format!(
+                concat!(
+                    "set +e; shopt -s inherit_errexit; shopt -u nullglob; ",
+                    "shopt -s execfail; {}; exit\n"
+                ),
+                PRINT_ENV
+            ),
+        )
+        .unwrap();
+        let output = Command::new("bash")
+            .arg("--rcfile")
+            .arg(&rc)
+            .arg("-i")
+            .env_clear()
+            .stdin(std::process::Stdio::null())
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        let env = Environment::from_vars(read_env(&output.stdout[..]).unwrap());
+        assert!(env.shopts.is_some());
+        assert_eq!(env.shell_setup(), None);
+    }
+
+    #[test]
+    fn test_print_env_shell_setup() {
+        let output = Command::new("bash")
+            .args([
+                "-c",
+                &format!(
+                    "shopt -s extglob; alias greet='echo hi'; {}",
+                    PRINT_ENV
+                ),
+            ])
+            .env_clear()
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        let env = Environment::from_vars(read_env(&output.stdout[..]).unwrap());
+        assert!(!env.vars.contains_key(OsStr::new("CACHED_NIX_SHELL_SHOPTS")));
+
+        // The command relies on both.
+        let run = args::RunMode::Shell("greet; x=abc; echo ${x/+(b)/B}".into());
+        let setup = env.shell_setup();
+        let (cmd, cmd_args, _) =
+            shell_command(&run, &[], setup.as_deref(), None, false);
+        let output = Command::new(cmd)
+            .args(cmd_args)
+            .env_clear()
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        assert_eq!(output.stdout, b"hi\naBc\n");
+    }
+
+    #[test]
+    fn test_keep_build_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let exec = Command::new("sh")
+            .args(["-c", "echo building >&2"])
+            .output()
+            .unwrap();
+        keep_build_files(dir.path(), &exec);
+        assert_eq!(
+            std::fs::read(dir.path().join("stderr")).unwrap(),
+            b"building\n"
+        );
+    }
+
+    #[test]
+    fn test_set_nofile_limit() {
+        // Limits are process-wide.
+        if std::env::var_os(CHILD_TEST_VAR).is_none() {
+            let tmp = tempfile::tempdir().unwrap();
+            let mut child =
+                child_test("test::test_set_nofile_limit", tmp.path());
+            assert!(child.wait().unwrap().success());
+            return;
+        }
+        let (_, hard) = nofile_limit().unwrap();
+        // Raised no further than the hard limit.
+        set_nofile_limit(environment::UNLIMITED).unwrap();
+        assert_eq!(nofile_limit().unwrap(), (hard, hard));
+        set_nofile_limit(64).unwrap();
+        assert_eq!(nofile_limit().unwrap(), (64.min(hard), hard));
+    }
+
+    #[test]
+    fn test_print_build_command() {
+        let args = ["-p", "hello", "-A", "foo"];
+        let out =
+            print_build_command(args.iter().map(OsString::from).collect());
+        let lines = out.lines().collect::<Vec<_>>();
+        assert!(lines[0].starts_with("cd /"), "{}", out);
+        assert_eq!(lines[1], "env -i \\");
+        assert!(lines.iter().any(|x| x.starts_with("  LD_PRELOAD=")));
+        assert!(lines.iter().any(|x| x.starts_with("  TRACE_NIX=")));
+        assert!(lines.iter().any(|x| x.starts_with("  PATH=")));
+        let cmd = lines.last().unwrap();
+        assert!(
+            cmd.starts_with(
+                "  nix-shell --keep CACHED_NIX_SHELL_CAPTURING --pure --packages "
+            ),
+            "{}",
+            cmd
+        );
+        assert!(
+            cmd.contains(&format!(" --run '{}' -A foo -- hello", PRINT_ENV)),
+            "{}",
+            cmd
+        );
+    }
+
+    #[test]
+    fn test_capture_bash() {
+        let args = Args::parse(vec!["-p".into(), "git".into()], false).unwrap();
+        let inp = args_to_inp("/".into(), &args, &EnvMap::new());
+        assert!(!inp.env.contains_key(OsStr::new("NIX_BUILD_SHELL")));
+        let default_key = cache_inputs(&inp);
+
+        let caller = env(&[("CACHED_NIX_SHELL_BASH", "/opt/bash/bin/bash")]);
+        let inp = args_to_inp("/".into(), &args, &caller);
+        assert_eq!(
+            inp.env[OsStr::new("NIX_BUILD_SHELL")],
+            "/opt/bash/bin/bash"
+        );
+        assert_ne!(cache_inputs(&inp), default_key);
+    }
+
+    #[test]
+    fn test_write_atomic_mode() {
+        // The environment is process-wide.
+        if std::env::var_os(CHILD_TEST_VAR).is_none() {
+            let tmp = tempfile::tempdir().unwrap();
+            let mut child =
+                child_test("test::test_write_atomic_mode", tmp.path());
+            assert!(child.wait().unwrap().success());
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("x.env");
+        let mode = |fname: &std::path::Path| {
+            std::fs::metadata(fname).unwrap().permissions().mode() & 0o777
+        };
+
+        std::env::remove_var("CACHED_NIX_SHELL_CACHE_UMASK");
+        write_atomic(&fname, b"A=1\0").unwrap();
+        assert_eq!(mode(&fname), 0o600);
+
+        // Readable by the group and others, i.e. by a user with another uid.
+        std::env::set_var("CACHED_NIX_SHELL_CACHE_UMASK", "022");
+        write_atomic(&fname, b"A=2\0").unwrap();
+        std::env::remove_var("CACHED_NIX_SHELL_CACHE_UMASK");
+        assert_eq!(mode(&fname), 0o644);
+        assert_eq!(std::fs::read(&fname).unwrap(), b"A=2\0");
+    }
+
+    #[test]
+    fn test_in_nix_shell() {
+        let mode = |flags: &[&str], caller: &[(&str, &str)]| {
+            let args = Args::parse(
+                flags
+                    .iter()
+                    .chain(&["-p", "git"])
+                    .map(OsString::from)
+                    .collect(),
+                false,
+            )
+            .unwrap();
+            let shell = env(&[
+                ("IN_NIX_SHELL", "pure"),
+                ("PATH", "/nix/store/git/bin"),
+            ]);
+            combine_with_caller(&args, shell, env(caller))
+                [OsStr::new("IN_NIX_SHELL")]
+            .clone()
+        };
+        let nested = [("IN_NIX_SHELL", "impure"), ("PATH", "/usr/bin")];
+        assert_eq!(mode(&[], &[]), "impure");
+        assert_eq!(mode(&["--impure"], &nested), "impure");
+        assert_eq!(mode(&["--pure"], &nested), "pure");
+        assert_eq!(
+            mode(&["--pure", "--keep", "IN_NIX_SHELL"], &nested),
+            "pure"
+        );
+        assert_eq!(mode(&["--clean-env"], &nested), "pure");
+    }
+
+    #[test]
+    fn test_packages_env_key() {
+        let inp = |n| {
+            let caller = env(&[
+                ("XDG_RUNTIME_DIR", &format!("/run/user/{}", n)),
+                ("CACHED_NIX_SHELL_TEST_UNRELATED", n),
+            ]);
+            Args::parse(vec!["-p".into(), "git".into()], false)
+                .unwrap()
+                .pipe(|args| args_to_inp("/".into(), &args, &caller))
+        };
+        let one = inp("1");
+        let two = inp("2");
+
+        // Variables nix can read in packages mode are a part of the key...
+        assert_eq!(one.env[OsStr::new("XDG_RUNTIME_DIR")], "/run/user/1");
+        assert_ne!(cache_inputs(&one), cache_inputs(&two));
+        // ...and other ones don't reach nix.
+        assert!(!one
+            .env
+            .contains_key(OsStr::new("CACHED_NIX_SHELL_TEST_UNRELATED")));
+    }
+
+    #[test]
+    fn test_clean_env() {
+        let caller = env(&[
+            ("HOME", "/home/user"),
+            ("TERM", "xterm"),
+            ("EDITOR", "vi"),
+            ("http_proxy", "http://proxy"),
+            ("PATH", "/usr/bin"),
+        ]);
+        let shell = env(&[("PATH", "/nix/store/coreutils/bin")]);
+        let keep = ["http_proxy"].iter().map(OsString::from).collect();
+        assert_eq!(
+            clean_env(shell, keep, &caller),
+            env(&[
+                ("HOME", "/home/user"),
+                ("TERM", "xterm"),
+                ("http_proxy", "http://proxy"),
+                ("PATH", "/nix/store/coreutils/bin"),
+            ])
+        );
+
+        let key = |args: &[&str]| key("/", args);
+        assert_ne!(key(&["-p", "git"]), key(&["--clean-env", "-p", "git"]));
+    }
+
+    #[test]
+    fn test_keep() {
+        let args = Args::parse(
+            ["--pure", "--keep", "FOO", "-p", "git"]
+                .iter()
+                .map(OsString::from)
+                .collect(),
+            false,
+        )
+        .unwrap();
+        let keep = kept_vars(
+            &args,
+            Some("http_proxy::https_proxy".into()),
+            &EnvMap::new(),
+        );
+        assert_eq!(
+            keep.iter().collect::<Vec<_>>(),
+            vec!["FOO", "http_proxy", "https_proxy"]
+        );
+
+        let caller_env = env(&[
+            ("FOO", "caller"),
+            ("http_proxy", "http://proxy"),
+            ("SECRET", "1"),
+        ]);
+        let kept =
+            keep_caller_vars(env(&[("FOO", "shell")]), &keep, &caller_env);
+        assert_eq!(
+            kept,
+            env(&[("FOO", "shell"), ("http_proxy", "http://proxy")])
+        );
+
+        let parse = |args: &[&str]| key("/", args);
+        assert_ne!(
+            parse(&["--pure", "-p", "git"]),
+            parse(&["--pure", "--keep", "FOO", "-p", "git"]),
+        );
+        assert_ne!(
+            parse(&["--pure", "--keep", "FOO", "-p", "git"]),
+            parse(&["--pure", "--keep", "BAR", "-p", "git"]),
+        );
+    }
+
+    #[test]
+    fn test_dropped_vars() {
+        let caller_env =
+            env(&[("TERM", "xterm"), ("LANG", "C.UTF-8"), ("SECRET", "1")]);
+        let pure = |keep: &[&str]| {
+            let mut args = vec!["--pure"];
+            for name in keep {
+                args.extend(["--keep", name]);
+            }
+            args.extend(["-p", "git"]);
+            let args =
+                Args::parse(args.iter().map(OsString::from).collect(), false)
+                    .unwrap();
+            let keep = kept_vars(&args, None, &caller_env);
+            let env = keep_caller_vars(
+                env(&[("LANG", "en_US.UTF-8")]),
+                &keep,
+                &caller_env,
+            );
+            dropped_vars(&env, &caller_env)
+        };
+        // LANG is set by the shell, and SECRET isn't worth a warning.
+        assert_eq!(pure(&[]), vec!["TERM"]);
+        assert!(pure(&["TERM"]).is_empty());
+    }
+
+    #[test]
+    fn test_keep_pattern() {
+        let args = Args::parse(
+            ["--pure", "--keep", "AWS_*", "--keep", "FOO", "-p", "git"]
+                .iter()
+                .map(OsString::from)
+                .collect(),
+            false,
+        )
+        .unwrap();
+        let caller_env = env(&[
+            ("AWS_PROFILE", "dev"),
+            ("AWS_REGION", "eu-west-1"),
+            ("MY_AWS_KEY", "1"),
+            ("SECRET", "1"),
+        ]);
+        let keep = kept_vars(&args, Some("*_proxy".into()), &caller_env);
+        assert_eq!(
+            keep.iter().collect::<Vec<_>>(),
+            vec!["AWS_PROFILE", "AWS_REGION", "FOO"]
+        );
+        assert_eq!(
+            keep_caller_vars(EnvMap::new(), &keep, &caller_env),
+            env(&[("AWS_PROFILE", "dev"), ("AWS_REGION", "eu-west-1")])
+        );
+
+        assert!(glob_match(b"*_proxy", b"https_proxy"));
+        assert!(glob_match(b"A*B*C", b"AxxBxCxC"));
+        assert!(!glob_match(b"A*B*C", b"AxxBxCx"));
+        assert!(glob_match(b"*", b""));
+    }
+
+    #[test]
+    fn test_keep_pattern_key() {
+        let key = |vars: &[(&str, &str)]| {
+            Args::parse(
+                ["--pure", "--keep", "CNS_TEST_KEEP_*", "-p", "git"]
+                    .iter()
+                    .map(OsString::from)
+                    .collect(),
+                false,
+            )
+            .unwrap()
+            .pipe(|args| args_to_inp("/".into(), &args, &env(vars)))
+            .pipe(|inp| cache_inputs(&inp))
+        };
+        let one = key(&[("CNS_TEST_KEEP_A", "1")]);
+        // Values aren't keyed, only which variables are kept.
+        assert_eq!(one, key(&[("CNS_TEST_KEEP_A", "2")]));
+        assert_ne!(
+            one,
+            key(&[("CNS_TEST_KEEP_A", "1"), ("CNS_TEST_KEEP_B", "1")])
+        );
+    }
+
+    #[test]
+    fn test_script_command_path() {
+        // The interpreter is only in the PATH of the shell.
+        let dir = tempfile::tempdir().unwrap();
+        let helper = dir.path().join("somehelper");
+        std::fs::write(&helper, "#!/bin/sh\necho \"helper $*\"\n").unwrap();
+        std::fs::set_permissions(
+            &helper,
+            std::fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        assert!(std::env::split_paths(&std::env::var_os("PATH").unwrap())
+            .all(|x| !x.join("somehelper").exists()));
+        let mut env = EnvMap::new();
+        let path = format!("/nonexistent:{}", dir.path().display());
+        env.insert("PATH".into(), path.into());
+
+        let output = script_command(
+            OsStr::new("somehelper"),
+            "script.sh".into(),
+            vec!["arg".into()],
+            &env,
+        )
+        .output()
+        .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        assert_eq!(output.stdout, b"helper script.sh arg\n");
+    }
+
+    #[test]
+    fn test_interpreter_command() {
+        let cmd = |i: &str| {
+            interpreter_command(OsStr::new(i))
+                .into_iter()
+                .map(|x| x.into_string().unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(cmd("/bin/bash"), vec!["/bin/bash"]);
+        assert_eq!(cmd("zsh"), vec!["zsh"]);
+        assert_eq!(cmd("env python3"), vec!["python3"]);
+        assert_eq!(cmd("/usr/bin/env python3 -u"), vec!["python3", "-u"]);
+        assert_eq!(cmd("env"), vec!["env"]);
+        assert_eq!(
+            cmd(r#"python3 -W "ignore x""#),
+            vec!["python3", "-W", "ignore x"]
+        );
+    }
+
+    #[test]
+    fn test_interpreter_args_in_shebang() {
+        let mut script = NamedTempFile::new().unwrap();
+        script
+            .write_all(
+                b"#!/usr/bin/env cached-nix-shell\n\
+                  #! nix-shell -i \"python3 -O -W \\\"ignore x\\\"\" -p python3\n",
+            )
+            .unwrap();
+        let args = shebang::parse_script(script.path().as_os_str()).unwrap();
+        let args = Args::parse(args, true).unwrap();
+        assert_eq!(
+            interpreter_command(&args.interpreter),
+            vec!["python3", "-O", "-W", "ignore x"]
+        );
+        assert_eq!(args.rest, vec!["python3"]);
+    }
+
+    #[test]
+    fn test_relative_include_key() {
+        let key = |include| key("/", &["-I", include, "-p", "hello"]);
+        let abs = current_dir().unwrap().join("foo");
+        let abs = format!("nixpkgs={}", abs.to_str().unwrap());
+        assert_eq!(key("nixpkgs=./foo"), key(&abs));
+        assert_ne!(key("nixpkgs=./foo"), key("nixpkgs=./bar"));
+    }
+
+    #[test]
+    fn test_include_order_key() {
+        let inp = |includes: &[&str]| {
+            let mut args = Vec::new();
+            for include in includes {
+                args.extend(["-I", include]);
+            }
+            args.extend(["-p", "hello"]);
+            input("/", &args)
+        };
+        let ab = inp(&["nixpkgs=/a", "nixpkgs=/b"]);
+        let ba = inp(&["nixpkgs=/b", "nixpkgs=/a"]);
+        // The first entry wins, so the order is a part of the key.
+        assert_ne!(cache_inputs(&ab), cache_inputs(&ba));
+        assert_eq!(
+            cache_inputs(&ab),
+            cache_inputs(&inp(&["nixpkgs=/a", "nixpkgs=/b"]))
+        );
+
+        let cmd = nix_shell_command(&ab, None, None);
+        let includes = cmd
+            .get_args()
+            .zip(cmd.get_args().skip(1))
+            .filter(|(flag, _)| *flag == "-I")
+            .map(|(_, include)| include)
+            .collect::<Vec<_>>();
+        assert_eq!(includes, vec!["nixpkgs=/a", "nixpkgs=/b"]);
+    }
+
+    #[test]
+    fn test_key_env_file() {
+        let file = NamedTempFile::new().unwrap();
+        let fname = file.path().to_str().unwrap().to_string();
+        std::fs::write(&fname, b"FOO=1\n").unwrap();
+        let key = |extra: &[&str]| {
+            let mut args = vec!["--env-file", &fname];
+            args.extend(extra);
+            args.extend(&["-p", "hello"]);
+            key("/", &args)
+        };
+
+        let unkeyed = key(&[]);
+        let keyed = key(&["--key-env-file"]);
+        assert_ne!(unkeyed, keyed);
+
+        std::fs::write(&fname, b"FOO=2\n").unwrap();
+        assert_eq!(key(&[]), unkeyed);
+        assert_ne!(key(&["--key-env-file"]), keyed);
+    }
+
+    #[test]
+    fn test_default_shell_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let find = |override_: Option<&str>| {
+            default_shell_file(dir.path(), override_.map(OsString::from))
+                .map(|p| p.strip_prefix(dir.path()).unwrap().to_path_buf())
+        };
+
+        assert_eq!(find(None), None);
+        assert_eq!(find(Some("foo.nix")), Some("foo.nix".into()));
+
+        File::create(dir.path().join("default.nix")).unwrap();
+        assert_eq!(find(None), Some("default.nix".into()));
+
+        File::create(dir.path().join("shell.nix")).unwrap();
+        assert_eq!(find(None), Some("shell.nix".into()));
+        assert_eq!(find(Some("foo.nix")), Some("foo.nix".into()));
+    }
+
+    #[test]
+    fn test_preload_path() {
+        let (lib, dir) =
+            preload_path(Path::new("/nix/store/x/trace-nix.so")).unwrap();
+        assert_eq!(lib, Path::new("/nix/store/x/trace-nix.so"));
+        assert!(dir.is_none());
+
+        let tmp = tempfile::tempdir().unwrap();
+        for name in &["with space", "with:colon"] {
+            let real = tmp.path().join(name).join("trace-nix.so");
+            std::fs::create_dir_all(real.parent().unwrap()).unwrap();
+            std::fs::write(&real, "").unwrap();
+            let (lib, dir) = preload_path(&real).unwrap();
+            assert!(!lib
+                .as_os_str()
+                .as_bytes()
+                .iter()
+                .any(|&b| b == b' ' || b == b':'));
+            assert_eq!(
+                std::fs::canonicalize(&lib).unwrap(),
+                std::fs::canonicalize(&real).unwrap()
+            );
+            drop(dir);
+            assert!(!lib.exists());
+        }
+    }
+
+    #[test]
+    fn test_remove_internal_vars() {
+        let mut vars = env(&[
+            ("TRACE_NIX", "/tmp/trace"),
+            ("LD_PRELOAD", "/lib/a.so /nix/trace-nix.so:/lib/b.so"),
+            ("FOO", "bar"),
+        ]);
+        remove_internal_vars(&mut vars, "/nix/trace-nix.so");
+        assert_eq!(
+            vars,
+            env(&[("LD_PRELOAD", "/lib/a.so /lib/b.so"), ("FOO", "bar")])
+        );
+
+        let mut vars = env(&[("LD_PRELOAD", "/nix/trace-nix.so")]);
+        remove_internal_vars(&mut vars, "/nix/trace-nix.so");
+        assert_eq!(vars, env(&[]));
+    }
+
+    #[test]
+    fn test_print_args() {
+        let mut script = NamedTempFile::new().unwrap();
+        script
+            .write_all(
+                b"#! /usr/bin/env cached-nix-shell\n\
+                  #! nix-shell -i lua -p lua\n\
+                  #! nix-shell --pure -I nixpkgs=./nixpkgs\n",
+            )
+            .unwrap();
+        let printed =
+            print_args(vec![script.path().as_os_str().to_owned()]).unwrap();
+        assert!(printed.contains("packages: true,"));
+        assert!(printed.contains("pure: true,"));
+        assert!(printed.contains("interpreter: \"lua\","));
+        assert!(printed.contains("run: InteractiveShell,"));
+        assert!(printed.contains("\"-I\",\n        \"nixpkgs=./nixpkgs\","));
+
+        let printed = print_args(
+            ["-p", "hello", "--run", "hello"]
+                .iter()
+                .map(OsString::from)
+                .collect(),
+        )
+        .unwrap();
+        assert!(printed.contains("Shell(\n        \"hello\",\n    ),"));
+    }
+
+    #[test]
+    fn test_describe() {
+        let parse = |args: &[&str]| {
+            Args::parse(args.iter().map(OsString::from).collect(), false)
+                .unwrap()
+        };
+        let pwd = OsStr::new("/home/user/project");
+        assert_eq!(
+            describe(&parse(&["-p", "hello", "lua"]), pwd),
+            "-p hello lua"
+        );
+        assert_eq!(
+            describe(&parse(&["./shell.nix"]), pwd),
+            "/home/user/project/shell.nix"
+        );
+        assert_eq!(describe(&parse(&[]), pwd), "/home/user/project");
+    }
+
+    #[test]
+    fn test_merge_env_path_precedence() {
+        let merged = merge_env(
+            env(&[("PATH", "/nix/store/hello/bin"), ("FOO", "shell")]),
+            env(&[("PATH", "/home/user/bin:/usr/bin"), ("FOO", "caller")]),
+        );
+        assert_eq!(
+            merged[OsStr::new("PATH")],
+            "/nix/store/hello/bin:/home/user/bin:/usr/bin"
+        );
+        assert_eq!(merged[OsStr::new("FOO")], "shell");
+        assert_eq!(merged[OsStr::new("IN_NIX_SHELL")], "impure");
     }
-    run_from_args(std::env::args_os().skip(1).collect());
 }
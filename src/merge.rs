@@ -0,0 +1,116 @@
+//! `--merge-file FILE`: one shell made of several shell files.
+//!
+//! nix-shell itself only takes one file, so the files are combined by a
+//! generated expression, passed with `--expr`.  Each file is imported and
+//! called like nix-shell would call it (with the `--arg`s it accepts), and
+//! the results are merged with `mkShell`: their inputs through
+//! `inputsFrom`, and their plain variables (strings, numbers, booleans and
+//! paths) with later files taking precedence.  The files are read during
+//! the evaluation, so they are in the trace like any shell file.
+
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+/// Attributes of the merged shells that aren't variables of the user, or
+/// that `mkShell` sets itself.
+const SKIPPED_ATTRS: &[&str] = &[
+    "args",
+    "builder",
+    "name",
+    "nobuildPhase",
+    "outputs",
+    "phases",
+    "shellHook",
+    "stdenv",
+    "system",
+];
+
+/// The expression that merges `files`, which are absolute.
+pub fn expression(files: &[PathBuf]) -> OsString {
+    let files = files
+        .iter()
+        .map(|x| format!("(/. + {})", nix_string(x.as_os_str().as_bytes())))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let skipped = SKIPPED_ATTRS
+        .iter()
+        .map(|x| format!("\"{}\"", x))
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!(
+        r#"args@{{ ... }}:
+let
+  pkgs = import <nixpkgs> {{ }};
+  call = file:
+    let f = import file;
+    in if builtins.isFunction f
+      then f (builtins.intersectAttrs (builtins.functionArgs f) args)
+      else f;
+  shells = map call [ {files} ];
+  isValue = x: builtins.isString x || builtins.isInt x
+    || builtins.isFloat x || builtins.isBool x || builtins.isPath x;
+  skipped = [ {skipped} ];
+  vars = shell: pkgs.lib.filterAttrs
+    (k: v: isValue v && !builtins.elem k skipped) shell.drvAttrs;
+  env = builtins.foldl' (acc: shell: acc // vars shell) {{ }} shells;
+in
+pkgs.mkShell (env // {{ inputsFrom = shells; }})
+"#,
+        files = files,
+        skipped = skipped
+    )
+    .into()
+}
+
+/// A nix string literal.  Paths aren't always valid path literals (e.g.
+/// with spaces), so they are written as strings appended to `/.`.
+fn nix_string(s: &[u8]) -> String {
+    let s = String::from_utf8_lossy(s);
+    let mut res = String::from("\"");
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' | '\\' => {
+                res.push('\\');
+                res.push(c);
+            }
+            '$' if chars.peek() == Some(&'{') => res.push_str("\\$"),
+            _ => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_nix_string() {
+        assert_eq!(nix_string(b"/src/a.nix"), r#""/src/a.nix""#);
+        assert_eq!(
+            nix_string(br#"/my dir/"q"\${x}$y.nix"#),
+            r#""/my dir/\"q\"\\\${x}$y.nix""#
+        );
+    }
+
+    #[test]
+    fn test_expression() {
+        let expr = expression(&[
+            PathBuf::from("/src/a.nix"),
+            PathBuf::from("/src/my b.nix"),
+        ]);
+        let expr = expr.to_str().unwrap();
+        assert!(expr.starts_with("args@{ ... }:\n"));
+        assert!(
+            expr.contains(r#"shells = map call [ (/. + "/src/a.nix") (/. + "/src/my b.nix") ];"#),
+            "{}",
+            expr
+        );
+        assert!(
+            expr.ends_with("pkgs.mkShell (env // { inputsFrom = shells; })\n")
+        );
+    }
+}
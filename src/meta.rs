@@ -0,0 +1,147 @@
+//! Human-readable metadata of cache entries, shown by `--cache-info`
+//!
+//! Stored in `HASH.meta` as JSON.  It is informational only: the entry is
+//! looked up and validated using the hash and its trace, never the metadata.
+
+use serde_json::{json, Value};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, PartialEq)]
+pub struct Meta {
+    /// The target file or the package list
+    pub description: String,
+    /// When the entry was created, in seconds since the Unix epoch
+    pub time: u64,
+}
+
+impl Meta {
+    pub fn new(description: String) -> Meta {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        Meta { description, time }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        json!({"description": self.description, "time": self.time})
+            .to_string()
+            .into_bytes()
+    }
+
+    pub fn load(data: &[u8]) -> Option<Meta> {
+        let value: Value = serde_json::from_slice(data).ok()?;
+        Some(Meta {
+            description: value.get("description")?.as_str()?.to_string(),
+            time: value.get("time")?.as_u64()?,
+        })
+    }
+}
+
+/// List cache entries in the given directory, one per line.
+pub fn list(dir: &Path, verbose: bool) -> String {
+    let mut hashes = std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            if path.extension()? != "env" {
+                return None;
+            }
+            Some(path.file_stem()?.to_str()?.to_string())
+        })
+        .collect::<Vec<_>>();
+    hashes.sort();
+
+    let mut result = String::new();
+    for hash in hashes {
+        if verbose {
+            result.push_str(&describe(dir, &hash));
+        } else {
+            result.push_str(&hash);
+        }
+        result.push('\n');
+    }
+    result
+}
+
+/// The hash of an entry, when it was created and for what, e.g.
+/// `HASH  2020-09-13 12:26 UTC  -p hello`.
+pub fn describe(dir: &Path, hash: &str) -> String {
+    let meta = std::fs::read(crate::entry::path(dir, hash, crate::entry::META))
+        .ok()
+        .and_then(|data| Meta::load(&data));
+    match meta {
+        Some(meta) => format!(
+            "{}  {}  {}",
+            hash,
+            format_time(meta.time),
+            meta.description
+        ),
+        None => format!("{}  (no metadata)", hash),
+    }
+}
+
+/// Format a Unix timestamp as `YYYY-MM-DD HH:MM UTC`.
+fn format_time(time: u64) -> String {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (time / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        time % 86400 / 3600,
+        time % 3600 / 60
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let meta = Meta {
+            description: "/home/user/project/shell.nix".into(),
+            time: 1_600_000_000,
+        };
+        assert_eq!(Meta::load(&meta.serialize()), Some(meta));
+        assert_eq!(Meta::load(b"garbage"), None);
+    }
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(0), "1970-01-01 00:00 UTC");
+        assert_eq!(format_time(951_782_400), "2000-02-29 00:00 UTC");
+        assert_eq!(format_time(1_600_000_000), "2020-09-13 12:26 UTC");
+    }
+
+    #[test]
+    fn test_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let meta = Meta {
+            description: "-p hello".into(),
+            time: 0,
+        };
+        std::fs::write(dir.path().join("aaa.env"), b"").unwrap();
+        std::fs::write(dir.path().join("aaa.meta"), meta.serialize()).unwrap();
+        std::fs::write(dir.path().join("bbb.env"), b"").unwrap();
+        std::fs::write(dir.path().join("bbb.trace"), b"").unwrap();
+
+        assert_eq!(list(dir.path(), false), "aaa\nbbb\n");
+        assert_eq!(
+            list(dir.path(), true),
+            "aaa  1970-01-01 00:00 UTC  -p hello\nbbb  (no metadata)\n"
+        );
+    }
+}
@@ -2,13 +2,12 @@ use bytelines::ByteLinesReader;
 use regex::bytes::Regex;
 use std::ffi::{OsStr, OsString};
 use std::fs::File;
-use std::os::unix::ffi::OsStrExt;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::sync::OnceLock;
 
 /// Parse script in the same way as nix-shell does.
 /// Reference: https://github.com/NixOS/nix/blob/2.3.1/src/nix-build/nix-build.cc#L113-L126
 pub fn parse_script(fname: &OsStr) -> Option<Vec<OsString>> {
-    let re = Regex::new(r#"^#!\s*nix-shell (.*)$"#).unwrap();
-
     let f = File::open(fname).ok()?;
     let reader = std::io::BufReader::new(&f);
     let mut lines = reader.byte_lines();
@@ -21,8 +20,8 @@ pub fn parse_script(fname: &OsStr) -> Option<Vec<OsString>> {
 
     while let Some(line) = lines.next() {
         let line = line.unwrap();
-        if let Some(m) = re.captures(line) {
-            let mut items = shellwords(m.get(1).unwrap().as_bytes())
+        if let Some(m) = directive(line) {
+            let mut items = shellwords(m)
                 .into_iter()
                 .map(|x| OsStr::from_bytes(&x).to_os_string())
                 .collect::<Vec<OsString>>();
@@ -33,8 +32,76 @@ pub fn parse_script(fname: &OsStr) -> Option<Vec<OsString>> {
     Some(result)
 }
 
+/// The arguments of a `#! nix-shell ARGS` line.  A trailing `\r` of CRLF line
+/// endings is dropped, so that it doesn't end up in the last argument.
+pub fn directive(line: &[u8]) -> Option<&[u8]> {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    static RE: OnceLock<Regex> = OnceLock::new();
+    let re = RE.get_or_init(|| Regex::new(r#"^#!\s*nix-shell (.*)$"#).unwrap());
+    Some(re.captures(line)?.get(1)?.as_bytes())
+}
+
+/// Whether `shellwords` would end inside double quotes.
+pub fn unterminated_quote(s: &[u8]) -> bool {
+    let mut quoted = false;
+    let mut it = s.iter();
+    while let Some(c) = it.next() {
+        match c {
+            b'"' => quoted = !quoted,
+            b'\\' => {
+                it.next();
+            }
+            _ => {}
+        }
+    }
+    quoted
+}
+
+/// Split the options of `#!/path/to/cached-nix-shell -i bash -p hello`, which
+/// the kernel passes as a single argument (as does `env -S` where it isn't
+/// supported), the way `env -S` would: words are separated by whitespace,
+/// and may be quoted with single or double quotes, or escaped with `\`.
+/// `None` if `arg` doesn't look like such options, or a quote isn't closed.
+pub fn split_combined(arg: &OsStr) -> Option<Vec<OsString>> {
+    let arg = arg.as_bytes();
+    if arg.first() != Some(&b'-') || !arg.iter().any(u8::is_ascii_whitespace) {
+        return None;
+    }
+    let mut res = Vec::new();
+    let mut cur: Option<Vec<u8>> = None;
+    let mut it = arg.iter().copied();
+    while let Some(c) = it.next() {
+        match c {
+            c if c.is_ascii_whitespace() => res.extend(cur.take()),
+            b'\'' => {
+                let word = cur.get_or_insert_with(Vec::new);
+                loop {
+                    match it.next()? {
+                        b'\'' => break,
+                        c => word.push(c),
+                    }
+                }
+            }
+            b'"' => {
+                let word = cur.get_or_insert_with(Vec::new);
+                loop {
+                    match it.next()? {
+                        b'"' => break,
+                        b'\\' => word.push(it.next()?),
+                        c => word.push(c),
+                    }
+                }
+            }
+            b'\\' => cur.get_or_insert_with(Vec::new).push(it.next()?),
+            c => cur.get_or_insert_with(Vec::new).push(c),
+        }
+    }
+    res.extend(cur);
+    Some(res.into_iter().map(OsString::from_vec).collect())
+}
+
 /// Reference: https://github.com/NixOS/nix/blob/2.3.1/src/nix-build/nix-build.cc#L26-L68
-fn shellwords(s: &[u8]) -> Vec<Vec<u8>> {
+pub fn shellwords(s: &[u8]) -> Vec<Vec<u8>> {
     let whitespace = Regex::new(r#"^(\s+).*"#).unwrap();
     let mut res = Vec::new();
     let mut it = 0;
@@ -75,7 +142,8 @@ fn shellwords(s: &[u8]) -> Vec<Vec<u8>> {
 
 #[cfg(test)]
 mod tests {
-    use super::shellwords;
+    use super::{parse_script, shellwords, split_combined, unterminated_quote};
+    use std::ffi::{OsStr, OsString};
     macro_rules! v {
         ( $($a:literal),* ) => {{
             vec![ $( Vec::<u8>::from($a as &[_])),* ]
@@ -107,4 +175,76 @@ mod tests {
             v![b"foo", b"bar baz", b"qoox"],
         );
     }
+
+    #[test]
+    fn test_crlf() {
+        let lines = [
+            "#!/usr/bin/env nix-shell",
+            "#! nix-shell -i bash",
+            r#"#! nix-shell -p hello "python3.withPackages (p: [])""#,
+            "hello",
+        ];
+        let parse = |sep: &str| {
+            let file = tempfile::NamedTempFile::new().unwrap();
+            std::fs::write(file.path(), lines.join(sep) + sep).unwrap();
+            parse_script(file.path().as_os_str()).unwrap()
+        };
+        let args = parse("\n");
+        assert_eq!(args.last().unwrap(), "python3.withPackages (p: [])");
+        assert_eq!(parse("\r\n"), args);
+    }
+
+    #[test]
+    fn test_empty_word() {
+        assert_eq!(
+            shellwords(br#"--option foo "" -p hello"#),
+            v![b"--option", b"foo", b"", b"-p", b"hello"],
+        );
+    }
+
+    #[test]
+    fn test_split_combined() {
+        let split = |arg: &str| split_combined(OsStr::new(arg));
+        let words = |words: &[&str]| {
+            Some(words.iter().map(OsString::from).collect::<Vec<_>>())
+        };
+        assert_eq!(
+            split("-i bash -p hello"),
+            words(&["-i", "bash", "-p", "hello"])
+        );
+        assert_eq!(
+            split(r#"-i python3 -p 'python3.withPackages (p: [p.requests])'"#),
+            words(&[
+                "-i",
+                "python3",
+                "-p",
+                "python3.withPackages (p: [p.requests])"
+            ])
+        );
+        assert_eq!(
+            split(r#"-i  "python3 -W \"ignore\"" --argstr x a\ b"#),
+            words(&["-i", r#"python3 -W "ignore""#, "--argstr", "x", "a b"])
+        );
+        assert_eq!(split("-p hello ''"), words(&["-p", "hello", ""]));
+        assert_eq!(split("--pure"), None);
+        assert_eq!(split("shell.nix other.nix"), None);
+        assert_eq!(split("-p 'hello"), None);
+
+        let args = split("-i bash -p hello").unwrap();
+        let args = crate::args::Args::parse(args, true).unwrap();
+        assert_eq!(args.interpreter, "bash");
+        assert!(args.packages);
+        assert_eq!(args.rest, vec!["hello"]);
+    }
+
+    #[test]
+    fn test_unterminated_quote() {
+        assert!(!unterminated_quote(
+            br#"-i bash -p "python3.withPackages (p: [])""#
+        ));
+        assert!(!unterminated_quote(br#"-p \"hello"#));
+        assert!(!unterminated_quote(br#"-i "python3 -W \"ignore\"""#));
+        assert!(unterminated_quote(br#"-i "python3 -p hello"#));
+        assert!(unterminated_quote(br#"-p "a\""#));
+    }
 }
@@ -0,0 +1,379 @@
+//! Local cache statistics, enabled by `CACHED_NIX_SHELL_STATS=1`
+//!
+//! Each invocation appends a line to `stats.log` in the cache directory:
+//! `TIMESTAMP hit` or `TIMESTAMP miss BUILD_MILLIS`.  Lines are written with a
+//! single `write()` to a file opened with `O_APPEND`, so concurrent writers
+//! don't interleave.  When the log grows over `MAX_LOG_SIZE`, it is rotated to
+//! `stats.log.old`, so at most two logs are kept.  Writers hold a lock on
+//! the log itself, so it is only rotated once, and nothing is written to a
+//! log that was rotated in the meantime.
+
+use nix::fcntl::{flock, FlockArg};
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAX_LOG_SIZE: u64 = 1 << 20;
+
+pub enum Record {
+    Hit,
+    Miss(Duration),
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    pub hits: u64,
+    pub misses: u64,
+    /// Build times of the misses, in the order of the log
+    pub build_times: Vec<Duration>,
+}
+
+pub fn enabled() -> bool {
+    crate::env_flag("CACHED_NIX_SHELL_STATS")
+}
+
+pub fn log_path() -> std::io::Result<PathBuf> {
+    let dir = crate::cache_dir();
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir.join("stats.log"))
+}
+
+/// Append a record to the stats log, if enabled.  Errors are reported, but
+/// not fatal.
+pub fn record(rec: Record) {
+    if !enabled() {
+        return;
+    }
+    if let Err(e) = log_path().and_then(|path| append(&path, &rec)) {
+        warning!("can't write stats: {}", e);
+    }
+}
+
+pub fn append(path: &Path, rec: &Record) -> std::io::Result<()> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let line = match rec {
+        Record::Hit => format!("{} hit\n", timestamp),
+        Record::Miss(time) => {
+            format!("{} miss {}\n", timestamp, time.as_millis())
+        }
+    };
+    open_locked(path)?.write_all(line.as_bytes())
+}
+
+/// Open the log for appending, locked until the file is closed, and rotate
+/// it first if it's too big.
+fn open_locked(path: &Path) -> std::io::Result<File> {
+    loop {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        flock(file.as_raw_fd(), FlockArg::LockExclusive)
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        let meta = file.metadata()?;
+        let current = std::fs::metadata(path)
+            .is_ok_and(|m| (m.dev(), m.ino()) == (meta.dev(), meta.ino()));
+        if !current {
+            // Rotated by another writer while we waited.
+            continue;
+        }
+        if meta.len() <= MAX_LOG_SIZE {
+            return Ok(file);
+        }
+        std::fs::rename(path, old_path(path))?;
+    }
+}
+
+/// Aggregate both current and rotated logs.  Malformed lines are skipped.
+pub fn summarize(path: &Path) -> Summary {
+    let mut summary = Summary::default();
+    for path in &[old_path(path), path.to_path_buf()] {
+        let data = std::fs::read_to_string(path).unwrap_or_default();
+        for line in data.lines() {
+            let fields = line.split(' ').collect::<Vec<_>>();
+            match fields[..] {
+                [_, "hit"] => summary.hits += 1,
+                [_, "miss", millis] => {
+                    if let Ok(millis) = millis.parse() {
+                        summary.misses += 1;
+                        summary.build_times.push(Duration::from_millis(millis));
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+    summary
+}
+
+impl Summary {
+    pub fn hit_rate(&self) -> Option<f64> {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            None
+        } else {
+            Some(self.hits as f64 / total as f64)
+        }
+    }
+
+    pub fn total_build_time(&self) -> Duration {
+        self.build_times.iter().sum()
+    }
+
+    pub fn average_build_time(&self) -> Option<Duration> {
+        if self.misses == 0 {
+            None
+        } else {
+            Some(self.total_build_time() / self.misses as u32)
+        }
+    }
+
+    pub fn median_build_time(&self) -> Option<Duration> {
+        let mut times = self.build_times.clone();
+        times.sort();
+        let mid = times.len() / 2;
+        match times.len() {
+            0 => None,
+            n if n % 2 == 1 => Some(times[mid]),
+            _ => Some((times[mid - 1] + times[mid]) / 2),
+        }
+    }
+
+    /// `--stats --json`: the summary and the size of the cache.  Times are
+    /// in seconds; rates and times are `null` without records.
+    pub fn json(&self, cache: &CacheSize) -> serde_json::Value {
+        let secs = |x: Option<Duration>| x.map(|x| x.as_secs_f64());
+        serde_json::json!({
+            "hits": self.hits,
+            "misses": self.misses,
+            "hit_rate": self.hit_rate(),
+            "average_build_time": secs(self.average_build_time()),
+            "median_build_time": secs(self.median_build_time()),
+            "cache_entries": cache.entries,
+            "cache_bytes": cache.bytes,
+        })
+    }
+
+    pub fn print(&self) {
+        println!("hits: {}", self.hits);
+        println!("misses: {}", self.misses);
+        if let Some(rate) = self.hit_rate() {
+            println!("hit rate: {:.1}%", rate * 100.0);
+        }
+        if let Some(time) = self.average_build_time() {
+            println!("average build time: {:.2}s", time.as_secs_f64());
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct CacheSize {
+    /// Entries with an environment, as listed by `--cache-info`
+    pub entries: u64,
+    /// Size of the files in the cache directory, including `blobs/` and
+    /// the logs.  Hard links are counted once, symlinks aren't followed.
+    pub bytes: u64,
+}
+
+pub fn cache_size(dir: &Path) -> CacheSize {
+    let mut size = CacheSize::default();
+    let mut seen = HashSet::new();
+    // Blobs aren't entries, only hard links to them in the top directory are.
+    for (top, dir) in [(true, dir.to_path_buf()), (false, dir.join("blobs"))] {
+        for entry in std::fs::read_dir(dir).into_iter().flatten().flatten() {
+            let path = entry.path();
+            let meta = match path.symlink_metadata() {
+                Ok(meta) if meta.is_file() => meta,
+                _ => continue,
+            };
+            if top && path.extension().is_some_and(|x| x == "env") {
+                size.entries += 1;
+            }
+            if seen.insert((meta.dev(), meta.ino())) {
+                size.bytes += meta.len();
+            }
+        }
+    }
+    size
+}
+
+fn old_path(path: &Path) -> PathBuf {
+    let mut old = path.as_os_str().to_os_string();
+    old.push(".old");
+    PathBuf::from(old)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_summarize() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats.log");
+        append(&path, &Record::Hit).unwrap();
+        append(&path, &Record::Miss(Duration::from_millis(1000))).unwrap();
+        append(&path, &Record::Hit).unwrap();
+        append(&path, &Record::Miss(Duration::from_millis(3000))).unwrap();
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"garbage\n1 miss nan\n")
+            .unwrap();
+
+        let summary = summarize(&path);
+        assert_eq!(
+            summary,
+            Summary {
+                hits: 2,
+                misses: 2,
+                build_times: vec![
+                    Duration::from_millis(1000),
+                    Duration::from_millis(3000)
+                ],
+            }
+        );
+        assert_eq!(summary.total_build_time(), Duration::from_millis(4000));
+        assert_eq!(summary.hit_rate(), Some(0.5));
+        assert_eq!(summary.average_build_time(), Some(Duration::from_secs(2)));
+        assert_eq!(summarize(&dir.path().join("nonexistent")).hit_rate(), None);
+    }
+
+    #[test]
+    fn test_concurrent_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats.log");
+        let threads = (0..8)
+            .map(|i| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        let rec = if i % 2 == 0 {
+                            Record::Hit
+                        } else {
+                            Record::Miss(Duration::from_millis(10))
+                        };
+                        append(&path, &rec).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        let summary = summarize(&path);
+        assert_eq!(summary.hits, 400);
+        assert_eq!(summary.misses, 400);
+        assert_eq!(summary.total_build_time(), Duration::from_millis(4000));
+    }
+
+    #[test]
+    fn test_median() {
+        let summary = |millis: &[u64]| Summary {
+            hits: 0,
+            misses: millis.len() as u64,
+            build_times: millis
+                .iter()
+                .map(|&x| Duration::from_millis(x))
+                .collect(),
+        };
+        assert_eq!(summary(&[]).median_build_time(), None);
+        assert_eq!(
+            summary(&[9000, 1000, 2000]).median_build_time(),
+            Some(Duration::from_millis(2000))
+        );
+        assert_eq!(
+            summary(&[9000, 1000, 2000, 4000]).median_build_time(),
+            Some(Duration::from_millis(3000))
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path();
+        let path = cache.join("stats.log");
+        std::fs::write(
+            &path,
+            "1 hit\n2 hit\n3 hit\n4 miss 1000\n5 miss 5000\n6 miss 3000\n",
+        )
+        .unwrap();
+        std::fs::write(cache.join("aaa.env"), "A=1\0").unwrap();
+        std::fs::write(cache.join("aaa.inputs"), "x").unwrap();
+        std::os::unix::fs::symlink("/nonexistent", cache.join("aaa.drv"))
+            .unwrap();
+        std::fs::create_dir(cache.join("blobs")).unwrap();
+        std::fs::hard_link(cache.join("aaa.env"), cache.join("blobs/b.env"))
+            .unwrap();
+
+        let size = cache_size(cache);
+        let log_size = std::fs::metadata(&path).unwrap().len();
+        assert_eq!(
+            size,
+            CacheSize {
+                entries: 1,
+                bytes: 5 + log_size
+            }
+        );
+
+        assert_eq!(
+            summarize(&path).json(&size),
+            serde_json::json!({
+                "hits": 3,
+                "misses": 3,
+                "hit_rate": 0.5,
+                "average_build_time": 3.0,
+                "median_build_time": 3.0,
+                "cache_entries": 1,
+                "cache_bytes": 5 + log_size,
+            })
+        );
+
+        let empty = Summary::default().json(&CacheSize::default());
+        assert_eq!(empty["hit_rate"], serde_json::Value::Null);
+        assert_eq!(empty["median_build_time"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats.log");
+        std::fs::write(&path, "1 hit\n".repeat(MAX_LOG_SIZE as usize / 5))
+            .unwrap();
+        append(&path, &Record::Hit).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+        assert!(old_path(&path).exists());
+    }
+
+    #[test]
+    fn test_concurrent_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats.log");
+        let lines = MAX_LOG_SIZE as usize / 5;
+        std::fs::write(&path, "1 hit\n".repeat(lines)).unwrap();
+        let threads = (0..8)
+            .map(|_| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        append(&path, &Record::Hit).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        // Rotated once, and no record is lost.
+        assert_eq!(summarize(&path).hits, lines as u64 + 800);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap().lines().count(),
+            800
+        );
+    }
+}
@@ -0,0 +1,182 @@
+//! `--timeout DURATION`: kill the command if it runs for too long, e.g. when
+//! a test hangs in CI.
+//!
+//! The command runs in its own process group, so that the processes it
+//! starts are killed too.  The group gets the terminal if cached-nix-shell
+//! had it, so the command can read from it, and `SIGINT`, `SIGTERM` and
+//! `SIGHUP` sent to cached-nix-shell are passed on to the group.  Without
+//! `--timeout`, cached-nix-shell is replaced by the command instead.
+
+use nix::sys::signal::{
+    killpg, sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal,
+};
+use nix::unistd::{getpgrp, tcsetpgrp, Pid};
+use std::convert::TryFrom;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, ExitStatus};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::{Duration, Instant};
+
+/// How often to check whether the command has exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Signals passed on to the command.
+const FORWARDED: &[Signal] = &[Signal::SIGINT, Signal::SIGTERM, Signal::SIGHUP];
+
+/// The last forwarded signal received, or 0.
+static RECEIVED: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn receive(signal: i32) {
+    RECEIVED.store(signal, Ordering::SeqCst);
+}
+
+/// Set the handler of `signal`, and return the previous one.
+fn set_handler(signal: Signal, handler: SigHandler) -> Option<SigAction> {
+    let action = SigAction::new(handler, SaFlags::SA_RESTART, SigSet::empty());
+    // SAFETY: the handlers are `SIG_IGN`, previous handlers, or `receive`,
+    // which only stores to an atomic.
+    unsafe { sigaction(signal, &action) }.ok()
+}
+
+/// Run `cmd`, killing it after `timeout`.  Returns `None` if it was killed.
+/// `foreground`: stdin is the terminal, and we are in its foreground group.
+pub fn run(
+    cmd: &mut Command,
+    timeout: Duration,
+    foreground: bool,
+) -> std::io::Result<Option<ExitStatus>> {
+    cmd.process_group(0);
+    if foreground {
+        // SAFETY: only async-signal-safe calls, in the child before exec.
+        // Done there rather than after spawning, so that the command can't
+        // read before it has the terminal.
+        unsafe {
+            cmd.pre_exec(|| {
+                let old = set_handler(Signal::SIGTTOU, SigHandler::SigIgn);
+                let _ = tcsetpgrp(0, getpgrp());
+                if let Some(old) = old {
+                    let _ = sigaction(Signal::SIGTTOU, &old);
+                }
+                Ok(())
+            })
+        };
+    }
+    let previous = FORWARDED
+        .iter()
+        .map(|&x| (x, set_handler(x, SigHandler::Handler(receive))))
+        .collect::<Vec<_>>();
+    let result = wait(cmd, timeout);
+    if foreground {
+        // We are in the background now: taking the terminal back would stop
+        // us with `SIGTTOU`.
+        let old = set_handler(Signal::SIGTTOU, SigHandler::SigIgn);
+        let _ = tcsetpgrp(0, getpgrp());
+        if let Some(old) = old {
+            // SAFETY: as in `set_handler`.
+            let _ = unsafe { sigaction(Signal::SIGTTOU, &old) };
+        }
+    }
+    for (signal, old) in previous {
+        if let Some(old) = old {
+            // SAFETY: as in `set_handler`.
+            let _ = unsafe { sigaction(signal, &old) };
+        }
+    }
+    result
+}
+
+fn wait(
+    cmd: &mut Command,
+    timeout: Duration,
+) -> std::io::Result<Option<ExitStatus>> {
+    let mut child = cmd.spawn()?;
+    let group = Pid::from_raw(child.id() as i32);
+    // A timeout too long to be represented never expires.
+    let deadline = Instant::now().checked_add(timeout);
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if let Ok(signal) = Signal::try_from(RECEIVED.swap(0, Ordering::SeqCst))
+        {
+            let _ = killpg(group, signal);
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            let _ = killpg(group, Signal::SIGKILL);
+            child.wait()?;
+            return Ok(None);
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    fn sh(script: &str) -> Command {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", script]);
+        cmd
+    }
+
+    #[test]
+    fn test_finished() {
+        let status =
+            run(&mut sh("exit 3"), Duration::from_secs(10), false).unwrap();
+        assert_eq!(status.and_then(|x| x.code()), Some(3));
+    }
+
+    #[test]
+    fn test_killed() {
+        let start = Instant::now();
+        let status =
+            run(&mut sh("sleep 10"), Duration::from_millis(200), false);
+        assert!(status.unwrap().is_none());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_killed_group() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("marker");
+        // The background process would create the marker after the timeout.
+        let script = format!("(sleep 1; touch '{}') & wait", marker.display());
+        let status = run(&mut sh(&script), Duration::from_millis(200), false);
+        assert!(status.unwrap().is_none());
+        std::thread::sleep(Duration::from_millis(1500));
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn test_forwarded_signal() {
+        // Signals are process-wide, so cached-nix-shell is played by this
+        // test in a child process.
+        if let Some(dir) = std::env::var_os(crate::test::CHILD_TEST_VAR) {
+            let dir = std::path::PathBuf::from(dir);
+            let script = format!(
+                "(sleep 1; touch '{}') & touch '{}'; wait",
+                dir.join("marker").display(),
+                dir.join("started").display(),
+            );
+            let status = run(&mut sh(&script), Duration::from_secs(10), false);
+            let status = status.unwrap().unwrap();
+            assert_eq!(status.signal(), Some(Signal::SIGTERM as i32));
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let mut parent = crate::test::child_test(
+            "timeout::test::test_forwarded_signal",
+            dir.path(),
+        );
+        while !dir.path().join("started").exists() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let pid = Pid::from_raw(parent.id() as i32);
+        nix::sys::signal::kill(pid, Signal::SIGTERM).unwrap();
+        assert!(parent.wait().unwrap().success());
+        std::thread::sleep(Duration::from_millis(1500));
+        assert!(!dir.path().join("marker").exists());
+    }
+}
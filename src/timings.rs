@@ -0,0 +1,76 @@
+//! `--timings`: how long cached-nix-shell spent looking up the cache and
+//! building the shell, printed to stderr even with `--quiet`.
+//!
+//! On a cache miss, the number of accesses nix made to files and the number
+//! of distinct paths among them hint at evaluations that read far more files
+//! than expected.
+
+use crate::trace::Trace;
+use std::time::Duration;
+
+/// The lookup, including the validation of the entry, if there is one.
+pub fn lookup(time: Duration, hit: bool) -> String {
+    let result = if hit { "hit" } else { "miss" };
+    format!(
+        "cached-nix-shell: timings: lookup {} ({})\n",
+        format_duration(time),
+        result
+    )
+}
+
+/// The build on a cache miss, and the trace of it, unless tracing is off.
+pub fn build(time: Duration, trace: Option<&Trace>) -> String {
+    let mut res = format!(
+        "cached-nix-shell: timings: build {}\n",
+        format_duration(time)
+    );
+    if let Some(trace) = trace {
+        res.push_str(&format!(
+            "cached-nix-shell: timings: traced {} accesses, {} paths\n",
+            trace.accesses(),
+            trace.paths().count()
+        ));
+    }
+    res
+}
+
+fn format_duration(time: Duration) -> String {
+    if time < Duration::from_secs(1) {
+        format!("{:.1}ms", time.as_secs_f64() * 1000.0)
+    } else {
+        format!("{:.2}s", time.as_secs_f64())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(
+            lookup(Duration::from_micros(2500), true),
+            "cached-nix-shell: timings: lookup 2.5ms (hit)\n"
+        );
+    }
+
+    #[test]
+    fn test_build() {
+        // Three files, one of them read twice, and a directory listing.
+        let trace = Trace::read(
+            &b"f/src/a.nix\0x\0f/src/b.nix\0y\0f/src/a.nix\0x\0d/src\0z\0f/src/c.nix\0-\0"[..],
+        )
+        .unwrap();
+        assert_eq!(
+            build(Duration::from_millis(3250), Some(&trace)),
+            concat!(
+                "cached-nix-shell: timings: build 3.25s\n",
+                "cached-nix-shell: timings: traced 5 accesses, 4 paths\n",
+            )
+        );
+        assert_eq!(
+            build(Duration::from_millis(3250), None),
+            "cached-nix-shell: timings: build 3.25s\n"
+        );
+    }
+}
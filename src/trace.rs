@@ -1,30 +1,127 @@
+use crate::path_clean::PathClean;
 use crypto::digest::Digest;
 use crypto::md5::Md5;
 use itertools::Itertools;
+use regex::bytes::Regex;
 use std::collections::BTreeMap;
 use std::ffi::{OsStr, OsString};
 use std::fs::{read_dir, File};
-use std::io::Read;
-use std::os::unix::ffi::OsStrExt;
+use std::io::{BufRead, Read};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+/// Key of the record that `serialize` puts first, with the number of records
+/// that follow, so that `load` notices a truncated file.  It looks like a
+/// `stat` of an empty path, so versions that don't know about it see a changed
+/// file and rebuild the entry, rather than fail.
+const HEADER: &[u8] = b"s";
+
+/// Top-level store paths mentioned in `value`, e.g. `/nix/store/HASH-name`
+/// in `/nix/store/HASH-name/bin:/usr/bin`.
+pub fn store_paths(value: &[u8]) -> impl Iterator<Item = &[u8]> {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    let re = RE.get_or_init(|| {
+        Regex::new(r"/nix/store/[0-9a-z]{32}-[A-Za-z0-9+._?=-]+").unwrap()
+    });
+    re.find_iter(value).map(|x| x.as_bytes())
+}
 
 /// Output of trace-nix.so, sorted and deduplicated.
 pub struct Trace {
     items: BTreeMap<Vec<u8>, Vec<u8>>,
+    /// Records read by `read`, including repeated accesses to the same path.
+    accesses: usize,
 }
 
 impl Trace {
-    pub fn load(vec: Vec<u8>) -> Trace {
-        let items = vec
-            .split(|&b| b == 0)
-            .filter(|&fname| !fname.is_empty()) // last entry has trailing NUL
-            .map(Vec::from)
-            .tuples::<(_, _)>()
-            .collect::<BTreeMap<Vec<u8>, Vec<u8>>>();
-        Trace { items }
+    /// Read records one by one, so only deduplicated items are kept in memory
+    /// regardless of how many times nix accessed the same file.
+    pub fn read(reader: impl BufRead) -> std::io::Result<Trace> {
+        let mut items = BTreeMap::new();
+        let mut accesses = 0;
+        let mut fields = reader
+            .split(0)
+            // serialized traces have a leading NUL
+            .filter(|field| field.as_ref().map_or(true, |f| !f.is_empty()));
+        while let (Some(k), Some(v)) = (fields.next(), fields.next()) {
+            items.insert(k?, v?);
+            accesses += 1;
+        }
+        Ok(Trace { items, accesses })
+    }
+
+    /// Read a trace stored by `serialize`.  Unlike `read`, an incomplete
+    /// trace is an error rather than a shorter list of dependencies, since
+    /// the entry would be reused although some of them changed.
+    pub fn load(mut reader: impl BufRead) -> Result<Trace, String> {
+        let mut data = Vec::new();
+        reader
+            .read_to_end(&mut data)
+            .map_err(|e| format!("can't read: {}", e))?;
+        if data.is_empty() {
+            return Ok(Trace {
+                items: BTreeMap::new(),
+                accesses: 0,
+            });
+        }
+        let fields = match data.split_first() {
+            Some((0, rest)) => rest.split(|&b| b == 0).collect::<Vec<_>>(),
+            _ => return Err("no leading NUL".to_string()),
+        };
+        if fields.len() % 2 != 0 {
+            return Err("truncated record".to_string());
+        }
+        let mut expected = None;
+        let mut items = BTreeMap::new();
+        for (i, pair) in fields.chunks(2).enumerate() {
+            let (k, v) = (pair[0], pair[1]);
+            if k == HEADER && i == 0 {
+                let count = std::str::from_utf8(v).ok();
+                expected = Some(
+                    count
+                        .and_then(|x| x.parse::<usize>().ok())
+                        .ok_or_else(|| "invalid record count".to_string())?,
+                );
+                continue;
+            }
+            match k.split_first() {
+                Some((b's' | b'f' | b'd', path)) if !path.is_empty() => (),
+                _ => {
+                    return Err(format!(
+                        "unexpected record {:?}",
+                        OsStr::from_bytes(k)
+                    ))
+                }
+            }
+            items.insert(k.to_vec(), v.to_vec());
+        }
+        // Traces stored by older versions have no header.
+        if let Some(expected) = expected {
+            if items.len() != expected {
+                return Err(format!(
+                    "expected {} records, got {}",
+                    expected,
+                    items.len()
+                ));
+            }
+        }
+        Ok(Trace { items, accesses: 0 })
+    }
+
+    /// How many accesses of nix were read (`--timings`); 0 for a trace
+    /// loaded from the cache.
+    pub fn accesses(&self) -> usize {
+        self.accesses
     }
 
     pub fn serialize(&self) -> Vec<u8> {
         let mut result = Vec::<u8>::new();
+        result.push(0);
+        result.extend(HEADER);
+        result.push(0);
+        result.extend(self.items.len().to_string().as_bytes());
         for (a, b) in self.items.iter() {
             result.push(0);
             result.extend(a);
@@ -34,6 +131,122 @@ impl Trace {
         result
     }
 
+    /// Record the current state of a file or a directory with all its
+    /// contents, as if nix read them.  A missing file is recorded too, so
+    /// creating it later invalidates the trace.
+    pub fn add_path(&mut self, path: &Path) {
+        let kind = if path.is_dir() { b'd' } else { b'f' };
+        let key = [&[kind], path.as_os_str().as_bytes()].concat();
+        let value = item_value(&key);
+        self.items.insert(key, value.into_vec());
+        if kind == b'd' {
+            let entries = read_dir(path).into_iter().flatten();
+            for entry in entries.filter_map(Result::ok) {
+                self.add_path(&entry.path());
+            }
+        }
+    }
+
+    /// Record a symlink, the symlinks it points through, and the file it
+    /// finally resolves to, so that both retargeting a link and editing the
+    /// destination invalidate the trace.  Does nothing if `path` isn't a
+    /// symlink.
+    pub fn add_symlink(&mut self, path: &Path) {
+        let mut path = path.to_path_buf();
+        let mut linked = false;
+        // Same limit as the kernel, in case of a loop.
+        for _ in 0..40 {
+            let target = match std::fs::read_link(&path) {
+                Ok(target) => target,
+                Err(_) => break,
+            };
+            let key = [&b"s"[..], path.as_os_str().as_bytes()].concat();
+            let value = item_value(&key);
+            self.items.insert(key, value.into_vec());
+            linked = true;
+            path = match path.parent() {
+                Some(parent) => parent.join(target).clean(),
+                None => target,
+            };
+        }
+        // A directory is read through its default.nix, which nix traces.
+        if linked && !path.is_dir() {
+            self.add_path(&path);
+        }
+    }
+
+    /// Forget paths outside of `dirs`, except for the immutable nix store
+    /// (`--trace-scope`).
+    pub fn retain_scope(&mut self, dirs: &[PathBuf]) {
+        self.items.retain(|k, _| {
+            let path = Path::new(OsStr::from_bytes(&k[1..]));
+            path.starts_with("/nix/store")
+                || dirs.iter().any(|dir| path.starts_with(dir))
+        });
+    }
+
+    /// Forget everything out of the nix store, and record the store paths
+    /// that `values` (of the captured variables) refer to, so that only
+    /// their removal, e.g. by the garbage collector, invalidates the trace
+    /// (`--trace-filter-store-only`).
+    pub fn retain_store_closure<'a>(
+        &mut self,
+        values: impl IntoIterator<Item = &'a OsStr>,
+    ) {
+        self.retain_scope(&[]);
+        for value in values {
+            for path in store_paths(value.as_bytes()) {
+                let key = [b"s", path].concat();
+                let value = item_value(&key);
+                self.items.insert(key, value.into_vec());
+            }
+        }
+    }
+
+    /// Forget listings of directories more than `depth` levels below `root`
+    /// (`--trace-dir-depth`); files read there are still recorded.
+    pub fn limit_dir_depth(&mut self, root: &Path, depth: usize) {
+        self.items.retain(|k, _| {
+            let path = Path::new(OsStr::from_bytes(&k[1..]));
+            k[0] != b'd'
+                || path
+                    .strip_prefix(root)
+                    .map_or(true, |rel| rel.components().count() <= depth)
+        });
+    }
+
+    /// Paths of all recorded files, symlinks and directories.
+    pub fn paths(&self) -> impl Iterator<Item = &OsStr> {
+        self.items.keys().map(|k| OsStr::from_bytes(&k[1..]))
+    }
+
+    /// List recorded paths for `--list-deps`; `verbose` also shows how nix
+    /// accessed each of them.
+    pub fn list(&self, verbose: bool) -> String {
+        let mut res = Vec::new();
+        for k in self.items.keys() {
+            if verbose {
+                res.extend(format!("{:8}", access(k)).as_bytes());
+            }
+            res.extend(&k[1..]);
+            res.push(b'\n');
+        }
+        String::from_utf8_lossy(&res).into_owned()
+    }
+
+    /// Check every item rather than stopping at the first changed one
+    /// (`--validate-only`): how nix accessed each path, the path, and whether
+    /// it's unchanged.
+    pub fn validate(&self) -> Vec<(&'static str, &OsStr, bool)> {
+        self.items
+            .iter()
+            .map(|(k, v)| {
+                let path = OsStr::from_bytes(&k[1..]);
+                (access(k), path, item_value(k).as_bytes() == &v[..])
+            })
+            .collect()
+    }
+
     /// Return true if trace doesn't match (i.e. some file is changed)
     pub fn check_for_changes(&self) -> bool {
         for (k, v) in self.items.iter() {
@@ -43,21 +256,67 @@ impl Trace {
         }
         false
     }
+
+    /// Like `check_for_changes`, but faster (`--fast-validate`): files in the
+    /// nix store are only checked to exist, since store paths are immutable,
+    /// and the others are checked in parallel.
+    pub fn check_for_changes_fast(&self) -> bool {
+        self.check_fast(Path::new("/nix/store"))
+    }
+
+    fn check_fast(&self, store: &Path) -> bool {
+        let (in_store, others): (Vec<_>, Vec<_>) =
+            self.items.iter().partition(|(k, _)| {
+                let path = Path::new(OsStr::from_bytes(&k[1..]));
+                path.starts_with(store) && path != store
+            });
+        if in_store.iter().any(|(k, v)| check_item_exists(k, v)) {
+            return true;
+        }
+
+        let threads =
+            std::thread::available_parallelism().map_or(1, |x| x.get());
+        let chunk_size = others.len().div_ceil(threads).max(1);
+        let changed = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            for chunk in others.chunks(chunk_size) {
+                let changed = &changed;
+                scope.spawn(move || {
+                    for (k, v) in chunk {
+                        if changed.load(Ordering::Relaxed) {
+                            return;
+                        }
+                        if check_item_updated(k, v) {
+                            changed.store(true, Ordering::Relaxed);
+                        }
+                    }
+                });
+            }
+        });
+        changed.into_inner()
+    }
 }
 
-fn check_item_updated(k: &[u8], v: &[u8]) -> bool {
-    let tmp: OsString;
+fn access(k: &[u8]) -> &'static str {
+    match k[0] {
+        b's' => "stat",
+        b'f' => "open",
+        b'd' => "readdir",
+        _ => "?",
+    }
+}
+
+/// Current state of a traced item: the target of a symlink, the hash of a
+/// file, or the hash of a directory listing; `-` if it doesn't exist.
+pub fn item_value(k: &[u8]) -> OsString {
     let fname = OsStr::from_bytes(&k[1..]);
-    let res = match k.iter().next() {
+    match k.iter().next() {
         Some(b's') => match nix::sys::stat::lstat(fname) {
             Ok(_) => match nix::fcntl::readlink(fname) {
-                Ok(x) => {
-                    tmp = x;
-                    tmp.as_os_str()
-                }
-                Err(_) => OsStr::new("+"),
+                Ok(x) => x,
+                Err(_) => OsString::from("+"),
             },
-            Err(_) => OsStr::new("-"),
+            Err(_) => OsString::from("-"),
         },
         Some(b'f') => match File::open(fname) {
             Ok(mut file) => {
@@ -67,22 +326,21 @@ fn check_item_updated(k: &[u8], v: &[u8]) -> bool {
                 let mut digest = Md5::new();
                 digest.input(&data);
 
-                tmp = OsString::from(digest.result_str());
-                tmp.as_os_str()
+                OsString::from(digest.result_str())
             }
-            Err(_) => OsStr::new("-"),
+            Err(_) => OsString::from("-"),
         },
-        Some(b'd') => {
-            tmp = hash_dir(fname);
-            tmp.as_os_str()
-        }
+        Some(b'd') => hash_dir(fname),
         _ => panic!("Unexpected"),
-    };
+    }
+}
 
+fn check_item_updated(k: &[u8], v: &[u8]) -> bool {
+    let res = item_value(k);
     if res.as_bytes() != v {
-        eprintln!(
+        note!(
             "cached-nix-shell: {:?}: expected {:?}, got {:?}",
-            fname,
+            OsStr::from_bytes(&k[1..]),
             OsStr::from_bytes(v),
             res
         );
@@ -91,6 +349,22 @@ fn check_item_updated(k: &[u8], v: &[u8]) -> bool {
     false
 }
 
+/// Whether a traced item has been created or removed since; `-` values are
+/// recorded for missing items of any kind.
+fn check_item_exists(k: &[u8], v: &[u8]) -> bool {
+    let fname = OsStr::from_bytes(&k[1..]);
+    let exists = nix::sys::stat::lstat(fname).is_ok();
+    if exists != (v != b"-") {
+        note!(
+            "cached-nix-shell: {:?}: {}",
+            fname,
+            if exists { "created" } else { "removed" }
+        );
+        return true;
+    }
+    false
+}
+
 fn hash_dir(fname: &OsStr) -> OsString {
     let entries = match read_dir(fname) {
         Ok(x) => x,
@@ -121,3 +395,272 @@ fn hash_dir(fname: &OsStr) -> OsString {
         .for_each(|entry| digest.input(&entry));
     OsString::from(digest.result_str())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_list() {
+        let trace = Trace::read(&b"\0s/a/link\0-\0f/a/file.nix\0-\0d/a\0-"[..])
+            .unwrap();
+        assert_eq!(trace.list(false), "/a\n/a/file.nix\n/a/link\n");
+        assert_eq!(
+            trace.list(true),
+            "readdir /a\nopen    /a/file.nix\nstat    /a/link\n"
+        );
+    }
+
+    #[test]
+    fn test_add_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let overlays = dir.path().join("overlays");
+        std::fs::create_dir(&overlays).unwrap();
+        std::fs::write(overlays.join("foo.nix"), "self: super: {}").unwrap();
+        let config = dir.path().join("config.nix");
+
+        let mut trace = Trace::read(&b""[..]).unwrap();
+        trace.add_path(&overlays);
+        trace.add_path(&config);
+        assert!(!trace.check_for_changes());
+
+        std::fs::write(overlays.join("foo.nix"), "self: super: { x = 1; }")
+            .unwrap();
+        assert!(trace.check_for_changes());
+
+        let mut trace = Trace::read(&b""[..]).unwrap();
+        trace.add_path(&config);
+        std::fs::write(&config, "{}").unwrap();
+        assert!(trace.check_for_changes());
+    }
+
+    #[test]
+    fn test_retain_store_closure() {
+        let dir = tempfile::tempdir().unwrap();
+        let project = dir.path().join("shell.nix");
+        std::fs::write(&project, "{}").unwrap();
+        let hello = "/nix/store/00000000000000000000000000000000-hello-2.12";
+        let mut trace = Trace::read(&b""[..]).unwrap();
+        trace.add_path(&project);
+        trace.retain_store_closure(vec![
+            OsStr::new(&format!("{}/bin:/usr/bin", hello)),
+            OsStr::new("no store paths"),
+        ]);
+        assert_eq!(trace.list(true), format!("stat    {}\n", hello));
+        // A project file edit is ignored; the missing store path is what
+        // invalidates the trace.
+        std::fs::write(&project, "{ x = 1; }").unwrap();
+        assert!(!trace.check_for_changes());
+    }
+
+    #[test]
+    fn test_accesses() {
+        let trace =
+            Trace::read(&b"f/a\0x\0f/a\0x\0s/b\0-\0f/a\0x\0d/c\0y\0"[..])
+                .unwrap();
+        assert_eq!(trace.accesses(), 5);
+        assert_eq!(trace.paths().count(), 3);
+        let loaded = Trace::load(&trace.serialize()[..]).unwrap();
+        assert_eq!((loaded.accesses(), loaded.paths().count()), (0, 3));
+    }
+
+    #[test]
+    fn test_validate() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.nix");
+        std::fs::write(&file, "1").unwrap();
+        let mut trace = Trace::read(&b""[..]).unwrap();
+        trace.add_path(&file);
+        trace.add_path(&dir.path().join("missing.nix"));
+        let report = |trace: &Trace| {
+            trace
+                .validate()
+                .into_iter()
+                .map(|(access, path, ok)| (access, path.to_owned(), ok))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            report(&trace),
+            vec![
+                ("open", file.clone().into_os_string(), true),
+                (
+                    "open",
+                    dir.path().join("missing.nix").into_os_string(),
+                    true
+                ),
+            ]
+        );
+
+        std::fs::write(&file, "2").unwrap();
+        assert_eq!(report(&trace)[0], ("open", file.into_os_string(), false));
+        assert!(report(&trace)[1].2);
+    }
+
+    #[test]
+    fn test_limit_dir_depth() {
+        let mut trace = Trace::read(
+            &b"d/src/app\0-\0d/src/app/a\0-\0d/src/app/a/b\0-\0f/src/app/a/b/c.nix\0-\0d/nix/store/x/a/b\0-\0"[..],
+        )
+        .unwrap();
+        trace.limit_dir_depth(Path::new("/src/app"), 1);
+        assert_eq!(
+            trace.paths().collect::<Vec<_>>(),
+            vec![
+                "/nix/store/x/a/b",
+                "/src/app",
+                "/src/app/a",
+                "/src/app/a/b/c.nix"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_add_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        std::fs::create_dir(dir.join("project")).unwrap();
+        std::fs::create_dir(dir.join("shared")).unwrap();
+        std::fs::write(dir.join("shared/a.nix"), "1").unwrap();
+        std::fs::write(dir.join("shared/b.nix"), "1").unwrap();
+        let link = dir.join("project/shell.nix");
+        std::os::unix::fs::symlink("../shared/a.nix", &link).unwrap();
+
+        let mut trace = Trace::read(&b""[..]).unwrap();
+        trace.add_symlink(&link);
+        assert_eq!(
+            trace.list(true),
+            format!(
+                "open    {}\nstat    {}\n",
+                dir.join("shared/a.nix").display(),
+                link.display()
+            )
+        );
+        assert!(!trace.check_for_changes());
+
+        // Retargeting the link to a file with the same contents.
+        std::fs::remove_file(&link).unwrap();
+        std::os::unix::fs::symlink("../shared/b.nix", &link).unwrap();
+        assert!(trace.check_for_changes());
+
+        let mut trace = Trace::read(&b""[..]).unwrap();
+        trace.add_symlink(&link);
+        std::fs::write(dir.join("shared/b.nix"), "2").unwrap();
+        assert!(trace.check_for_changes());
+
+        // Regular files are left to the trace of nix.
+        let mut trace = Trace::read(&b""[..]).unwrap();
+        trace.add_symlink(&dir.join("shared/a.nix"));
+        assert_eq!(trace.paths().count(), 0);
+    }
+
+    #[test]
+    fn test_check_fast() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = dir.path().join("store");
+        let project = dir.path().join("project");
+        std::fs::create_dir_all(store.join("aaa-nixpkgs")).unwrap();
+        std::fs::create_dir(&project).unwrap();
+        std::fs::write(store.join("aaa-nixpkgs/default.nix"), "1").unwrap();
+        for i in 0..20 {
+            std::fs::write(project.join(format!("{}.nix", i)), "1").unwrap();
+        }
+        let mut trace = Trace::read(&b""[..]).unwrap();
+        trace.add_path(&store.join("aaa-nixpkgs"));
+        trace.add_path(&store.join("bbb-missing"));
+        trace.add_path(&project);
+        let verdicts = |trace: &Trace| {
+            (trace.check_for_changes(), trace.check_fast(&store))
+        };
+        assert_eq!(verdicts(&trace), (false, false));
+
+        // Only a project file changed: both notice it.
+        std::fs::write(project.join("13.nix"), "2").unwrap();
+        assert_eq!(verdicts(&trace), (true, true));
+        std::fs::write(project.join("13.nix"), "1").unwrap();
+        assert_eq!(verdicts(&trace), (false, false));
+
+        // Store paths that appear or disappear are noticed too.
+        std::fs::write(store.join("bbb-missing"), "").unwrap();
+        assert_eq!(verdicts(&trace), (true, true));
+        std::fs::remove_file(store.join("bbb-missing")).unwrap();
+
+        // Contents of store paths are trusted, which only matters if
+        // something modifies the store behind the back of nix.
+        std::fs::write(store.join("aaa-nixpkgs/default.nix"), "2").unwrap();
+        assert_eq!(verdicts(&trace), (true, false));
+    }
+
+    #[test]
+    fn test_retain_scope() {
+        let dir = tempfile::tempdir().unwrap();
+        let project = dir.path().join("project");
+        let system = dir.path().join("system");
+        for d in &[&project, &system] {
+            std::fs::create_dir(d).unwrap();
+            std::fs::write(d.join("file.nix"), "1").unwrap();
+        }
+        let mut trace = Trace::read(&b""[..]).unwrap();
+        trace.add_path(&project.join("file.nix"));
+        trace.add_path(&system.join("file.nix"));
+        trace.add_path(Path::new("/nix/store/aaa-nixpkgs/default.nix"));
+        trace.retain_scope(std::slice::from_ref(&project));
+        assert_eq!(
+            trace.paths().collect::<Vec<_>>(),
+            vec![
+                OsStr::new("/nix/store/aaa-nixpkgs/default.nix"),
+                project.join("file.nix").as_os_str(),
+            ]
+        );
+
+        // Changes out of scope go unnoticed, the ones in scope don't.
+        std::fs::write(system.join("file.nix"), "2").unwrap();
+        assert!(!trace.check_for_changes());
+        std::fs::write(project.join("file.nix"), "2").unwrap();
+        assert!(trace.check_for_changes());
+    }
+
+    #[test]
+    fn test_read_large_stream() {
+        let records = (0..100_000)
+            .flat_map(|i| format!("f/file{}\0{}\0", i % 100, i).into_bytes())
+            .collect::<Vec<u8>>();
+        let trace = Trace::read(&records[..]).unwrap();
+        assert_eq!(trace.items.len(), 100);
+        // The latest record wins
+        assert_eq!(
+            trace.items[&b"f/file42"[..]],
+            format!("{}", 99_942).into_bytes()
+        );
+
+        let serialized = trace.serialize();
+        let reread = Trace::load(&serialized[..]).unwrap();
+        assert_eq!(reread.items, trace.items);
+    }
+
+    #[test]
+    fn test_load_corrupt() {
+        let trace = Trace::read(&b"\0f/a\0aaa\0f/b\0bbb\0s/c\0-"[..]).unwrap();
+        let serialized = trace.serialize();
+        assert!(serialized.starts_with(b"\0s\x003\0"));
+        assert_eq!(Trace::load(&serialized[..]).unwrap().items, trace.items);
+
+        // Cut at a record boundary, mid-record, and mid-header.
+        let boundary = serialized.len() - b"\0s/c\0-".len();
+        for len in [boundary, boundary + 3, 2] {
+            assert!(Trace::load(&serialized[..len]).is_err(), "{}", len);
+        }
+        assert!(Trace::load(&b"f/a\0aaa"[..]).is_err());
+        assert!(Trace::load(&b"\0x/a\0aaa"[..]).is_err());
+
+        // Traces of older versions have no header.
+        let legacy = b"\0f/a\0aaa\0f/b\0bbb";
+        assert_eq!(Trace::load(&legacy[..]).unwrap().items.len(), 2);
+        assert_eq!(Trace::load(&b""[..]).unwrap().items.len(), 0);
+    }
+
+    #[test]
+    fn test_read_truncated() {
+        let trace = Trace::read(&b"f/a\0-\0f/b"[..]).unwrap();
+        assert_eq!(trace.items.len(), 1);
+    }
+}
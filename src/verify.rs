@@ -0,0 +1,130 @@
+//! `cached-nix-shell --verify`: a self-check for "it rebuilds every time"
+//! reports.
+//!
+//! Builds a trivial shell twice using a temporary cache directory and checks
+//! that the trace library recorded the shell file and that the second run is
+//! a cache hit.
+
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::process::Command;
+use tempfile::{NamedTempFile, TempDir};
+
+struct Report {
+    ok: bool,
+}
+
+impl Report {
+    fn check(&mut self, name: &str, result: Result<(), String>) {
+        match result {
+            Ok(()) => println!("ok: {}", name),
+            Err(e) => {
+                println!("FAIL: {}: {}", name, e);
+                self.ok = false;
+            }
+        }
+    }
+}
+
+/// Run all checks and print a report.  Return true if all checks passed.
+pub fn run() -> bool {
+    let mut report = Report { ok: true };
+
+    report.check("cache directory is writable", check_cache_dir());
+
+    match TempDir::new() {
+        Ok(tmp) => check_shell(&mut report, tmp.path()),
+        Err(e) => {
+            report.check("create a temporary directory", Err(e.to_string()))
+        }
+    }
+
+    report.ok
+}
+
+fn check_cache_dir() -> Result<(), String> {
+    let dir = xdg::BaseDirectories::with_prefix("cached-nix-shell")
+        .map_err(|e| e.to_string())?
+        .create_cache_directory("")
+        .map_err(|e| e.to_string())?;
+    NamedTempFile::new_in(&dir)
+        .and_then(|mut f| f.write_all(b"test"))
+        .map_err(|e| format!("{:?}: {}", dir, e))
+}
+
+fn check_shell(report: &mut Report, tmp: &Path) {
+    let shell_nix = tmp.join("shell.nix");
+    let cache_dir = tmp.join("cache");
+    let written =
+        std::fs::write(&shell_nix, "with import <nixpkgs> { }; mkShell { }\n");
+    if let Err(e) = written {
+        report.check("create a test shell", Err(e.to_string()));
+        return;
+    }
+
+    let run = || -> Result<bool, String> {
+        let exec = std::env::current_exe()
+            .and_then(|exe| {
+                Command::new(exe)
+                    .arg(&shell_nix)
+                    .args(["--run", ":"])
+                    .env("XDG_CACHE_HOME", &cache_dir)
+                    .output()
+            })
+            .map_err(|e| format!("can't run: {}", e))?;
+        let stderr = String::from_utf8_lossy(&exec.stderr);
+        if !exec.status.success() {
+            return Err(format!("{}: {}", exec.status, stderr.trim_end()));
+        }
+        Ok(!stderr.contains("cached-nix-shell: updating cache"))
+    };
+
+    report.check(
+        "first run is a cache miss",
+        run().and_then(|hit| {
+            if hit {
+                Err("unexpected hit".into())
+            } else {
+                Ok(())
+            }
+        }),
+    );
+    report.check(
+        "trace library records the shell file",
+        traced(&cache_dir.join("cached-nix-shell"), &shell_nix),
+    );
+    report.check(
+        "second run is a cache hit",
+        run().and_then(|hit| {
+            if hit {
+                Ok(())
+            } else {
+                Err("the cache was not reused".into())
+            }
+        }),
+    );
+}
+
+/// Check whether any trace in the cache directory mentions the given file.
+fn traced(cache_dir: &Path, fname: &Path) -> Result<(), String> {
+    let fname = fname.as_os_str().as_bytes();
+    let entries = std::fs::read_dir(cache_dir)
+        .map_err(|e| format!("{:?}: {}", cache_dir, e))?;
+    for entry in entries.filter_map(Result::ok) {
+        if entry
+            .path()
+            .extension()
+            .is_some_and(|x| x == crate::entry::TRACE)
+        {
+            let data = std::fs::read(entry.path()).unwrap_or_default();
+            if data.windows(fname.len()).any(|w| w == fname) {
+                return Ok(());
+            }
+        }
+    }
+    Err(format!(
+        "{:?} is not in the trace",
+        Path::new(std::ffi::OsStr::from_bytes(fname))
+    ))
+}
@@ -0,0 +1,114 @@
+//! Deduplication of warnings, so that running cached-nix-shell in a loop
+//! doesn't flood stderr with the same warning.
+//!
+//! A warning is printed once per process.  With
+//! `CACHED_NIX_SHELL_WARN_INTERVAL=DURATION` (e.g. `1h`), it is also not
+//! repeated by later runs within that duration; the time each warning was
+//! last printed is kept in `~/.cache/cached-nix-shell/warnings`.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+static SEEN: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// Print `Warning: MSG`, unless it has already been printed.
+pub fn warn(msg: String) {
+    if crate::quiet() || !first_in_process(&SEEN, &msg) {
+        return;
+    }
+    if let Some((interval, path)) = interval().zip(log_path()) {
+        if recently_warned(&path, &msg, interval, SystemTime::now()) {
+            return;
+        }
+    }
+    eprintln!("{} {}", crate::color::warning("Warning:"), msg);
+}
+
+fn first_in_process(seen: &Mutex<BTreeSet<String>>, msg: &str) -> bool {
+    seen.lock()
+        .map(|mut seen| seen.insert(msg.to_string()))
+        .unwrap_or(true)
+}
+
+fn interval() -> Option<Duration> {
+    let interval = std::env::var("CACHED_NIX_SHELL_WARN_INTERVAL").ok()?;
+    crate::gc::parse_duration(&interval).ok()
+}
+
+fn log_path() -> Option<PathBuf> {
+    xdg::BaseDirectories::with_prefix("cached-nix-shell")
+        .ok()?
+        .place_cache_file("warnings")
+        .ok()
+}
+
+/// Whether `msg` was printed within `interval` before `now`, according to the
+/// log at `path`.  If not, it is recorded as printed `now`.  Failing to use
+/// the log is not worth a warning of its own, so the warning is printed then.
+fn recently_warned(
+    path: &Path,
+    msg: &str,
+    interval: Duration,
+    now: SystemTime,
+) -> bool {
+    let secs = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    };
+    let cutoff = secs(now).saturating_sub(interval.as_secs());
+    let msg = msg.replace('\n', " ");
+
+    // Lines are `TIMESTAMP MESSAGE`; expired ones are dropped.
+    let log = std::fs::read_to_string(path).unwrap_or_default();
+    let mut lines = log
+        .lines()
+        .filter_map(|line| {
+            let (time, text) = line.split_once(' ')?;
+            Some((time.parse::<u64>().ok()?, text))
+        })
+        .filter(|&(time, _)| time > cutoff)
+        .collect::<Vec<_>>();
+    if lines.iter().any(|&(_, text)| text == msg) {
+        return true;
+    }
+    lines.push((secs(now), &msg));
+
+    let log = lines
+        .iter()
+        .map(|(time, text)| format!("{} {}\n", time, text))
+        .collect::<String>();
+    let _ = crate::write_atomic(path, log.as_bytes());
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_first_in_process() {
+        let seen = Mutex::new(BTreeSet::new());
+        assert!(first_in_process(&seen, "can't create gc root: x"));
+        assert!(!first_in_process(&seen, "can't create gc root: x"));
+        assert!(first_in_process(&seen, "can't store cache: y"));
+    }
+
+    #[test]
+    fn test_recently_warned() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("warnings");
+        let hour = Duration::from_secs(60 * 60);
+        let now = SystemTime::now();
+
+        assert!(!recently_warned(&path, "a", hour, now));
+        assert!(recently_warned(&path, "a", hour, now + hour / 2));
+        assert!(!recently_warned(&path, "b", hour, now + hour / 2));
+        // "a" has expired, but "b" hasn't.
+        assert!(!recently_warned(&path, "a", hour, now + hour * 5 / 4));
+        assert!(recently_warned(&path, "b", hour, now + hour * 5 / 4));
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+    }
+}
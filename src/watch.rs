@@ -0,0 +1,110 @@
+//! Implementation of `--watch`: wait until some file recorded in a trace is
+//! changed.
+
+use crate::trace::Trace;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::Duration;
+
+/// Store paths are immutable, so they are not worth watching.  Skipping them
+/// also keeps the number of watches well below the inotify limit.
+fn is_watched(path: &OsStr) -> bool {
+    !path.as_bytes().starts_with(b"/nix/store/") && !path.is_empty()
+}
+
+/// Block until the trace doesn't match anymore.
+pub fn wait_for_changes(trace: &Trace) -> Result<(), String> {
+    loop {
+        let inotify = Inotify::init(InitFlags::IN_CLOEXEC)
+            .map_err(|e| format!("can't init inotify: {}", e))?;
+        add_watches(&inotify, trace);
+        inotify
+            .read_events()
+            .map_err(|e| format!("can't read inotify events: {}", e))?;
+
+        // Let editors finish writing before checking the files.
+        std::thread::sleep(Duration::from_millis(100));
+        if trace.check_for_changes() {
+            return Ok(());
+        }
+    }
+}
+
+fn add_watches(inotify: &Inotify, trace: &Trace) {
+    let flags = AddWatchFlags::IN_MODIFY
+        | AddWatchFlags::IN_ATTRIB
+        | AddWatchFlags::IN_CLOSE_WRITE
+        | AddWatchFlags::IN_CREATE
+        | AddWatchFlags::IN_DELETE
+        | AddWatchFlags::IN_MOVE
+        | AddWatchFlags::IN_DELETE_SELF
+        | AddWatchFlags::IN_MOVE_SELF
+        | AddWatchFlags::IN_DONT_FOLLOW;
+    let mut parents = HashSet::new();
+    for path in trace.paths().filter(|&path| is_watched(path)) {
+        if inotify.add_watch(path, flags).is_err() {
+            // The file doesn't exist (yet), so watch for it to be created.
+            if let Some(parent) = Path::new(path).parent() {
+                parents.insert(parent.to_owned());
+            }
+        }
+    }
+    for parent in parents {
+        let _ = inotify.add_watch(&parent, flags);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_watched() {
+        assert!(is_watched(OsStr::new("/home/user/shell.nix")));
+        assert!(!is_watched(OsStr::new(
+            "/nix/store/abc-nixpkgs/default.nix"
+        )));
+        assert!(!is_watched(OsStr::new("")));
+    }
+
+    fn file_trace(items: &[(&Path, &str)]) -> Trace {
+        let mut buf = Vec::new();
+        for (path, value) in items {
+            buf.push(b'f');
+            buf.extend(path.as_os_str().as_bytes());
+            buf.push(0);
+            buf.extend(value.as_bytes());
+            buf.push(0);
+        }
+        Trace::read(&buf[..]).unwrap()
+    }
+
+    fn write_later(path: &Path, text: &'static str) {
+        let path = path.to_owned();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            std::fs::write(path, text).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_wait_for_changes() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // md5 of "a"
+        let existing = dir.path().join("existing");
+        std::fs::write(&existing, "a").unwrap();
+        let trace =
+            file_trace(&[(&existing, "0cc175b9c0f1b6a831c399e269772661")]);
+        write_later(&existing, "b");
+        wait_for_changes(&trace).unwrap();
+
+        let created = dir.path().join("created");
+        let trace = file_trace(&[(&created, "-")]);
+        write_later(&created, "c");
+        wait_for_changes(&trace).unwrap();
+    }
+}
@@ -0,0 +1,4 @@
+with import <nixpkgs> {};
+mkShell {
+  x = throw "cached-nix-shell-test-error";
+}
@@ -0,0 +1,4 @@
+#!/usr/bin/env cached-nix-shell
+#! nix-shell -i sh -p luajit
+
+lua -v
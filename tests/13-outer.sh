@@ -0,0 +1,5 @@
+#!/usr/bin/env cached-nix-shell
+#! nix-shell -i sh -p hello
+
+hello
+./13-inner.sh
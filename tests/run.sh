@@ -28,6 +28,11 @@ check() {
 }
 
 check_contains() { check "contains $1" grep -q "$1" tmp/out; }
+check_not_contains() { check "doesn't contain $1" not grep -q "$1" tmp/out; }
+check_stderr_contains() { check "stderr contains $1" grep -q "$1" tmp/err; }
+check_stderr_not_contains() {
+	check "stderr doesn't contain $1" not grep -q "$1" tmp/err
+}
 check_slow() {
 	check "slow ($(cat tmp/time))" \
 		grep -q "^cached-nix-shell: updating cache$" tmp/err
@@ -66,6 +71,10 @@ run ./01-luajit.sh
 check_contains "http://luajit.org/"
 check_fast
 
+# The interpreter comes from -p, and is looked up in the PATH of the shell.
+if command -v lua > /dev/null; then
+	skip "lua is on the host PATH, so it may be found there"
+fi
 run ./02-lua.lua
 check_contains "42"
 check_fast
@@ -84,19 +93,104 @@ run ./03-file-dep.sh
 check_contains "val2"
 check_slow
 
+# Without a trace, the change of 03-foo.nix goes unnoticed; --audit catches it.
+echo '"val-audit1"' > ./tmp/03-foo.nix
+run env CACHED_NIX_SHELL_NO_TRACE=1 \
+	cached-nix-shell -p "(callPackage ./03-small.nix {})" --run x
+check_contains "val-audit1"
+echo '"val-audit2"' > ./tmp/03-foo.nix
+run cached-nix-shell --audit -p "(callPackage ./03-small.nix {})" --run x
+check_contains "val-audit1"
+check_stderr_contains "audit: cached environment differs from a fresh build: .*PATH"
+check_stderr_contains "audit: files missing from the trace: .*03-foo.nix"
+
+echo 'with import <nixpkgs> {}; mkShell { buildInputs = [ (callPackage ../03-small.nix {}) ]; }' > ./tmp/watch.nix
+rm -f ./tmp/watch-out
+cached-nix-shell ./tmp/watch.nix --watch --run 'x >> ./tmp/watch-out' &
+watch_pid=$!
+wait_for_lines() {
+	for _ in $(seq 600); do
+		[ "$(cat ./tmp/watch-out 2>/dev/null | wc -l)" -ge "$1" ] && return
+		sleep 0.1
+	done
+}
+wait_for_lines 1
+echo '"val3"' > ./tmp/03-foo.nix
+wait_for_lines 2
+kill $watch_pid
+check "--watch re-runs the command" grep -q "val3" ./tmp/watch-out
+
+mkdir -p tmp/home/.config/nixpkgs/overlays
+echo 'self: super: {}' > tmp/home/.config/nixpkgs/overlays/foo.nix
+run env HOME=$PWD/tmp/home cached-nix-shell -p luajit hello --run hello
+check_contains "Hello, world!"
+check_slow
+# The cache in XDG_CACHE_HOME was made with the real HOME.
+check_stderr_contains "Warning: HOME is \"$PWD/tmp/home\", but the cache"
+
+run env HOME=$PWD/tmp/home cached-nix-shell -p luajit hello --run hello
+check_fast
+run cached-nix-shell -p hello --run hello
+check_stderr_not_contains "Warning: HOME"
+run env CACHED_NIX_SHELL_DEBUG=1 cached-nix-shell -p hello --run hello
+check_stderr_contains "debug: cache directory \"$XDG_CACHE_HOME/cached-nix-shell\""
+
+echo 'self: super: { foo = 1; }' > tmp/home/.config/nixpkgs/overlays/foo.nix
+run env HOME=$PWD/tmp/home cached-nix-shell -p luajit hello --run hello
+check_contains "Hello, world!"
+check_slow
+
+# A -p shell whose package comes from an overlay reading a variable.
+mkdir -p tmp/overlays
+echo 'self: super: { env-pkg = super.writeShellScriptBin "env-pkg" "echo tmpdir=${builtins.getEnv "TMPDIR"}"; }' \
+	> tmp/overlays/env.nix
+mkdir -p tmp/tmpdir-a tmp/tmpdir-b
+for dir in a a b; do
+	run env TMPDIR=$PWD/tmp/tmpdir-$dir cached-nix-shell \
+		-I nixpkgs-overlays=$PWD/tmp/overlays -p env-pkg --run env-pkg
+	check_contains "^tmpdir=$PWD/tmp/tmpdir-$dir$"
+done
+
+run ./13-outer.sh
+check_contains "Hello, world!"
+check_contains "http://luajit.org/"
+check "both scripts are cached" \
+	test "$(grep -c "^cached-nix-shell: updating cache$" tmp/err)" = 2
+
+run ./13-outer.sh
+check_contains "http://luajit.org/"
+check_fast
+
+run ./13-inner.sh
+check_contains "http://luajit.org/"
+check_fast
+
 run ./04-path-impure.sh
 check_contains "running-some-bin"
 check_contains "Hello, world!"
+check_not_contains "evil"
+
+run env CACHED_NIX_SHELL_DEBUG=1 ./04-path-impure.sh
+check_contains "Hello, world!"
+check_stderr_contains "bin/hello\" from caller's PATH is shadowed"
 
 run ./05-path-pure.sh
 check_contains "cant-find-some-bin"
 
+run cached-nix-shell --pure --keep-path -p hello --run 'hello; some_bin'
+check_contains "Hello, world!"
+check_contains "running-some-bin"
+
 run ./06-env-impure.sh
 check_contains "some-var-value"
 
 run ./07-env-pure.sh
 check_contains "doesnt-have-some-var"
 
+printf 'name=from-env-file\nSOME_VAR="overridden value"\n' > tmp/env-file
+run cached-nix-shell --env-file tmp/env-file -p hello --run 'echo $name $SOME_VAR'
+check_contains "from-env-file overridden value"
+
 run ./08-with_-I.lua
 check_contains "aGVsbG8="
 
@@ -115,6 +209,15 @@ run env --chdir .. cached-nix-shell tests/09-lua.nix --run 'lua -v'
 check_contains "Lua.org"
 check_fast
 
+mkdir -p tmp/default-only
+cp 09-lua.nix tmp/default-only/default.nix
+run env --chdir tmp/default-only cached-nix-shell --run 'lua -v'
+check_contains "Lua.org"
+
+run env --chdir tmp/default-only cached-nix-shell --run 'lua -v'
+check_contains "Lua.org"
+check_fast
+
 run ./10-with_--attr.sh
 check_contains "i-am-foo"
 check_contains "cant-find-bar"
@@ -144,6 +247,22 @@ run cached-nix-shell -p lua --run 'lua -v | rev'
 check_contains "gro.auL"
 check_fast
 
+run cached-nix-shell --show-trace -p lua --run 'lua -v | rev'
+check_contains "gro.auL"
+check_fast
+
+run cached-nix-shell ./12-error.nix --run :
+check_stderr_contains "cached-nix-shell-test-error"
+
+run cached-nix-shell --show-trace ./12-error.nix --run :
+check_stderr_contains "cached-nix-shell-test-error"
+
+entries=$(ls tmp/cache/cached-nix-shell/*.env | wc -l)
+run cached-nix-shell -K ./12-error.nix --run :
+check_stderr_contains "cached-nix-shell-test-error"
+check "failed build isn't cached" \
+	test "$(ls tmp/cache/cached-nix-shell/*.env | wc -l)" = "$entries"
+
 run cached-nix-shell -p luajit --run 'lua -v'
 check_contains "http://luajit.org/"
 check_slow
@@ -155,4 +274,777 @@ check_fast
 run cached-nix-shell -pj16 luajit --exec lua -v
 check_contains "http://luajit.org/"
 
+run cached-nix-shell -p --run 'hello; lua -v' -- hello lua
+check_contains "Hello, world!"
+check_contains "Lua.org"
+
+run env USER=cached-nix-shell-no-such-user cached-nix-shell -p hello --run hello
+check_contains "Hello, world!"
+check_stderr_contains "can't create gc root"
+check_slow
+
+run env USER=cached-nix-shell-no-such-user cached-nix-shell -p hello --run hello
+check_contains "Hello, world!"
+check_fast
+
+run env USER=cached-nix-shell-no-such-user cached-nix-shell --quiet -p lua hello --run hello
+check_contains "Hello, world!"
+check_stderr_not_contains "can't create gc root"
+check_stderr_not_contains "updating cache"
+
+run cached-nix-shell --quiet ./12-error.nix --run :
+check_stderr_contains "cached-nix-shell-test-error"
+
+gcroots=/nix/var/nix/gcroots/per-user/$USER
+roots=$(ls "$gcroots" | grep -c '^cached-nix-shell-')
+run cached-nix-shell --no-gc-root -p lua luajit --run 'lua -v'
+check_contains "Lua.org"
+check_slow
+check "--no-gc-root doesn't create a gc root" \
+	test "$(ls "$gcroots" | grep -c '^cached-nix-shell-')" = "$roots"
+
+run cached-nix-shell --no-gc-root -p lua luajit --run 'lua -v'
+check_fast
+
+run env TRACE_NIX=/nonexistent/trace cached-nix-shell -p hello --run '
+	cached-nix-shell -p lua --run "lua -v"
+	echo "trace=${TRACE_NIX-unset}"'
+check_contains "Lua.org"
+check_contains "trace=unset"
+
+# Tracing works with --pure, and its variables reach neither the captured
+# environment nor the command.
+echo 'with import <nixpkgs> {}; mkShell { A = import ./pure-dep.nix; }' > tmp/pure-trace.nix
+echo '"one"' > tmp/pure-dep.nix
+run cached-nix-shell --pure tmp/pure-trace.nix --run 'echo "$A ${LD_PRELOAD-unset} ${TRACE_NIX-unset}"'
+check_contains "^one unset unset$"
+check_slow
+check "the captured environment has no trace variables" \
+	not grep -qa "TRACE_NIX=\|trace-nix.so" tmp/cache/cached-nix-shell/*.env
+echo '"two"' > tmp/pure-dep.nix
+run cached-nix-shell --pure tmp/pure-trace.nix --run 'echo "$A ${LD_PRELOAD-unset} ${TRACE_NIX-unset}"'
+check_contains "^two unset unset$"
+check_slow
+
+# --merge-file merges the inputs and the variables of several files.
+echo 'with import <nixpkgs> {}; mkShell { buildInputs = [ lua ]; A = "a"; B = "from-a"; }' > tmp/merge-a.nix
+echo '{ pkgs ? import <nixpkgs> {} }: pkgs.mkShell { buildInputs = [ pkgs.luajit ]; B = "from-b"; }' > tmp/merge-b.nix
+merge_cmd='echo "$A $B"; command -v lua luajit >/dev/null && echo both'
+run cached-nix-shell --merge-file tmp/merge-a.nix --merge-file tmp/merge-b.nix --run "$merge_cmd"
+check_contains "^a from-b$"
+check_contains "^both$"
+check_slow
+run cached-nix-shell --merge-file tmp/merge-a.nix --merge-file tmp/merge-b.nix --run "$merge_cmd"
+check_fast
+for merged in a b; do
+	sed -i "s/B = \"from-$merged\"/B = \"edited-$merged\"/" tmp/merge-$merged.nix
+	run cached-nix-shell --merge-file tmp/merge-a.nix --merge-file tmp/merge-b.nix --run "$merge_cmd"
+	check_slow
+done
+check_contains "^a edited-b$"
+
+# A derivation that is unknown to nix is rebuilt, even if the path exists on
+# disk.  The result is remembered, so the next hit doesn't ask nix again.
+run cached-nix-shell -p hello --run hello
+unregistered=/nix/store/00000000000000000000000000000000-unregistered.drv
+if [ -w /nix/store ]; then
+	mkdir -p "$unregistered"
+	for drv in tmp/cache/cached-nix-shell/*.drv; do
+		rm "$drv" && ln -s "$unregistered" "$drv"
+	done
+	run cached-nix-shell -p hello --run hello
+	check_contains "Hello, world!"
+	check_stderr_contains "\"$unregistered\" is not valid in the nix store"
+	check_slow
+	run cached-nix-shell -p hello --run hello
+	check_fast
+	rmdir "$unregistered"
+else
+	skip "unregistered store paths (/nix/store isn't writable)"
+fi
+
+# Deprecated setting names are passed on, with a suggestion with --verbose.
+run cached-nix-shell --print-build-command --option build-max-jobs 2 -p hello
+check_contains " --option build-max-jobs 2 "
+run cached-nix-shell --verbose --option build-max-jobs 2 -p hello --run hello
+check_contains "Hello, world!"
+check_stderr_contains "use --option max-jobs"
+run cached-nix-shell --option build-max-jobs 2 -p hello --run hello
+check_stderr_not_contains "use --option max-jobs"
+
+# A truncated trace is ignored, and the entry is rebuilt.
+run cached-nix-shell -p hello --run hello
+for trace in tmp/cache/cached-nix-shell/*.trace; do
+	head -c 20 "$trace" > tmp/trace && mv tmp/trace "$trace"
+done
+run cached-nix-shell -p hello --run hello
+check_contains "Hello, world!"
+check_stderr_contains "ignoring .*\.trace"
+check_stderr_not_contains "internal error"
+check_slow
+run cached-nix-shell -p hello --run hello
+check_fast
+
+# Reading a directory as a file fails in the middle of the check.
+run cached-nix-shell --keep-path -p hello --run :
+for trace in tmp/cache/cached-nix-shell/*.trace; do
+	printf '\0f/\0+' > "$trace"
+done
+run cached-nix-shell -p hello --run hello
+check_contains "Hello, world!"
+check_stderr_contains "internal error, running nix-shell"
+
+# The fallback passes nix-shell only the options it knows.
+run cached-nix-shell --keep-path --color never -p hello --run hello
+check_contains "Hello, world!"
+check_stderr_contains "internal error, running nix-shell"
+run cached-nix-shell --dump-env=json --keep-path -p hello --run hello
+check_contains "Hello, world!"
+check_stderr_contains "internal error, running nix-shell"
+
+# Interrupt nix-shell while it captures the environment.  Background jobs
+# ignore SIGINT, so SIGTERM stands in for Ctrl-C.
+echo 'with import <nixpkgs> {}; mkShell { shellHook = "sleep 10"; }' > tmp/slow.nix
+entries=$(ls tmp/cache/cached-nix-shell/*.env | wc -l)
+cached-nix-shell tmp/slow.nix --run : &
+sleep 3
+kill -TERM $!
+wait $!
+check "interrupted capture isn't cached" \
+	test "$(ls tmp/cache/cached-nix-shell/*.env | wc -l)" = "$entries"
+run cached-nix-shell tmp/slow.nix --run 'echo rebuilt'
+check_contains "rebuilt"
+check_slow
+
+run env CACHED_NIX_SHELL_TEST_VAR=1 cached-nix-shell --clean-env -p hello \
+	--run 'echo "var=${CACHED_NIX_SHELL_TEST_VAR-unset} home=$HOME"; hello'
+check_contains "var=unset home=$HOME"
+check_contains "Hello, world!"
+
+run cached-nix-shell -I "cache=$XDG_CACHE_HOME/cached-nix-shell" -p hello --run hello
+check_contains "Hello, world!"
+check_stderr_contains "points into the cache of cached-nix-shell"
+
+# Paths with spaces in the cache and in the trace file.
+mkdir -p "tmp/cache dir" "tmp/tmp dir"
+echo '"val-space1"' > ./tmp/03-foo.nix
+run env XDG_CACHE_HOME="$PWD/tmp/cache dir" TMPDIR="$PWD/tmp/tmp dir" ./03-file-dep.sh
+check_contains "val-space1"
+check_slow
+echo '"val-space2"' > ./tmp/03-foo.nix
+run env XDG_CACHE_HOME="$PWD/tmp/cache dir" TMPDIR="$PWD/tmp/tmp dir" ./03-file-dep.sh
+check_contains "val-space2"
+check_slow
+run env XDG_CACHE_HOME="$PWD/tmp/cache dir" TMPDIR="$PWD/tmp/tmp dir" ./03-file-dep.sh
+check_contains "val-space2"
+check_fast
+
+echo 'with import <nixpkgs> {}; mkShell { name = "trace-to"; }' > tmp/trace-to.nix
+run cached-nix-shell --trace-to tmp/trace-to.log tmp/trace-to.nix --run :
+check_slow
+check "--trace-to records the shell file" grep -qa "$PWD/tmp/trace-to.nix" tmp/trace-to.log
+run cached-nix-shell --trace-to /nonexistent/trace.log -p hello --clean-env --run hello
+check_contains "Hello, world!"
+
+run cached-nix-shell -p hello --exec printf '[%s]' 'a b' '"q" $HOME' '`id`'
+check "--exec passes arguments literally" \
+	test "$(cat tmp/out)" = '[a b]["q" $HOME][`id`]'
+
+run cached-nix-shell -p hello --exec printf '[%s]' --pure
+check "options after --exec are passed to the command" \
+	test "$(cat tmp/out)" = '[--pure]'
+check_stderr_contains "--pure after --exec is passed to"
+
+for mode in pure impure; do
+	run cached-nix-shell --$mode -p hello --run 'echo "in=$IN_NIX_SHELL"'
+	check_contains "^in=$mode$"
+done
+
+cached-nix-shell --no-such-flag 2> /dev/null
+check "argument errors exit with 2" test $? = 2
+cached-nix-shell ./12-error.nix --run : 2> /dev/null
+check "build failures exit with 3" test $? = 3
+cached-nix-shell -p hello --run false
+check "--run false exits with 1" test $? = 1
+cached-nix-shell -p hello --run 'exit 42'
+check "the exit code of the command is passed through" test $? = 42
+start=$(date +%s)
+cached-nix-shell -p hello --timeout 1s --run 'sleep 30' 2> /dev/null
+check "timed out commands exit with 124" test $? = 124
+check "the command is killed at the deadline" test $(($(date +%s) - start)) -lt 10
+cached-nix-shell -p hello --timeout 10s --run 'exit 42'
+check "the exit code is passed through with --timeout" test $? = 42
+cached-nix-shell -p hello --timeout 10s 2> /dev/null < /dev/null
+check "--timeout is rejected in interactive mode" test $? = 2
+
+# 03-foo.nix is out of scope of tmp/scope, but in scope of tmp.
+mkdir -p tmp/scope
+small='(callPackage ./03-small.nix {})'
+echo '"val-scope1"' > ./tmp/03-foo.nix
+run cached-nix-shell --trace-scope tmp/scope -p "$small" --run x
+check_contains "val-scope1"
+echo '"val-scope2"' > ./tmp/03-foo.nix
+run cached-nix-shell --trace-scope tmp/scope -p "$small" --run x
+check_contains "val-scope1"
+check_fast
+run cached-nix-shell --trace-scope tmp -p "$small" --run x
+check_contains "val-scope2"
+echo '"val-scope3"' > ./tmp/03-foo.nix
+run cached-nix-shell --trace-scope tmp -p "$small" --run x
+check_contains "val-scope3"
+check_slow
+
+# A derivation that is never in the store yet, so it is built each time.
+stream_shell() {
+	echo "with import <nixpkgs> {}; mkShell { buildInputs = [ (runCommand \"stream-$1\" {} \"echo building-stream-test; mkdir \$out\") ]; }" > tmp/stream.nix
+}
+stream_shell "$(date +%s%N)"
+run cached-nix-shell tmp/stream.nix --run :
+check_stderr_contains "building-stream-test"
+run cached-nix-shell tmp/stream.nix --run :
+check_fast
+check "cache hits print nothing" test ! -s tmp/err
+stream_shell "$(date +%s%N)"
+run cached-nix-shell --quiet tmp/stream.nix --run :
+check_stderr_not_contains "building-stream-test"
+stream_shell "$(date +%s%N)"
+run cached-nix-shell --quiet --verbose tmp/stream.nix --run :
+check_stderr_contains "building-stream-test"
+
+printf '%s\n' '#!/usr/bin/env nix-shell' '#! nix-shell -i bash -p hello' > tmp/lint-ok.sh
+printf '%s\n' '#!/usr/bin/env nix-shell' '#! nix-shell -p hello --frobnicate' > tmp/lint-flag.sh
+printf '%s\n' '#!/usr/bin/env nix-shell' '#! nix-shell -p "hello' > tmp/lint-quote.sh
+run cached-nix-shell --lint tmp/lint-ok.sh
+check "well-formed scripts pass the lint" test ! -s tmp/err
+cached-nix-shell --lint tmp/lint-ok.sh tmp/lint-flag.sh 2> tmp/err
+check "unknown flags fail the lint" test $? = 1
+check_stderr_contains 'lint-flag.sh: unexpected arg "--frobnicate"'
+cached-nix-shell --lint tmp/lint-quote.sh 2> tmp/err
+check "unterminated quotes fail the lint" test $? = 1
+check_stderr_contains "lint-quote.sh: 2: unterminated quote"
+
+printf '%s\n' '#!/bin/sh' 'sed '"'"'s/^{/{"HOOK_VAR":"from-hook",/'"'"'' > tmp/hook
+chmod +x tmp/hook
+run env CACHED_NIX_SHELL_POST_HOOK=$PWD/tmp/hook cached-nix-shell -p hello \
+	--run 'echo "hook=$HOOK_VAR"; hello'
+check_contains "^hook=from-hook$"
+check_contains "Hello, world!"
+env CACHED_NIX_SHELL_POST_HOOK=false cached-nix-shell -p hello \
+	--run 'echo started' > tmp/out 2> /dev/null
+check "a failing hook aborts" test $? = 2
+check_not_contains "started"
+
+git init -q tmp/repo
+touch tmp/repo/shell.nix
+printf '#!/bin/sh\necho existing-hook\n' > tmp/repo/.git/hooks/post-merge
+(cd tmp/repo && cached-nix-shell --install-hook) 2> tmp/err
+check "--install-hook succeeds" test $? = 0
+check "the post-checkout hook is created" \
+	grep -qxF "cached-nix-shell './shell.nix' --run : || true" tmp/repo/.git/hooks/post-checkout
+check "the post-checkout hook is executable" test -x tmp/repo/.git/hooks/post-checkout
+check "existing hooks are preserved" grep -qx "echo existing-hook" tmp/repo/.git/hooks/post-merge
+check "existing hooks are extended" grep -q "^cached-nix-shell './shell.nix'" tmp/repo/.git/hooks/post-merge
+(cd / && cached-nix-shell --install-hook) 2> tmp/err
+check "--install-hook refuses outside of git repositories" test $? = 2
+check_stderr_contains "not inside a git repository"
+
+# stdin is not a terminal, so --command doesn't wait for input.
+run cached-nix-shell -p hello --command 'echo "command-ran"; return' < /dev/null
+check_contains "^command-ran$"
+cached-nix-shell -p hello --command 'exit 5' < /dev/null
+check "--command without a terminal passes the exit code through" test $? = 5
+# Like `ssh host cached-nix-shell ...` without a terminal: stdin and stdout
+# are pipes.
+status=$(echo input | {
+	cached-nix-shell -p hello --command 'read -r line; echo "got $line"; exit 6'
+	echo "status $?"
+} | tee tmp/out | sed -n 's/^status //p')
+check "--command over pipes reads them" grep -qx "got input" tmp/out
+check "--command over pipes passes the exit code through" test "$status" = 6
+
+# The build doesn't consume stdin, whether the shell is cached or not.
+echo '{"piped": 1}' > tmp/piped.json
+echo 'with import <nixpkgs> {}; mkShell { name = "stdin-test"; }' > tmp/stdin.nix
+for i in 1 2; do
+	run cached-nix-shell tmp/stdin.nix --run 'cat' < tmp/piped.json
+	check_contains '^{"piped": 1}$'
+done
+echo piped-data | cached-nix-shell tmp/stdin.nix --exec cat > tmp/out
+check_contains '^piped-data$'
+echo piped-data | cached-nix-shell tmp/stdin.nix --timeout 1m --run cat > tmp/out
+check_contains '^piped-data$'
+
+run cached-nix-shell -p hello --run 'echo "arg=$1"' -- hello
+check_contains "^arg=hello$"
+run cached-nix-shell -p hello --run 'printf "<%s>" "$@"' -- "a b" --run
+check_contains "^<a b><--run>$"
+
+mkdir -p tmp/linked tmp/shared
+echo 'with import <nixpkgs> {}; mkShell { GREETING = "a"; }' > tmp/shared/a.nix
+echo 'with import <nixpkgs> {}; mkShell { GREETING = "a"; }' > tmp/shared/b.nix
+ln -s ../shared/a.nix tmp/linked/shell.nix
+run cached-nix-shell tmp/linked/shell.nix --run 'echo "$GREETING"'
+check_contains "^a$"
+check_slow
+run cached-nix-shell tmp/linked/shell.nix --run 'echo "$GREETING"'
+check_fast
+ln -sfn ../shared/b.nix tmp/linked/shell.nix
+run cached-nix-shell tmp/linked/shell.nix --run 'echo "$GREETING"'
+check "retargeting the symlinked shell file invalidates the cache" \
+	grep -q "^cached-nix-shell: updating cache$" tmp/err
+echo 'with import <nixpkgs> {}; mkShell { GREETING = "b"; }' > tmp/shared/b.nix
+run cached-nix-shell --trace-scope tmp/linked tmp/linked/shell.nix \
+	--run 'echo "$GREETING"'
+check_contains "^b$"
+echo 'with import <nixpkgs> {}; mkShell { GREETING = "c"; }' > tmp/shared/b.nix
+run cached-nix-shell --trace-scope tmp/linked tmp/linked/shell.nix \
+	--run 'echo "$GREETING"'
+check "editing the destination invalidates the cache, even out of scope" \
+	grep -q "^c$" tmp/out
+
+run cached-nix-shell --argstr name one --key-ignore name -p hello --run :
+run cached-nix-shell --argstr name two --key-ignore name -p hello --run :
+check "--key-ignore shares the entry between values of the argument" \
+	not grep -q "^cached-nix-shell: updating cache$" tmp/err
+run cached-nix-shell --argstr name two -p hello --run :
+check_slow
+
+run cached-nix-shell --print-build-command -p hello -A foo
+check_contains "^  nix-shell --keep CACHED_NIX_SHELL_CAPTURING --pure --packages --run 'CACHED_NIX_SHELL_UMASK=$(umask) CACHED_NIX_SHELL_NOFILE=$(ulimit -Sn) CACHED_NIX_SHELL_SHOPTS="$(shopt -p)" CACHED_NIX_SHELL_ALIASES="$(alias -p)" env -0' -A foo -- hello$"
+check_contains "^  LD_PRELOAD=.*trace-nix.so \\\\$"
+check "--print-build-command doesn't build anything" \
+	not grep -q "updating cache" tmp/err
+
+run cached-nix-shell --explain-key --argstr name one -p hello
+check_contains '^arg "one"$'
+check_contains '^extra nix="/nix/store/.*/bin/nix-shell"$'
+cp tmp/out tmp/explain-one
+run cached-nix-shell --explain-key --argstr name two -p hello
+check "--explain-key shows where keys differ" \
+	test "$(diff tmp/explain-one tmp/out | grep -c '^[<>] arg ')" = 2
+check "--explain-key doesn't build anything" \
+	not grep -q "updating cache" tmp/err
+
+run cached-nix-shell --print-build-command --nixpkgs-rev 0123abc -p hello
+check_contains " -I nixpkgs=https://github.com/NixOS/nixpkgs/archive/0123abc.tar.gz -- hello$"
+
+# --fast-validate agrees with the full check when a project file changes.
+echo 'with import <nixpkgs> {}; mkShell { GREETING = import ./fast-dep.nix; }' > tmp/fast.nix
+echo '"one"' > tmp/fast-dep.nix
+run cached-nix-shell tmp/fast.nix --run 'echo "$GREETING"'
+check_slow
+run cached-nix-shell --fast-validate tmp/fast.nix --run 'echo "$GREETING"'
+check_contains "^one$"
+check_fast
+echo '"two"' > tmp/fast-dep.nix
+run cached-nix-shell --fast-validate tmp/fast.nix --run 'echo "$GREETING"'
+check_contains "^two$"
+check_slow
+run cached-nix-shell tmp/fast.nix --run 'echo "$GREETING"'
+check_fast
+
+# A rebuild replaces the dependencies of the entry.
+echo 'with import <nixpkgs> {}; mkShell { GREETING = import ./old-dep.nix; }' > tmp/prune.nix
+echo '"old"' > tmp/old-dep.nix
+run cached-nix-shell tmp/prune.nix --run 'echo "$GREETING"'
+check_contains "^old$"
+echo 'with import <nixpkgs> {}; mkShell { GREETING = import ./new-dep.nix; }' > tmp/prune.nix
+echo '"new"' > tmp/new-dep.nix
+rm tmp/old-dep.nix
+run cached-nix-shell tmp/prune.nix --run 'echo "$GREETING"'
+check_contains "^new$"
+check_slow
+run cached-nix-shell --list-deps tmp/prune.nix
+check_contains "/tmp/new-dep.nix$"
+check_not_contains "old-dep.nix"
+run cached-nix-shell tmp/prune.nix --run 'echo "$GREETING"'
+check_fast
+
+# The kernel passes the options of the interpreter as a single argument.
+printf '#!%s -i sh -p hello\nprintf "<%%s>" "$@"\nhello\n' \
+	"$(command -v cached-nix-shell)" > tmp/combined.sh
+chmod +x tmp/combined.sh
+run ./tmp/combined.sh "a b"
+check_contains "<a b>Hello, world!$"
+
+# bash increments SHLVL, so it tells whether the command ran in bash.
+run cached-nix-shell -p hello --exec printenv SHLVL
+direct_shlvl=$(cat tmp/out)
+run cached-nix-shell -p hello --run 'printenv SHLVL'
+check "--run starts bash" not grep -qx "$direct_shlvl" tmp/out
+run cached-nix-shell -p hello --reuse-env --run 'printenv SHLVL'
+check "--reuse-env runs a plain program without bash" grep -qx "$direct_shlvl" tmp/out
+run cached-nix-shell -p hello --reuse-env --run 'printenv SHLVL && hello'
+check "--reuse-env still uses bash for shell syntax" not grep -qx "$direct_shlvl" tmp/out
+check_contains "Hello, world!"
+
+# An entry of the read-only system cache is used, and it isn't written to.
+echo 'with import <nixpkgs> {}; mkShell { GREETING = "system"; }' > tmp/system.nix
+run env XDG_CACHE_HOME="$PWD/tmp/system-cache" \
+	cached-nix-shell tmp/system.nix --run 'echo "$GREETING"'
+check_slow
+chmod -R a-w tmp/system-cache
+ls -lR --full-time tmp/system-cache > tmp/system-before
+run env XDG_CACHE_HOME="$PWD/tmp/user-cache" \
+	CACHED_NIX_SHELL_SYSTEM_CACHE="$PWD/tmp/system-cache/cached-nix-shell" \
+	cached-nix-shell tmp/system.nix --run 'echo "$GREETING"'
+check_contains "^system$"
+check_fast
+ls -lR --full-time tmp/system-cache > tmp/system-after
+check "the system cache isn't written to" cmp -s tmp/system-before tmp/system-after
+check "a system cache hit isn't copied" \
+	not ls tmp/user-cache/cached-nix-shell/*.env
+chmod -R u+w tmp/system-cache
+
+# A shell.nix that calls into a local flake depends on its flake.lock.
+mkdir -p tmp/flake
+cat > tmp/flake/flake.nix << 'EOF'
+{ outputs = { self }: { greeting = "from-flake"; }; }
+EOF
+echo '{"nodes":{"root":{}},"root":"root","version":7}' > tmp/flake/flake.lock
+cat > tmp/flake/shell.nix << 'EOF'
+with import <nixpkgs> {};
+mkShell { GREETING = (builtins.getFlake "path:${toString ./.}").greeting; }
+EOF
+flakes="NIX_CONFIG=experimental-features = nix-command flakes"
+run env "$flakes" cached-nix-shell tmp/flake/shell.nix --run 'echo "$GREETING"'
+if grep -q "^from-flake$" tmp/out; then
+	check_slow
+	run env "$flakes" cached-nix-shell tmp/flake/shell.nix --run :
+	check_fast
+	echo '{"nodes":{"root":{}},"root":"root","version":7} ' > tmp/flake/flake.lock
+	run env "$flakes" cached-nix-shell tmp/flake/shell.nix --run :
+	check "editing flake.lock invalidates the cache" \
+		grep -q "^cached-nix-shell: updating cache$" tmp/err
+else
+	skip "getFlake (flakes aren't supported by this nix)"
+fi
+
+printf 'echo foo\0bar\n' > tmp/nul-cmd
+cached-nix-shell -p hello --run @tmp/nul-cmd > tmp/out 2> tmp/err
+check "a command with a NUL byte is rejected" test $? = 2
+check_stderr_contains "contains a NUL byte"
+
+run env CNS_KEEP_A=1 CNS_KEEP_B=2 OTHER=3 \
+	cached-nix-shell --pure --keep 'CNS_KEEP_*' -p hello --run 'env'
+check_contains "^CNS_KEEP_A=1$"
+check_contains "^CNS_KEEP_B=2$"
+check_not_contains "^OTHER="
+
+# The umask set by a shellHook is restored on cache hits.
+echo 'with import <nixpkgs> {}; mkShell { shellHook = "umask 0027"; }' > tmp/umask.nix
+run cached-nix-shell tmp/umask.nix --run umask
+check_contains "^0027$"
+check_slow
+run cached-nix-shell tmp/umask.nix --run umask
+check_contains "^0027$"
+check_fast
+run sh -c 'umask 0002 && cached-nix-shell -p hello --run umask'
+check "the umask of the caller is kept otherwise" grep -qx "0002" tmp/out
+
+# Listings of directories deeper than --trace-dir-depth aren't recorded.
+mkdir -p tmp/depth/a/b
+echo 'with import <nixpkgs> {}; mkShell { A = builtins.attrNames (builtins.readDir ./a); B = builtins.attrNames (builtins.readDir ./a/b); }' > tmp/depth/shell.nix
+run cached-nix-shell --trace-dir-depth 1 tmp/depth/shell.nix --run :
+check_slow
+touch tmp/depth/a/b/new
+run cached-nix-shell --trace-dir-depth 1 tmp/depth/shell.nix --run :
+check_fast
+touch tmp/depth/a/new
+run cached-nix-shell --trace-dir-depth 1 tmp/depth/shell.nix --run :
+check_slow
+
+run cached-nix-shell -p hello --keep-env-file tmp/kept.env --run hello
+check_contains "Hello, world!"
+check "--keep-env-file saves the environment" grep -q '^IN_CACHED_NIX_SHELL="1"$' tmp/kept.env
+run cached-nix-shell -p hello --keep-env-file tmp/missing/kept.env --run hello
+check_contains "Hello, world!"
+check_stderr_contains "Warning: can't write"
+
+run env CACHED_NIX_SHELL_OPTS='--pure --keep "CNS_OPT"' CNS_OPT=kept CNS_OTHER=x \
+	cached-nix-shell -p hello --run 'echo "${CNS_OPT-unset} ${CNS_OTHER-unset}"'
+check_contains "^kept unset$"
+run env CACHED_NIX_SHELL_OPTS='--pure' CNS_OTHER=x \
+	cached-nix-shell --impure -p hello --run 'echo "${CNS_OTHER-unset}"'
+check "the command line overrides CACHED_NIX_SHELL_OPTS" grep -qx "x" tmp/out
+
+# A shellHook running cached-nix-shell for its own shell gets nix-shell,
+# rather than capturing the shell again and again; without the options of
+# cached-nix-shell.  Other shells are cached as usual.
+echo 'with import <nixpkgs> {}; mkShell { shellHook = "[ -n \"$NESTED\" ] || NESTED=1 cached-nix-shell --timeout 1m ${toString ./recursive.nix} --run \"echo nested >&2\""; }' > tmp/recursive.nix
+run timeout 300 cached-nix-shell tmp/recursive.nix --run 'echo outer'
+check_contains "^outer$"
+check_stderr_contains "run while capturing this shell, running nix-shell"
+check_stderr_contains "^nested$"
+echo 'with import <nixpkgs> {}; mkShell { shellHook = "cached-nix-shell -p hello --run hello >&2"; }' > tmp/recursive-other.nix
+run timeout 300 cached-nix-shell tmp/recursive-other.nix --run 'echo outer'
+check_contains "^outer$"
+check_stderr_contains "^Hello, world!$"
+check_stderr_not_contains "run while capturing"
+
+echo 'with import <nixpkgs> {}; mkShell { GREETING = import ./validate-dep.nix; }' > tmp/validate.nix
+echo '"one"' > tmp/validate-dep.nix
+run cached-nix-shell tmp/validate.nix --run :
+cached-nix-shell --validate-only tmp/validate.nix > tmp/out
+check "--validate-only accepts a valid entry" test $? = 0
+check_contains "^ok      open    $PWD/tmp/validate-dep.nix$"
+check_contains "^0 of [0-9]* dependencies changed$"
+check_not_contains "^stale"
+echo '"two"' > tmp/validate-dep.nix
+cached-nix-shell --validate-only tmp/validate.nix > tmp/out
+check "--validate-only rejects a stale entry" test $? = 1
+check_contains "^stale   open    $PWD/tmp/validate-dep.nix$"
+check_contains "^1 of [0-9]* dependencies changed$"
+cached-nix-shell --validate-only -p not-cached-yet 2> tmp/err
+check "--validate-only rejects a missing entry" test $? = 1
+
+echo 'with import <nixpkgs> {}; mkShell { }' > tmp/timings.nix
+run cached-nix-shell --timings tmp/timings.nix --run :
+check_stderr_contains "^cached-nix-shell: timings: lookup .* (miss)$"
+check_stderr_contains "^cached-nix-shell: timings: traced [0-9]* accesses, [0-9]* paths$"
+paths=$(sed -n 's/.*traced [0-9]* accesses, \([0-9]*\) paths$/\1/p' tmp/err)
+deps=$(cached-nix-shell --list-deps tmp/timings.nix | wc -l)
+check "--timings counts the dependencies" test "$paths" = "$deps"
+run cached-nix-shell --timings tmp/timings.nix --run :
+check_stderr_contains "^cached-nix-shell: timings: lookup .* (hit)$"
+check_stderr_not_contains "timings: build"
+
+# The limit of open files set by a shellHook is restored on request.
+echo 'with import <nixpkgs> {}; mkShell { shellHook = "ulimit -Sn 321"; }' > tmp/nofile.nix
+run cached-nix-shell --restore-limits tmp/nofile.nix --run 'ulimit -Sn'
+check_contains "^321$"
+check_slow
+run cached-nix-shell --restore-limits tmp/nofile.nix --run 'ulimit -Sn'
+check_contains "^321$"
+check_fast
+run cached-nix-shell tmp/nofile.nix --run 'ulimit -Sn'
+check "without --restore-limits the limit is left alone" not grep -qx "321" tmp/out
+
+# A failing CACHED_NIX_SHELL_VERIFY_HOOK keeps the shell out of the cache.
+echo 'with import <nixpkgs> {}; mkShell { buildInputs = [ hello ]; }' > tmp/verify-hook.nix
+run env CACHED_NIX_SHELL_VERIFY_HOOK='hello --version | grep -q "^no such version"' \
+	cached-nix-shell tmp/verify-hook.nix --run 'echo ran'
+check_stderr_contains "verify hook .* failed"
+check_not_contains "^ran$"
+env CACHED_NIX_SHELL_VERIFY_HOOK=false cached-nix-shell tmp/verify-hook.nix --run : 2>/dev/null
+check "a failing verify hook exits with 3" test $? = 3
+run env CACHED_NIX_SHELL_VERIFY_HOOK='hello --version | grep -q "^hello"' \
+	cached-nix-shell tmp/verify-hook.nix --run 'echo ran'
+check_contains "^ran$"
+check_slow
+run cached-nix-shell tmp/verify-hook.nix --run 'echo ran'
+check_contains "^ran$"
+check_fast
+
+# A shell whose shellHook fails is used but not cached, unless asked to.
+echo 'with import <nixpkgs> {}; mkShell { shellHook = "export A=1; false"; }' > tmp/failed-hook.nix
+for _ in 1 2; do
+	run cached-nix-shell tmp/failed-hook.nix --run 'echo "$A"'
+	check_contains "^1$"
+	check_stderr_contains "the shellHook failed with status 1, not caching"
+	check_slow
+done
+run cached-nix-shell --cache-failed-hook tmp/failed-hook.nix --run 'echo "$A"'
+check_contains "^1$"
+check_slow
+run cached-nix-shell --cache-failed-hook tmp/failed-hook.nix --run 'echo "$A"'
+check_fast
+
+# Concurrent misses build the shell once, and concurrent hits still work.
+echo 'with import <nixpkgs> {}; mkShell { CONCURRENT = "1"; }' > tmp/concurrent.nix
+for i in 1 2 3; do
+	cached-nix-shell tmp/concurrent.nix --run 'echo "$CONCURRENT"' > tmp/concurrent-$i.out 2> tmp/concurrent-$i.err &
+done
+wait
+check "concurrent misses build once" \
+	test "$(cat tmp/concurrent-*.err | grep -c "updating cache")" = 1
+check "concurrent misses all get the shell" \
+	test "$(cat tmp/concurrent-*.out)" = "$(printf '1\n1\n1')"
+for i in 1 2 3; do
+	cached-nix-shell tmp/concurrent.nix --run 'echo "$CONCURRENT"' > tmp/concurrent-$i.out 2> tmp/concurrent-$i.err &
+done
+wait
+check "concurrent hits don't build" not grep -q "updating cache" tmp/concurrent-*.err
+
+# --trace-filter-store-only ignores project files, but not the key.
+echo 'with import <nixpkgs> {}; mkShell { buildInputs = [ hello ]; A = "1"; }' > tmp/store-only.nix
+run cached-nix-shell --trace-filter-store-only tmp/store-only.nix --run 'echo "$A"'
+check_contains "^1$"
+check_slow
+echo 'with import <nixpkgs> {}; mkShell { buildInputs = [ hello ]; A = "2"; }' > tmp/store-only.nix
+run cached-nix-shell --trace-filter-store-only tmp/store-only.nix --run 'echo "$A"'
+check_contains "^1$"
+check_fast
+run cached-nix-shell --trace-filter-store-only -p hello --run hello
+check_contains "^Hello, world!$"
+check_slow
+run cached-nix-shell tmp/store-only.nix --run 'echo "$A"'
+check_contains "^2$"
+check_slow
+
+# --git-aware keys the shell by the checked out commit.
+rm -rf tmp/git-aware && mkdir tmp/git-aware
+echo 'with import <nixpkgs> {}; mkShell { buildInputs = [ hello ]; }' > tmp/git-aware/shell.nix
+gitc() { git -C tmp/git-aware -c user.name=t -c user.email=t@t "$@"; }
+gitc init -q && gitc add shell.nix && gitc commit -qm 1 && gitc commit -qm 2 --allow-empty
+head=$(gitc rev-parse HEAD)
+run cached-nix-shell --git-aware tmp/git-aware/shell.nix --run hello
+check_slow
+run cached-nix-shell --git-aware tmp/git-aware/shell.nix --run hello
+check_fast
+gitc checkout -q HEAD~
+run cached-nix-shell --git-aware tmp/git-aware/shell.nix --run hello
+check_slow
+gitc checkout -q "$head"
+run cached-nix-shell --git-aware tmp/git-aware/shell.nix --run hello
+check_fast
+
+# Variables defined in terms of others are stored with their values.
+cat > tmp/interdependent.nix <<'EOF'
+with import <nixpkgs> {}; mkShell {
+  shellHook = ''
+    export DATA_DIR="$out/share"
+    export SEARCH_PATH="$DATA_DIR/a:$DATA_DIR/b"
+  '';
+}
+EOF
+for _ in 1 2; do
+	run cached-nix-shell tmp/interdependent.nix --run 'echo "$SEARCH_PATH"; echo "$out"'
+	out=$(sed -n 2p tmp/out)
+	check "\$out is set" test -n "$out"
+	check "interdependent variables are expanded" \
+		test "$(sed -n 1p tmp/out)" = "$out/share/a:$out/share/b"
+done
+
+# --keep-build-dir leaves the scratch files of a build behind.
+mkdir -p tmp/build-tmp
+echo 'with import <nixpkgs> {}; mkShell { KEPT = "1"; }' > tmp/keep-build.nix
+run env TMPDIR=$PWD/tmp/build-tmp cached-nix-shell --keep-build-dir tmp/keep-build.nix --run :
+check_stderr_contains "^cached-nix-shell: build files kept in $PWD/tmp/build-tmp/cached-nix-shell-build"
+dir=$(sed -n 's/^cached-nix-shell: build files kept in //p' tmp/err)
+check "the build dir has the trace" test -f "$dir/trace"
+check "the build dir has the environment" grep -qz "^KEPT=1$" "$dir/env"
+rm -rf tmp/build-tmp/*
+echo 'with import <nixpkgs> {}; mkShell { KEPT = "2"; }' > tmp/keep-build.nix
+run env TMPDIR=$PWD/tmp/build-tmp cached-nix-shell tmp/keep-build.nix --run :
+check_stderr_not_contains "build files kept"
+check "without --keep-build-dir the build dir is removed" \
+	test -z "$(ls tmp/build-tmp | grep cached-nix-shell-build)"
+
+# Shell options and aliases set by a shellHook apply to the command.
+cat > tmp/shopt.nix <<'EOF'
+with import <nixpkgs> {}; mkShell {
+  shellHook = "shopt -s extglob; alias greet='echo hi'";
+}
+EOF
+run cached-nix-shell tmp/shopt.nix --run 'greet; x=abc; echo ${x/+(b)/B}'
+check_contains "^hi$"
+check_contains "^aBc$"
+check_slow
+run cached-nix-shell tmp/shopt.nix --run 'greet; x=abc; echo ${x/+(b)/B}'
+check_contains "^hi$"
+check_contains "^aBc$"
+check_fast
+
+# CACHED_NIX_SHELL_WARN_PURE reports variables dropped by --pure.
+run env TERM=xterm CACHED_NIX_SHELL_WARN_PURE=1 cached-nix-shell --pure -p --run :
+check_stderr_contains "^Warning: --pure drops .*TERM.*, use --keep to keep them$"
+run env TERM=xterm CACHED_NIX_SHELL_WARN_PURE=1 cached-nix-shell --pure --keep TERM -p --run :
+check_stderr_not_contains "drops .*TERM"
+run env TERM=xterm cached-nix-shell --pure -p --run :
+check_stderr_not_contains "drops"
+
+# --cache-key-from keys the entry by the string alone.
+echo 'with import <nixpkgs> {}; mkShell { A = "a"; }' > tmp/key-from.nix
+run cached-nix-shell --cache-key-from gen-1 tmp/key-from.nix --run 'echo "$A"'
+check_contains "^a$"
+check_slow
+run cached-nix-shell --cache-key-from gen-1 tmp/key-from.nix --run 'echo "$A"'
+check_contains "^a$"
+check_fast
+run cached-nix-shell --cache-key-from gen-1 --keep FOO tmp/key-from.nix --run 'echo "$A"'
+check_contains "^a$"
+check_fast
+run cached-nix-shell --cache-key-from gen-2 tmp/key-from.nix --run 'echo "$A"'
+check_contains "^a$"
+check_slow
+
+esc=$(printf '\033[')
+cached-nix-shell --color=always -j 99999 -p hello --run : 2> tmp/err
+check "--color=always colors warnings even to a non-terminal" \
+	grep -qF "${esc}33mWarning:" tmp/err
+cached-nix-shell --color=always --no-color -j 99999 -p hello --run : 2> tmp/err
+check "--no-color suppresses colors" not grep -qF "$esc" tmp/err
+check_stderr_contains "^Warning: "
+if script -qec true /dev/null > /dev/null 2>&1; then
+	NO_COLOR=1 script -qec 'cached-nix-shell -j 99999 -p hello --run :' \
+		/dev/null > tmp/err 2>&1
+	check "NO_COLOR suppresses colors on a terminal" not grep -qF "$esc" tmp/err
+else
+	skip "NO_COLOR on a terminal (no util-linux script)"
+fi
+
+mkdir -p tmp/cwd
+run sh -c 'cd tmp/cwd && cached-nix-shell -p hello --run pwd'
+check_contains "^$PWD/tmp/cwd$"
+run sh -c 'cd tmp/cwd && cached-nix-shell --pure -p hello --run pwd'
+check_contains "^$PWD/tmp/cwd$"
+
+run cached-nix-shell --dump-env=env -p hello
+check_contains "^export IN_CACHED_NIX_SHELL='1'$"
+
+run cached-nix-shell --dump-env=fish -p hello
+check_contains "^set -gx IN_CACHED_NIX_SHELL '1'$"
+
+run cached-nix-shell --list-deps --verbose -p hello
+check_contains "^open    /"
+
+# --gc-cache --dry-run lists the entries it would remove, and keeps them.
+echo 'with import <nixpkgs> {}; mkShell { name = "gc-dead"; }' > tmp/gc-dead.nix
+run cached-nix-shell tmp/gc-dead.nix --run true
+run cached-nix-shell --explain-key tmp/gc-dead.nix
+dead=$(sed -n 's/^key //p' tmp/out)
+ln -sf /nix/store/00000000000000000000000000000000-collected.drv \
+	tmp/cache/cached-nix-shell/$dead.drv
+run cached-nix-shell --gc-cache --dry-run
+check_contains "^$dead  .*/tmp/gc-dead.nix$"
+check_stderr_contains "would remove 1 cache entries"
+check "--dry-run keeps the entry" test -e tmp/cache/cached-nix-shell/$dead.env
+run cached-nix-shell --gc-cache
+check_stderr_contains "removed 1 cache entries"
+check "--gc-cache removes the entry" \
+	not test -e tmp/cache/cached-nix-shell/$dead.env
+
+run cached-nix-shell --export-cache tmp/cache.tar
+ls tmp/cache/cached-nix-shell > tmp/entries-before
+rm -rf tmp/cache/cached-nix-shell
+run cached-nix-shell --import-cache tmp/cache.tar
+check "--import-cache restores entries" \
+	sh -c 'ls tmp/cache/cached-nix-shell | grep -v stats | cmp - tmp/entries-before'
+run cached-nix-shell -p hello --run hello
+check_contains "Hello, world!"
+check_fast
+
+bash=$(command -v bash)
+printf '#!%s\necho "$@" > %s/tmp/capture-bash\nexec %s "$@"\n' \
+	"$bash" "$PWD" "$bash" > tmp/bash
+chmod +x tmp/bash
+rm -f tmp/capture-bash
+run env CACHED_NIX_SHELL_BASH=$PWD/tmp/bash cached-nix-shell -p hello --run hello
+check_contains "Hello, world!"
+check_slow
+check "CACHED_NIX_SHELL_BASH captures the environment" test -e tmp/capture-bash
+
+run cached-nix-shell --verify
+check_contains "ok: trace library records the shell file"
+check_contains "ok: second run is a cache hit"
+check_not_contains "FAIL"
+
+run env CACHED_NIX_SHELL_NO_TRACE=1 cached-nix-shell --verify
+check_contains "FAIL: trace library records the shell file"
+
+run cached-nix-shell --bench 3
+check "--bench reports each hit" test "$(grep -c '^hit [0-9]*: ' tmp/out)" -ge 3
+check_contains "hits: min "
+
 exit $result